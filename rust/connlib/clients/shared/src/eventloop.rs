@@ -141,6 +141,14 @@ where
                 );
                 self.connection_intents.register_new_intent(id, resource);
             }
+            firezone_tunnel::ClientEvent::ConnectionPathChanged {
+                conn_id: gateway,
+                kind,
+                local,
+                remote,
+            } => {
+                tracing::info!(%gateway, ?kind, %local, %remote, "Connection path changed");
+            }
             firezone_tunnel::ClientEvent::RefreshResources { connections } => {
                 for connection in connections {
                     self.portal
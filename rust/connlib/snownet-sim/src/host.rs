@@ -0,0 +1,203 @@
+//! A simulated host running a [`snownet`] node.
+
+use crate::link::Link;
+use crate::network::{HostId, NatId};
+use boringtun::x25519::PublicKey;
+use ip_packet::IpPacket;
+use snownet::{
+    CandidatePolicy, ClientNode, ConnectionStats, Event, NatBehaviour, RelaySocket, ServerNode,
+    Transmit,
+};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use tracing::Span;
+
+/// Calls the same method on either kind of node.
+macro_rules! dispatch {
+    ($node:expr, $n:ident => $call:expr) => {
+        match $node {
+            Node::Client($n) => $call,
+            Node::Server($n) => $call,
+        }
+    };
+}
+
+pub(crate) enum Node {
+    Client(ClientNode<u64, u64>),
+    Server(ServerNode<u64, u64>),
+}
+
+pub struct Host {
+    pub(crate) node: Node,
+    pub(crate) span: Span,
+
+    /// The socket the host sends from, i.e. its address on the LAN if it is behind a NAT.
+    local: SocketAddr,
+    /// The IP the host uses inside the tunnel.
+    tunnel_ip: IpAddr,
+    pub(crate) nat: Option<NatId>,
+    pub(crate) link: Link,
+
+    events: Vec<(Event<u64>, Instant)>,
+    received_packets: Vec<IpPacket<'static>>,
+
+    buffer: Box<[u8; 65535]>,
+}
+
+impl Host {
+    pub(crate) fn new(
+        node: Node,
+        local: SocketAddr,
+        tunnel_ip: IpAddr,
+        nat: Option<NatId>,
+        link: Link,
+        span: Span,
+    ) -> Self {
+        Self {
+            node,
+            span,
+            local,
+            tunnel_ip,
+            nat,
+            link,
+            events: Vec::default(),
+            received_packets: Vec::default(),
+            buffer: Box::new([0u8; 65535]),
+        }
+    }
+
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn tunnel_ip(&self) -> IpAddr {
+        self.tunnel_ip
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        dispatch!(&self.node, n => n.public_key())
+    }
+
+    /// All events the node emitted so far, together with when it emitted them.
+    pub fn events(&self) -> &[(Event<u64>, Instant)] {
+        &self.events
+    }
+
+    /// All IP packets we received through the tunnel.
+    pub fn received_packets(&self) -> &[IpPacket<'static>] {
+        &self.received_packets
+    }
+
+    pub fn connection_stats(&self, remote: HostId) -> Option<ConnectionStats<u64>> {
+        dispatch!(&self.node, n => n
+            .stats()
+            .1
+            .find_map(|(id, stats)| (id == remote.0).then_some(stats)))
+    }
+
+    /// How the node classified the NAT it is behind.
+    pub fn nat_behaviour(&self) -> NatBehaviour {
+        dispatch!(&self.node, n => n.stats().0.nat)
+    }
+
+    pub(crate) fn connection_id(&self, key: PublicKey) -> Option<u64> {
+        dispatch!(&self.node, n => n.connection_id(key))
+    }
+
+    pub(crate) fn switch_network(&mut self, local: SocketAddr, now: Instant) {
+        self.local = local;
+
+        self.span
+            .in_scope(|| dispatch!(&mut self.node, n => n.reconnect(now)));
+        self.add_local_host_candidate();
+    }
+
+    pub(crate) fn add_local_host_candidate(&mut self) {
+        let _guard = self.span.enter();
+
+        if let Err(e) = dispatch!(&mut self.node, n => n.add_local_host_candidate(self.local)) {
+            tracing::warn!("Failed to add host candidate: {e}");
+        }
+    }
+
+    pub(crate) fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        let _guard = self.span.enter();
+        dispatch!(&mut self.node, n => n.set_candidate_policy(policy));
+    }
+
+    pub(crate) fn update_relays(
+        &mut self,
+        relays: &HashSet<(u64, RelaySocket, String, String, String)>,
+        now: Instant,
+    ) {
+        let _guard = self.span.enter();
+        dispatch!(&mut self.node, n => n.update_relays(HashSet::default(), relays, now));
+    }
+
+    pub(crate) fn add_remote_candidate(&mut self, id: u64, candidate: String, now: Instant) {
+        let _guard = self.span.enter();
+        dispatch!(&mut self.node, n => n.add_remote_candidate(id, candidate, now));
+    }
+
+    pub(crate) fn remove_remote_candidate(&mut self, id: u64, candidate: String) {
+        let _guard = self.span.enter();
+        dispatch!(&mut self.node, n => n.remove_remote_candidate(id, candidate));
+    }
+
+    /// Sends an ICMP echo request through the tunnel to the given IP.
+    ///
+    /// Returns `None` if the packet could not be encapsulated, e.g. because the connection is not yet established.
+    pub(crate) fn ping(
+        &mut self,
+        remote: HostId,
+        dst: IpAddr,
+        now: Instant,
+    ) -> Option<Transmit<'static>> {
+        let _guard = self.span.enter();
+        let packet = ip_packet::make::icmp_request_packet(self.tunnel_ip, dst);
+
+        dispatch!(&mut self.node, n => n.encapsulate(remote.0, packet.to_immutable(), now))
+            .inspect_err(|e| tracing::debug!("Failed to encapsulate: {e}"))
+            .ok()?
+            .map(|t| t.into_owned())
+    }
+
+    pub(crate) fn receive(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) {
+        let _guard = self.span.enter();
+
+        match dispatch!(&mut self.node, n => n.decapsulate(local, from, packet, now, self.buffer.as_mut()))
+        {
+            Ok(Some((_, packet))) => self.received_packets.push(packet.to_immutable().to_owned()),
+            Ok(None) => {}
+            Err(e) => tracing::debug!(%local, %from, "Failed to decapsulate: {e}"),
+        }
+    }
+
+    pub(crate) fn poll_transmit(&mut self) -> Option<Transmit<'static>> {
+        dispatch!(&mut self.node, n => n.poll_transmit())
+    }
+
+    /// Returns the next event of the node and records it.
+    pub(crate) fn poll_event(&mut self, now: Instant) -> Option<Event<u64>> {
+        let event = dispatch!(&mut self.node, n => n.poll_event())?;
+        self.events.push((event.clone(), now));
+
+        Some(event)
+    }
+
+    pub(crate) fn poll_timeout(&mut self) -> Option<Instant> {
+        dispatch!(&mut self.node, n => n.poll_timeout())
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let _guard = self.span.enter();
+        dispatch!(&mut self.node, n => n.handle_timeout(now));
+    }
+}
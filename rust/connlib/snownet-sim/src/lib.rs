@@ -0,0 +1,30 @@
+//! A deterministic, in-process network simulator for end-to-end tests of [`snownet`].
+//!
+//! The [`Network`] connects hosts running [`snownet`] nodes and relays running [`firezone_relay::Server`].
+//! Hosts can sit behind a [`Nat`] whose mappings expire without outgoing traffic and each host and relay has a [`Link`] that adds latency, jitter, loss and an MTU.
+//! Time is virtual: [`Network::step`] advances it by a fixed tick, so simulating a minute takes milliseconds.
+//!
+//! ```no_run
+//! # use snownet_sim::{Link, Nat, NatType, Network};
+//! # use std::time::Duration;
+//! let mut network = Network::new(0);
+//! network.add_relay("203.0.113.1:3478".parse::<std::net::SocketAddrV4>().unwrap(), Link::default());
+//!
+//! let nat = network.add_nat(Nat::new(NatType::Symmetric, "198.51.100.1".parse().unwrap()));
+//! let client = network.add_client("192.168.0.2:52625".parse().unwrap(), Some(nat), Link::default());
+//! let server = network.add_server("192.0.2.1:52625".parse().unwrap(), None, Link::default());
+//!
+//! network.connect(client, server);
+//! network.run_until(Duration::from_secs(3), |n| n.is_connected(client, server));
+//! ```
+
+mod host;
+mod link;
+mod nat;
+mod network;
+mod relay;
+
+pub use host::Host;
+pub use link::Link;
+pub use nat::{Nat, NatType};
+pub use network::{HostId, NatId, Network, NetworkStats, RelayId};
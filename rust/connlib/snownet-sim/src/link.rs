@@ -0,0 +1,154 @@
+//! The properties of the access link between a host and the internet.
+
+use rand::Rng;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Size of the IPv4 and UDP headers.
+const IP4_UDP_HEADER_LEN: usize = 20 + 8;
+/// Size of the IPv6 and UDP headers.
+const IP6_UDP_HEADER_LEN: usize = 40 + 8;
+
+/// The access link of a host or relay.
+///
+/// A packet traverses the link of its sender and the link of its receiver.
+/// Jitter delays each packet by a random amount, which reorders packets that are sent close to each other.
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+    mtu: usize,
+}
+
+/// Why a link dropped a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dropped {
+    Loss,
+    ExceedsMtu,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            mtu: 1500,
+        }
+    }
+}
+
+impl Link {
+    /// One-way latency of the link.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+
+        self
+    }
+
+    /// Maximum additional, random delay per packet.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+
+        self
+    }
+
+    /// Probability in `[0, 1]` that a packet gets lost.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        assert!((0.0..=1.0).contains(&loss), "loss must be a probability");
+
+        self.loss = loss;
+
+        self
+    }
+
+    /// Largest IP packet the link can carry, including the IP and UDP headers.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+
+        self
+    }
+
+    /// Computes how long a UDP payload takes to traverse the link or why it is dropped.
+    pub(crate) fn traverse(
+        &self,
+        dst: SocketAddr,
+        payload_len: usize,
+        rng: &mut impl Rng,
+    ) -> Result<Duration, Dropped> {
+        let header_len = match dst {
+            SocketAddr::V4(_) => IP4_UDP_HEADER_LEN,
+            SocketAddr::V6(_) => IP6_UDP_HEADER_LEN,
+        };
+
+        if payload_len + header_len > self.mtu {
+            return Err(Dropped::ExceedsMtu);
+        }
+
+        if self.loss > 0.0 && rng.gen_bool(self.loss) {
+            return Err(Dropped::Loss);
+        }
+
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rng.gen_range(Duration::ZERO..=self.jitter)
+        };
+
+        Ok(self.latency + jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn drops_packets_exceeding_mtu() {
+        let link = Link::default().with_mtu(1280);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(
+            link.traverse(dst(), 1280 - IP4_UDP_HEADER_LEN, &mut rng),
+            Ok(Duration::from_millis(10))
+        );
+        assert_eq!(
+            link.traverse(dst(), 1281 - IP4_UDP_HEADER_LEN, &mut rng),
+            Err(Dropped::ExceedsMtu)
+        );
+    }
+
+    #[test]
+    fn loses_roughly_the_configured_share_of_packets() {
+        let link = Link::default().with_loss(0.25);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let lost = (0..10_000)
+            .filter(|_| link.traverse(dst(), 100, &mut rng) == Err(Dropped::Loss))
+            .count();
+
+        assert!((2_000..3_000).contains(&lost), "lost {lost} packets");
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let link = Link::default()
+            .with_latency(Duration::from_millis(20))
+            .with_jitter(Duration::from_millis(5));
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..1000 {
+            let delay = link.traverse(dst(), 100, &mut rng).unwrap();
+
+            assert!(delay >= Duration::from_millis(20));
+            assert!(delay <= Duration::from_millis(25));
+        }
+    }
+
+    fn dst() -> SocketAddr {
+        "1.1.1.1:3478".parse().unwrap()
+    }
+}
@@ -0,0 +1,315 @@
+//! Network address translation, modelled after the behaviours described in RFC 4787.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// The first port we hand out for mappings.
+const FIRST_MAPPED_PORT: u16 = 40000;
+
+/// For how long a mapping stays alive without outgoing traffic, the minimum RFC 4787 (REQ-5) allows.
+const DEFAULT_MAPPING_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Endpoint-independent mapping and filtering.
+    ///
+    /// Once a mapping exists, anybody can send to it.
+    FullCone,
+    /// Endpoint-independent mapping, address-dependent filtering.
+    ///
+    /// Only IPs we previously sent to can send to a mapping.
+    RestrictedCone,
+    /// Endpoint-independent mapping, address- and port-dependent filtering.
+    ///
+    /// Only sockets we previously sent to can send to a mapping.
+    PortRestrictedCone,
+    /// Address- and port-dependent mapping and filtering.
+    ///
+    /// Every destination gets its own mapping and only that destination can send to it.
+    Symmetric,
+}
+
+/// A NAT device that hosts can sit behind.
+#[derive(Debug)]
+pub struct Nat {
+    kind: NatType,
+    public_ip: IpAddr,
+    hairpinning: bool,
+    mapping_timeout: Duration,
+
+    next_port: u16,
+    /// Maps an internal socket (and for symmetric NATs, the destination) to a public port.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    bindings: HashMap<u16, Binding>,
+}
+
+#[derive(Debug)]
+struct Binding {
+    internal: SocketAddr,
+    /// All destinations we sent to from this binding.
+    remotes: HashSet<SocketAddr>,
+    /// Only outgoing traffic keeps a binding alive, see RFC 4787 (REQ-6).
+    last_outbound: Instant,
+}
+
+impl Nat {
+    pub fn new(kind: NatType, public_ip: IpAddr) -> Self {
+        Self {
+            kind,
+            public_ip,
+            hairpinning: false,
+            mapping_timeout: DEFAULT_MAPPING_TIMEOUT,
+            next_port: FIRST_MAPPED_PORT,
+            mappings: HashMap::default(),
+            bindings: HashMap::default(),
+        }
+    }
+
+    /// Allow hosts behind this NAT to reach each other via their public mappings.
+    pub fn with_hairpinning(mut self) -> Self {
+        self.hairpinning = true;
+
+        self
+    }
+
+    /// Sets for how long a mapping stays alive without outgoing traffic.
+    pub fn with_mapping_timeout(mut self, timeout: Duration) -> Self {
+        self.mapping_timeout = timeout;
+
+        self
+    }
+
+    pub fn kind(&self) -> NatType {
+        self.kind
+    }
+
+    pub fn public_ip(&self) -> IpAddr {
+        self.public_ip
+    }
+
+    pub fn supports_hairpinning(&self) -> bool {
+        self.hairpinning
+    }
+
+    /// Translates the source of an outgoing packet, creating a mapping if necessary.
+    pub fn outbound(&mut self, internal: SocketAddr, dst: SocketAddr, now: Instant) -> SocketAddr {
+        let key = match self.kind {
+            NatType::Symmetric => (internal, Some(dst)),
+            NatType::FullCone | NatType::RestrictedCone | NatType::PortRestrictedCone => {
+                (internal, None)
+            }
+        };
+
+        if let Some(port) = self.mappings.get(&key).copied() {
+            if self.is_expired(port, now) {
+                tracing::trace!(target: "nat", %internal, %port, "Mapping expired");
+
+                self.mappings.remove(&key);
+                self.bindings.remove(&port);
+            }
+        }
+
+        let port = match self.mappings.get(&key) {
+            Some(port) => *port,
+            None => {
+                let port = self.next_port;
+                self.next_port = self
+                    .next_port
+                    .checked_add(1)
+                    .expect("to not run out of ports");
+
+                self.mappings.insert(key, port);
+                self.bindings.insert(
+                    port,
+                    Binding {
+                        internal,
+                        remotes: HashSet::default(),
+                        last_outbound: now,
+                    },
+                );
+
+                tracing::trace!(target: "nat", %internal, %dst, %port, "Created new mapping");
+
+                port
+            }
+        };
+
+        let binding = self
+            .bindings
+            .get_mut(&port)
+            .expect("every mapping to have a binding");
+        binding.remotes.insert(dst);
+        binding.last_outbound = now;
+
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    /// Translates the destination of an incoming packet.
+    ///
+    /// Returns `None` if there is no live mapping for the port or our filtering behaviour drops the packet.
+    pub fn inbound(&self, from: SocketAddr, public_port: u16, now: Instant) -> Option<SocketAddr> {
+        if self.is_expired(public_port, now) {
+            tracing::trace!(target: "nat", %from, %public_port, "Dropping incoming packet for expired mapping");
+
+            return None;
+        }
+
+        let binding = self.bindings.get(&public_port)?;
+
+        let allowed = match self.kind {
+            NatType::FullCone => true,
+            NatType::RestrictedCone => binding.remotes.iter().any(|r| r.ip() == from.ip()),
+            NatType::PortRestrictedCone | NatType::Symmetric => binding.remotes.contains(&from),
+        };
+
+        if !allowed {
+            tracing::trace!(target: "nat", %from, %public_port, "Filtered incoming packet");
+
+            return None;
+        }
+
+        Some(binding.internal)
+    }
+
+    /// The internal socket a public port maps to, regardless of filtering.
+    pub(crate) fn peek(&self, public_port: u16, now: Instant) -> Option<SocketAddr> {
+        if self.is_expired(public_port, now) {
+            return None;
+        }
+
+        Some(self.bindings.get(&public_port)?.internal)
+    }
+
+    fn is_expired(&self, public_port: u16, now: Instant) -> bool {
+        self.bindings
+            .get(&public_port)
+            .is_some_and(|b| now >= b.last_outbound + self.mapping_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERNAL: &str = "192.168.0.2:5000";
+    const SERVER_1: &str = "1.1.1.1:3478";
+    const SERVER_2: &str = "2.2.2.2:3478";
+
+    #[test]
+    fn full_cone_accepts_traffic_from_anybody() {
+        let now = Instant::now();
+        let mut nat = Nat::new(NatType::FullCone, ip("9.9.9.9"));
+
+        let mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+
+        assert_eq!(
+            nat.inbound(s(SERVER_2), mapped.port(), now),
+            Some(s(INTERNAL))
+        );
+    }
+
+    #[test]
+    fn restricted_cone_filters_by_ip() {
+        let now = Instant::now();
+        let mut nat = Nat::new(NatType::RestrictedCone, ip("9.9.9.9"));
+
+        let mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+
+        assert_eq!(
+            nat.inbound(s("1.1.1.1:1234"), mapped.port(), now),
+            Some(s(INTERNAL))
+        );
+        assert_eq!(nat.inbound(s(SERVER_2), mapped.port(), now), None);
+    }
+
+    #[test]
+    fn port_restricted_cone_filters_by_socket() {
+        let now = Instant::now();
+        let mut nat = Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9"));
+
+        let mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+
+        assert_eq!(
+            nat.inbound(s(SERVER_1), mapped.port(), now),
+            Some(s(INTERNAL))
+        );
+        assert_eq!(nat.inbound(s("1.1.1.1:1234"), mapped.port(), now), None);
+    }
+
+    #[test]
+    fn cone_nats_reuse_mapping_for_all_destinations() {
+        let now = Instant::now();
+        let mut nat = Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9"));
+
+        let mapped_1 = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+        let mapped_2 = nat.outbound(s(INTERNAL), s(SERVER_2), now);
+
+        assert_eq!(mapped_1, mapped_2);
+    }
+
+    #[test]
+    fn symmetric_nat_creates_mapping_per_destination() {
+        let now = Instant::now();
+        let mut nat = Nat::new(NatType::Symmetric, ip("9.9.9.9"));
+
+        let mapped_1 = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+        let mapped_2 = nat.outbound(s(INTERNAL), s(SERVER_2), now);
+
+        assert_ne!(mapped_1, mapped_2);
+        assert_eq!(nat.inbound(s(SERVER_2), mapped_1.port(), now), None);
+        assert_eq!(
+            nat.inbound(s(SERVER_2), mapped_2.port(), now),
+            Some(s(INTERNAL))
+        );
+    }
+
+    #[test]
+    fn mapping_expires_without_outbound_traffic() {
+        let mut now = Instant::now();
+        let mut nat = Nat::new(NatType::FullCone, ip("9.9.9.9"))
+            .with_mapping_timeout(Duration::from_secs(30));
+
+        let mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+
+        now += Duration::from_secs(20);
+        assert_eq!(
+            nat.inbound(s(SERVER_1), mapped.port(), now),
+            Some(s(INTERNAL))
+        );
+
+        // Incoming traffic doesn't keep the mapping alive.
+        now += Duration::from_secs(10);
+        assert_eq!(nat.inbound(s(SERVER_1), mapped.port(), now), None);
+
+        let new_mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+        assert_ne!(mapped, new_mapped);
+    }
+
+    #[test]
+    fn outbound_traffic_keeps_mapping_alive() {
+        let mut now = Instant::now();
+        let mut nat = Nat::new(NatType::FullCone, ip("9.9.9.9"))
+            .with_mapping_timeout(Duration::from_secs(30));
+
+        let mapped = nat.outbound(s(INTERNAL), s(SERVER_1), now);
+
+        for _ in 0..5 {
+            now += Duration::from_secs(20);
+            assert_eq!(nat.outbound(s(INTERNAL), s(SERVER_1), now), mapped);
+        }
+
+        assert_eq!(
+            nat.inbound(s(SERVER_1), mapped.port(), now),
+            Some(s(INTERNAL))
+        );
+    }
+
+    fn s(socket: &str) -> SocketAddr {
+        socket.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+}
@@ -0,0 +1,637 @@
+use crate::host::{Host, Node};
+use crate::link::{Dropped, Link};
+use crate::nat::Nat;
+use crate::relay::Relay;
+use boringtun::x25519::StaticSecret;
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
+use snownet::{CandidatePolicy, ClientNode, ConnectionStats, Event, RelaySocket, ServerNode};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::debug_span;
+
+/// How far we advance time on each step.
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HostId(pub(crate) u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RelayId(pub(crate) u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NatId(usize);
+
+impl fmt::Display for HostId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Host({})", self.0)
+    }
+}
+
+impl fmt::Display for RelayId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Relay({})", self.0)
+    }
+}
+
+/// Counters of what happened to the packets sent across the network.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub delivered: usize,
+    /// Packets dropped due to the loss configured on a [`Link`].
+    pub lost: usize,
+    /// Packets dropped because they exceeded the MTU of a [`Link`].
+    pub exceeded_mtu: usize,
+    /// Packets dropped by a [`Nat`], either due to filtering or a missing mapping.
+    pub filtered: usize,
+    /// Packets to an address nobody listens on.
+    pub unroutable: usize,
+}
+
+/// An in-process network, driven by virtual time.
+///
+/// All randomness (keys, loss, jitter and that of the [`snownet`] nodes) is derived from the seed passed to [`Network::new`].
+/// Running the same scenario with the same seed thus yields the same events and [`NetworkStats`].
+pub struct Network {
+    now: Instant,
+    start: Instant,
+    rng: StdRng,
+
+    hosts: BTreeMap<HostId, Host>,
+    relays: BTreeMap<RelayId, Relay>,
+    nats: Vec<Nat>,
+
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_id: u64,
+    next_seq: u64,
+
+    stats: NetworkStats,
+}
+
+/// Who sent or receives a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Host(HostId),
+    Relay(RelayId),
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    deliver_at: Instant,
+    /// Breaks ties between packets delivered at the same time in the order they were sent.
+    seq: u64,
+
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// The NAT the packet is still behind, i.e. it was sent to another host on the same LAN.
+    lan: Option<NatId>,
+    payload: Vec<u8>,
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Self {
+        let now = Instant::now();
+
+        Self {
+            now,
+            start: now,
+            rng: StdRng::seed_from_u64(seed),
+            hosts: BTreeMap::default(),
+            relays: BTreeMap::default(),
+            nats: Vec::default(),
+            in_flight: BinaryHeap::default(),
+            next_id: 0,
+            next_seq: 0,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// How much virtual time passed since the network was created.
+    pub fn elapsed(&self) -> Duration {
+        self.now.duration_since(self.start)
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    pub fn add_nat(&mut self, nat: Nat) -> NatId {
+        self.nats.push(nat);
+
+        NatId(self.nats.len() - 1)
+    }
+
+    /// Adds a relay that is reachable from everywhere and configures it on all hosts.
+    pub fn add_relay(&mut self, listen_addr: impl Into<RelaySocket>, link: Link) -> RelayId {
+        let id = RelayId(self.next_id());
+        let rng = StdRng::seed_from_u64(self.rng.gen());
+
+        self.relays.insert(
+            id,
+            Relay::new(listen_addr.into(), link, rng, debug_span!("Relay", %id)),
+        );
+
+        for host in self.hosts.values_mut() {
+            let relays = relay_credentials(&self.relays, host);
+            host.update_relays(&relays, self.now);
+        }
+
+        id
+    }
+
+    /// Adds a host running a [`ClientNode`], e.g. a Firezone client.
+    ///
+    /// If the host is behind a NAT, `local` is its address on the NAT's LAN.
+    pub fn add_client(&mut self, local: SocketAddr, nat: Option<NatId>, link: Link) -> HostId {
+        let key = StaticSecret::random_from_rng(&mut self.rng);
+        let node = ClientNode::with_seed(key, self.rng.gen());
+
+        self.add_host(Node::Client(node), local, nat, link)
+    }
+
+    /// Adds a host running a [`ServerNode`], e.g. a Firezone gateway.
+    ///
+    /// If the host is behind a NAT, `local` is its address on the NAT's LAN.
+    pub fn add_server(&mut self, local: SocketAddr, nat: Option<NatId>, link: Link) -> HostId {
+        let key = StaticSecret::random_from_rng(&mut self.rng);
+        let node = ServerNode::with_seed(key, self.rng.gen());
+
+        self.add_host(Node::Server(node), local, nat, link)
+    }
+
+    pub fn host(&self, id: HostId) -> &Host {
+        &self.hosts[&id]
+    }
+
+    pub fn nat(&self, id: NatId) -> &Nat {
+        &self.nats[id.0]
+    }
+
+    /// Sets which of its candidates a host uses and signals, see [`CandidatePolicy`].
+    pub fn set_candidate_policy(&mut self, id: HostId, policy: CandidatePolicy) {
+        self.hosts
+            .get_mut(&id)
+            .expect("unknown host")
+            .set_candidate_policy(policy);
+    }
+
+    /// Sets up a connection between a client and a server, exchanging offer and answer instantly.
+    ///
+    /// Candidates are signalled to the remote host on the next step.
+    pub fn connect(&mut self, client: HostId, server: HostId) {
+        let now = self.now;
+
+        let mut client_host = self.hosts.remove(&client).expect("unknown client");
+        let server_host = self.hosts.get_mut(&server).expect("unknown server");
+
+        let (Node::Client(client_node), Node::Server(server_node)) =
+            (&mut client_host.node, &mut server_host.node)
+        else {
+            panic!("can only connect a client to a server");
+        };
+
+        let offer = client_host.span.in_scope(|| {
+            client_node.new_connection(server.0, HashSet::default(), HashSet::default(), now, now)
+        });
+        let answer = server_host.span.in_scope(|| {
+            server_node.accept_connection(
+                client.0,
+                offer,
+                client_node.public_key(),
+                HashSet::default(),
+                HashSet::default(),
+                now,
+            )
+        });
+        client_host.span.in_scope(|| {
+            client_node.accept_answer(server.0, server_node.public_key(), answer, now)
+        });
+
+        self.hosts.insert(client, client_host);
+    }
+
+    /// Whether `a` has completed a wireguard handshake with `b`.
+    pub fn is_connected(&self, a: HostId, b: HostId) -> bool {
+        self.host(a)
+            .connection_id(self.host(b).public_key())
+            .is_some()
+    }
+
+    pub fn connection_stats(&self, a: HostId, b: HostId) -> Option<ConnectionStats<u64>> {
+        self.host(a).connection_stats(b)
+    }
+
+    /// Sends an ICMP echo request from `a` to `b` through the tunnel.
+    ///
+    /// Returns `false` if `a` could not encapsulate the packet.
+    pub fn ping(&mut self, a: HostId, b: HostId) -> bool {
+        let dst = self.host(b).tunnel_ip();
+        let now = self.now;
+
+        let Some(transmit) = self
+            .hosts
+            .get_mut(&a)
+            .expect("unknown host")
+            .ping(b, dst, now)
+        else {
+            return false;
+        };
+
+        self.send_from_host(a, transmit.src, transmit.dst, transmit.payload.into_owned());
+
+        true
+    }
+
+    /// How many IP packets `b` received from `a` through the tunnel.
+    pub fn num_received(&self, a: HostId, b: HostId) -> usize {
+        let src = self.host(a).tunnel_ip();
+
+        self.host(b)
+            .received_packets()
+            .iter()
+            .filter(|p| p.source() == src)
+            .count()
+    }
+
+    /// Moves a host to a different network, e.g. from Wi-Fi to cellular, and tells its node to reconnect.
+    ///
+    /// Packets sent from the old address are dropped from now on.
+    pub fn switch_network(&mut self, id: HostId, local: SocketAddr, nat: Option<NatId>) {
+        let now = self.now;
+        let host = self.hosts.get_mut(&id).expect("unknown host");
+
+        host.nat = nat;
+        host.switch_network(local, now);
+    }
+
+    /// Advances time by the given duration.
+    pub fn advance(&mut self, duration: Duration) {
+        let until = self.now + duration;
+
+        while self.now < until {
+            self.step();
+        }
+    }
+
+    /// Advances time until `condition` holds or `timeout` elapsed.
+    ///
+    /// Returns how long it took for `condition` to hold or `None` if it didn't within `timeout`.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut condition: impl FnMut(&Network) -> bool,
+    ) -> Option<Duration> {
+        let start = self.now;
+        let deadline = start + timeout;
+
+        loop {
+            if condition(self) {
+                return Some(self.now.duration_since(start));
+            }
+
+            if self.now >= deadline {
+                return None;
+            }
+
+            self.step();
+        }
+    }
+
+    /// Advances time by a single tick.
+    pub fn step(&mut self) {
+        self.now += TICK;
+        let now = self.now;
+
+        self.signal_events();
+
+        let host_ids = self.hosts.keys().copied().collect::<Vec<_>>();
+        for id in &host_ids {
+            while let Some(transmit) = self.hosts.get_mut(id).unwrap().poll_transmit() {
+                self.send_from_host(
+                    *id,
+                    transmit.src,
+                    transmit.dst,
+                    transmit.payload.into_owned(),
+                );
+            }
+        }
+        self.send_relay_transmits();
+
+        while self
+            .in_flight
+            .peek()
+            .is_some_and(|Reverse(p)| p.deliver_at <= now)
+        {
+            let Reverse(packet) = self.in_flight.pop().unwrap();
+
+            self.deliver(packet);
+            self.send_relay_transmits();
+        }
+
+        for id in &host_ids {
+            let host = self.hosts.get_mut(id).unwrap();
+
+            if host.poll_timeout().is_some_and(|t| t <= now) {
+                host.handle_timeout(now);
+            }
+        }
+        for relay in self.relays.values_mut() {
+            if relay.poll_timeout().is_some_and(|t| t <= now) {
+                relay.handle_timeout(now);
+            }
+        }
+        self.send_relay_transmits();
+
+        if self.elapsed().as_millis() % 60_000 == 0 {
+            tracing::info!("Time since start: {:?}", self.elapsed())
+        }
+    }
+
+    fn add_host(
+        &mut self,
+        node: Node,
+        local: SocketAddr,
+        nat: Option<NatId>,
+        link: Link,
+    ) -> HostId {
+        let id = HostId(self.next_id());
+        let tunnel_ip = IpAddr::V4(Ipv4Addr::from(0x6440_0000 + id.0 as u32)); // 100.64.0.0/10
+
+        let mut host = Host::new(node, local, tunnel_ip, nat, link, debug_span!("Host", %id));
+        host.add_local_host_candidate();
+        host.update_relays(&relay_credentials(&self.relays, &host), self.now);
+
+        self.hosts.insert(id, host);
+
+        id
+    }
+
+    /// Forwards the candidates the hosts emitted to the respective remote hosts.
+    fn signal_events(&mut self) {
+        let now = self.now;
+        let mut signalled = Vec::new();
+
+        for (id, host) in self.hosts.iter_mut() {
+            while let Some(event) = host.poll_event(now) {
+                signalled.push((*id, event));
+            }
+        }
+
+        for (from, event) in signalled {
+            match event {
+                Event::NewIceCandidate {
+                    connection,
+                    candidate,
+                } => {
+                    if let Some(remote) = self.hosts.get_mut(&HostId(connection)) {
+                        remote.add_remote_candidate(from.0, candidate, now);
+                    }
+                }
+                Event::InvalidateIceCandidate {
+                    connection,
+                    candidate,
+                } => {
+                    if let Some(remote) = self.hosts.get_mut(&HostId(connection)) {
+                        remote.remove_remote_candidate(from.0, candidate);
+                    }
+                }
+                Event::ConnectionEstablished(_)
+                | Event::ConnectionPathChanged { .. }
+                | Event::ConnectionFailed(_) => {}
+            }
+        }
+    }
+
+    fn send_relay_transmits(&mut self) {
+        let mut transmits = Vec::new();
+
+        for (id, relay) in self.relays.iter_mut() {
+            while let Some((src, dst, payload)) = relay.poll_transmit() {
+                transmits.push((*id, src, dst, payload));
+            }
+        }
+
+        for (id, src, dst, payload) in transmits {
+            self.send(Endpoint::Relay(id), src, None, dst, payload);
+        }
+    }
+
+    fn send_from_host(
+        &mut self,
+        id: HostId,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: Vec<u8>,
+    ) {
+        let host = &self.hosts[&id];
+        let local = host.local();
+
+        // The node doesn't specify a source when talking to relays, we send those from the primary interface.
+        if src.is_some_and(|src| src != local) {
+            tracing::debug!(target: "network", %id, ?src, %dst, "Dropping packet from address the host no longer has");
+            self.stats.unroutable += 1;
+            return;
+        }
+
+        let Some(nat_id) = host.nat else {
+            self.send(Endpoint::Host(id), local, None, dst, payload);
+            return;
+        };
+
+        let is_on_same_lan = self
+            .hosts
+            .values()
+            .any(|h| h.nat == Some(nat_id) && h.local() == dst);
+        if is_on_same_lan {
+            self.send(Endpoint::Host(id), local, Some(nat_id), dst, payload);
+            return;
+        }
+
+        let nat = &mut self.nats[nat_id.0];
+
+        if dst.ip() == nat.public_ip() && !nat.supports_hairpinning() {
+            tracing::debug!(target: "nat", %id, %dst, "Dropping hairpinned packet");
+            self.stats.filtered += 1;
+            return;
+        }
+
+        let src = nat.outbound(local, dst, self.now);
+
+        self.send(Endpoint::Host(id), src, None, dst, payload);
+    }
+
+    /// Puts a packet on the wire, applying the links of sender and receiver.
+    fn send(
+        &mut self,
+        from: Endpoint,
+        src: SocketAddr,
+        lan: Option<NatId>,
+        dst: SocketAddr,
+        payload: Vec<u8>,
+    ) {
+        let sender_link = self.link(from);
+        let receiver_link = self
+            .resolve(dst, lan)
+            .map(|to| self.link(to))
+            .unwrap_or_default();
+
+        let delay = sender_link
+            .traverse(dst, payload.len(), &mut self.rng)
+            .and_then(|d1| {
+                let d2 = receiver_link.traverse(dst, payload.len(), &mut self.rng)?;
+
+                Ok(d1 + d2)
+            });
+
+        let delay = match delay {
+            Ok(delay) => delay,
+            Err(Dropped::Loss) => {
+                tracing::trace!(target: "network", %src, %dst, "Packet lost");
+                self.stats.lost += 1;
+                return;
+            }
+            Err(Dropped::ExceedsMtu) => {
+                tracing::debug!(target: "network", %src, %dst, num_bytes = %payload.len(), "Packet exceeds MTU");
+                self.stats.exceeded_mtu += 1;
+                return;
+            }
+        };
+
+        self.in_flight.push(Reverse(InFlight {
+            deliver_at: self.now + delay,
+            seq: self.next_seq,
+            src,
+            dst,
+            lan,
+            payload,
+        }));
+        self.next_seq += 1;
+    }
+
+    fn deliver(&mut self, packet: InFlight) {
+        let now = self.now;
+        let InFlight {
+            src,
+            dst,
+            lan,
+            payload,
+            ..
+        } = packet;
+
+        if let Some(relay) = self.relays.values_mut().find(|r| r.wants(dst)) {
+            relay.handle_input(src, dst, &payload, now);
+            self.stats.delivered += 1;
+            return;
+        }
+
+        if lan.is_none() {
+            if let Some(nat) = self.nats.iter().position(|n| n.public_ip() == dst.ip()) {
+                let Some(internal) = self.nats[nat].inbound(src, dst.port(), now) else {
+                    self.stats.filtered += 1;
+                    return;
+                };
+
+                match self.host_at(internal, Some(NatId(nat))) {
+                    Some(host) => {
+                        self.hosts
+                            .get_mut(&host)
+                            .unwrap()
+                            .receive(internal, src, &payload, now);
+                        self.stats.delivered += 1;
+                    }
+                    None => self.stats.unroutable += 1,
+                }
+
+                return;
+            }
+        }
+
+        match self.host_at(dst, lan) {
+            Some(host) => {
+                self.hosts
+                    .get_mut(&host)
+                    .unwrap()
+                    .receive(dst, src, &payload, now);
+                self.stats.delivered += 1;
+            }
+            None => {
+                tracing::debug!(target: "network", %src, %dst, "No route to host");
+                self.stats.unroutable += 1;
+            }
+        }
+    }
+
+    /// Who would receive a packet sent to `dst`, without modifying any NAT state.
+    fn resolve(&self, dst: SocketAddr, lan: Option<NatId>) -> Option<Endpoint> {
+        if let Some((id, _)) = self.relays.iter().find(|(_, r)| r.wants(dst)) {
+            return Some(Endpoint::Relay(*id));
+        }
+
+        if lan.is_none() {
+            if let Some(nat) = self.nats.iter().position(|n| n.public_ip() == dst.ip()) {
+                let internal = self.nats[nat].peek(dst.port(), self.now)?;
+
+                return self.host_at(internal, Some(NatId(nat))).map(Endpoint::Host);
+            }
+        }
+
+        self.host_at(dst, lan).map(Endpoint::Host)
+    }
+
+    /// The host with the given local address that is reachable from the given LAN.
+    ///
+    /// Hosts behind a NAT are only reachable from within the same LAN, all others are reachable from everywhere.
+    fn host_at(&self, local: SocketAddr, lan: Option<NatId>) -> Option<HostId> {
+        self.hosts
+            .iter()
+            .find(|(_, h)| h.local() == local && (h.nat.is_none() || h.nat == lan))
+            .map(|(id, _)| *id)
+    }
+
+    fn link(&self, endpoint: Endpoint) -> Link {
+        match endpoint {
+            Endpoint::Host(id) => self.hosts[&id].link,
+            Endpoint::Relay(id) => self.relays[&id].link,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        id
+    }
+}
+
+/// The TURN credentials of all relays for the given host.
+fn relay_credentials(
+    relays: &BTreeMap<RelayId, Relay>,
+    host: &Host,
+) -> HashSet<(u64, RelaySocket, String, String, String)> {
+    let username = match host.node {
+        Node::Client(_) => "client",
+        Node::Server(_) => "server",
+    };
+
+    relays
+        .iter()
+        .map(|(id, relay)| {
+            let (username, password) = relay.make_credentials(username);
+
+            (
+                id.0,
+                relay.listen_addr(),
+                username,
+                password,
+                "firezone".to_owned(),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,193 @@
+//! Drives a [`firezone_relay::Server`] inside the simulated network.
+
+use crate::link::Link;
+use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, Command, IpStack, PeerSocket};
+use rand::rngs::StdRng;
+use snownet::RelaySocket;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::Span;
+
+pub(crate) struct Relay {
+    inner: firezone_relay::Server<StdRng>,
+    listen_addr: RelaySocket,
+    pub(crate) link: Link,
+    span: Span,
+
+    allocations: HashSet<(AddressFamily, AllocationPort)>,
+    /// Packets the relay wants to send, as `(src, dst, payload)`.
+    transmits: VecDeque<(SocketAddr, SocketAddr, Vec<u8>)>,
+}
+
+impl Relay {
+    pub(crate) fn new(listen_addr: RelaySocket, link: Link, rng: StdRng, span: Span) -> Self {
+        Self {
+            inner: firezone_relay::Server::new(to_ip_stack(listen_addr), rng, 49152, 65535),
+            listen_addr,
+            link,
+            span,
+            allocations: HashSet::default(),
+            transmits: VecDeque::default(),
+        }
+    }
+
+    pub(crate) fn listen_addr(&self) -> RelaySocket {
+        self.listen_addr
+    }
+
+    /// Whether the relay listens on the given socket, either as a TURN server or for one of its allocations.
+    pub(crate) fn wants(&self, dst: SocketAddr) -> bool {
+        if self.listen_addr.matches(dst) {
+            return true;
+        }
+
+        let family = match dst {
+            SocketAddr::V4(dst) if self.listen_addr.as_v4().is_some_and(|s| s.ip() == dst.ip()) => {
+                AddressFamily::V4
+            }
+            SocketAddr::V6(dst) if self.listen_addr.as_v6().is_some_and(|s| s.ip() == dst.ip()) => {
+                AddressFamily::V6
+            }
+            _ => return false,
+        };
+
+        self.allocations
+            .contains(&(family, AllocationPort::new(dst.port())))
+    }
+
+    pub(crate) fn handle_input(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+        now: Instant,
+    ) {
+        let span = self.span.clone();
+        let _guard = span.enter();
+
+        if self.listen_addr.matches(dst) {
+            if let Some((port, peer)) =
+                self.inner
+                    .handle_client_input(payload, ClientSocket::new(src), now)
+            {
+                // The `dst` of the relayed packet is what TURN calls a "peer".
+                let peer = peer.into_socket();
+
+                // The relayed packet is sent from the allocated port.
+                let src = SocketAddr::new(
+                    self.matching_listen_socket(peer)
+                        .expect("to have an allocation for the peer's address family")
+                        .ip(),
+                    port.value(),
+                );
+
+                self.transmits.push_back((src, peer, payload[4..].to_vec()));
+            }
+        } else if let Some((client, channel)) = self.inner.handle_peer_traffic(
+            payload,
+            PeerSocket::new(src),
+            AllocationPort::new(dst.port()),
+            now,
+        ) {
+            let mut buffer = vec![0u8; 4 + payload.len()];
+            let full_length = firezone_relay::ChannelData::encode_header_to_slice(
+                channel,
+                payload.len() as u16,
+                &mut buffer[..4],
+            );
+            buffer[4..full_length].copy_from_slice(payload);
+
+            let client = client.into_socket();
+            let src = self
+                .matching_listen_socket(client)
+                .expect("to listen on the client's address family");
+
+            self.transmits.push_back((src, client, buffer));
+        }
+
+        self.drain_commands();
+    }
+
+    pub(crate) fn poll_transmit(&mut self) -> Option<(SocketAddr, SocketAddr, Vec<u8>)> {
+        self.transmits.pop_front()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.span.in_scope(|| self.inner.handle_timeout(now));
+        self.drain_commands();
+    }
+
+    /// Creates credentials for the given username that are valid for the next hour.
+    pub(crate) fn make_credentials(&self, username: &str) -> (String, String) {
+        let expiry = SystemTime::now() + Duration::from_secs(60 * 60);
+
+        let secs = expiry
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("expiry must be later than UNIX_EPOCH")
+            .as_secs();
+
+        let password =
+            firezone_relay::auth::generate_password(self.inner.auth_secret(), expiry, username);
+
+        (format!("{secs}:{username}"), password)
+    }
+
+    fn drain_commands(&mut self) {
+        while let Some(command) = self.inner.next_command() {
+            match command {
+                Command::SendMessage { payload, recipient } => {
+                    let recipient = recipient.into_socket();
+                    let src = self
+                        .matching_listen_socket(recipient)
+                        .expect("to listen on the client's address family");
+
+                    self.transmits.push_back((src, recipient, payload));
+                }
+                Command::CreateAllocation { port, family, .. } => {
+                    self.allocations.insert((family, port));
+                }
+                Command::FreeAllocation { port, family } => {
+                    self.allocations.remove(&(family, port));
+                }
+                Command::RelayToPeer {
+                    payload,
+                    port,
+                    peer,
+                } => {
+                    let peer = peer.into_socket();
+                    let src = SocketAddr::new(
+                        self.matching_listen_socket(peer)
+                            .expect("to have an allocation for the peer's address family")
+                            .ip(),
+                        port.value(),
+                    );
+
+                    self.transmits.push_back((src, peer, payload));
+                }
+            }
+        }
+    }
+
+    fn matching_listen_socket(&self, other: SocketAddr) -> Option<SocketAddr> {
+        match other {
+            SocketAddr::V4(_) => Some(SocketAddr::V4(*self.listen_addr.as_v4()?)),
+            SocketAddr::V6(_) => Some(SocketAddr::V6(*self.listen_addr.as_v6()?)),
+        }
+    }
+}
+
+fn to_ip_stack(socket: RelaySocket) -> IpStack {
+    match socket {
+        RelaySocket::V4(v4) => IpStack::Ip4(*v4.ip()),
+        RelaySocket::V6(v6) => IpStack::Ip6(*v6.ip()),
+        RelaySocket::Dual { v4, v6 } => IpStack::Dual {
+            ip4: *v4.ip(),
+            ip6: *v6.ip(),
+        },
+    }
+}
@@ -0,0 +1,273 @@
+use snownet::{
+    CandidatePolicy, Event, FilteringBehaviour, MappingBehaviour, NatBehaviour, PathKind,
+};
+use snownet_sim::{HostId, Link, Nat, NatType, Network};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[test]
+fn connects_directly_without_nat() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let client = network.add_client(s("1.1.1.1:52625"), None, Link::default());
+    let server = network.add_server(s("2.2.2.2:52625"), None, Link::default());
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(2));
+
+    assert_eq!(path(&network, client, server), PathKind::Host);
+}
+
+#[test]
+fn port_restricted_cone_nats_on_both_sides_connect_directly() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let client_nat = network.add_nat(Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9")));
+    let server_nat = network.add_nat(Nat::new(NatType::PortRestrictedCone, ip("8.8.8.8")));
+    let client = network.add_client(s("192.168.0.2:52625"), Some(client_nat), Link::default());
+    let server = network.add_server(s("10.0.0.2:52625"), Some(server_nat), Link::default());
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(3));
+
+    assert_ne!(path(&network, client, server), PathKind::Relayed);
+}
+
+#[test]
+fn symmetric_nat_on_both_sides_falls_back_to_relay_within_3s() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let client_nat = network.add_nat(Nat::new(NatType::Symmetric, ip("9.9.9.9")));
+    let server_nat = network.add_nat(Nat::new(NatType::Symmetric, ip("8.8.8.8")));
+    let client = network.add_client(s("192.168.0.2:52625"), Some(client_nat), Link::default());
+    let server = network.add_server(s("10.0.0.2:52625"), Some(server_nat), Link::default());
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(3));
+
+    assert_eq!(path(&network, client, server), PathKind::Relayed);
+    assert!(network.stats().filtered > 0, "NATs to drop direct traffic");
+}
+
+#[test]
+fn no_host_policy_does_not_reveal_local_address_behind_nat() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let client_nat = network.add_nat(Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9")));
+    let client = network.add_client(s("192.168.0.2:52625"), Some(client_nat), Link::default());
+    let server = network.add_server(s("2.2.2.2:52625"), None, Link::default());
+    network.set_candidate_policy(client, CandidatePolicy::NoHost);
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(3));
+
+    let signalled = network
+        .host(client)
+        .events()
+        .iter()
+        .filter_map(|(e, _)| match e {
+            Event::NewIceCandidate { candidate, .. } => Some(candidate.as_str()),
+            Event::InvalidateIceCandidate { .. }
+            | Event::ConnectionEstablished(_)
+            | Event::ConnectionPathChanged { .. }
+            | Event::ConnectionFailed(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert!(signalled.iter().any(|c| c.contains("9.9.9.9")));
+    assert!(signalled.iter().all(|c| !c.contains("192.168.0.2")));
+}
+
+#[test]
+fn connects_and_delivers_packets_over_lossy_link() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let lossy = Link::default()
+        .with_latency(Duration::from_millis(40))
+        .with_jitter(Duration::from_millis(20))
+        .with_loss(0.1);
+    let client = network.add_client(s("1.1.1.1:52625"), None, lossy);
+    let server = network.add_server(s("2.2.2.2:52625"), None, Link::default());
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(10));
+
+    for _ in 0..20 {
+        assert!(network.ping(client, server));
+        network.advance(Duration::from_millis(100));
+    }
+
+    let received = network.num_received(client, server);
+    assert!(received > 10, "only received {received} out of 20 packets");
+    assert!(network.stats().lost > 0);
+}
+
+#[test]
+fn classifies_symmetric_nat() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+    network.add_relay(s4("4.4.4.4:3478"), Link::default());
+
+    let nat = network.add_nat(Nat::new(NatType::Symmetric, ip("9.9.9.9")));
+    let client = network.add_client(s("192.168.0.2:52625"), Some(nat), Link::default());
+
+    let nat = nat_behaviour_within(&mut network, client, Duration::from_secs(10));
+
+    assert_eq!(nat.mapping, Some(MappingBehaviour::EndpointDependent));
+    assert_eq!(
+        nat.filtering,
+        Some(FilteringBehaviour::AddressAndPortDependent)
+    );
+    assert_eq!(nat.hairpinning, Some(false));
+}
+
+#[test]
+fn classifies_full_cone_nat_with_hairpinning() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+    network.add_relay(s4("4.4.4.4:3478"), Link::default());
+
+    let nat = network.add_nat(Nat::new(NatType::FullCone, ip("9.9.9.9")).with_hairpinning());
+    let client = network.add_client(s("192.168.0.2:52625"), Some(nat), Link::default());
+
+    let nat = nat_behaviour_within(&mut network, client, Duration::from_secs(10));
+
+    assert_eq!(nat.mapping, Some(MappingBehaviour::EndpointIndependent));
+    assert_eq!(nat.filtering, Some(FilteringBehaviour::AddressDependent));
+    assert_eq!(nat.hairpinning, Some(true));
+}
+
+#[test]
+fn direct_connection_outlives_nat_mapping_timeout() {
+    let _guard = setup_tracing();
+    let mut network = Network::new(0);
+    network.add_relay(relay_addr(), Link::default());
+
+    let timeout = Duration::from_secs(30);
+    let client_nat = network.add_nat(
+        Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9")).with_mapping_timeout(timeout),
+    );
+    let server_nat = network.add_nat(
+        Nat::new(NatType::PortRestrictedCone, ip("8.8.8.8")).with_mapping_timeout(timeout),
+    );
+    let client = network.add_client(s("192.168.0.2:52625"), Some(client_nat), Link::default());
+    let server = network.add_server(s("10.0.0.2:52625"), Some(server_nat), Link::default());
+    network.connect(client, server);
+
+    connected_within(&mut network, client, server, Duration::from_secs(3));
+    network.advance(timeout * 3);
+
+    assert!(network.is_connected(client, server));
+    assert_ne!(path(&network, client, server), PathKind::Relayed);
+}
+
+#[test]
+fn same_seed_produces_same_network_behaviour() {
+    let run = |seed| {
+        let mut network = Network::new(seed);
+        let start = network.now();
+        network.add_relay(relay_addr(), Link::default());
+
+        let lossy = Link::default()
+            .with_latency(Duration::from_millis(40))
+            .with_jitter(Duration::from_millis(20))
+            .with_loss(0.2);
+        let nat = network.add_nat(Nat::new(NatType::PortRestrictedCone, ip("9.9.9.9")));
+        let client = network.add_client(s("192.168.0.2:52625"), Some(nat), lossy);
+        let server = network.add_server(s("2.2.2.2:52625"), None, lossy);
+        network.connect(client, server);
+        network.advance(Duration::from_secs(5));
+
+        for _ in 0..20 {
+            network.ping(client, server);
+            network.advance(Duration::from_millis(100));
+        }
+
+        // Each network starts at a different `Instant`, only the time since then is reproducible.
+        let events = |host| {
+            network
+                .host(host)
+                .events()
+                .iter()
+                .map(|(event, at)| (event.clone(), at.duration_since(start)))
+                .collect::<Vec<_>>()
+        };
+
+        (
+            network.host(client).public_key(),
+            network.stats(),
+            events(client),
+            events(server),
+            network.num_received(client, server),
+        )
+    };
+
+    assert_eq!(run(1), run(1));
+    assert_ne!(run(1), run(2));
+}
+
+fn connected_within(network: &mut Network, client: HostId, server: HostId, timeout: Duration) {
+    let elapsed = network
+        .run_until(timeout, |n| {
+            n.is_connected(client, server) && n.is_connected(server, client)
+        })
+        .unwrap_or_else(|| panic!("not connected within {timeout:?}"));
+
+    tracing::info!(?elapsed, "Connected");
+}
+
+fn nat_behaviour_within(network: &mut Network, host: HostId, timeout: Duration) -> NatBehaviour {
+    network
+        .run_until(timeout, |n| {
+            let nat = n.host(host).nat_behaviour();
+
+            nat.mapping.is_some() && nat.filtering.is_some() && nat.hairpinning.is_some()
+        })
+        .unwrap_or_else(|| panic!("NAT not classified within {timeout:?}"));
+
+    network.host(host).nat_behaviour()
+}
+
+fn path(network: &Network, a: HostId, b: HostId) -> PathKind {
+    network
+        .connection_stats(a, b)
+        .and_then(|s| s.path)
+        .expect("connection to have a nominated path")
+}
+
+fn setup_tracing() -> tracing::subscriber::DefaultGuard {
+    tracing_subscriber::fmt()
+        .with_test_writer()
+        .with_env_filter("debug")
+        .finish()
+        .set_default()
+}
+
+fn relay_addr() -> SocketAddrV4 {
+    s4("3.3.3.3:3478")
+}
+
+fn s4(socket: &str) -> SocketAddrV4 {
+    socket.parse().unwrap()
+}
+
+fn s(socket: &str) -> SocketAddr {
+    socket.parse().unwrap()
+}
+
+fn ip(ip: &str) -> std::net::IpAddr {
+    ip.parse().unwrap()
+}
@@ -6,18 +6,21 @@ use crate::{
 };
 use ::backoff::backoff::Backoff;
 use bytecodec::{DecodeExt as _, EncodeExt as _};
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng as _};
 use std::{
     borrow::Cow,
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, VecDeque},
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     time::{Duration, Instant},
 };
 use str0m::{net::Protocol, Candidate};
 use stun_codec::{
     rfc5389::{
-        attributes::{ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress},
-        errors::{StaleNonce, Unauthorized},
+        attributes::{
+            AlternateServer, ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+        },
+        errors::{StaleNonce, TryAlternate, Unauthorized},
         methods::BINDING,
     },
     rfc5766::{
@@ -33,6 +36,11 @@ use tracing::{field, Span};
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// How many times we follow a 300 (Try Alternate) before giving up on making an allocation.
+///
+/// Guards against relays redirecting to each other in a loop.
+const MAX_REDIRECTS: usize = 3;
+
 /// Represents a TURN allocation that refreshes itself.
 ///
 /// Allocations have a lifetime and need to be continuously refreshed to stay active.
@@ -40,6 +48,10 @@ const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 pub struct Allocation {
     /// The known sockets of the relay.
     server: RelaySocket,
+    /// The relay we were originally told to use, in case it redirected us to [`Allocation::server`].
+    redirected_from: Option<RelaySocket>,
+    /// How many redirects we followed since our last successful allocation.
+    num_redirects: usize,
     /// The socket we have chosen to use to communicate with the relay.
     ///
     /// A relay may be reachable on IPv4, IPv6 or both.
@@ -64,7 +76,7 @@ pub struct Allocation {
     buffered_transmits: VecDeque<Transmit<'static>>,
     events: VecDeque<CandidateEvent>,
 
-    sent_requests: HashMap<
+    sent_requests: BTreeMap<
         TransactionId,
         (
             SocketAddr,
@@ -78,9 +90,14 @@ pub struct Allocation {
     channel_bindings: ChannelBindings,
     buffered_channel_bindings: RingBuffer<SocketAddr>,
 
+    /// Generates the transaction IDs of our requests.
+    rng: StdRng,
+
     last_now: Instant,
 
     credentials: Option<Credentials>,
+    /// The other relays we know about and their credentials, see [`Allocation::set_alternate_relays`].
+    alternate_relays: Vec<(RelaySocket, Credentials)>,
 }
 
 #[derive(Debug, Clone)]
@@ -180,10 +197,13 @@ impl Allocation {
         username: Username,
         password: String,
         realm: Realm,
+        rng: &mut impl Rng,
         now: Instant,
     ) -> Self {
         let mut allocation = Self {
             server,
+            redirected_from: None,
+            num_redirects: 0,
             active_socket: None,
             ip4_srflx_candidate: Default::default(),
             ip6_srflx_candidate: Default::default(),
@@ -198,10 +218,12 @@ impl Allocation {
                 realm,
                 nonce: Default::default(),
             }),
+            alternate_relays: Default::default(),
             allocation_lifetime: Default::default(),
             channel_bindings: Default::default(),
             last_now: now,
             buffered_channel_bindings: RingBuffer::new(100),
+            rng: StdRng::seed_from_u64(rng.gen()),
         };
 
         allocation.send_binding_requests();
@@ -231,6 +253,13 @@ impl Allocation {
         realm: Realm,
         now: Instant,
     ) {
+        // The relay that redirected us is still the same, keep using the credentials of the alternate one.
+        if self.redirected_from == Some(socket) {
+            self.refresh(now);
+
+            return;
+        }
+
         self.credentials = Some(Credentials {
             username,
             realm,
@@ -245,12 +274,61 @@ impl Allocation {
             return;
         }
         self.server = socket;
+        self.redirected_from = None;
+        self.num_redirects = 0;
 
         // Server isn't the same, let's pick a new socket.
         self.active_socket = None;
         self.send_binding_requests();
     }
 
+    /// Sets the other relays that we may follow a 300 (Try Alternate) to.
+    ///
+    /// Each relay derives credentials from its own secret, thus we can only authenticate with an alternate relay if we know its credentials.
+    /// If we are already using one of these relays after a redirect, we also switch to its updated credentials.
+    pub fn set_alternate_relays(
+        &mut self,
+        relays: impl IntoIterator<Item = (RelaySocket, Username, String, Realm)>,
+    ) {
+        self.alternate_relays = relays
+            .into_iter()
+            .map(|(socket, username, password, realm)| {
+                (
+                    socket,
+                    Credentials {
+                        username,
+                        password,
+                        realm,
+                        nonce: None,
+                    },
+                )
+            })
+            .collect();
+
+        if self.redirected_from.is_none() {
+            return;
+        }
+
+        let Some((_, alternate)) = self
+            .alternate_relays
+            .iter()
+            .find(|(s, _)| *s == self.server)
+        else {
+            return;
+        };
+
+        let Some(current) = self.credentials.as_mut() else {
+            return;
+        };
+
+        if current.username != alternate.username
+            || current.password != alternate.password
+            || current.realm != alternate.realm
+        {
+            *current = alternate.clone();
+        }
+    }
+
     /// Refresh this allocation.
     ///
     /// In case refreshing the allocation fails, we will attempt to make a new one.
@@ -338,8 +416,15 @@ impl Allocation {
                     return true;
                 };
 
-                if let Some(new_nonce) = message.get_attribute::<Nonce>() {
-                    let _ = nonce.insert(new_nonce.clone());
+                match message.get_attribute::<Nonce>() {
+                    Some(new_nonce) => {
+                        let _ = nonce.insert(new_nonce.clone());
+                    }
+                    None if error.code() == StaleNonce::CODEPOINT => {
+                        // A stale nonce must not be re-used. Start over without a nonce and let the server issue a new one.
+                        *nonce = None;
+                    }
+                    None => {}
                 };
 
                 if let Some(offered_realm) = message.get_attribute::<Realm>() {
@@ -359,6 +444,22 @@ impl Allocation {
                 return true;
             }
 
+            // The relay cannot serve us (e.g. because it is full or shutting down) and points us to another one.
+            if error.code() == TryAlternate::CODEPOINT && message.method() == ALLOCATE {
+                let Some(alternate_server) = message
+                    .get_attribute::<AlternateServer>()
+                    .map(|a| a.address())
+                else {
+                    tracing::warn!("Relay sent 300 (Try Alternate) without `ALTERNATE-SERVER`");
+                    self.buffered_channel_bindings.clear();
+                    return true;
+                };
+
+                self.follow_redirect(alternate_server);
+
+                return true;
+            }
+
             match message.method() {
                 ALLOCATE => {
                     self.buffered_channel_bindings.clear();
@@ -387,7 +488,9 @@ impl Allocation {
                 }
                 REFRESH => {
                     self.invalidate_allocation();
-                    self.authenticate_and_queue(make_allocate_request(), None);
+
+                    let request = make_allocate_request(self.new_transaction_id());
+                    self.authenticate_and_queue(request, None);
                 }
                 _ => {}
             }
@@ -432,11 +535,12 @@ impl Allocation {
                 // If the socket isn't set yet, use the `original_dst` as the primary socket.
                 self.active_socket = Some(original_dst);
 
-                if self.has_allocation() {
-                    self.authenticate_and_queue(make_refresh_request(), None);
+                let request = if self.has_allocation() {
+                    make_refresh_request(self.new_transaction_id())
                 } else {
-                    self.authenticate_and_queue(make_allocate_request(), None);
-                }
+                    make_allocate_request(self.new_transaction_id())
+                };
+                self.authenticate_and_queue(request, None);
             }
             ALLOCATE => {
                 let Some(lifetime) = message.get_attribute::<Lifetime>().map(|l| l.lifetime())
@@ -458,6 +562,7 @@ impl Allocation {
                 }
 
                 self.allocation_lifetime = Some((now, lifetime));
+                self.num_redirects = 0;
                 update_candidate(
                     maybe_ip4_relay_candidate,
                     &mut self.ip4_allocation,
@@ -579,7 +684,8 @@ impl Allocation {
         if let Some(refresh_at) = self.refresh_allocation_at() {
             if (now >= refresh_at) && !self.refresh_in_flight() {
                 tracing::debug!("Allocation is due for a refresh");
-                let queued = self.authenticate_and_queue(make_refresh_request(), None);
+                let request = make_refresh_request(self.new_transaction_id());
+                let queued = self.authenticate_and_queue(request, None);
 
                 // If we fail to queue the refresh message because we've exceeded our backoff, give up.
                 if !queued {
@@ -588,7 +694,7 @@ impl Allocation {
             }
         }
 
-        let channel_refresh_messages = self
+        let channels_to_refresh = self
             .channel_bindings
             .channels_to_refresh(now, |number| {
                 self.channel_binding_in_flight_by_number(number)
@@ -596,10 +702,10 @@ impl Allocation {
             .inspect(|(number, peer)| {
                 tracing::debug!(%number, %peer, "Channel is due for a refresh");
             })
-            .map(|(number, peer)| make_channel_bind_request(peer, number))
             .collect::<Vec<_>>(); // Need to allocate here to satisfy borrow-checker. Number of channel refresh messages should be small so this shouldn't be a big impact.
 
-        for message in channel_refresh_messages {
+        for (number, peer) in channels_to_refresh {
+            let message = make_channel_bind_request(peer, number, self.new_transaction_id());
             self.authenticate_and_queue(message, None);
         }
 
@@ -668,7 +774,16 @@ impl Allocation {
             return;
         };
 
-        self.authenticate_and_queue(make_channel_bind_request(peer, channel), None);
+        let request = make_channel_bind_request(peer, channel, self.new_transaction_id());
+        self.authenticate_and_queue(request, None);
+    }
+
+    /// Stops using the channel to the given peer.
+    ///
+    /// TURN has no way of unbinding a channel, so we merely stop refreshing it and let it expire on the relay.
+    /// Its number is only reused once the relay has forgotten about it.
+    pub fn release_channel(&mut self, peer: SocketAddr) {
+        self.channel_bindings.release(peer);
     }
 
     /// Encodes the packet contained in the given buffer into a [`Transmit`].
@@ -845,12 +960,49 @@ impl Allocation {
         no_allocation && nothing_in_flight && nothing_buffered && waiting_on_nothing
     }
 
+    /// Switches to `alternate_server` and starts over with a new allocation there.
+    ///
+    /// We can only follow the redirect if `alternate_server` is one of the relays set via [`Allocation::set_alternate_relays`].
+    fn follow_redirect(&mut self, alternate_server: SocketAddr) {
+        if self.num_redirects >= MAX_REDIRECTS {
+            tracing::warn!(%alternate_server, "Exceeded maximum number of redirects, giving up");
+            self.buffered_channel_bindings.clear();
+            return;
+        }
+
+        let Some((socket, credentials)) = self
+            .alternate_relays
+            .iter()
+            .find(|(s, _)| s.matches(alternate_server))
+            .cloned()
+        else {
+            tracing::warn!(%alternate_server, "Relay redirected us to an unknown relay, giving up");
+            self.buffered_channel_bindings.clear();
+            return;
+        };
+        self.num_redirects += 1;
+
+        tracing::info!(from = ?self.server, to = ?socket, "Relay redirected us to alternate server");
+
+        self.redirected_from.get_or_insert(self.server);
+        self.server = socket;
+
+        // These don't have a nonce yet: Nonces are issued per relay, the alternate one will send us a new one.
+        self.credentials = Some(credentials);
+
+        self.active_socket = None;
+        self.sent_requests.clear();
+        self.send_binding_requests();
+    }
+
     fn send_binding_requests(&mut self) {
         if let Some(v4) = self.server.as_v4() {
-            self.queue((*v4).into(), make_binding_request(), None);
+            let request = make_binding_request(self.new_transaction_id());
+            self.queue((*v4).into(), request, None);
         }
         if let Some(v6) = self.server.as_v6() {
-            self.queue((*v6).into(), make_binding_request(), None);
+            let request = make_binding_request(self.new_transaction_id());
+            self.queue((*v6).into(), request, None);
         }
     }
 
@@ -876,7 +1028,8 @@ impl Allocation {
             return false;
         };
 
-        let authenticated_message = authenticate(message, credentials);
+        let authenticated_message =
+            authenticate(message, credentials, TransactionId::new(self.rng.gen()));
         self.queue(dst, authenticated_message, backoff);
 
         true
@@ -911,6 +1064,10 @@ impl Allocation {
         true
     }
 
+    fn new_transaction_id(&mut self) -> TransactionId {
+        TransactionId::new(self.rng.gen())
+    }
+
     fn update_now(&mut self, now: Instant) {
         if now <= self.last_now {
             return;
@@ -924,7 +1081,11 @@ impl Allocation {
     }
 }
 
-fn authenticate(message: Message<Attribute>, credentials: &Credentials) -> Message<Attribute> {
+fn authenticate(
+    message: Message<Attribute>,
+    credentials: &Credentials,
+    transaction_id: TransactionId,
+) -> Message<Attribute> {
     let attributes = message
         .attributes()
         .filter(|a| !matches!(a, Attribute::Nonce(_)))
@@ -938,7 +1099,6 @@ fn authenticate(message: Message<Attribute>, credentials: &Credentials) -> Messa
         ])
         .chain(credentials.nonce.clone().map(Attribute::Nonce));
 
-    let transaction_id = TransactionId::new(random());
     let mut message = Message::new(MessageClass::Request, message.method(), transaction_id);
 
     for attribute in attributes {
@@ -977,16 +1137,12 @@ fn update_candidate(
     }
 }
 
-fn make_binding_request() -> Message<Attribute> {
-    Message::new(MessageClass::Request, BINDING, TransactionId::new(random()))
+fn make_binding_request(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::new(MessageClass::Request, BINDING, transaction_id)
 }
 
-fn make_allocate_request() -> Message<Attribute> {
-    let mut message = Message::new(
-        MessageClass::Request,
-        ALLOCATE,
-        TransactionId::new(random()),
-    );
+fn make_allocate_request(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message = Message::new(MessageClass::Request, ALLOCATE, transaction_id);
 
     message.add_attribute(RequestedTransport::new(17));
     message.add_attribute(AdditionalAddressFamily::new(
@@ -996,8 +1152,8 @@ fn make_allocate_request() -> Message<Attribute> {
     message
 }
 
-fn make_refresh_request() -> Message<Attribute> {
-    let mut message = Message::new(MessageClass::Request, REFRESH, TransactionId::new(random()));
+fn make_refresh_request(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message = Message::new(MessageClass::Request, REFRESH, transaction_id);
 
     message.add_attribute(RequestedTransport::new(17));
     message.add_attribute(AdditionalAddressFamily::new(
@@ -1007,12 +1163,12 @@ fn make_refresh_request() -> Message<Attribute> {
     message
 }
 
-fn make_channel_bind_request(target: SocketAddr, channel: u16) -> Message<Attribute> {
-    let mut message = Message::new(
-        MessageClass::Request,
-        CHANNEL_BIND,
-        TransactionId::new(random()),
-    );
+fn make_channel_bind_request(
+    target: SocketAddr,
+    channel: u16,
+    transaction_id: TransactionId,
+) -> Message<Attribute> {
+    let mut message = Message::new(MessageClass::Request, CHANNEL_BIND, transaction_id);
 
     message.add_attribute(XorPeerAddress::new(target));
     message.add_attribute(ChannelNumber::new(channel).unwrap());
@@ -1087,13 +1243,14 @@ stun_codec::define_attribute_enums!(
         XorRelayAddress,
         XorPeerAddress,
         ChannelNumber,
-        Lifetime
+        Lifetime,
+        AlternateServer
     ]
 );
 
 #[derive(Debug)]
 struct ChannelBindings {
-    inner: HashMap<u16, Channel>,
+    inner: BTreeMap<u16, Channel>,
     next_channel: u16,
 }
 
@@ -1150,6 +1307,7 @@ impl ChannelBindings {
             Channel {
                 peer,
                 bound: false,
+                released: false,
                 bound_at: now,
                 last_received: now,
             },
@@ -1177,6 +1335,14 @@ impl ChannelBindings {
             .map(|(n, _)| *n)
     }
 
+    fn release(&mut self, peer: SocketAddr) {
+        for (number, channel) in self.inner.iter_mut().filter(|(_, c)| c.peer == peer) {
+            tracing::debug!(channel = %number, %peer, "Released channel");
+
+            channel.released = true;
+        }
+    }
+
     fn handle_failed_binding(&mut self, c: u16) {
         if self.inner.remove(&c).is_none() {
             debug_assert!(false, "No channel binding for {c}");
@@ -1207,6 +1373,8 @@ struct Channel {
 
     /// If `false`, the channel binding has not yet been confirmed.
     bound: bool,
+    /// If `true`, we no longer use this channel and let it expire.
+    released: bool,
 
     /// When the channel was created or last refreshed.
     bound_at: Instant,
@@ -1223,11 +1391,11 @@ impl Channel {
     ///
     /// In case the channel is older than its lifetime (10 minutes), this returns false because the relay will have de-allocated the channel.
     fn connected_to_peer(&self, peer: SocketAddr, now: Instant) -> bool {
-        self.peer == peer && self.age(now) < Self::CHANNEL_LIFETIME && self.bound
+        self.peer == peer && self.age(now) < Self::CHANNEL_LIFETIME && self.bound && !self.released
     }
 
     fn can_rebind(&self, now: Instant) -> bool {
-        self.no_activity()
+        (self.no_activity() || self.released)
             && (self.age(now) >= Self::CHANNEL_LIFETIME + Self::CHANNEL_REBIND_TIMEOUT)
     }
 
@@ -1236,7 +1404,12 @@ impl Channel {
     /// We will refresh all channels that:
     /// - are older than 5 minutes
     /// - we have received data on since we created / refreshed them
+    /// - we didn't release
     fn needs_refresh(&self, now: Instant) -> bool {
+        if self.released {
+            return false;
+        }
+
         let channel_refresh_threshold = Self::CHANNEL_LIFETIME / 2;
 
         if self.age(now) < channel_refresh_threshold {
@@ -1294,6 +1467,7 @@ mod tests {
 
     const RELAY_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478);
     const RELAY_V6: SocketAddrV6 = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 3478, 0, 0);
+    const ALTERNATE_RELAY_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3479);
     const RELAY_ADDR_IP4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9999);
     const RELAY_ADDR_IP6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9999);
 
@@ -1396,6 +1570,30 @@ mod tests {
         assert!(maybe_refresh.is_none())
     }
 
+    #[test]
+    fn released_channel_is_neither_used_nor_refreshed() {
+        let mut channel_bindings = ChannelBindings::default();
+        let start = Instant::now();
+
+        let channel = channel_bindings.new_channel_to_peer(PEER1, start).unwrap();
+        channel_bindings.set_confirmed(channel, start + Duration::from_secs(1));
+
+        let packet = crate::channel_data::encode(channel, b"foobar");
+        channel_bindings
+            .try_decode(&packet, start + Duration::from_secs(2))
+            .unwrap();
+        channel_bindings.release(PEER1);
+
+        assert_eq!(
+            channel_bindings.channel_to_peer(PEER1, start + Duration::from_secs(3)),
+            None
+        );
+        assert!(channel_bindings
+            .channels_to_refresh(start + 6 * MINUTE, |_| false)
+            .next()
+            .is_none());
+    }
+
     #[test]
     fn channel_that_is_less_than_5_min_old_should_not_be_refreshed() {
         let now = Instant::now();
@@ -1683,6 +1881,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn upon_stale_nonce_without_new_nonce_reauthorizes_without_nonce() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now()).with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation
+            .handle_test_input_ip4(&unauthorized_response(&allocate, "nonce1"), Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &stale_nonce_response_without_nonce(&allocate),
+            Instant::now(),
+        );
+
+        let allocate = allocation.next_message().unwrap();
+        assert!(allocate.get_attribute::<Nonce>().is_none());
+
+        allocation
+            .handle_test_input_ip4(&unauthorized_response(&allocate, "nonce2"), Instant::now());
+
+        assert_eq!(
+            allocation
+                .next_message()
+                .unwrap()
+                .get_attribute::<Nonce>()
+                .map(|n| n.value()),
+            Some("nonce2"),
+            "expect to recover with the nonce from the following 401"
+        );
+    }
+
     #[test]
     fn given_a_request_with_nonce_and_we_are_unauthorized_dont_retry() {
         let mut allocation = Allocation::for_test_ip4(Instant::now()).with_binding_response(PEER1);
@@ -2179,6 +2408,174 @@ mod tests {
         assert_eq!(allocation.poll_transmit().unwrap().dst, RELAY_V6.into())
     }
 
+    #[test]
+    fn try_alternate_switches_to_alternate_server() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now())
+            .with_alternate_relay()
+            .with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+
+        let transmit = allocation.poll_transmit().unwrap();
+        assert_eq!(transmit.dst, ALTERNATE_RELAY_V4.into());
+        assert_eq!(
+            decode(&transmit.payload).unwrap().unwrap().method(),
+            BINDING
+        );
+        assert_eq!(allocation.server(), RelaySocket::V4(ALTERNATE_RELAY_V4));
+    }
+
+    #[test]
+    fn allocates_on_alternate_server_without_nonce() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now())
+            .with_alternate_relay()
+            .with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation
+            .handle_test_input_ip4(&unauthorized_response(&allocate, "nonce1"), Instant::now());
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+
+        let binding = allocation.next_message().unwrap();
+        allocation.handle_input(
+            ALTERNATE_RELAY_V4.into(),
+            PEER1,
+            &binding_response(&binding, PEER1),
+            Instant::now(),
+        );
+
+        let allocate = allocation.next_message().unwrap();
+        assert_eq!(allocate.method(), ALLOCATE);
+        assert!(allocate.get_attribute::<Nonce>().is_none());
+    }
+
+    #[test]
+    fn gives_up_after_too_many_redirects() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now())
+            .with_alternate_relay()
+            .with_binding_response(PEER1);
+
+        let mut relay = SocketAddr::from(RELAY_V4);
+
+        for _ in 0..MAX_REDIRECTS {
+            let allocate = allocation.next_message().unwrap();
+            allocation.handle_input(
+                relay,
+                PEER1,
+                &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+                Instant::now(),
+            );
+            relay = ALTERNATE_RELAY_V4.into();
+
+            let binding = allocation.next_message().unwrap();
+            allocation.handle_input(
+                relay,
+                PEER1,
+                &binding_response(&binding, PEER1),
+                Instant::now(),
+            );
+        }
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_input(
+            relay,
+            PEER1,
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+
+        assert!(allocation.next_message().is_none());
+    }
+
+    #[test]
+    fn updating_credentials_of_original_server_after_redirect_refreshes() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now())
+            .with_alternate_relay()
+            .with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+        let _drained_messages = iter::from_fn(|| allocation.poll_transmit()).collect::<Vec<_>>();
+
+        allocation.refresh_with_same_credentials();
+
+        assert_eq!(allocation.server(), RelaySocket::V4(ALTERNATE_RELAY_V4));
+        assert_eq!(
+            allocation.credentials.as_ref().unwrap().username.name(),
+            "alternate"
+        );
+        assert_eq!(
+            allocation.poll_transmit().unwrap().dst,
+            ALTERNATE_RELAY_V4.into()
+        );
+    }
+
+    #[test]
+    fn authenticates_with_credentials_of_alternate_server() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now())
+            .with_alternate_relay()
+            .with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+
+        let binding = allocation.next_message().unwrap();
+        allocation.handle_input(
+            ALTERNATE_RELAY_V4.into(),
+            PEER1,
+            &binding_response(&binding, PEER1),
+            Instant::now(),
+        );
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_input(
+            ALTERNATE_RELAY_V4.into(),
+            PEER1,
+            &unauthorized_response(&allocate, "nonce1"),
+            Instant::now(),
+        );
+
+        let allocate = allocation.next_message().unwrap();
+        let username = allocate.get_attribute::<Username>().unwrap();
+        assert_eq!(username.name(), "alternate");
+        allocate
+            .get_attribute::<MessageIntegrity>()
+            .unwrap()
+            .check_long_term_credential(
+                username,
+                &Realm::new("firezone".to_owned()).unwrap(),
+                "alternate-password",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn does_not_follow_redirect_to_unknown_relay() {
+        let mut allocation = Allocation::for_test_ip4(Instant::now()).with_binding_response(PEER1);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input_ip4(
+            &try_alternate_response(&allocate, ALTERNATE_RELAY_V4.into()),
+            Instant::now(),
+        );
+
+        assert_eq!(allocation.server(), RelaySocket::V4(RELAY_V4));
+        assert!(allocation.next_message().is_none());
+    }
+
     #[test]
     fn allocation_is_not_freed_on_startup() {
         let allocation = Allocation::for_test_ip4(Instant::now());
@@ -2317,7 +2714,7 @@ mod tests {
             allocation.handle_timeout(timeout);
 
             // We expect two transmits.
-            // The order is not deterministic because internally it is a `HashMap`.
+            // The order depends on the randomly generated transaction IDs.
             let _ = allocation.poll_transmit().unwrap();
             let _ = allocation.poll_transmit().unwrap();
         }
@@ -2329,6 +2726,7 @@ mod tests {
         Channel {
             peer,
             bound: true,
+            released: false,
             bound_at: now,
             last_received: now,
         }
@@ -2376,6 +2774,21 @@ mod tests {
         encode(message)
     }
 
+    fn try_alternate_response(
+        request: &Message<Attribute>,
+        alternate_server: SocketAddr,
+    ) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(ErrorCode::from(TryAlternate));
+        message.add_attribute(AlternateServer::new(alternate_server));
+
+        encode(message)
+    }
+
     fn server_error(request: &Message<Attribute>) -> Vec<u8> {
         let mut message = Message::new(
             MessageClass::ErrorResponse,
@@ -2400,6 +2813,18 @@ mod tests {
         encode(message)
     }
 
+    fn stale_nonce_response_without_nonce(request: &Message<Attribute>) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(ErrorCode::from(StaleNonce));
+        message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+
+        encode(message)
+    }
+
     fn failed_refresh(request: &Message<Attribute>) -> Vec<u8> {
         let mut message = Message::new(
             MessageClass::ErrorResponse,
@@ -2441,6 +2866,7 @@ mod tests {
                 Username::new("foobar".to_owned()).unwrap(),
                 "baz".to_owned(),
                 Realm::new("firezone".to_owned()).unwrap(),
+                &mut rand::thread_rng(),
                 start,
             )
         }
@@ -2454,10 +2880,23 @@ mod tests {
                 Username::new("foobar".to_owned()).unwrap(),
                 "baz".to_owned(),
                 Realm::new("firezone".to_owned()).unwrap(),
+                &mut rand::thread_rng(),
                 start,
             )
         }
 
+        /// Configures [`ALTERNATE_RELAY_V4`] with credentials different from the ones of [`RELAY_V4`].
+        fn with_alternate_relay(mut self) -> Self {
+            self.set_alternate_relays([(
+                RelaySocket::V4(ALTERNATE_RELAY_V4),
+                Username::new("alternate".to_owned()).unwrap(),
+                "alternate-password".to_owned(),
+                Realm::new("firezone".to_owned()).unwrap(),
+            )]);
+
+            self
+        }
+
         fn with_binding_response(mut self, srflx_addr: SocketAddr) -> Self {
             let binding = self.next_message().unwrap();
             self.handle_test_input_ip4(&binding_response(&binding, srflx_addr), Instant::now());
@@ -2491,7 +2930,7 @@ mod tests {
 
         fn refresh_with_same_credentials(&mut self) {
             self.update_credentials(
-                self.server,
+                self.redirected_from.unwrap_or(self.server),
                 Username::new("foobar".to_owned()).unwrap(),
                 "baz",
                 Realm::new("firezone".to_owned()).unwrap(),
@@ -1,3 +1,5 @@
+use rand::Rng;
+
 // A basic linear-feedback shift register implemented as xorshift, used to
 // distribute peer indexes across the 24-bit address space reserved for peer
 // identification.
@@ -12,11 +14,20 @@ pub(crate) struct IndexLfsr {
 }
 
 impl IndexLfsr {
+    pub(crate) fn new(rng: &mut impl Rng) -> Self {
+        let seed = Self::random_index(rng);
+        IndexLfsr {
+            initial: seed,
+            lfsr: seed,
+            mask: Self::random_index(rng),
+        }
+    }
+
     /// Generate a random 24-bit nonzero integer
-    fn random_index() -> u32 {
+    fn random_index(rng: &mut impl Rng) -> u32 {
         const LFSR_MAX: u32 = 0xffffff; // 24-bit seed
         loop {
-            let i = rand::random::<u32>() & LFSR_MAX;
+            let i = rng.gen::<u32>() & LFSR_MAX;
             if i > 0 {
                 // LFSR seed must be non-zero
                 break i;
@@ -36,14 +47,3 @@ impl IndexLfsr {
         value ^ self.mask
     }
 }
-
-impl Default for IndexLfsr {
-    fn default() -> Self {
-        let seed = Self::random_index();
-        IndexLfsr {
-            initial: seed,
-            lfsr: seed,
-            mask: Self::random_index(),
-        }
-    }
-}
@@ -4,15 +4,18 @@ mod allocation;
 mod backoff;
 mod channel_data;
 mod index;
+mod nat_discovery;
 mod node;
+mod relay_selection;
 mod ringbuffer;
 mod stats;
 mod stun_binding;
 mod utils;
 
-pub use allocation::RelaySocket;
+pub use allocation::{Allocation, RelaySocket};
+pub use nat_discovery::{FilteringBehaviour, MappingBehaviour, NatBehaviour};
 pub use node::{
-    Answer, Client, ClientNode, Credentials, Error, Event, Node, Offer, Server, ServerNode,
-    Transmit,
+    Answer, CandidateEvent, CandidatePolicy, Client, ClientNode, Credentials, Error, Event, Node,
+    Offer, Server, ServerNode, Transmit,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use stats::{ConnectionStats, NodeStats, PathKind};
@@ -0,0 +1,472 @@
+//! Classifies the NAT we are behind, using the terminology of RFC 5780.
+//!
+//! Full RFC 5780 discovery needs a STUN server that can answer from a different IP and port (`CHANGE-REQUEST`).
+//! Our relays don't support that, so we derive what we can from the servers we already talk to:
+//!
+//! - Mapping behaviour: compare the server-reflexive addresses that different servers observe for the same local socket.
+//! - Filtering behaviour: relay a binding request through one of our allocations to our own server-reflexive address.
+//!   It arrives from the allocation's port which we never sent to.
+//! - Hairpinning: send a binding request from our socket to our own server-reflexive address.
+
+use bytecodec::EncodeExt;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use stun_codec::{rfc5389, Message, MessageClass, TransactionId};
+
+/// How long we wait between probes and for the last probe to arrive.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many probes we send before concluding that they don't get through.
+const MAX_PROBES: u8 = 3;
+
+/// What we know about the NAT we are behind.
+///
+/// Each field is `None` until we have enough information to classify it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NatBehaviour {
+    pub mapping: Option<MappingBehaviour>,
+    pub filtering: Option<FilteringBehaviour>,
+    /// Whether we can reach our own server-reflexive address from behind the NAT.
+    pub hairpinning: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingBehaviour {
+    /// Servers observe our local address, i.e. we are not behind a NAT.
+    NoNat,
+    /// All servers observe the same address for the same local socket.
+    EndpointIndependent,
+    /// Servers with different IPs observe different addresses for the same local socket.
+    ///
+    /// This is either address-dependent or address- and port-dependent mapping.
+    /// Telling them apart requires a STUN server that listens on multiple ports.
+    EndpointDependent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilteringBehaviour {
+    /// The NAT lets through packets from ports we didn't send to, as long as we sent to their IP.
+    ///
+    /// This includes endpoint-independent filtering.
+    /// Telling them apart requires a server with an IP we never sent to.
+    AddressDependent,
+    /// The NAT only lets through packets from sockets we sent to.
+    AddressAndPortDependent,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct NatDiscovery {
+    mapping: Option<MappingBehaviour>,
+    /// The `(server, local, observed)` addresses we classified [`NatDiscovery::mapping`] from.
+    observations: Vec<(SocketAddr, SocketAddr, SocketAddr)>,
+    pub(crate) filtering: Probe,
+    pub(crate) hairpinning: Probe,
+}
+
+impl NatDiscovery {
+    /// Updates the addresses our servers observe for us, re-classifying our mapping behaviour if they changed.
+    pub(crate) fn observe(
+        &mut self,
+        observations: impl Iterator<Item = (SocketAddr, SocketAddr, SocketAddr)> + Clone,
+    ) {
+        if self.observations.iter().copied().eq(observations.clone()) {
+            return;
+        }
+
+        self.observations = observations.collect();
+        self.mapping = classify_mapping(self.observations.iter().copied());
+    }
+
+    /// An address that differs from the local one it was observed for, i.e. one that was translated by a NAT.
+    pub(crate) fn translated_address(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.observations
+            .iter()
+            .find(|(_, local, observed)| local != observed)
+            .map(|(_, local, observed)| (*local, *observed))
+    }
+
+    pub(crate) fn behaviour(&self) -> NatBehaviour {
+        NatBehaviour {
+            mapping: self.mapping,
+            filtering: self.filtering.result().map(|received| {
+                if received {
+                    FilteringBehaviour::AddressDependent
+                } else {
+                    FilteringBehaviour::AddressAndPortDependent
+                }
+            }),
+            hairpinning: self.hairpinning.result(),
+        }
+    }
+
+    /// Whether the packet is one of our own probes that made it back to us.
+    pub(crate) fn handle_input(&mut self, packet: &[u8]) -> bool {
+        self.filtering.handle_input(packet) || self.hairpinning.handle_input(packet)
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        crate::utils::earliest(
+            self.filtering.poll_timeout(),
+            self.hairpinning.poll_timeout(),
+        )
+    }
+}
+
+/// Sends binding requests to a target until one of them arrives back at us.
+#[derive(Debug, Default)]
+pub(crate) struct Probe {
+    state: ProbeState,
+}
+
+#[derive(Debug)]
+enum ProbeState {
+    /// We haven't sent a request yet, e.g. because we don't have a target yet.
+    Idle {
+        /// When we want to try again, if we already tried.
+        retry_at: Option<Instant>,
+    },
+    Sent {
+        ids: Vec<TransactionId>,
+        last_sent_at: Instant,
+    },
+    Done {
+        received: bool,
+    },
+}
+
+impl Default for ProbeState {
+    fn default() -> Self {
+        Self::Idle { retry_at: None }
+    }
+}
+
+impl Probe {
+    pub(crate) fn result(&self) -> Option<bool> {
+        match self.state {
+            ProbeState::Done { received } => Some(received),
+            ProbeState::Idle { .. } | ProbeState::Sent { .. } => None,
+        }
+    }
+
+    /// Postpones the next request by [`PROBE_INTERVAL`], e.g. because there is nothing to probe right now.
+    ///
+    /// A request that is already in flight gets more time to arrive; it is not counted as another attempt.
+    pub(crate) fn retry_later(&mut self, now: Instant) {
+        match &mut self.state {
+            ProbeState::Idle { retry_at } => *retry_at = Some(now + PROBE_INTERVAL),
+            ProbeState::Sent { last_sent_at, .. } if now >= *last_sent_at + PROBE_INTERVAL => {
+                *last_sent_at = now;
+            }
+            ProbeState::Sent { .. } | ProbeState::Done { .. } => {}
+        }
+    }
+
+    /// Sends the next binding request via `send` if it is time to do so.
+    ///
+    /// `send` returns whether it could actually send the request, e.g. an allocation may not have a channel yet.
+    /// If it couldn't, we try again after [`PROBE_INTERVAL`].
+    /// Once [`MAX_PROBES`] requests didn't arrive within [`PROBE_INTERVAL`], we conclude that they don't get through.
+    pub(crate) fn handle_timeout(
+        &mut self,
+        now: Instant,
+        rng: &mut impl Rng,
+        send: impl FnOnce(Vec<u8>) -> bool,
+    ) {
+        let num_sent = match &self.state {
+            ProbeState::Idle { .. } => 0,
+            ProbeState::Sent { ids, last_sent_at } if now >= *last_sent_at + PROBE_INTERVAL => {
+                ids.len()
+            }
+            ProbeState::Sent { .. } | ProbeState::Done { .. } => return,
+        };
+
+        if num_sent >= MAX_PROBES as usize {
+            self.state = ProbeState::Done { received: false };
+            return;
+        }
+
+        let (id, request) = new_binding_request(rng);
+
+        if !send(request) {
+            self.retry_later(now);
+            return;
+        }
+
+        match &mut self.state {
+            ProbeState::Sent { ids, last_sent_at } => {
+                ids.push(id);
+                *last_sent_at = now;
+            }
+            ProbeState::Idle { .. } | ProbeState::Done { .. } => {
+                self.state = ProbeState::Sent {
+                    ids: vec![id],
+                    last_sent_at: now,
+                };
+            }
+        }
+    }
+
+    fn handle_input(&mut self, packet: &[u8]) -> bool {
+        let ProbeState::Sent { ids, .. } = &self.state else {
+            return false;
+        };
+
+        // Binding requests start with a zero byte, the transaction ID is at bytes 8 to 20.
+        if packet.len() < 20 || packet[0] != 0 {
+            return false;
+        }
+
+        if !ids.iter().any(|id| id.as_bytes() == &packet[8..20]) {
+            return false;
+        }
+
+        self.state = ProbeState::Done { received: true };
+
+        true
+    }
+
+    fn poll_timeout(&self) -> Option<Instant> {
+        match self.state {
+            ProbeState::Idle { retry_at } => retry_at,
+            ProbeState::Sent { last_sent_at, .. } => Some(last_sent_at + PROBE_INTERVAL),
+            ProbeState::Done { .. } => None,
+        }
+    }
+}
+
+/// Classifies our mapping behaviour from the addresses different servers observed.
+///
+/// Each observation is a `(server, local, observed)` tuple.
+fn classify_mapping(
+    observations: impl IntoIterator<Item = (SocketAddr, SocketAddr, SocketAddr)>,
+) -> Option<MappingBehaviour> {
+    let mut observed_by_local = HashMap::<SocketAddr, HashMap<IpAddr, SocketAddr>>::new();
+
+    for (server, local, observed) in observations {
+        observed_by_local
+            .entry(local)
+            .or_default()
+            .insert(server.ip(), observed);
+    }
+
+    if observed_by_local.is_empty() {
+        return None;
+    }
+
+    let no_nat = observed_by_local
+        .iter()
+        .all(|(local, observed)| observed.values().all(|o| o == local));
+
+    if no_nat {
+        return Some(MappingBehaviour::NoNat);
+    }
+
+    let mut mapping = None;
+
+    for observed in observed_by_local.values().filter(|o| o.len() >= 2) {
+        let mut addresses = observed.values();
+        let first = addresses.next().expect("at least two observations");
+
+        if addresses.any(|a| a != first) {
+            return Some(MappingBehaviour::EndpointDependent);
+        }
+
+        mapping = Some(MappingBehaviour::EndpointIndependent);
+    }
+
+    mapping
+}
+
+fn new_binding_request(rng: &mut impl Rng) -> (TransactionId, Vec<u8>) {
+    let request = Message::<rfc5389::Attribute>::new(
+        MessageClass::Request,
+        rfc5389::methods::BINDING,
+        TransactionId::new(rng.gen()),
+    );
+    let id = request.transaction_id();
+
+    let payload = stun_codec::MessageEncoder::<rfc5389::Attribute>::default()
+        .encode_into_bytes(request)
+        .unwrap();
+
+    (id, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: &str = "192.168.0.2:52625";
+    const SERVER_1: &str = "1.1.1.1:3478";
+    const SERVER_2: &str = "2.2.2.2:3478";
+
+    #[test]
+    fn classifies_endpoint_independent_mapping() {
+        let mapping = classify_mapping([
+            (s(SERVER_1), s(LOCAL), s("9.9.9.9:40000")),
+            (s(SERVER_2), s(LOCAL), s("9.9.9.9:40000")),
+        ]);
+
+        assert_eq!(mapping, Some(MappingBehaviour::EndpointIndependent));
+    }
+
+    #[test]
+    fn classifies_endpoint_dependent_mapping() {
+        let mapping = classify_mapping([
+            (s(SERVER_1), s(LOCAL), s("9.9.9.9:40000")),
+            (s(SERVER_2), s(LOCAL), s("9.9.9.9:40001")),
+        ]);
+
+        assert_eq!(mapping, Some(MappingBehaviour::EndpointDependent));
+    }
+
+    #[test]
+    fn single_server_is_not_enough_to_classify_mapping() {
+        let mapping = classify_mapping([
+            (s(SERVER_1), s(LOCAL), s("9.9.9.9:40000")),
+            (s("1.1.1.1:3479"), s(LOCAL), s("9.9.9.9:40001")),
+        ]);
+
+        assert_eq!(mapping, None);
+    }
+
+    #[test]
+    fn detects_absence_of_nat() {
+        let mapping = classify_mapping([(s(SERVER_1), s(LOCAL), s(LOCAL))]);
+
+        assert_eq!(mapping, Some(MappingBehaviour::NoNat));
+    }
+
+    #[test]
+    fn classifies_mapping_once_observations_change() {
+        let mut discovery = NatDiscovery::default();
+
+        discovery.observe([(s(SERVER_1), s(LOCAL), s("9.9.9.9:40000"))].into_iter());
+        assert_eq!(discovery.behaviour().mapping, None);
+
+        let observations = [
+            (s(SERVER_1), s(LOCAL), s("9.9.9.9:40000")),
+            (s(SERVER_2), s(LOCAL), s("9.9.9.9:40000")),
+        ];
+        discovery.observe(observations.into_iter());
+        assert_eq!(
+            discovery.behaviour().mapping,
+            Some(MappingBehaviour::EndpointIndependent)
+        );
+        assert_eq!(
+            discovery.translated_address(),
+            Some((s(LOCAL), s("9.9.9.9:40000")))
+        );
+    }
+
+    #[test]
+    fn probe_succeeds_once_request_arrives() {
+        let mut probe = Probe::default();
+        let now = Instant::now();
+
+        let mut request = None;
+        probe.handle_timeout(now, &mut rand::thread_rng(), |r| {
+            request = Some(r);
+            true
+        });
+
+        assert!(probe.handle_input(&request.unwrap()));
+        assert_eq!(probe.result(), Some(true));
+    }
+
+    #[test]
+    fn probe_fails_after_max_probes() {
+        let mut probe = Probe::default();
+        let mut now = Instant::now();
+
+        let mut num_sent = 0;
+        while probe.result().is_none() {
+            probe.handle_timeout(now, &mut rand::thread_rng(), |_| {
+                num_sent += 1;
+                true
+            });
+            now += PROBE_INTERVAL;
+        }
+
+        assert_eq!(num_sent, MAX_PROBES);
+        assert_eq!(probe.result(), Some(false));
+    }
+
+    #[test]
+    fn probe_does_not_count_requests_that_could_not_be_sent() {
+        let mut probe = Probe::default();
+        let mut now = Instant::now();
+
+        for _ in 0..10 {
+            probe.handle_timeout(now, &mut rand::thread_rng(), |_| false);
+            now += PROBE_INTERVAL;
+        }
+
+        assert_eq!(probe.result(), None);
+    }
+
+    #[test]
+    fn idle_probe_retries_requests_that_could_not_be_sent() {
+        let mut probe = Probe::default();
+        let now = Instant::now();
+
+        probe.handle_timeout(now, &mut rand::thread_rng(), |_| false);
+
+        assert_eq!(probe.poll_timeout(), Some(now + PROBE_INTERVAL));
+    }
+
+    #[test]
+    fn discovery_wakes_up_for_idle_probes_without_target() {
+        let mut discovery = NatDiscovery::default();
+        let now = Instant::now();
+
+        discovery.filtering.retry_later(now);
+
+        assert_eq!(discovery.poll_timeout(), Some(now + PROBE_INTERVAL));
+    }
+
+    #[test]
+    fn due_probe_without_target_waits_instead_of_spinning() {
+        let mut probe = Probe::default();
+        let mut now = Instant::now();
+
+        probe.handle_timeout(now, &mut rand::thread_rng(), |_| true);
+        now += PROBE_INTERVAL;
+        probe.retry_later(now);
+
+        assert_eq!(probe.poll_timeout(), Some(now + PROBE_INTERVAL));
+    }
+
+    #[test]
+    fn finished_probe_does_not_wake_up() {
+        let mut probe = Probe::default();
+        let now = Instant::now();
+
+        let mut request = None;
+        probe.handle_timeout(now, &mut rand::thread_rng(), |r| {
+            request = Some(r);
+            true
+        });
+        probe.handle_input(&request.unwrap());
+        probe.retry_later(now);
+
+        assert_eq!(probe.poll_timeout(), None);
+    }
+
+    #[test]
+    fn probe_ignores_unrelated_requests() {
+        let mut probe = Probe::default();
+        let now = Instant::now();
+
+        probe.handle_timeout(now, &mut rand::thread_rng(), |_| true);
+        let (_, other) = new_binding_request(&mut rand::thread_rng());
+
+        assert!(!probe.handle_input(&other));
+        assert_eq!(probe.result(), None);
+    }
+
+    fn s(socket: &str) -> SocketAddr {
+        socket.parse().unwrap()
+    }
+}
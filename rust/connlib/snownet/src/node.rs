@@ -1,7 +1,9 @@
 use crate::allocation::{Allocation, RelaySocket, Socket};
 use crate::index::IndexLfsr;
+use crate::nat_discovery::NatDiscovery;
+use crate::relay_selection::{self, Latency, Relay};
 use crate::ringbuffer::RingBuffer;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::stats::{ConnectionStats, NodeStats, PathKind};
 use crate::stun_binding::StunBinding;
 use crate::utils::earliest;
 use boringtun::noise::errors::WireGuardError;
@@ -12,7 +14,8 @@ use core::fmt;
 use ip_packet::ipv4::MutableIpv4Packet;
 use ip_packet::ipv6::MutableIpv6Packet;
 use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
 use secrecy::{ExposeSecret, Secret};
 use std::borrow::Cow;
 use std::hash::Hash;
@@ -21,7 +24,7 @@ use std::mem;
 use std::ops::ControlFlow;
 use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
     net::SocketAddr,
     sync::Arc,
 };
@@ -40,6 +43,11 @@ const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
 /// How long we will at most wait for an [`Answer`] from the remote.
 const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
+/// How long we will at most keep using host candidates from before a network change.
+///
+/// Usually, ICE nominates a new pair before that and we stop using them right away.
+const MIGRATION_TIMEOUT: Duration = Duration::from_secs(10);
+
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 
 /// Manages a set of wireguard connections for a server.
@@ -83,21 +91,42 @@ pub struct Node<T, TId, RId> {
     private_key: StaticSecret,
     index: IndexLfsr,
     rate_limiter: Arc<RateLimiter>,
-    host_candidates: HashSet<Candidate>,
+    /// Our host candidates, by their address.
+    host_candidates: BTreeMap<SocketAddr, Candidate>,
+    /// Host candidates from before the last network change that connections may still be using.
+    stale_host_candidates: BTreeMap<SocketAddr, Candidate>,
+    /// When we stop using [`Node::stale_host_candidates`] regardless of whether connections migrated.
+    migration_deadline: Option<Instant>,
     buffered_transmits: VecDeque<Transmit<'static>>,
 
     next_rate_limiter_reset: Option<Instant>,
 
-    bindings: HashMap<SocketAddr, StunBinding>,
-    allocations: HashMap<RId, Allocation>,
+    bindings: BTreeMap<SocketAddr, StunBinding>,
+    /// All relays we know about, only the fastest `max_relays` of them have an [`Allocation`].
+    relays: BTreeMap<RId, Relay>,
+    allocations: BTreeMap<RId, Allocation>,
+    /// Allocations on relays we no longer selected but that connections still relay through.
+    ///
+    /// We keep them until no connection uses them anymore but don't offer them to new connections.
+    retired_allocations: BTreeSet<RId>,
+    max_relays: usize,
 
     connections: Connections<TId, RId>,
     pending_events: VecDeque<Event<TId>>,
 
     buffer: Box<[u8; MAX_UDP_SIZE]>,
 
+    candidate_policy: CandidatePolicy,
+
+    nat_discovery: NatDiscovery,
+    /// The allocation and our server-reflexive address we bound a channel to for probing our filtering behaviour.
+    nat_probe_channel: Option<(RId, SocketAddr)>,
+
     stats: NodeStats,
 
+    /// All randomness of the node and its state machines is derived from this, see [`Node::with_seed`].
+    rng: StdRng,
+
     marker: PhantomData<T>,
 }
 
@@ -121,42 +150,137 @@ pub enum Error {
 
 impl<T, TId, RId> Node<T, TId, RId>
 where
-    TId: Eq + Hash + Copy + fmt::Display,
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
     pub fn new(private_key: StaticSecret) -> Self {
+        Self::with_seed(private_key, rand::random())
+    }
+
+    /// Creates a node whose transaction IDs, session indices and keys are derived from `seed`.
+    ///
+    /// Given the same inputs, two nodes with the same seed behave exactly the same, e.g. in a simulated network.
+    pub fn with_seed(private_key: StaticSecret, seed: u64) -> Self {
         let public_key = &(&private_key).into();
+        let mut rng = StdRng::seed_from_u64(seed);
+
         Self {
             private_key,
             marker: Default::default(),
-            index: IndexLfsr::default(),
+            index: IndexLfsr::new(&mut rng),
             rate_limiter: Arc::new(RateLimiter::new(public_key, HANDSHAKE_RATE_LIMIT)),
-            host_candidates: HashSet::default(),
+            host_candidates: BTreeMap::default(),
+            stale_host_candidates: BTreeMap::default(),
+            migration_deadline: None,
             buffered_transmits: VecDeque::default(),
             next_rate_limiter_reset: None,
             pending_events: VecDeque::default(),
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
-            bindings: HashMap::default(),
-            allocations: HashMap::default(),
+            bindings: BTreeMap::default(),
+            relays: BTreeMap::default(),
+            allocations: BTreeMap::default(),
+            retired_allocations: BTreeSet::default(),
+            max_relays: relay_selection::DEFAULT_MAX_RELAYS,
             connections: Default::default(),
+            candidate_policy: CandidatePolicy::default(),
+            nat_discovery: NatDiscovery::default(),
+            nat_probe_channel: None,
             stats: Default::default(),
+            rng,
         }
     }
 
+    /// Sets which of our candidates we use and signal to the remote.
+    ///
+    /// Candidates that are no longer allowed are invalidated on all connections.
+    /// Whether we signal a candidate is decided once, when it is added: host candidates that have already been signalled cannot be taken back by switching to [`CandidatePolicy::NoHost`].
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        let previous = mem::replace(&mut self.candidate_policy, policy);
+
+        if previous == policy {
+            return;
+        }
+
+        tracing::info!(?previous, new = ?policy, "Updating candidate policy");
+
+        for (id, agent) in self.connections.agents_mut() {
+            let disallowed = agent
+                .local_candidates()
+                .iter()
+                .filter(|c| !policy.uses(c.kind()))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for candidate in disallowed {
+                remove_local_candidate(id, agent, &candidate, previous, &mut self.pending_events);
+            }
+        }
+
+        // Add candidates that the new policy allows again.
+        let candidates = self
+            .host_candidates
+            .values()
+            .cloned()
+            .chain(self.bindings.values().filter_map(|b| b.candidate()))
+            .chain(
+                self.allocations
+                    .values()
+                    .flat_map(|a| a.current_candidates()),
+            )
+            .collect::<Vec<_>>();
+
+        for candidate in candidates {
+            add_local_candidate_to_all(
+                candidate,
+                &mut self.connections,
+                policy,
+                &mut self.pending_events,
+            );
+        }
+    }
+
+    /// Sets on how many relays we make allocations.
+    ///
+    /// We measure the latency to all relays and only use the fastest ones.
+    pub fn set_max_relays(&mut self, max_relays: usize, now: Instant) {
+        self.max_relays = max_relays;
+        self.select_relays(now);
+    }
+
+    /// Migrates all connections to a new network, e.g. after switching from Wi-Fi to cellular.
+    ///
+    /// Existing wireguard sessions are kept.
+    /// We gather new candidates and ICE runs connectivity checks for them in the background.
+    /// Each connection keeps using its current path until ICE nominates a new one, only then do we invalidate the host candidates from before the network change.
     pub fn reconnect(&mut self, now: Instant) {
         for binding in self.bindings.values_mut() {
             binding.refresh(now);
         }
 
+        for relay in self.relays.values_mut() {
+            relay.refresh(now);
+        }
+
         for allocation in self.allocations.values_mut() {
             allocation.refresh(now);
         }
 
-        for candidate in self.host_candidates.drain() {
-            for (id, agent) in self.connections.agents_mut() {
-                remove_local_candidate(id, agent, &candidate, &mut self.pending_events)
-            }
-        }
+        self.stale_host_candidates.append(&mut self.host_candidates);
+        self.migration_deadline = Some(now + MIGRATION_TIMEOUT);
+        self.nat_discovery = NatDiscovery::default(); // We are likely behind a different NAT now.
+        self.release_nat_probe_channel();
+
+        tracing::info!(
+            num_stale_candidates = %self.stale_host_candidates.len(),
+            "Migrating connections to new network"
+        );
+    }
+
+    /// Whether connections may still be using host candidates from before the last [`Node::reconnect`].
+    ///
+    /// Until the migration is completed, the sockets of these candidates must stay open.
+    pub fn is_migrating(&self) -> bool {
+        self.migration_deadline.is_some()
     }
 
     pub fn public_key(&self) -> PublicKey {
@@ -170,8 +294,18 @@ where
         })
     }
 
-    pub fn stats(&self) -> (NodeStats, impl Iterator<Item = (TId, ConnectionStats)> + '_) {
-        (self.stats, self.connections.stats())
+    pub fn stats(
+        &self,
+    ) -> (
+        NodeStats,
+        impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_,
+    ) {
+        let stats = NodeStats {
+            nat: self.nat_discovery.behaviour(),
+            ..self.stats
+        };
+
+        (stats, self.connections.stats())
     }
 
     /// Add an address as a `host` candidate.
@@ -287,11 +421,22 @@ where
     ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
         self.add_local_as_host_candidate(local)?;
 
+        if self.nat_discovery.handle_input(packet) {
+            tracing::debug!("Received our own NAT discovery probe");
+
+            return Ok(None);
+        }
+
         match self.bindings_try_handle(from, local, packet, now) {
             ControlFlow::Continue(()) => {}
             ControlFlow::Break(()) => return Ok(None),
         }
 
+        match self.relays_try_handle(from, local, packet, now) {
+            ControlFlow::Continue(()) => {}
+            ControlFlow::Break(()) => return Ok(None),
+        }
+
         let (from, packet, relayed) = match self.allocations_try_handle(from, local, packet, now) {
             ControlFlow::Continue(c) => c,
             ControlFlow::Break(()) => return Ok(None),
@@ -344,6 +489,9 @@ where
             return Ok(None);
         };
 
+        conn.stats.packets_sent += 1;
+        conn.stats.bytes_sent += packet.packet().len();
+
         let packet_start = 4;
         let packet_end = 4 + packet_len;
 
@@ -399,10 +547,16 @@ where
         for b in self.bindings.values_mut() {
             connection_timeout = earliest(connection_timeout, b.poll_timeout());
         }
+        for r in self.relays.values_mut() {
+            connection_timeout = earliest(connection_timeout, r.poll_timeout());
+        }
         for a in self.allocations.values_mut() {
             connection_timeout = earliest(connection_timeout, a.poll_timeout());
         }
 
+        connection_timeout = earliest(connection_timeout, self.migration_deadline);
+        connection_timeout = earliest(connection_timeout, self.nat_discovery.poll_timeout());
+
         earliest(connection_timeout, self.next_rate_limiter_reset)
     }
 
@@ -431,6 +585,7 @@ where
                 now,
                 &mut self.allocations,
                 &mut self.buffered_transmits,
+                self.candidate_policy,
                 &mut self.pending_events,
             );
         }
@@ -439,14 +594,32 @@ where
             connection.handle_timeout(id, now);
         }
 
+        self.invalidate_stale_host_candidates(now);
+
         for binding in self.bindings.values_mut() {
             binding.handle_timeout(now);
         }
 
+        let mut latencies_changed = false;
+
+        for relay in self.relays.values_mut() {
+            let before = relay.latency();
+            relay.handle_timeout(now);
+
+            latencies_changed |= !before.same_state(&relay.latency());
+        }
+
+        if latencies_changed {
+            self.select_relays(now);
+        }
+
         for allocation in self.allocations.values_mut() {
             allocation.handle_timeout(now);
         }
 
+        self.remove_unused_retired_allocations();
+        self.discover_nat_behaviour(now);
+
         let next_reset = *self.next_rate_limiter_reset.get_or_insert(now);
 
         if now >= next_reset {
@@ -477,6 +650,14 @@ where
             }
         }
 
+        for relay in self.relays.values_mut() {
+            if let Some(transmit) = relay.poll_transmit() {
+                self.stats.stun_bytes_to_relays += transmit.payload.len();
+
+                return Some(transmit);
+            }
+        }
+
         for allocation in self.allocations.values_mut() {
             if let Some(transmit) = allocation.poll_transmit() {
                 self.stats.stun_bytes_to_relays += transmit.payload.len();
@@ -488,28 +669,26 @@ where
         self.buffered_transmits.pop_front()
     }
 
+    /// Updates the set of relays we know about.
+    ///
+    /// We don't make an allocation on every relay.
+    /// Instead, we measure the latency to each relay via a STUN binding request and only keep allocations on the fastest [`Node::set_max_relays`] ones.
+    /// Each time relays come and go, we re-evaluate which ones to use.
     pub fn update_relays(
         &mut self,
         to_remove: HashSet<RId>,
         to_add: &HashSet<(RId, RelaySocket, String, String, String)>,
         now: Instant,
     ) {
+        // We iterate in a fixed order so a seeded node behaves the same regardless of how the sets are hashed.
+        let to_remove = to_remove.into_iter().collect::<BTreeSet<_>>();
+        let mut to_add = to_add.iter().collect::<Vec<_>>();
+        to_add.sort_by_key(|(id, ..)| *id);
+
         // First, invalidate all candidates from relays that we should stop using.
         for id in to_remove {
-            let Some(allocation) = self.allocations.remove(&id) else {
-                continue;
-            };
-
-            for (id, agent) in self.connections.agents_mut() {
-                let _span = info_span!("connection", %id).entered();
-
-                for candidate in allocation
-                    .current_candidates()
-                    .filter(|c| c.kind() == CandidateKind::Relayed)
-                {
-                    agent.invalidate_candidate(&candidate);
-                }
-            }
+            self.relays.remove(&id);
+            self.remove_allocation(id);
         }
 
         // Second, upsert all new relays.
@@ -524,17 +703,165 @@ where
             };
 
             if let Some(existing) = self.allocations.get_mut(id) {
-                existing.update_credentials(*server, username, password, realm, now);
+                existing.update_credentials(
+                    *server,
+                    username.clone(),
+                    password,
+                    realm.clone(),
+                    now,
+                );
+            }
+
+            if let Some(existing) = self.relays.get_mut(id) {
+                existing.update_credentials(*server, username, password.clone(), realm, now);
                 continue;
             }
 
-            self.allocations.insert(
+            self.relays.insert(
                 *id,
-                Allocation::new(*server, username, password.clone(), realm, now),
+                Relay::new(
+                    *server,
+                    username,
+                    password.clone(),
+                    realm,
+                    &mut self.rng,
+                    now,
+                ),
             );
 
             tracing::info!(%id, address = ?server, "Added new TURN server");
         }
+
+        self.select_relays(now);
+    }
+
+    /// Makes allocations on the fastest relays and removes the ones on all others.
+    ///
+    /// Allocations that connections still relay through are only retired, see [`Node::retired_allocations`].
+    fn select_relays(&mut self, now: Instant) {
+        let current = self
+            .allocations
+            .keys()
+            .filter(|id| !self.retired_allocations.contains(id))
+            .copied()
+            .collect::<BTreeSet<_>>();
+        let selected = relay_selection::select(
+            self.relays.iter().map(|(id, r)| (*id, r.latency())),
+            &current,
+            self.max_relays,
+        );
+
+        let unselected = self
+            .allocations
+            .keys()
+            .filter(|id| !selected.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        let in_use = self.connections.relays_in_use();
+
+        for id in unselected {
+            if in_use.contains(&id) {
+                if self.retired_allocations.insert(id) {
+                    tracing::info!(%id, "Keeping allocation on slower relay until no connection uses it anymore");
+                }
+
+                continue;
+            }
+
+            tracing::info!(%id, "Removing allocation on slower relay");
+
+            self.remove_allocation(id);
+        }
+
+        for id in selected {
+            if self.allocations.contains_key(&id) {
+                self.retired_allocations.remove(&id);
+                continue;
+            }
+
+            let Some(relay) = self.relays.get(&id) else {
+                continue;
+            };
+
+            let rtt = match relay.latency() {
+                Latency::Measured(rtt) => Some(rtt),
+                Latency::Pending | Latency::Unreachable => None,
+            };
+
+            self.allocations.insert(
+                id,
+                Allocation::new(
+                    relay.server,
+                    relay.username.clone(),
+                    relay.password.clone(),
+                    relay.realm.clone(),
+                    &mut self.rng,
+                    now,
+                ),
+            );
+
+            tracing::info!(%id, address = ?relay.server, ?rtt, "Making allocation on relay");
+        }
+
+        // Relays may redirect us to one another: Tell each allocation the credentials of the relays we don't have an allocation on yet.
+        let alternate_relays = self
+            .relays
+            .iter()
+            .filter(|(id, _)| !self.allocations.contains_key(id))
+            .map(|(_, r)| {
+                (
+                    r.server,
+                    r.username.clone(),
+                    r.password.clone(),
+                    r.realm.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for allocation in self.allocations.values_mut() {
+            allocation.set_alternate_relays(alternate_relays.iter().cloned());
+        }
+    }
+
+    /// Removes the retired allocations that no connection relays through anymore.
+    fn remove_unused_retired_allocations(&mut self) {
+        if self.retired_allocations.is_empty() {
+            return;
+        }
+
+        let in_use = self.connections.relays_in_use();
+        let unused = self
+            .retired_allocations
+            .iter()
+            .filter(|id| !in_use.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for id in unused {
+            tracing::info!(%id, "Removing allocation on slower relay");
+
+            self.remove_allocation(id);
+        }
+    }
+
+    /// Removes our allocation on the given relay and invalidates all its candidates.
+    fn remove_allocation(&mut self, id: RId) {
+        self.retired_allocations.remove(&id);
+
+        let Some(allocation) = self.allocations.remove(&id) else {
+            return;
+        };
+
+        for (id, agent) in self.connections.agents_mut() {
+            let _span = info_span!("connection", %id).entered();
+
+            for candidate in allocation
+                .current_candidates()
+                .filter(|c| c.kind() == CandidateKind::Relayed)
+            {
+                agent.invalidate_candidate(&candidate);
+            }
+        }
     }
 
     #[must_use]
@@ -584,7 +911,13 @@ where
     fn add_local_as_host_candidate(&mut self, local: SocketAddr) -> Result<(), Error> {
         let host_candidate = Candidate::host(local, Protocol::Udp)?;
 
-        let is_new = self.host_candidates.insert(host_candidate.clone());
+        // We might still see traffic on an interface that survived the network change.
+        self.stale_host_candidates.remove(&local);
+
+        let is_new = self
+            .host_candidates
+            .insert(local, host_candidate.clone())
+            .is_none();
 
         if !is_new {
             return Ok(());
@@ -593,12 +926,167 @@ where
         for (id, agent) in self.connections.agents_mut() {
             let _span = info_span!("connection", %id).entered();
 
-            add_local_candidate(id, agent, host_candidate.clone(), &mut self.pending_events);
+            add_local_candidate(
+                id,
+                agent,
+                host_candidate.clone(),
+                self.candidate_policy,
+                &mut self.pending_events,
+            );
         }
 
         Ok(())
     }
 
+    /// Classifies the NAT we are behind based on what our STUN servers and relays observe.
+    fn discover_nat_behaviour(&mut self, now: Instant) {
+        let before = self.nat_discovery.behaviour();
+
+        self.probe_nat_behaviour(now);
+
+        let after = self.nat_discovery.behaviour();
+
+        if before != after {
+            tracing::info!(mapping = ?after.mapping, filtering = ?after.filtering, hairpinning = ?after.hairpinning, "Updated NAT behaviour");
+        }
+    }
+
+    fn probe_nat_behaviour(&mut self, now: Instant) {
+        let observations = self
+            .bindings
+            .iter()
+            .filter_map(|(server, binding)| {
+                let candidate = binding.candidate()?;
+
+                Some((*server, candidate.base(), candidate.addr()))
+            })
+            .chain(self.relays.values().filter_map(|r| r.observed_address()));
+
+        self.nat_discovery.observe(observations);
+
+        // Without a NAT, there is nothing to probe.
+        let Some((local, srflx)) = self.nat_discovery.translated_address() else {
+            return;
+        };
+
+        self.nat_discovery
+            .hairpinning
+            .handle_timeout(now, &mut self.rng, |request| {
+                self.buffered_transmits.push_back(Transmit {
+                    src: Some(local),
+                    dst: srflx,
+                    payload: Cow::Owned(request),
+                });
+
+                true
+            });
+
+        if self.nat_discovery.filtering.result().is_some() {
+            self.release_nat_probe_channel();
+            return;
+        }
+
+        // To probe our filtering behaviour, we relay a request to ourselves through one of our allocations.
+        // We stick to one allocation so we only ever bind a single channel for it.
+        let target = self
+            .nat_probe_channel
+            .filter(|(rid, srflx)| {
+                self.allocations
+                    .get(rid)
+                    .is_some_and(|a| a.current_candidates().any(|c| c.addr() == *srflx))
+            })
+            .or_else(|| {
+                self.allocations.iter().find_map(|(rid, a)| {
+                    let srflx = a.current_candidates().find(|c| {
+                        c.kind() == CandidateKind::ServerReflexive && c.addr() != c.base()
+                    })?;
+
+                    Some((*rid, srflx.addr()))
+                })
+            });
+
+        let Some((rid, srflx)) = target else {
+            self.nat_discovery.filtering.retry_later(now);
+            return;
+        };
+
+        if self.nat_probe_channel != Some((rid, srflx)) {
+            self.release_nat_probe_channel();
+            self.nat_probe_channel = Some((rid, srflx));
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(&rid)
+            .expect("to only probe through existing allocations");
+
+        allocation.bind_channel(srflx, now);
+
+        self.nat_discovery
+            .filtering
+            .handle_timeout(now, &mut self.rng, |request| {
+                let Some(transmit) = allocation.encode_to_owned_transmit(srflx, &request, now)
+                else {
+                    return false;
+                };
+
+                self.buffered_transmits.push_back(transmit);
+
+                true
+            });
+    }
+
+    fn release_nat_probe_channel(&mut self) {
+        let Some((rid, srflx)) = self.nat_probe_channel.take() else {
+            return;
+        };
+
+        if let Some(allocation) = self.allocations.get_mut(&rid) {
+            allocation.release_channel(srflx);
+        }
+    }
+
+    /// Invalidates the host candidates from before a network change on all connections that no longer use them.
+    ///
+    /// Once the migration deadline passes, we invalidate them on all connections.
+    fn invalidate_stale_host_candidates(&mut self, now: Instant) {
+        let Some(deadline) = self.migration_deadline else {
+            return;
+        };
+        let deadline_passed = now >= deadline;
+
+        let initial_agents = self
+            .connections
+            .initial
+            .iter_mut()
+            .map(|(id, c)| (*id, &mut c.agent));
+        let migrated_agents = self
+            .connections
+            .established
+            .iter_mut()
+            .filter(|(_, c)| deadline_passed || !c.uses_any_of(&self.stale_host_candidates))
+            .map(|(id, c)| (*id, &mut c.agent));
+
+        for (id, agent) in initial_agents.chain(migrated_agents) {
+            for candidate in self.stale_host_candidates.values() {
+                remove_local_candidate(
+                    id,
+                    agent,
+                    candidate,
+                    self.candidate_policy,
+                    &mut self.pending_events,
+                );
+            }
+        }
+
+        if deadline_passed {
+            tracing::info!("Migration to new network completed");
+
+            self.stale_host_candidates.clear();
+            self.migration_deadline = None;
+        }
+    }
+
     #[must_use]
     fn bindings_try_handle(
         &mut self,
@@ -620,10 +1108,44 @@ where
         ControlFlow::Break(())
     }
 
+    /// Tries to handle the packet as a response to one of our latency probes of a relay.
+    #[must_use]
+    fn relays_try_handle(
+        &mut self,
+        from: SocketAddr,
+        local: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) -> ControlFlow<()> {
+        // STUN messages start with two zero bits, skip all other traffic (e.g. channel data) from relays early.
+        if !packet.first().is_some_and(|b| b >> 6 == 0) {
+            return ControlFlow::Continue(());
+        }
+
+        let mut latency_changed = None;
+
+        for relay in self.relays.values_mut() {
+            let before = relay.latency();
+
+            if relay.handle_input(from, local, packet, now) {
+                latency_changed = Some(!before.same_state(&relay.latency()));
+                break;
+            }
+        }
+
+        match latency_changed {
+            Some(true) => self.select_relays(now),
+            Some(false) => {}
+            None => return ControlFlow::Continue(()),
+        }
+
+        ControlFlow::Break(())
+    }
+
     /// Tries to handle the packet using one of our [`Allocation`]s.
     ///
     /// This function is in the hot-path of packet processing and thus must be as efficient as possible.
-    /// Even look-ups in [`HashMap`]s and linear searches across small lists are expensive at this point.
+    /// Even look-ups in [`BTreeMap`]s and linear searches across small lists are expensive at this point.
     /// Thus, we use the first byte of the message as a heuristic for whether we should attempt to handle it here.
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2> for details on de-multiplexing.
@@ -738,6 +1260,7 @@ where
             }
 
             let handshake_complete_before_decapsulate = conn.wg_handshake_complete();
+            let time_since_handshake_before_decapsulate = conn.tunnel.time_since_last_handshake();
 
             let control_flow = conn.decapsulate(
                 packet,
@@ -749,6 +1272,21 @@ where
 
             let handshake_complete_after_decapsulate = conn.wg_handshake_complete();
 
+            // A completed handshake resets the time since the last one.
+            match (
+                time_since_handshake_before_decapsulate,
+                conn.tunnel.time_since_last_handshake(),
+            ) {
+                (None, Some(_)) => conn.stats.handshakes += 1,
+                (Some(before), Some(after)) if after < before => conn.stats.handshakes += 1,
+                _ => {}
+            }
+
+            if let ControlFlow::Continue(packet) = &control_flow {
+                conn.stats.packets_received += 1;
+                conn.stats.bytes_received += packet.packet().len();
+            }
+
             // I can't think of a better way to detect this ...
             if !handshake_complete_before_decapsulate && handshake_complete_after_decapsulate {
                 tracing::info!(duration_since_intent = ?conn.duration_since_intent(now), "Completed wireguard handshake");
@@ -784,6 +1322,7 @@ where
                     add_local_candidate_to_all(
                         candidate,
                         &mut self.connections,
+                        self.candidate_policy,
                         &mut self.pending_events,
                     );
                 }
@@ -791,7 +1330,13 @@ where
                     for (id, agent) in self.connections.agents_mut() {
                         let _span = info_span!("connection", %id).entered();
 
-                        remove_local_candidate(id, agent, &candidate, &mut self.pending_events);
+                        remove_local_candidate(
+                            id,
+                            agent,
+                            &candidate,
+                            self.candidate_policy,
+                            &mut self.pending_events,
+                        );
                     }
                 }
             }
@@ -801,8 +1346,8 @@ where
 
 impl<TId, RId> Node<Client, TId, RId>
 where
-    TId: Eq + Hash + Copy + fmt::Display,
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
     /// Create a new connection indexed by the given ID.
     ///
@@ -833,7 +1378,7 @@ where
         agent.set_controlling(true);
         agent.set_max_candidate_pairs(300);
 
-        let session_key = Secret::new(random());
+        let session_key = Secret::new(self.rng.gen());
         let ice_creds = agent.local_credentials();
 
         let params = Offer {
@@ -901,8 +1446,8 @@ where
 
 impl<TId, RId> Node<Server, TId, RId>
 where
-    TId: Eq + Hash + Copy + fmt::Display,
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
     /// Accept a new connection indexed by the given ID.
     ///
@@ -965,23 +1510,29 @@ where
 
 impl<T, TId, RId> Node<T, TId, RId>
 where
-    TId: Eq + Hash + Copy + fmt::Display,
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
     fn upsert_stun_servers(&mut self, servers: &HashSet<SocketAddr>, now: Instant) {
-        for server in servers {
+        for server in servers.iter().collect::<BTreeSet<_>>() {
             if !self.bindings.contains_key(server) {
                 tracing::info!(address = %server, "Adding new STUN server");
 
                 self.bindings
-                    .insert(*server, StunBinding::new(*server, now));
+                    .insert(*server, StunBinding::new(*server, &mut self.rng, now));
             }
         }
     }
 
     fn seed_agent_with_local_candidates(&mut self, connection: TId, agent: &mut IceAgent) {
-        for candidate in self.host_candidates.iter().cloned() {
-            add_local_candidate(connection, agent, candidate, &mut self.pending_events);
+        for candidate in self.host_candidates.values().cloned() {
+            add_local_candidate(
+                connection,
+                agent,
+                candidate,
+                self.candidate_policy,
+                &mut self.pending_events,
+            );
         }
 
         for candidate in self
@@ -993,19 +1544,22 @@ where
                 connection,
                 agent,
                 candidate.clone(),
+                self.candidate_policy,
                 &mut self.pending_events,
             );
         }
 
         for candidate in self
             .allocations
-            .values()
-            .flat_map(|allocation| allocation.current_candidates())
+            .iter()
+            .filter(|(id, _)| !self.retired_allocations.contains(id))
+            .flat_map(|(_, allocation)| allocation.current_candidates())
         {
             add_local_candidate(
                 connection,
                 agent,
                 candidate.clone(),
+                self.candidate_policy,
                 &mut self.pending_events,
             );
         }
@@ -1013,8 +1567,8 @@ where
 }
 
 struct Connections<TId, RId> {
-    initial: HashMap<TId, InitialConnection>,
-    established: HashMap<TId, Connection<RId>>,
+    initial: BTreeMap<TId, InitialConnection>,
+    established: BTreeMap<TId, Connection<RId>>,
 }
 
 impl<TId, RId> Default for Connections<TId, RId> {
@@ -1028,8 +1582,8 @@ impl<TId, RId> Default for Connections<TId, RId> {
 
 impl<TId, RId> Connections<TId, RId>
 where
-    TId: Eq + Hash + Copy + fmt::Display,
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
     fn remove_failed(&mut self, events: &mut VecDeque<Event<TId>>) {
         self.initial.retain(|id, conn| {
@@ -1051,8 +1605,8 @@ where
         });
     }
 
-    fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+    fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_ {
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1076,6 +1630,17 @@ where
         self.established.get_mut(id)
     }
 
+    /// The relays that our established connections currently relay their traffic through.
+    fn relays_in_use(&self) -> HashSet<RId> {
+        self.established
+            .values()
+            .filter_map(|c| match c.socket()? {
+                PeerSocket::Relay { relay, .. } => Some(relay),
+                PeerSocket::Direct { .. } => None,
+            })
+            .collect()
+    }
+
     fn iter_established(&self) -> impl Iterator<Item = (TId, &Connection<RId>)> {
         self.established.iter().map(|(id, conn)| (*id, conn))
     }
@@ -1098,11 +1663,11 @@ fn encode_as_channel_data<RId>(
     relay: RId,
     dest: SocketAddr,
     contents: &[u8],
-    allocations: &mut HashMap<RId, Allocation>,
+    allocations: &mut BTreeMap<RId, Allocation>,
     now: Instant,
 ) -> Result<Transmit<'static>, EncodeError>
 where
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug,
 {
     let allocation = allocations
         .get_mut(&relay)
@@ -1123,6 +1688,7 @@ enum EncodeError {
 fn add_local_candidate_to_all<TId, RId>(
     candidate: Candidate,
     connections: &mut Connections<TId, RId>,
+    policy: CandidatePolicy,
     pending_events: &mut VecDeque<Event<TId>>,
 ) where
     TId: Copy + fmt::Display,
@@ -1139,7 +1705,7 @@ fn add_local_candidate_to_all<TId, RId>(
     for (id, agent) in initial_connections.chain(established_connections) {
         let _span = info_span!("connection", %id).entered();
 
-        add_local_candidate(id, agent, candidate.clone(), pending_events);
+        add_local_candidate(id, agent, candidate.clone(), policy, pending_events);
     }
 }
 
@@ -1147,16 +1713,21 @@ fn add_local_candidate<TId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: Candidate,
+    policy: CandidatePolicy,
     pending_events: &mut VecDeque<Event<TId>>,
 ) where
     TId: fmt::Display,
 {
+    if !policy.uses(candidate.kind()) {
+        return;
+    }
+
     let is_new = agent.add_local_candidate(candidate.clone());
 
-    if is_new {
+    if is_new && policy.signals(candidate.kind()) {
         pending_events.push_back(Event::NewIceCandidate {
             connection: id,
-            candidate: candidate.to_sdp_string(),
+            candidate: policy.sdp_string(&candidate),
         })
     }
 }
@@ -1165,20 +1736,84 @@ fn remove_local_candidate<TId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: &Candidate,
+    policy: CandidatePolicy,
     pending_events: &mut VecDeque<Event<TId>>,
 ) where
     TId: fmt::Display,
 {
     let was_present = agent.invalidate_candidate(candidate);
 
-    if was_present {
+    if was_present && policy.signals(candidate.kind()) {
         pending_events.push_back(Event::InvalidateIceCandidate {
             connection: id,
-            candidate: candidate.to_sdp_string(),
+            candidate: policy.sdp_string(candidate),
         })
     }
 }
 
+/// Which of our candidates a [`Node`] uses for its connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CandidatePolicy {
+    /// Use and signal all candidates.
+    #[default]
+    All,
+    /// Use all candidates but don't signal host candidates, i.e. don't reveal our local IPs to the remote.
+    ///
+    /// The related address of the candidates we do signal is stripped as well because it is the host candidate they are derived from.
+    /// Direct connections via server-reflexive candidates or peer-reflexive candidates learned by the remote are still possible.
+    NoHost,
+    /// Only use relayed candidates, i.e. never send traffic to the remote directly.
+    RelayOnly,
+}
+
+impl CandidatePolicy {
+    /// Whether we add a candidate of this kind to our ICE agents.
+    fn uses(&self, kind: CandidateKind) -> bool {
+        match self {
+            CandidatePolicy::All | CandidatePolicy::NoHost => true,
+            CandidatePolicy::RelayOnly => kind == CandidateKind::Relayed,
+        }
+    }
+
+    /// Whether we signal a candidate of this kind to the remote.
+    fn signals(&self, kind: CandidateKind) -> bool {
+        match self {
+            CandidatePolicy::All => true,
+            CandidatePolicy::NoHost => kind != CandidateKind::Host,
+            CandidatePolicy::RelayOnly => kind == CandidateKind::Relayed,
+        }
+    }
+
+    /// How we signal the given candidate to the remote.
+    fn sdp_string(&self, candidate: &Candidate) -> String {
+        let sdp = candidate.to_sdp_string();
+
+        match self {
+            CandidatePolicy::All | CandidatePolicy::RelayOnly => sdp,
+            CandidatePolicy::NoHost => strip_related_address(&sdp),
+        }
+    }
+}
+
+/// Removes the `raddr` and `rport` attributes from the SDP string of a candidate.
+///
+/// For server-reflexive candidates, these contain the address of the host candidate.
+fn strip_related_address(sdp: &str) -> String {
+    let mut tokens = sdp.split(' ');
+    let mut stripped = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        if token == "raddr" || token == "rport" {
+            tokens.next(); // Skip the value.
+            continue;
+        }
+
+        stripped.push(token);
+    }
+
+    stripped.join(" ")
+}
+
 pub struct Offer {
     /// The Wireguard session key for a connection.
     pub session_key: Secret<[u8; 32]>,
@@ -1216,6 +1851,19 @@ pub enum Event<TId> {
 
     ConnectionEstablished(TId),
 
+    /// ICE nominated a different pair of sockets for this connection.
+    ///
+    /// This happens once when the connection is first set up and again whenever it moves, e.g. from a direct to a relayed path or to a different relay.
+    ConnectionPathChanged {
+        connection: TId,
+        /// The kind of our local candidate in the nominated pair.
+        kind: PathKind,
+        /// Our local candidate's address, i.e. our relayed address in case of [`PathKind::Relayed`].
+        local: SocketAddr,
+        /// The remote's address we are sending to.
+        remote: SocketAddr,
+    },
+
     /// We failed to establish a connection.
     ///
     /// All state associated with the connection has been cleared.
@@ -1247,8 +1895,9 @@ impl<'a> Transmit<'a> {
     }
 }
 
+/// A candidate gathered or invalidated by an [`Allocation`](crate::Allocation).
 #[derive(Debug, PartialEq)]
-pub(crate) enum CandidateEvent {
+pub enum CandidateEvent {
     New(Candidate),
     Invalid(Candidate),
 }
@@ -1289,7 +1938,7 @@ struct Connection<RId> {
 
     state: ConnectionState<RId>,
 
-    stats: ConnectionStats,
+    stats: ConnectionStats<RId>,
     intent_sent_at: Instant,
     signalling_completed_at: Instant,
 
@@ -1349,7 +1998,7 @@ enum PeerSocket<RId> {
 
 impl<RId> Connection<RId>
 where
-    RId: PartialEq + Eq + Hash + fmt::Debug + Copy,
+    RId: PartialEq + Eq + Hash + Ord + fmt::Debug + Copy,
 {
     /// Checks if we want to accept a packet from a certain address.
     ///
@@ -1376,6 +2025,16 @@ where
         }
     }
 
+    fn stats(&self) -> ConnectionStats<RId> {
+        let (time_since_last_handshake, _, _, _, rtt_millis) = self.tunnel.stats();
+
+        ConnectionStats {
+            rtt: rtt_millis.map(|ms| Duration::from_millis(ms as u64)),
+            time_since_last_handshake,
+            ..self.stats
+        }
+    }
+
     fn wg_handshake_complete(&self) -> bool {
         self.tunnel.time_since_last_handshake().is_some()
     }
@@ -1406,8 +2065,9 @@ where
         &mut self,
         id: TId,
         now: Instant,
-        allocations: &mut HashMap<RId, Allocation>,
+        allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        candidate_policy: CandidatePolicy,
         pending_events: &mut VecDeque<Event<TId>>,
     ) where
         TId: fmt::Display + Copy,
@@ -1478,6 +2138,15 @@ where
                         .expect("to only nominate existing candidates")
                         .clone();
 
+                    let path = match nominated_candidate.kind() {
+                        CandidateKind::Host => PathKind::Host,
+                        CandidateKind::ServerReflexive => PathKind::ServerReflexive,
+                        CandidateKind::Relayed => PathKind::Relayed,
+                        CandidateKind::PeerReflexive => {
+                            unreachable!("local candidate is never `PeerReflexive`")
+                        }
+                    };
+
                     let remote_socket = match nominated_candidate.kind() {
                         CandidateKind::Relayed => {
                             let relay = allocations.iter().find_map(|(relay, allocation)| {
@@ -1550,7 +2219,25 @@ where
 
                     tracing::info!(?old, new = ?remote_socket, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
 
-                    self.invalidate_candiates(id, nominated_candidate, pending_events);
+                    pending_events.push_back(Event::ConnectionPathChanged {
+                        connection: id,
+                        kind: path,
+                        local: source,
+                        remote: destination,
+                    });
+
+                    self.stats.path = Some(path);
+                    self.stats.relay = match remote_socket {
+                        PeerSocket::Relay { relay, .. } => Some(relay),
+                        PeerSocket::Direct { .. } => None,
+                    };
+
+                    self.invalidate_candiates(
+                        id,
+                        nominated_candidate,
+                        candidate_policy,
+                        pending_events,
+                    );
                     self.force_handshake(allocations, transmits, now);
                 }
                 IceAgentEvent::IceRestart(_) | IceAgentEvent::IceConnectionStateChange(_) => {}
@@ -1614,7 +2301,7 @@ where
         &mut self,
         packet: &[u8],
         buffer: &'b mut [u8],
-        allocations: &mut HashMap<RId, Allocation>,
+        allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
         now: Instant,
     ) -> ControlFlow<Result<(), Error>, MutableIpPacket<'b>> {
@@ -1687,7 +2374,7 @@ where
 
     fn force_handshake(
         &mut self,
-        allocations: &mut HashMap<RId, Allocation>,
+        allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
         now: Instant,
     ) where
@@ -1722,6 +2409,7 @@ where
         &mut self,
         id: TId,
         nominated: Candidate,
+        candidate_policy: CandidatePolicy,
         pending_events: &mut VecDeque<Event<TId>>,
     ) where
         TId: Copy + fmt::Display,
@@ -1737,7 +2425,13 @@ where
             .collect::<Vec<_>>();
 
         for candidate in irrelevant_candidates {
-            remove_local_candidate(id, &mut self.agent, &candidate, pending_events)
+            remove_local_candidate(
+                id,
+                &mut self.agent,
+                &candidate,
+                candidate_policy,
+                pending_events,
+            )
         }
     }
 
@@ -1759,17 +2453,25 @@ where
     fn is_failed(&self) -> bool {
         matches!(self.state, ConnectionState::Failed)
     }
+
+    /// Whether our nominated socket is one of the given local candidates.
+    fn uses_any_of(&self, candidates: &BTreeMap<SocketAddr, Candidate>) -> bool {
+        match self.socket() {
+            Some(PeerSocket::Direct { source, .. }) => candidates.contains_key(&source),
+            Some(PeerSocket::Relay { .. }) | None => false,
+        }
+    }
 }
 
 #[must_use]
 fn make_owned_transmit<RId>(
     socket: PeerSocket<RId>,
     message: &[u8],
-    allocations: &mut HashMap<RId, Allocation>,
+    allocations: &mut BTreeMap<RId, Allocation>,
     now: Instant,
 ) -> Option<Transmit<'static>>
 where
-    RId: Copy + Eq + Hash + PartialEq + fmt::Debug,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug,
 {
     let transmit = match socket {
         PeerSocket::Direct {
@@ -1787,3 +2489,121 @@ where
 
     Some(transmit)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV4;
+
+    #[test]
+    fn keeps_allocations_while_relay_probes_are_pending() {
+        let mut now = Instant::now();
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([relay(1, "10.0.0.1:3478")]),
+            now,
+        );
+        assert!(node.allocations.contains_key(&1));
+
+        // Nobody answers our probe, yet the unreachable relay is the only one we have.
+        now += Duration::from_secs(3);
+        node.handle_timeout(now);
+        assert!(node.allocations.contains_key(&1));
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([relay(2, "10.0.0.2:3478")]),
+            now,
+        );
+        assert!(node.allocations.contains_key(&1));
+    }
+
+    #[test]
+    fn allocates_before_relay_probes_completed() {
+        let now = Instant::now();
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([
+                relay(1, "10.0.0.1:3478"),
+                relay(2, "10.0.0.2:3478"),
+                relay(3, "10.0.0.3:3478"),
+            ]),
+            now,
+        );
+
+        assert_eq!(node.allocations.len(), relay_selection::DEFAULT_MAX_RELAYS);
+    }
+
+    #[test]
+    fn switches_to_fastest_relay_only_once_all_probes_completed() {
+        let mut now = Instant::now();
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+        node.set_max_relays(1, now);
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([relay(1, "10.0.0.1:3478"), relay(2, "10.0.0.2:3478")]),
+            now,
+        );
+        let initial = *node.allocations.keys().next().unwrap();
+        let other = if initial == 1 { 2 } else { 1 };
+
+        // The relay we didn't pick answers quickly, yet we don't know about the other one.
+        now += Duration::from_millis(10);
+        answer_probe(&mut node, other, now);
+        assert_eq!(node.allocations.keys().collect::<Vec<_>>(), vec![&initial]);
+
+        now += Duration::from_millis(90);
+        answer_probe(&mut node, initial, now);
+        assert_eq!(node.allocations.keys().collect::<Vec<_>>(), vec![&other]);
+    }
+
+    #[test]
+    fn probes_relay_again_after_its_address_changed() {
+        let now = Instant::now();
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([relay(1, "10.0.0.1:3478")]),
+            now,
+        );
+        node.update_relays(
+            HashSet::default(),
+            &HashSet::from([relay(1, "10.0.0.3:3478")]),
+            now,
+        );
+
+        let probe = node.relays.get_mut(&1).unwrap().poll_transmit().unwrap();
+        assert_eq!(probe.dst, "10.0.0.3:3478".parse::<SocketAddr>().unwrap());
+    }
+
+    fn answer_probe(node: &mut ClientNode<u64, u64>, id: u64, now: Instant) {
+        let probe = node.relays.get_mut(&id).unwrap().poll_transmit().unwrap();
+        let from = probe.dst;
+        let response =
+            crate::stun_binding::generate_stun_response(probe, "1.1.1.1:4444".parse().unwrap());
+
+        let handled =
+            node.relays_try_handle(from, "192.168.0.1:4444".parse().unwrap(), &response, now);
+        assert_eq!(handled, ControlFlow::Break(()));
+    }
+
+    fn relay(id: u64, addr: &str) -> (u64, RelaySocket, String, String, String) {
+        (
+            id,
+            RelaySocket::from(addr.parse::<SocketAddrV4>().unwrap()),
+            "username".to_owned(),
+            "password".to_owned(),
+            "firezone".to_owned(),
+        )
+    }
+}
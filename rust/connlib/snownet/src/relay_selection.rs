@@ -0,0 +1,310 @@
+//! Picks the relays we make allocations on, based on their round-trip time.
+//!
+//! We send every relay a STUN binding request to measure its latency.
+//! Until all probes completed, we allocate on whichever relays we have to not delay connections. Afterwards, we only keep allocations on the fastest few.
+
+use crate::allocation::RelaySocket;
+use crate::node::Transmit;
+use crate::stun_binding::StunBinding;
+use crate::utils::earliest;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng as _};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use stun_codec::rfc5389::attributes::{Realm, Username};
+
+/// For how long we wait for a relay to answer our STUN binding request before we consider it unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// On how many relays we make allocations by default.
+pub(crate) const DEFAULT_MAX_RELAYS: usize = 2;
+
+/// A relay we know about together with the STUN binding we use to measure its latency.
+pub(crate) struct Relay {
+    pub(crate) server: RelaySocket,
+    pub(crate) username: Username,
+    pub(crate) password: String,
+    pub(crate) realm: Realm,
+
+    probe: StunBinding,
+    probe_address: SocketAddr,
+    added_at: Instant,
+    timed_out: bool,
+
+    /// Seeds the probes we make whenever the relay's address changes.
+    rng: StdRng,
+}
+
+/// The latency of a relay, as far as we know it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Latency {
+    /// We are still waiting for the first response.
+    Pending,
+    Measured(Duration),
+    /// The relay didn't answer in time.
+    Unreachable,
+}
+
+impl Latency {
+    /// Whether `self` and `other` are the same variant, ignoring the measured duration.
+    pub(crate) fn same_state(&self, other: &Latency) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl Relay {
+    pub(crate) fn new(
+        server: RelaySocket,
+        username: Username,
+        password: String,
+        realm: Realm,
+        rng: &mut impl Rng,
+        now: Instant,
+    ) -> Self {
+        let probe_address = probe_address(server);
+        let mut rng = StdRng::seed_from_u64(rng.gen());
+
+        Self {
+            server,
+            username,
+            password,
+            realm,
+            probe: StunBinding::new(probe_address, &mut rng, now),
+            probe_address,
+            added_at: now,
+            timed_out: false,
+            rng,
+        }
+    }
+
+    pub(crate) fn latency(&self) -> Latency {
+        match (self.probe.rtt(), self.timed_out) {
+            (Some(rtt), _) => Latency::Measured(rtt),
+            (None, false) => Latency::Pending,
+            (None, true) => Latency::Unreachable,
+        }
+    }
+
+    /// The address the relay observed for us, as `(server, local, observed)`.
+    pub(crate) fn observed_address(&self) -> Option<(SocketAddr, SocketAddr, SocketAddr)> {
+        let candidate = self.probe.candidate()?;
+
+        Some((self.probe_address, candidate.base(), candidate.addr()))
+    }
+
+    /// Handles a STUN binding response to our probe.
+    ///
+    /// Returns `false` if the packet is not for us, e.g. because it is a TURN response from the same relay.
+    pub(crate) fn handle_input(
+        &mut self,
+        from: SocketAddr,
+        local: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) -> bool {
+        if from != self.probe_address {
+            return false;
+        }
+
+        let handled = self.probe.handle_input(from, local, packet, now);
+
+        self.discard_events();
+
+        handled
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.probe.handle_timeout(now);
+
+        if self.probe.rtt().is_none() && now >= self.added_at + PROBE_TIMEOUT {
+            self.timed_out = true;
+        }
+
+        self.discard_events();
+    }
+
+    pub(crate) fn poll_timeout(&mut self) -> Option<Instant> {
+        let probe_timeout =
+            (self.latency() == Latency::Pending).then_some(self.added_at + PROBE_TIMEOUT);
+
+        earliest(self.probe.poll_timeout(), probe_timeout)
+    }
+
+    pub(crate) fn poll_transmit(&mut self) -> Option<Transmit<'static>> {
+        self.probe.poll_transmit()
+    }
+
+    pub(crate) fn refresh(&mut self, now: Instant) {
+        self.probe.refresh(now);
+    }
+
+    /// Updates the relay's address and credentials.
+    ///
+    /// If the address we probe changed, we measure the latency again.
+    pub(crate) fn update_credentials(
+        &mut self,
+        server: RelaySocket,
+        username: Username,
+        password: String,
+        realm: Realm,
+        now: Instant,
+    ) {
+        let new_probe_address = probe_address(server);
+
+        if new_probe_address != self.probe_address {
+            self.probe = StunBinding::new(new_probe_address, &mut self.rng, now);
+            self.probe_address = new_probe_address;
+            self.added_at = now;
+            self.timed_out = false;
+        }
+
+        self.server = server;
+        self.username = username;
+        self.password = password;
+        self.realm = realm;
+    }
+
+    /// The relay's server-reflexive candidates are the same as the ones of our allocation, we don't need them here.
+    fn discard_events(&mut self) {
+        while self.probe.poll_event().is_some() {}
+    }
+}
+
+/// We only need one address to measure the latency, prefer IPv4 if the relay is dual-stack.
+fn probe_address(server: RelaySocket) -> SocketAddr {
+    server
+        .as_v4()
+        .map(|s| SocketAddr::V4(*s))
+        .or_else(|| server.as_v6().map(|s| SocketAddr::V6(*s)))
+        .expect("relay to have at least one address")
+}
+
+/// Selects the `max` relays with the lowest latency.
+///
+/// While probes are pending, we don't know yet which relays are the fastest.
+/// To not delay our first allocations, we keep the `current` relays and only top them up to `max`, preferring measured over pending relays.
+/// Once all probes completed, we switch to the fastest relays.
+///
+/// Unreachable relays are only selected if no probes are pending and there aren't enough reachable relays.
+/// That way, we still make allocations if none of the relays answer STUN binding requests.
+pub(crate) fn select<RId>(
+    relays: impl IntoIterator<Item = (RId, Latency)>,
+    current: &BTreeSet<RId>,
+    max: usize,
+) -> BTreeSet<RId>
+where
+    RId: Copy + Ord,
+{
+    let mut measured = Vec::new();
+    let mut pending = Vec::new();
+    let mut unreachable = Vec::new();
+
+    for (id, latency) in relays {
+        match latency {
+            Latency::Measured(rtt) => measured.push((id, rtt)),
+            Latency::Pending => pending.push(id),
+            Latency::Unreachable => unreachable.push(id),
+        }
+    }
+
+    measured.sort_by_key(|(_, rtt)| *rtt);
+    let measured = measured.into_iter().map(|(id, _)| id);
+
+    if pending.is_empty() {
+        return measured.chain(unreachable).take(max).collect();
+    }
+
+    let mut selected = current.clone();
+    let missing = max.saturating_sub(selected.len());
+    let candidates = measured
+        .chain(pending)
+        .filter(|id| !current.contains(id))
+        .take(missing)
+        .collect::<Vec<_>>();
+
+    selected.extend(candidates);
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_fastest_relays() {
+        let selected = select(
+            [
+                (1, Latency::Measured(Duration::from_millis(80))),
+                (2, Latency::Measured(Duration::from_millis(20))),
+                (3, Latency::Measured(Duration::from_millis(50))),
+            ],
+            &BTreeSet::new(),
+            2,
+        );
+
+        assert_eq!(selected, BTreeSet::from([2, 3]));
+    }
+
+    #[test]
+    fn selects_pending_relays_before_probes_completed() {
+        let selected = select(
+            [
+                (1, Latency::Pending),
+                (2, Latency::Measured(Duration::from_millis(20))),
+                (3, Latency::Pending),
+            ],
+            &BTreeSet::new(),
+            2,
+        );
+
+        assert!(selected.contains(&2));
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn keeps_current_relays_while_probes_are_pending() {
+        let current = BTreeSet::from([1, 2]);
+
+        let selected = select(
+            [
+                (1, Latency::Pending),
+                (2, Latency::Measured(Duration::from_millis(80))),
+                (3, Latency::Measured(Duration::from_millis(20))),
+            ],
+            &current,
+            2,
+        );
+        assert_eq!(selected, current);
+
+        let selected = select(
+            [
+                (1, Latency::Measured(Duration::from_millis(50))),
+                (2, Latency::Measured(Duration::from_millis(80))),
+                (3, Latency::Measured(Duration::from_millis(20))),
+            ],
+            &current,
+            2,
+        );
+        assert_eq!(selected, BTreeSet::from([1, 3]));
+    }
+
+    #[test]
+    fn falls_back_to_unreachable_relays_once_all_probes_completed() {
+        let relays = [
+            (1, Latency::Unreachable),
+            (2, Latency::Measured(Duration::from_millis(20))),
+        ];
+
+        assert_eq!(select(relays, &BTreeSet::new(), 2), BTreeSet::from([1, 2]));
+        assert_eq!(
+            select(
+                relays.into_iter().chain([(3, Latency::Pending)]),
+                &BTreeSet::new(),
+                2
+            ),
+            BTreeSet::from([2, 3])
+        );
+    }
+}
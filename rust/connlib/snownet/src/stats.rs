@@ -1,17 +1,69 @@
+use crate::nat_discovery::NatBehaviour;
 use std::ops::AddAssign;
+use std::time::Duration;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
     /// How many bytes we sent as part of exchanging STUN messages with relays (control messages only).
     pub stun_bytes_to_relays: HumanBytes,
+    /// How the NAT we are behind behaves, as far as we could tell.
+    pub nat: NatBehaviour,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-pub struct ConnectionStats {
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStats<RId> {
     /// How many bytes we sent as part of exchanging STUN messages to other peers directly.
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+
+    /// The round-trip time to the peer, as estimated by wireguard during its handshakes.
+    pub rtt: Option<Duration>,
+
+    /// How many bytes of IP packets we sent through the tunnel.
+    pub bytes_sent: HumanBytes,
+    /// How many bytes of IP packets we received through the tunnel.
+    pub bytes_received: HumanBytes,
+    /// How many IP packets we sent through the tunnel.
+    pub packets_sent: u64,
+    /// How many IP packets we received through the tunnel.
+    pub packets_received: u64,
+
+    /// How many wireguard handshakes completed on this connection.
+    pub handshakes: u64,
+    /// How long ago the last wireguard handshake completed.
+    pub time_since_last_handshake: Option<Duration>,
+
+    /// The kind of path we nominated for talking to the peer, `None` whilst ICE is still running.
+    pub path: Option<PathKind>,
+    /// The relay we talk to the peer through if [`ConnectionStats::path`] is [`PathKind::Relayed`].
+    pub relay: Option<RId>,
+}
+
+impl<RId> Default for ConnectionStats<RId> {
+    fn default() -> Self {
+        Self {
+            stun_bytes_to_peer_direct: Default::default(),
+            stun_bytes_to_peer_relayed: Default::default(),
+            rtt: None,
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            packets_sent: 0,
+            packets_received: 0,
+            handshakes: 0,
+            time_since_last_handshake: None,
+            path: None,
+            relay: None,
+        }
+    }
+}
+
+/// The kind of local candidate of a nominated candidate pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    Host,
+    ServerReflexive,
+    Relayed,
 }
 
 #[derive(Default, Clone, Copy)]
@@ -5,6 +5,8 @@ use crate::{
 use ::backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
 use bytecodec::{DecodeExt, EncodeExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng as _};
 use std::{
     collections::VecDeque,
     net::SocketAddr,
@@ -24,6 +26,8 @@ const STUN_REFRESH: Duration = Duration::from_secs(5 * 60);
 pub struct StunBinding {
     server: SocketAddr,
     last_candidate: Option<Candidate>,
+    /// The round-trip time of our last successful request.
+    last_rtt: Option<Duration>,
     state: State,
     last_now: Instant,
 
@@ -31,14 +35,19 @@ pub struct StunBinding {
 
     buffered_transmits: VecDeque<Transmit<'static>>,
     events: VecDeque<CandidateEvent>,
+
+    /// Generates the transaction IDs of our requests.
+    rng: StdRng,
 }
 
 impl StunBinding {
-    pub fn new(server: SocketAddr, now: Instant) -> Self {
+    pub fn new(server: SocketAddr, rng: &mut impl Rng, now: Instant) -> Self {
         let mut backoff = backoff::new(now, STUN_TIMEOUT);
+        let mut rng = StdRng::seed_from_u64(rng.gen());
 
         let (state, transmit) = new_binding_request(
             server,
+            &mut rng,
             now,
             backoff.next_backoff().expect("to have an initial backoff"),
         );
@@ -46,11 +55,13 @@ impl StunBinding {
         Self {
             server,
             last_candidate: None,
+            last_rtt: None,
             state,
             last_now: now,
             buffered_transmits: VecDeque::from([transmit]),
             events: Default::default(),
             backoff,
+            rng,
         }
     }
 
@@ -58,6 +69,10 @@ impl StunBinding {
         self.last_candidate.clone()
     }
 
+    pub fn rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
     pub fn handle_input(
         &mut self,
         from: SocketAddr,
@@ -80,14 +95,15 @@ impl StunBinding {
 
         let transaction_id = message.transaction_id();
 
-        match self.state {
-            State::SentRequest { id, .. } if id == transaction_id => {}
+        let sent_at = match self.state {
+            State::SentRequest { id, at, .. } if id == transaction_id => at,
             State::SentRequest { .. } | State::ReceivedResponse { .. } | State::Failed => {
                 return false
             }
-        }
+        };
 
         self.state = State::ReceivedResponse { at: now };
+        self.last_rtt = Some(now.duration_since(sent_at));
 
         let Some(mapped_address) = message.get_attribute::<XorMappedAddress>() else {
             tracing::warn!("STUN server replied but is missing `XOR-MAPPED-ADDRESS");
@@ -132,7 +148,7 @@ impl StunBinding {
             .next_backoff()
             .expect("to have backoff right after resetting");
 
-        let (state, transmit) = new_binding_request(self.server, now, backoff);
+        let (state, transmit) = new_binding_request(self.server, &mut self.rng, now, backoff);
         self.state = state;
         self.buffered_transmits.push_back(transmit);
     }
@@ -168,7 +184,7 @@ impl StunBinding {
             State::Failed | State::SentRequest { .. } | State::ReceivedResponse { .. } => return,
         };
 
-        let (state, transmit) = new_binding_request(self.server, now, backoff);
+        let (state, transmit) = new_binding_request(self.server, &mut self.rng, now, backoff);
         self.state = state;
         self.buffered_transmits.push_back(transmit);
     }
@@ -204,13 +220,14 @@ impl StunBinding {
 
 fn new_binding_request(
     server: SocketAddr,
+    rng: &mut impl Rng,
     now: Instant,
     backoff: Duration,
 ) -> (State, Transmit<'static>) {
     let request = Message::<rfc5389::Attribute>::new(
         stun_codec::MessageClass::Request,
         rfc5389::methods::BINDING,
-        TransactionId::new(rand::random()),
+        TransactionId::new(rng.gen()),
     );
 
     let state = State::SentRequest {
@@ -237,6 +254,30 @@ where
         .unwrap()
 }
 
+/// Answers a binding request the way a STUN server would.
+#[cfg(test)]
+pub(crate) fn generate_stun_response(request: Transmit, mapped_address: SocketAddr) -> Vec<u8> {
+    let mut decoder = stun_codec::MessageDecoder::<stun_codec::rfc5389::Attribute>::default();
+
+    let message = decoder
+        .decode_from_bytes(&request.payload)
+        .unwrap()
+        .unwrap();
+
+    let transaction_id = message.transaction_id();
+
+    let mut response = Message::<rfc5389::Attribute>::new(
+        stun_codec::MessageClass::SuccessResponse,
+        rfc5389::methods::BINDING,
+        transaction_id,
+    );
+    response.add_attribute(stun_codec::rfc5389::Attribute::XorMappedAddress(
+        XorMappedAddress::new(mapped_address),
+    ));
+
+    encode(response)
+}
+
 #[derive(Debug)]
 enum State {
     SentRequest {
@@ -254,7 +295,6 @@ enum State {
 mod tests {
     use super::*;
     use std::net::{Ipv4Addr, SocketAddrV4};
-    use stun_codec::rfc5389::methods::BINDING;
 
     const SERVER1: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478));
     const SERVER2: SocketAddr =
@@ -264,16 +304,35 @@ mod tests {
 
     #[test]
     fn initial_binding_sends_request() {
-        let mut stun_binding = StunBinding::new(SERVER1, Instant::now());
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), Instant::now());
 
         let transmit = stun_binding.poll_transmit().unwrap();
 
         assert_eq!(transmit.dst, SERVER1);
     }
 
+    #[test]
+    fn measures_rtt_of_request() {
+        let mut now = Instant::now();
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), now);
+
+        let request = stun_binding.poll_transmit().unwrap();
+        assert_eq!(stun_binding.rtt(), None);
+
+        now += Duration::from_millis(30);
+        stun_binding.handle_input(
+            SERVER1,
+            MAPPED_ADDRESS,
+            &generate_stun_response(request, MAPPED_ADDRESS),
+            now,
+        );
+
+        assert_eq!(stun_binding.rtt(), Some(Duration::from_millis(30)));
+    }
+
     #[test]
     fn repeated_polling_does_not_generate_more_requests() {
-        let mut stun_binding = StunBinding::new(SERVER1, Instant::now());
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), Instant::now());
 
         assert!(stun_binding.poll_transmit().is_some());
         assert!(stun_binding.poll_transmit().is_none());
@@ -283,7 +342,7 @@ mod tests {
     fn backoff_resets_after_successful_response() {
         let mut now = Instant::now();
 
-        let mut stun_binding = StunBinding::new(SERVER1, now);
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), now);
 
         assert!(
             stun_binding.poll_transmit().is_some(),
@@ -327,7 +386,7 @@ mod tests {
     #[test]
     fn retries_requests_using_backoff_and_gives_up_eventually() {
         let start = Instant::now();
-        let mut stun_binding = StunBinding::new(SERVER1, start);
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), start);
 
         let mut expected_backoffs = VecDeque::from(backoff::steps(start));
 
@@ -351,7 +410,7 @@ mod tests {
     fn mapped_address_is_emitted_as_event() {
         let start = Instant::now();
 
-        let mut stun_binding = StunBinding::new(SERVER1, start);
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), start);
 
         let request = stun_binding.poll_transmit().unwrap();
         let response = generate_stun_response(request, MAPPED_ADDRESS);
@@ -378,7 +437,7 @@ mod tests {
     fn stun_binding_is_refreshed_every_five_minutes() {
         let start = Instant::now();
 
-        let mut stun_binding = StunBinding::new(SERVER1, start);
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), start);
         assert!(stun_binding.poll_transmit().is_some());
         stun_binding.set_received_at(MAPPED_ADDRESS, MAPPED_ADDRESS, start);
         assert!(stun_binding.poll_transmit().is_none());
@@ -392,7 +451,7 @@ mod tests {
     fn response_from_other_server_is_discarded() {
         let start = Instant::now();
 
-        let mut stun_binding = StunBinding::new(SERVER1, start);
+        let mut stun_binding = StunBinding::new(SERVER1, &mut rand::thread_rng(), start);
 
         let request = stun_binding.poll_transmit().unwrap();
         let response = generate_stun_response(request, MAPPED_ADDRESS);
@@ -407,26 +466,4 @@ mod tests {
         assert!(!handled);
         assert!(stun_binding.poll_event().is_none());
     }
-
-    fn generate_stun_response(request: Transmit, mapped_address: SocketAddr) -> Vec<u8> {
-        let mut decoder = stun_codec::MessageDecoder::<stun_codec::rfc5389::Attribute>::default();
-
-        let message = decoder
-            .decode_from_bytes(&request.payload)
-            .unwrap()
-            .unwrap();
-
-        let transaction_id = message.transaction_id();
-
-        let mut response = Message::<rfc5389::Attribute>::new(
-            stun_codec::MessageClass::SuccessResponse,
-            BINDING,
-            transaction_id,
-        );
-        response.add_attribute(stun_codec::rfc5389::Attribute::XorMappedAddress(
-            XorMappedAddress::new(mapped_address),
-        ));
-
-        encode(response)
-    }
 }
@@ -2,7 +2,11 @@ use boringtun::x25519::{PublicKey, StaticSecret};
 use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerSocket};
 use ip_packet::*;
 use rand::rngs::OsRng;
-use snownet::{Answer, ClientNode, Event, RelaySocket, ServerNode, Transmit};
+use snownet::Allocation;
+use snownet::{
+    Answer, CandidatePolicy, ClientNode, ConnectionStats, Event, PathKind, RelaySocket, ServerNode,
+    Transmit,
+};
 use std::{
     collections::{HashSet, VecDeque},
     iter,
@@ -10,7 +14,8 @@ use std::{
     time::{Duration, Instant, SystemTime},
     vec,
 };
-use str0m::{net::Protocol, Candidate};
+use str0m::{net::Protocol, Candidate, CandidateKind};
+use stun_codec::rfc5389::attributes::{Realm, Username};
 use tracing::{debug_span, info_span, Span};
 use tracing_subscriber::util::SubscriberInitExt;
 
@@ -93,6 +98,88 @@ fn smoke_relayed() {
     assert_eq!(alice.packets_from(ip("8.8.8.8")).count(), 1);
 }
 
+#[test]
+fn stats_report_nominated_path_and_traffic() {
+    let _guard = setup_tracing();
+    let mut pair = connected_pair(CandidatePolicy::All);
+
+    pair.alice
+        .ping(ip("9.9.9.9"), ip("8.8.8.8"), &pair.bob, pair.clock.now);
+    pair.progress();
+
+    let alice_stats = pair.alice.connection_stats(&pair.bob);
+    let bob_stats = pair.bob.connection_stats(&pair.alice);
+
+    assert_eq!(alice_stats.path, Some(PathKind::Relayed));
+    assert_eq!(alice_stats.relay, Some(1));
+    assert_eq!(alice_stats.packets_sent, 1);
+    assert_eq!(bob_stats.packets_received, 1);
+    assert_eq!(alice_stats.bytes_sent.0, bob_stats.bytes_received.0);
+    assert!(alice_stats.handshakes >= 1);
+    assert!(alice_stats.time_since_last_handshake.is_some());
+}
+
+#[test]
+fn relay_only_policy_connects_via_relay_without_signalling_other_candidates() {
+    let _guard = setup_tracing();
+    let mut pair = connected_pair(CandidatePolicy::RelayOnly);
+
+    pair.alice
+        .ping(ip("9.9.9.9"), ip("8.8.8.8"), &pair.bob, pair.clock.now);
+    pair.progress();
+    assert_eq!(pair.bob.packets_from(ip("9.9.9.9")).count(), 1);
+
+    assert!(pair
+        .alice
+        .signalled_candidates()
+        .chain(pair.bob.signalled_candidates())
+        .all(|(_, c, _)| c.kind() == CandidateKind::Relayed));
+    assert_eq!(
+        pair.alice.connection_stats(&pair.bob).path,
+        Some(PathKind::Relayed)
+    );
+}
+
+#[test]
+fn no_host_policy_connects_without_signalling_host_candidates() {
+    let _guard = setup_tracing();
+    let mut pair = connected_pair(CandidatePolicy::NoHost);
+
+    pair.alice
+        .ping(ip("9.9.9.9"), ip("8.8.8.8"), &pair.bob, pair.clock.now);
+    pair.progress();
+    assert_eq!(pair.bob.packets_from(ip("9.9.9.9")).count(), 1);
+
+    assert!(pair
+        .alice
+        .signalled_candidates()
+        .chain(pair.bob.signalled_candidates())
+        .all(|(_, c, _)| c.kind() != CandidateKind::Host));
+}
+
+#[test]
+fn keeps_allocation_on_unselected_relay_while_connection_relays_through_it() {
+    let _guard = setup_tracing();
+    let mut pair = connected_pair(CandidatePolicy::RelayOnly);
+
+    // Without any relay being selected, Alice would normally remove her allocation.
+    let now = pair.clock.now;
+    pair.alice
+        .span
+        .in_scope(|| pair.alice.node.set_max_relays(0, now));
+
+    for _ in 0..10 {
+        pair.progress();
+    }
+
+    pair.alice
+        .ping(ip("9.9.9.9"), ip("8.8.8.8"), &pair.bob, pair.clock.now);
+    pair.progress();
+
+    assert_eq!(pair.bob.packets_from(ip("9.9.9.9")).count(), 1);
+    assert_eq!(pair.alice.connection_stats(&pair.bob).relay, Some(1));
+}
+
 #[test]
 fn reconnect_discovers_new_interface() {
     let _guard = setup_tracing();
@@ -137,8 +224,22 @@ fn reconnect_discovers_new_interface() {
     alice.switch_network("10.0.0.1:80");
     alice.span.in_scope(|| alice.node.reconnect(clock.now));
 
-    // Make some progress.
-    for _ in 0..10 {
+    // Make progress until ICE moved the connection off the old, blocked path.
+    let old_local = "1.1.1.1:80".parse().unwrap();
+    let migration_started = clock.now;
+    loop {
+        if alice
+            .path_changes()
+            .last()
+            .is_some_and(|(_, local)| local != old_local)
+        {
+            break;
+        }
+        assert!(
+            clock.now.duration_since(migration_started) < Duration::from_secs(15),
+            "connection did not migrate"
+        );
+
         progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
     }
 
@@ -157,6 +258,67 @@ fn reconnect_discovers_new_interface() {
     assert_eq!(bob.failed_connections().count(), 0);
 }
 
+#[test]
+fn keeps_using_old_path_while_migrating_to_new_network() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+    let firewall = Firewall::default();
+
+    let (alice, bob) = alice_and_bob();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    // The old interface is still usable, e.g. because we connected to a VPN on top of it.
+    alice.switch_network("10.0.0.1:80");
+    alice.span.in_scope(|| alice.node.reconnect(clock.now));
+
+    // Traffic is not interrupted while ICE checks the new candidates.
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 1);
+
+    assert!(!alice
+        .invalidated_candidates()
+        .any(|c| c.addr().to_string() == "1.1.1.1:80"));
+
+    for _ in 0..150 {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    // Eventually, we stop using the old host candidate.
+    assert!(alice
+        .invalidated_candidates()
+        .any(|c| c.addr().to_string() == "1.1.1.1:80"));
+    assert_eq!(alice.failed_connections().count(), 0);
+    assert_eq!(bob.failed_connections().count(), 0);
+}
+
 #[test]
 fn migrate_connection_to_new_relay() {
     let _guard = setup_tracing();
@@ -217,6 +379,92 @@ fn migrate_connection_to_new_relay() {
     bob.ping(ip("8.8.8.8"), ip("9.9.9.9"), &alice, clock.now);
     progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
     assert_eq!(alice.packets_from(ip("8.8.8.8")).count(), 1);
+
+    let path_changes = alice.path_changes().collect::<Vec<_>>();
+    assert!(path_changes.len() >= 2);
+    assert!(path_changes
+        .iter()
+        .all(|(kind, _)| *kind == PathKind::Relayed));
+    assert_ne!(
+        path_changes.first().unwrap().1,
+        path_changes.last().unwrap().1
+    );
+}
+
+#[test]
+fn allocation_follows_redirect_to_relay_with_different_secret() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let client = s("1.1.1.1:80");
+
+    // Every relay has its own secret, thus the credentials for "Roger" are not valid on "Robert".
+    let robert = TestRelay::new(
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3478),
+        debug_span!("Robert"),
+    );
+    let mut roger = TestRelay::new(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+        debug_span!("Roger"),
+    );
+    roger
+        .inner
+        .set_alternate_servers(vec![SocketAddr::V4(*robert.listen_addr.as_v4().unwrap())]);
+    roger.inner.start_draining();
+
+    let (username, password) = roger.make_credentials("client");
+    let mut allocation = Allocation::new(
+        roger.listen_addr,
+        Username::new(username).unwrap(),
+        password,
+        Realm::new("firezone".to_owned()).unwrap(),
+        &mut rand::thread_rng(),
+        clock.now,
+    );
+    let (username, password) = robert.make_credentials("client");
+    allocation.set_alternate_relays([(
+        robert.listen_addr,
+        Username::new(username).unwrap(),
+        password,
+        Realm::new("firezone".to_owned()).unwrap(),
+    )]);
+
+    let mut relays = [roger, robert];
+
+    for _ in 0..10 {
+        while let Some(transmit) = allocation.poll_transmit() {
+            let relay = relays
+                .iter_mut()
+                .find(|r| r.listen_addr.matches(transmit.dst))
+                .unwrap();
+
+            relay.span.in_scope(|| {
+                relay.inner.handle_client_input(
+                    &transmit.payload,
+                    ClientSocket::new(client),
+                    clock.now,
+                )
+            });
+
+            while let Some(command) = relay.inner.next_command() {
+                if let firezone_relay::Command::SendMessage { payload, recipient } = command {
+                    assert_eq!(recipient.into_socket(), client);
+
+                    allocation.handle_input(transmit.dst, client, &payload, clock.now);
+                }
+            }
+        }
+
+        clock.tick();
+        allocation.handle_timeout(clock.now);
+    }
+
+    let [_, robert] = &relays;
+
+    assert_eq!(allocation.server(), robert.listen_addr);
+    assert!(allocation
+        .current_candidates()
+        .any(|c| c.kind() == CandidateKind::Relayed && robert.ip4() == Some(c.addr().ip())));
 }
 
 #[test]
@@ -340,6 +588,68 @@ fn setup_tracing() -> tracing::subscriber::DefaultGuard {
         .set_default()
 }
 
+/// Alice and Bob, connected via our relay "Roger" because the firewall blocks direct traffic between them.
+struct ConnectedPair {
+    alice: TestNode,
+    bob: TestNode,
+    relays: [(u64, TestRelay); 1],
+    firewall: Firewall,
+    clock: Clock,
+}
+
+impl ConnectedPair {
+    fn progress(&mut self) {
+        progress(
+            &mut self.alice,
+            &mut self.bob,
+            &mut self.relays,
+            &self.firewall,
+            &mut self.clock,
+        );
+    }
+}
+
+/// Connects Alice and Bob, both using `policy` and offering their primary socket as host candidate.
+fn connected_pair(policy: CandidatePolicy) -> ConnectedPair {
+    let clock = Clock::new();
+    let (alice, bob) = alice_and_bob();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80")
+        .with_candidate_policy(policy)
+        .with_primary_as_host_candidate()
+        .with_relays(HashSet::default(), &mut relays, clock.now);
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80")
+        .with_candidate_policy(policy)
+        .with_primary_as_host_candidate()
+        .with_relays(HashSet::default(), &mut relays, clock.now);
+    let firewall = Firewall::default()
+        .with_block_rule(&alice, &bob)
+        .with_block_rule(&bob, &alice);
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    let mut pair = ConnectedPair {
+        alice,
+        bob,
+        relays,
+        firewall,
+        clock,
+    };
+
+    while !(pair.alice.is_connected_to(&pair.bob) && pair.bob.is_connected_to(&pair.alice)) {
+        pair.progress();
+    }
+
+    pair
+}
+
 fn alice_and_bob() -> (ClientNode<u64, u64>, ServerNode<u64, u64>) {
     let alice = ClientNode::new(StaticSecret::random_from_rng(rand::thread_rng()));
     let bob = ServerNode::new(StaticSecret::random_from_rng(rand::thread_rng()));
@@ -586,7 +896,7 @@ impl TestRelay {
     ) {
         if let Some((client, channel)) = self
             .span
-            .in_scope(|| self.inner.handle_peer_traffic(payload, peer, port))
+            .in_scope(|| self.inner.handle_peer_traffic(payload, peer, port, now))
         {
             let full_length = firezone_relay::ChannelData::encode_header_to_slice(
                 channel,
@@ -625,12 +935,17 @@ impl TestRelay {
 
                     panic!("Relay generated traffic for unknown client")
                 }
-                firezone_relay::Command::CreateAllocation { port, family } => {
+                firezone_relay::Command::CreateAllocation { port, family, .. } => {
                     self.allocations.insert((family, port));
                 }
                 firezone_relay::Command::FreeAllocation { port, family } => {
                     self.allocations.remove(&(family, port));
                 }
+                firezone_relay::Command::RelayToPeer { .. } => {
+                    panic!(
+                        "Relay generated traffic from a SEND indication but we only use channels"
+                    )
+                }
             }
         }
     }
@@ -728,6 +1043,27 @@ impl EitherNode {
         }
     }
 
+    fn connection_stats(&self, id: u64) -> Option<ConnectionStats<u64>> {
+        match self {
+            EitherNode::Client(n) => n.stats().1.find_map(|(c, s)| (c == id).then_some(s)),
+            EitherNode::Server(n) => n.stats().1.find_map(|(c, s)| (c == id).then_some(s)),
+        }
+    }
+
+    fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        match self {
+            EitherNode::Client(n) => n.set_candidate_policy(policy),
+            EitherNode::Server(n) => n.set_candidate_policy(policy),
+        }
+    }
+
+    fn set_max_relays(&mut self, max_relays: usize, now: Instant) {
+        match self {
+            EitherNode::Client(n) => n.set_max_relays(max_relays, now),
+            EitherNode::Server(n) => n.set_max_relays(max_relays, now),
+        }
+    }
+
     fn public_key(&self) -> PublicKey {
         match self {
             EitherNode::Client(n) => n.public_key(),
@@ -850,6 +1186,12 @@ impl TestNode {
         self
     }
 
+    fn with_candidate_policy(mut self, policy: CandidatePolicy) -> Self {
+        self.node.set_candidate_policy(policy);
+
+        self
+    }
+
     fn switch_network(&mut self, new_primary: &str) {
         self.primary = new_primary.parse().unwrap();
         self.local.push(self.primary);
@@ -859,6 +1201,15 @@ impl TestNode {
         self.node.connection_id(other.node.public_key()).is_some()
     }
 
+    fn connection_stats(&self, other: &TestNode) -> ConnectionStats<u64> {
+        let id = self
+            .node
+            .connection_id(other.node.public_key())
+            .expect("not connected to node");
+
+        self.node.connection_stats(id).unwrap()
+    }
+
     fn ping(&mut self, src: IpAddr, dst: IpAddr, other: &TestNode, now: Instant) {
         let id = self
             .node
@@ -893,6 +1244,29 @@ impl TestNode {
             )),
             Event::InvalidateIceCandidate { .. }
             | Event::ConnectionEstablished(_)
+            | Event::ConnectionPathChanged { .. }
+            | Event::ConnectionFailed(_) => None,
+        })
+    }
+
+    fn invalidated_candidates(&self) -> impl Iterator<Item = Candidate> + '_ {
+        self.events.iter().filter_map(|(e, _)| match e {
+            Event::InvalidateIceCandidate { candidate, .. } => {
+                Some(Candidate::from_sdp_string(candidate).unwrap())
+            }
+            Event::NewIceCandidate { .. }
+            | Event::ConnectionEstablished(_)
+            | Event::ConnectionPathChanged { .. }
+            | Event::ConnectionFailed(_) => None,
+        })
+    }
+
+    fn path_changes(&self) -> impl Iterator<Item = (PathKind, SocketAddr)> + '_ {
+        self.events.iter().filter_map(|(e, _)| match e {
+            Event::ConnectionPathChanged { kind, local, .. } => Some((*kind, *local)),
+            Event::NewIceCandidate { .. }
+            | Event::InvalidateIceCandidate { .. }
+            | Event::ConnectionEstablished(_)
             | Event::ConnectionFailed(_) => None,
         })
     }
@@ -909,6 +1283,7 @@ impl TestNode {
             Event::NewIceCandidate { .. } => None,
             Event::InvalidateIceCandidate { .. } => None,
             Event::ConnectionEstablished(_) => None,
+            Event::ConnectionPathChanged { .. } => None,
         })
     }
 
@@ -943,6 +1318,7 @@ impl TestNode {
                     .span
                     .in_scope(|| other.node.remove_remote_candidate(connection, candidate)),
                 Event::ConnectionEstablished(_) => {}
+                Event::ConnectionPathChanged { .. } => {}
                 Event::ConnectionFailed(_) => {}
             };
         }
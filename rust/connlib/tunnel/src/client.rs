@@ -20,7 +20,7 @@ use crate::peer::GatewayOnClient;
 use crate::utils::{earliest, stun, turn};
 use crate::{ClientEvent, ClientTunnel};
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{ClientNode, RelaySocket};
+use snownet::{CandidatePolicy, ClientNode, RelaySocket};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter;
@@ -80,6 +80,10 @@ where
             .update_relays(to_remove, turn(&to_add), Instant::now())
     }
 
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.role_state.set_candidate_policy(policy)
+    }
+
     /// Adds a the given resource to the tunnel.
     pub fn add_resources(
         &mut self,
@@ -920,6 +924,19 @@ impl ClientState {
                     self.update_site_status_by_gateway(&id, Status::Online);
                     resources_updated = true;
                 }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    kind,
+                    local,
+                    remote,
+                } => self
+                    .buffered_events
+                    .push_back(ClientEvent::ConnectionPathChanged {
+                        conn_id: connection,
+                        kind,
+                        local,
+                        remote,
+                    }),
             }
         }
 
@@ -946,6 +963,11 @@ impl ClientState {
         self.node.reconnect(now)
     }
 
+    /// Whether connections may still be using the network from before the last [`ClientState::reconnect`].
+    pub(crate) fn is_migrating(&self) -> bool {
+        self.node.is_migrating()
+    }
+
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'_>> {
         self.node.poll_transmit()
     }
@@ -1088,6 +1110,11 @@ impl ClientState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    /// Sets which of our candidates we use and signal to gateways.
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.node.set_candidate_policy(policy);
+    }
 }
 
 fn effective_dns_servers(
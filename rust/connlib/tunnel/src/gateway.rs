@@ -13,7 +13,7 @@ use connlib_shared::{Callbacks, Dname, Error, Result, StaticSecret};
 use ip_network::IpNetwork;
 use ip_packet::{IpPacket, MutableIpPacket};
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{RelaySocket, ServerNode};
+use snownet::{CandidatePolicy, RelaySocket, ServerNode};
 use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
@@ -320,6 +320,20 @@ impl GatewayState {
                             candidate,
                         });
                 }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    kind,
+                    local,
+                    remote,
+                } => {
+                    self.buffered_events
+                        .push_back(GatewayEvent::ConnectionPathChanged {
+                            conn_id: connection,
+                            kind,
+                            local,
+                            remote,
+                        });
+                }
                 _ => {}
             }
         }
@@ -341,4 +355,9 @@ impl GatewayState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    /// Sets which of our candidates we use and signal to clients.
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.node.set_candidate_policy(policy);
+    }
 }
@@ -175,13 +175,16 @@ impl Io {
     }
 
     pub fn send_network(&mut self, transmit: snownet::Transmit) -> io::Result<()> {
-        self.sockets.try_send(Transmit {
-            destination: transmit.dst,
-            ecn: None,
-            contents: Bytes::copy_from_slice(&transmit.payload),
-            segment_size: None,
-            src_ip: transmit.src.map(|s| s.ip()),
-        })?;
+        self.sockets.try_send(
+            transmit.src,
+            Transmit {
+                destination: transmit.dst,
+                ecn: None,
+                contents: Bytes::copy_from_slice(&transmit.payload),
+                segment_size: None,
+                src_ip: transmit.src.map(|s| s.ip()),
+            },
+        )?;
 
         Ok(())
     }
@@ -12,12 +12,14 @@ use connlib_shared::{
 use io::Io;
 use std::{
     collections::HashSet,
+    net::SocketAddr,
     task::{Context, Poll},
     time::Instant,
 };
 
 pub use client::{ClientState, Request};
 pub use gateway::GatewayState;
+pub use snownet::CandidatePolicy;
 pub use sockets::Sockets;
 use utils::turn;
 
@@ -79,6 +81,10 @@ where
         })
     }
 
+    /// Migrates all connections to the current network, e.g. after the OS reported a change of the default route.
+    ///
+    /// Connections keep their wireguard sessions and continue to use their old path until ICE found a new one.
+    /// The sockets of the old path stay open until the migration is completed.
     pub fn reconnect(&mut self) -> std::io::Result<()> {
         self.role_state.reconnect(Instant::now());
         self.io.sockets_mut().rebind()?;
@@ -120,6 +126,10 @@ where
                 Poll::Ready(io::Input::Timeout(timeout)) => {
                     let resources_updated = self.role_state.handle_timeout(timeout);
 
+                    if !self.role_state.is_migrating() {
+                        self.io.sockets_mut().close_stale();
+                    }
+
                     if resources_updated {
                         self.callbacks
                             .on_update_resources(self.role_state.resources());
@@ -186,6 +196,10 @@ where
             .update_relays(to_remove, turn(&to_add), Instant::now())
     }
 
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.role_state.set_candidate_policy(policy)
+    }
+
     pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<Result<GatewayEvent>> {
         loop {
             if let Some(other) = self.role_state.poll_event() {
@@ -262,6 +276,12 @@ pub enum ClientEvent {
     RefreshResources {
         connections: Vec<ReuseConnection>,
     },
+    ConnectionPathChanged {
+        conn_id: GatewayId,
+        kind: snownet::PathKind,
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
 }
 
 pub enum GatewayEvent {
@@ -273,4 +293,10 @@ pub enum GatewayEvent {
         conn_id: ClientId,
         candidate: String,
     },
+    ConnectionPathChanged {
+        conn_id: ClientId,
+        kind: snownet::PathKind,
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
 }
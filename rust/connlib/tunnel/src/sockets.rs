@@ -14,6 +14,12 @@ pub struct Sockets {
     socket_v4: Option<Socket>,
     socket_v6: Option<Socket>,
 
+    /// The sockets from before the last [`Sockets::rebind`].
+    ///
+    /// Connections keep using them until they migrated to the new network, see [`Sockets::close_stale`].
+    stale_v4: Vec<Socket>,
+    stale_v6: Vec<Socket>,
+
     #[cfg(unix)]
     protect: Box<dyn Fn(std::os::fd::RawFd) -> io::Result<()> + Send + 'static>,
 }
@@ -32,6 +38,8 @@ impl Sockets {
         Self {
             socket_v4: None,
             socket_v6: None,
+            stale_v4: Vec::new(),
+            stale_v6: Vec::new(),
             #[cfg(unix)]
             protect: Box::new(protect),
         }
@@ -41,6 +49,8 @@ impl Sockets {
         Self {
             socket_v4: None,
             socket_v6: None,
+            stale_v4: Vec::new(),
+            stale_v6: Vec::new(),
             #[cfg(unix)]
             protect: Box::new(|_| Ok(())),
         }
@@ -53,6 +63,9 @@ impl Sockets {
         }
     }
 
+    /// Binds new sockets.
+    ///
+    /// The previous sockets stay open until [`Sockets::close_stale`] is called, so connections can keep using them whilst they migrate.
     pub fn rebind(&mut self) -> io::Result<()> {
         let socket_v4 = Socket::ip4();
         let socket_v6 = Socket::ip6();
@@ -89,44 +102,71 @@ impl Sockets {
             }
         }
 
-        self.socket_v4 = socket_v4.ok();
-        self.socket_v6 = socket_v6.ok();
+        self.stale_v4
+            .extend(std::mem::replace(&mut self.socket_v4, socket_v4.ok()));
+        self.stale_v6
+            .extend(std::mem::replace(&mut self.socket_v6, socket_v6.ok()));
 
         Ok(())
     }
 
+    /// Closes the sockets from before the last [`Sockets::rebind`].
+    pub fn close_stale(&mut self) {
+        if self.stale_v4.is_empty() && self.stale_v6.is_empty() {
+            return;
+        }
+
+        tracing::debug!(
+            num_sockets = %(self.stale_v4.len() + self.stale_v6.len()),
+            "Closing sockets from before network change"
+        );
+
+        self.stale_v4.clear();
+        self.stale_v6.clear();
+    }
+
     /// Flushes all buffered data on the sockets.
     ///
     /// Returns `Ready` if the socket is able to accept more data.
     pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if let Some(socket) = self.socket_v4.as_mut() {
-            ready!(socket.poll_flush(cx))?;
-        }
-
-        if let Some(socket) = self.socket_v6.as_mut() {
+        for socket in self
+            .socket_v4
+            .iter_mut()
+            .chain(self.socket_v6.iter_mut())
+            .chain(self.stale_v4.iter_mut())
+            .chain(self.stale_v6.iter_mut())
+        {
             ready!(socket.poll_flush(cx))?;
         }
 
         Poll::Ready(Ok(()))
     }
 
-    pub fn try_send(&mut self, transmit: quinn_udp::Transmit) -> io::Result<()> {
-        match transmit.destination {
-            SocketAddr::V4(dst) => {
-                let socket = self.socket_v4.as_mut().ok_or(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    format!("failed send packet to {dst}: no IPv4 socket"),
-                ))?;
-                socket.send(transmit);
-            }
-            SocketAddr::V6(dst) => {
-                let socket = self.socket_v6.as_mut().ok_or(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    format!("failed send packet to {dst}: no IPv6 socket"),
-                ))?;
-                socket.send(transmit);
-            }
-        }
+    /// Sends the transmit from the socket bound to the port of `src`, if we have one.
+    ///
+    /// Otherwise, the transmit is sent from our current socket of the destination's address family.
+    pub fn try_send(
+        &mut self,
+        src: Option<SocketAddr>,
+        transmit: quinn_udp::Transmit,
+    ) -> io::Result<()> {
+        let (current, stale, family) = match transmit.destination {
+            SocketAddr::V4(_) => (&mut self.socket_v4, &mut self.stale_v4, "IPv4"),
+            SocketAddr::V6(_) => (&mut self.socket_v6, &mut self.stale_v6, "IPv6"),
+        };
+
+        let stale = stale
+            .iter_mut()
+            .find(|s| src.is_some_and(|src| src.port() == s.port));
+
+        let socket = stale.or(current.as_mut()).ok_or(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!(
+                "failed send packet to {}: no {family} socket",
+                transmit.destination
+            ),
+        ))?;
+        socket.send(transmit);
 
         Ok(())
     }
@@ -139,18 +179,16 @@ impl Sockets {
     ) -> Poll<io::Result<impl Iterator<Item = Received<'b>>>> {
         let mut iter = PacketIter::new();
 
-        if let Some(Poll::Ready(packets)) = self
-            .socket_v4
-            .as_ref()
-            .map(|s| s.poll_recv_from(ip4_buffer, cx))
+        if let Some(Poll::Ready(packets)) =
+            ready_socket(self.socket_v4.iter().chain(&self.stale_v4), cx)
+                .map(|s| s.poll_recv_from(ip4_buffer, cx))
         {
             iter.ip4 = Some(packets?);
         }
 
-        if let Some(Poll::Ready(packets)) = self
-            .socket_v6
-            .as_ref()
-            .map(|s| s.poll_recv_from(ip6_buffer, cx))
+        if let Some(Poll::Ready(packets)) =
+            ready_socket(self.socket_v6.iter().chain(&self.stale_v6), cx)
+                .map(|s| s.poll_recv_from(ip6_buffer, cx))
         {
             iter.ip6 = Some(packets?);
         }
@@ -163,6 +201,16 @@ impl Sockets {
     }
 }
 
+/// The first of the given sockets that has data to read or failed.
+///
+/// Registers the waker with all sockets up to that one.
+fn ready_socket<'s>(
+    mut sockets: impl Iterator<Item = &'s Socket>,
+    cx: &mut Context<'_>,
+) -> Option<&'s Socket> {
+    sockets.find(|s| s.socket.poll_recv_ready(cx).is_ready())
+}
+
 struct PacketIter<T4, T6> {
     ip4: Option<T4>,
     ip6: Option<T6>,
@@ -1,10 +1,16 @@
+use anyhow::{bail, Context as _, Result};
 use clap::Args;
+use secrecy::SecretString;
+use std::path::{Path, PathBuf};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
     fmt, prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Layer, Registry,
 };
 use url::Url;
 
+/// The environment variable that holds the token.
+pub const TOKEN_ENV_KEY: &str = "FIREZONE_TOKEN";
+
 pub fn setup_global_subscriber<L>(additional_layer: L)
 where
     L: Layer<Registry> + Send + Sync,
@@ -28,9 +34,93 @@ pub struct CommonArgs {
     )]
     pub api_url: Url,
     /// Token generated by the portal to authorize websocket connection.
-    #[arg(env = "FIREZONE_TOKEN")]
-    pub token: String,
+    #[arg(env = "FIREZONE_TOKEN", required_unless_present = "token_path")]
+    pub token: Option<String>,
+    /// A file containing the token, e.g. a Docker or Kubernetes secret.
+    ///
+    /// The file must only be accessible by its owner, i.e. have mode 0400 or 0600.
+    #[arg(long, env = "FIREZONE_TOKEN_FILE", conflicts_with = "token")]
+    pub token_path: Option<PathBuf>,
     /// Friendly name to display in the UI
     #[arg(short = 'n', long, env = "FIREZONE_NAME")]
     pub firezone_name: Option<String>,
 }
+
+impl CommonArgs {
+    /// Takes the token out of these arguments, reading it from [`CommonArgs::token_path`] if necessary.
+    ///
+    /// This also removes the token from our environment, see [`remove_token_env_var`].
+    pub fn take_token(&mut self) -> Result<SecretString> {
+        remove_token_env_var();
+
+        if let Some(token) = self.token.take() {
+            return Ok(SecretString::new(token));
+        }
+
+        let path = self
+            .token_path
+            .as_deref()
+            .context("Neither a token nor a token file was provided")?;
+
+        read_token_file(path)
+    }
+}
+
+/// Reads the token from the given file, stripping any surrounding whitespace.
+///
+/// On Unix, the file must only be accessible by its owner.
+pub fn read_token_file(path: &Path) -> Result<SecretString> {
+    check_token_permissions(path)?;
+
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read token file `{}`", path.display()))?;
+    let token = token.trim();
+
+    if token.is_empty() {
+        bail!("Token file `{}` is empty", path.display());
+    }
+
+    tracing::info!(?path, "Loaded token from disk");
+
+    Ok(SecretString::new(token.to_owned()))
+}
+
+/// Removes the token from the environment per <https://security.stackexchange.com/a/271285>.
+///
+/// Modifying the environment of a running process is unsafe if any other thread is reading or writing the environment.
+/// Thus, this must be called as early as possible during startup, before any other threads are spawned.
+pub fn remove_token_env_var() {
+    // Docs indicate that `remove_var` should actually be marked unsafe
+    // SAFETY: Callers ensure that we haven't spawned any other threads yet, so nobody else is reading the environment.
+    #[allow(unused_unsafe)]
+    unsafe {
+        std::env::remove_var(TOKEN_ENV_KEY);
+    }
+    debug_assert!(std::env::var(TOKEN_ENV_KEY).is_err());
+}
+
+/// Checks that the token file is only accessible by its owner.
+#[cfg(unix)]
+pub fn check_token_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Token file `{}` doesn't exist", path.display()))?;
+
+    if metadata.permissions().mode() & 0o177 != 0 {
+        bail!(
+            "Token file `{}` should have mode 0o400 or 0o600",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+// The return value is useful on Unix
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn check_token_permissions(_path: &Path) -> Result<()> {
+    // TODO: Make sure the token is only readable by its owner on Windows
+    Ok(())
+}
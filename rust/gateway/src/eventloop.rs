@@ -110,6 +110,14 @@ impl Eventloop {
                     }),
                 );
             }
+            firezone_tunnel::GatewayEvent::ConnectionPathChanged {
+                conn_id: client,
+                kind,
+                local,
+                remote,
+            } => {
+                tracing::info!(%client, ?kind, %local, %remote, "Connection path changed");
+            }
         }
     }
 
@@ -1,15 +1,17 @@
 use crate::eventloop::{Eventloop, PHOENIX_TOPIC};
 use crate::messages::InitGateway;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use connlib_shared::{get_user_agent, keypair, Callbacks, LoginUrl, StaticSecret};
+use connlib_shared::{get_user_agent, keypair, Callbacks, LoginUrl, PublicKey, StaticSecret};
 use firezone_cli_utils::{setup_global_subscriber, CommonArgs};
-use firezone_tunnel::{GatewayTunnel, Sockets};
+use firezone_tunnel::{CandidatePolicy, GatewayTunnel, Sockets};
 use futures::{future, TryFutureExt};
 use secrecy::{Secret, SecretString};
 use std::collections::HashSet;
 use std::convert::Infallible;
+use std::io;
+use std::os::unix::fs::PermissionsExt as _;
 use std::path::Path;
 use std::pin::pin;
 use tokio::io::AsyncWriteExt;
@@ -21,37 +23,46 @@ mod eventloop;
 mod messages;
 
 const ID_PATH: &str = "/var/lib/firezone/gateway_id";
+const PRIVATE_KEY_PATH: &str = "/var/lib/firezone/gateway_private_key";
+
+fn main() {
+    let mut cli = Cli::parse();
+    setup_global_subscriber(layer::Identity::new());
+
+    // Modifying the environment of a running process is unsafe.
+    // Thus, we take the token before the runtime spawns any threads.
+    let token = cli.common.take_token();
 
-#[tokio::main]
-async fn main() {
     // Enforce errors only being printed on a single line using the technique recommended in the anyhow docs:
     // https://docs.rs/anyhow/latest/anyhow/struct.Error.html#display-representations
     //
     // By default, `anyhow` prints a stacktrace when it exits.
     // That looks like a "crash" but we "just" exit with a fatal error.
-    if let Err(e) = try_main().await {
+    if let Err(e) =
+        token.and_then(|token| tokio::runtime::Runtime::new()?.block_on(try_main(cli, token)))
+    {
         tracing::error!("{e:#}");
         std::process::exit(1);
     }
 }
 
-async fn try_main() -> Result<()> {
-    let cli = Cli::parse();
-    setup_global_subscriber(layer::Identity::new());
-
+async fn try_main(cli: Cli, token: SecretString) -> Result<()> {
     let firezone_id = get_firezone_id(cli.firezone_id).await
         .context("Couldn't read FIREZONE_ID or write it to disk: Please provide it through the env variable or provide rw access to /var/lib/firezone/")?;
 
-    let (private_key, public_key) = keypair();
+    let private_key = get_private_key(cli.rotate_key).await.context(
+        "Couldn't read the private key or write it to disk: Please provide rw access to /var/lib/firezone/",
+    )?;
+    let public_key = PublicKey::from(&private_key);
     let login = LoginUrl::gateway(
         cli.common.api_url,
-        &SecretString::new(cli.common.token),
+        &token,
         firezone_id,
         cli.common.firezone_name,
         public_key.to_bytes(),
     )?;
 
-    let task = tokio::spawn(run(login, private_key)).err_into();
+    let task = tokio::spawn(run(login, private_key, cli.candidate_policy.into())).err_into();
 
     let ctrl_c = pin!(ctrl_c().map_err(anyhow::Error::new));
 
@@ -94,8 +105,72 @@ async fn get_firezone_id(env_id: Option<String>) -> Result<String> {
     Ok(id)
 }
 
-async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
+/// Reads the persisted private key or generates (and persists) a new one.
+///
+/// Re-using the private key across restarts keeps our public key stable, which means clients don't have to re-establish their connections.
+async fn get_private_key(rotate: bool) -> Result<StaticSecret> {
+    let key_path = Path::new(PRIVATE_KEY_PATH);
+
+    if rotate {
+        tracing::info!("Rotating private key");
+    } else if let Some(private_key) = read_private_key(key_path).await? {
+        return Ok(private_key);
+    }
+
+    let (private_key, _) = keypair();
+
+    tokio::fs::create_dir_all(key_path.parent().unwrap()).await?;
+
+    // Write to a temporary file first so we never leave a partially written key behind.
+    let tmp_path = key_path.with_extension("tmp");
+    let _ = tokio::fs::remove_file(&tmp_path).await; // Ensure `mode` below applies.
+    let mut key_file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .await?;
+    key_file.write_all(&private_key.to_bytes()).await?;
+    key_file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, key_path).await?;
+
+    Ok(private_key)
+}
+
+async fn read_private_key(path: &Path) -> Result<Option<StaticSecret>> {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if metadata.permissions().mode() & 0o177 != 0 {
+        bail!(
+            "Private key file `{}` should have mode 0o400 or 0o600",
+            path.display()
+        );
+    }
+
+    let bytes = tokio::fs::read(path).await?;
+    let bytes = <[u8; 32]>::try_from(bytes).map_err(|bytes| {
+        anyhow!(
+            "Private key file `{}` should contain 32 bytes but has {}",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+
+    Ok(Some(StaticSecret::from(bytes)))
+}
+
+async fn run(
+    login: LoginUrl,
+    private_key: StaticSecret,
+    candidate_policy: CandidatePolicy,
+) -> Result<Infallible> {
     let mut tunnel = GatewayTunnel::new(private_key, Sockets::new(), CallbackHandler)?;
+    tunnel.set_candidate_policy(candidate_policy);
 
     let (portal, init) = phoenix_channel::init::<_, InitGateway, _, _>(
         Secret::new(login),
@@ -139,4 +214,33 @@ struct Cli {
     /// Identifier generated by the portal to identify and display the device.
     #[arg(short = 'i', long, env = "FIREZONE_ID")]
     pub firezone_id: Option<String>,
+
+    /// Replace the persisted WireGuard private key with a newly generated one.
+    ///
+    /// All clients will have to re-establish their connections to this gateway.
+    #[arg(long)]
+    rotate_key: bool,
+
+    /// Which of the gateway's ICE candidates to use and advertise to clients.
+    ///
+    /// `no-host` keeps the gateway's local IPs private, `relay-only` only allows relayed connections.
+    #[arg(long, env = "FIREZONE_CANDIDATE_POLICY", value_enum, default_value_t = CandidatePolicyArg::All)]
+    candidate_policy: CandidatePolicyArg,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CandidatePolicyArg {
+    All,
+    NoHost,
+    RelayOnly,
+}
+
+impl From<CandidatePolicyArg> for CandidatePolicy {
+    fn from(value: CandidatePolicyArg) -> Self {
+        match value {
+            CandidatePolicyArg::All => CandidatePolicy::All,
+            CandidatePolicyArg::NoHost => CandidatePolicy::NoHost,
+            CandidatePolicyArg::RelayOnly => CandidatePolicy::RelayOnly,
+        }
+    }
 }
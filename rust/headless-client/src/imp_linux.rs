@@ -1,6 +1,6 @@
 //! Implementation, Linux-specific
 
-use super::{Cli, IpcClientMsg, IpcServerMsg, FIREZONE_GROUP, TOKEN_ENV_KEY};
+use super::{Cli, IpcClientMsg, IpcServerMsg, FIREZONE_GROUP};
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
 use connlib_client_shared::{file_logger, Callbacks, Sockets};
@@ -81,16 +81,13 @@ pub fn run_only_ipc_service() -> Result<()> {
     run_ipc_service(cli, rt, shutdown_rx)
 }
 
-pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
-    let Ok(stat) = nix::sys::stat::fstatat(None, path, nix::fcntl::AtFlags::empty()) else {
-        // File doesn't exist or can't be read
-        tracing::info!(
-            ?path,
-            ?TOKEN_ENV_KEY,
-            "No token found in env var or on disk"
-        );
-        bail!("Token file doesn't exist");
-    };
+/// Checks that the token file is owned by root, because we run as root.
+///
+/// The file's mode is checked by [`firezone_cli_utils::check_token_permissions`].
+pub(crate) fn check_token_owner(path: &Path) -> Result<()> {
+    let stat = nix::sys::stat::fstatat(None, path, nix::fcntl::AtFlags::empty())
+        .with_context(|| format!("Token file `{}` doesn't exist", path.display()))?;
+
     if stat.st_uid != ROOT_USER {
         bail!(
             "Token file `{}` should be owned by root user",
@@ -103,12 +100,6 @@ pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
             path.display()
         );
     }
-    if stat.st_mode & 0o177 != 0 {
-        bail!(
-            "Token file `{}` should have mode 0o400 or 0x600",
-            path.display()
-        );
-    }
     Ok(())
 }
 
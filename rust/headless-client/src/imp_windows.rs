@@ -5,7 +5,7 @@ use connlib_client_shared::file_logger;
 use std::{
     ffi::OsString,
     net::IpAddr,
-    path::{Path, PathBuf},
+    path::PathBuf,
     str::FromStr,
     task::{Context, Poll},
     time::Duration,
@@ -42,13 +42,6 @@ impl Signals {
     }
 }
 
-// The return value is useful on Linux
-#[allow(clippy::unnecessary_wraps)]
-pub(crate) fn check_token_permissions(_path: &Path) -> Result<()> {
-    // TODO: Make sure the token is only readable by admin / our service user on Windows
-    Ok(())
-}
-
 pub(crate) fn default_token_path() -> std::path::PathBuf {
     // TODO: System-wide default token path for Windows
     PathBuf::from("token.txt")
@@ -12,7 +12,7 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use connlib_client_shared::{file_logger, keypair, Callbacks, LoginUrl, Session, Sockets};
 use connlib_shared::callbacks;
-use firezone_cli_utils::setup_global_subscriber;
+use firezone_cli_utils::{remove_token_env_var, setup_global_subscriber, TOKEN_ENV_KEY};
 use secrecy::SecretString;
 use std::{future, net::IpAddr, path::PathBuf, task::Poll};
 use tokio::sync::mpsc;
@@ -47,8 +47,6 @@ pub const GIT_VERSION: &str = git_version::git_version!(
     fallback = "unknown"
 );
 
-const TOKEN_ENV_KEY: &str = "FIREZONE_TOKEN";
-
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -139,15 +137,8 @@ pub fn run_only_headless_client() -> Result<()> {
     let token_env_var = cli.token.take().map(SecretString::from);
     let cli = cli;
 
-    // Docs indicate that `remove_var` should actually be marked unsafe
-    // SAFETY: We haven't spawned any other threads, this code should be the first
-    // thing to run after entering `main`. So nobody else is reading the environment.
-    #[allow(unused_unsafe)]
-    unsafe {
-        // This removes the token from the environment per <https://security.stackexchange.com/a/271285>. We run as root so it may not do anything besides defense-in-depth.
-        std::env::remove_var(TOKEN_ENV_KEY);
-    }
-    assert!(std::env::var(TOKEN_ENV_KEY).is_err());
+    // We run as root so this may not do anything besides defense-in-depth.
+    remove_token_env_var();
 
     let (layer, _handle) = cli.log_dir.as_deref().map(file_logger::layer).unzip();
     setup_global_subscriber(layer);
@@ -283,7 +274,7 @@ fn read_token_file(cli: &Cli) -> Result<Option<SecretString>> {
     let path = PathBuf::from(&cli.token_path);
 
     if let Ok(token) = std::env::var(TOKEN_ENV_KEY) {
-        std::env::remove_var(TOKEN_ENV_KEY);
+        remove_token_env_var();
 
         let token = SecretString::from(token);
         // Token was provided in env var
@@ -298,7 +289,9 @@ fn read_token_file(cli: &Cli) -> Result<Option<SecretString>> {
     if std::fs::metadata(&path).is_err() {
         return Ok(None);
     }
-    imp::check_token_permissions(&path)?;
+    #[cfg(target_os = "linux")]
+    imp::check_token_owner(&path)?;
+    firezone_cli_utils::check_token_permissions(&path)?;
 
     let Ok(bytes) = std::fs::read(&path) else {
         // We got the metadata a second ago, but can't read the file itself.
@@ -1,29 +1,54 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::routing::get;
 use axum::Router;
 use std::net::SocketAddr;
 
+/// The content type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
 /// Runs an HTTP server that responds to `GET /healthz` with 200 OK or 400 BAD REQUEST, depending on the return value of `is_healthy`.
 pub async fn serve(
     addr: impl Into<SocketAddr>,
     is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
 ) -> std::io::Result<()> {
-    let addr = addr.into();
-
-    let service = Router::new()
-        .route(
-            "/healthz",
-            get(move || async move {
-                if is_healthy() {
-                    StatusCode::OK
-                } else {
-                    StatusCode::BAD_REQUEST
-                }
-            }),
-        )
-        .into_make_service();
-
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+    serve_router(addr.into(), health_check_router(is_healthy)).await
+}
+
+/// Like [`serve`] but additionally responds to `GET /metrics` with the return value of `metrics`.
+///
+/// `metrics` is expected to render the metrics in the Prometheus text exposition format.
+pub async fn serve_with_metrics(
+    addr: impl Into<SocketAddr>,
+    is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    metrics: impl Fn() -> String + Clone + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let router = health_check_router(is_healthy).route(
+        "/metrics",
+        get(move || async move { ([(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], metrics()) }),
+    );
+
+    serve_router(addr.into(), router).await
+}
+
+fn health_check_router(is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static) -> Router {
+    Router::new().route(
+        "/healthz",
+        get(move || async move {
+            if is_healthy() {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_REQUEST
+            }
+        }),
+    )
+}
+
+async fn serve_router(addr: SocketAddr, router: Router) -> std::io::Result<()> {
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        router.into_make_service(),
+    )
+    .await?;
 
     Ok(())
 }
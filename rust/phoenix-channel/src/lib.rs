@@ -258,6 +258,13 @@ where
         self.pending_join_requests.insert(request_id);
     }
 
+    /// Replaces the payload of the join request.
+    ///
+    /// The new payload is used the next time we (re-)join the room, i.e. after reconnecting.
+    pub fn set_init_req(&mut self, init_req: TInitReq) {
+        self.init_req = init_req;
+    }
+
     /// Send a message to a topic.
     pub fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
         let (id, msg) = self.make_message(topic, message);
@@ -0,0 +1,339 @@
+//! Generates load against a relay by simulating many TURN clients.
+//!
+//! Each client makes an allocation using snownet's [`Allocation`], binds a channel to one of our peer sockets and then sends channel data at a fixed rate.
+//! The peer sockets count what arrives, which gives us the loss and throughput of the relay.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use secrecy::SecretString;
+use snownet::{Allocation, CandidateEvent, RelaySocket};
+use str0m::CandidateKind;
+use stun_codec::rfc5389::attributes::{Realm, Username};
+use tokio::{net::UdpSocket, task::JoinSet};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+const MAX_UDP_SIZE: usize = (1 << 16) - 1;
+
+/// How long we wait for packets still in flight after the clients stopped sending.
+const DRAIN_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The relay to generate load against.
+    #[arg(long, env, default_value = "127.0.0.1:3478")]
+    relay: SocketAddrV4,
+    /// How many TURN clients to simulate.
+    #[arg(long, env, default_value = "1000")]
+    clients: usize,
+    /// How many channel data messages each client sends per second.
+    #[arg(long, env, default_value = "10")]
+    packets_per_second: u32,
+    /// The size of the payload of each channel data message in bytes.
+    #[arg(long, env, default_value = "1000")]
+    payload_size: usize,
+    /// For how long to generate load, in seconds.
+    #[arg(long, env, default_value = "30")]
+    duration: u64,
+    /// How many peer sockets the clients relay their data to.
+    #[arg(long, env, default_value = "4")]
+    peers: usize,
+    /// The IP under which the relay can reach our peer sockets.
+    #[arg(long, env, default_value = "127.0.0.1")]
+    peer_ip: Ipv4Addr,
+    /// The secret the relay derives passwords from.
+    ///
+    /// If not set, we derive the secret from `--relay-rng-seed`.
+    #[arg(long, env)]
+    relay_secret: Option<String>,
+    /// The `--rng-seed` the relay was started with.
+    #[arg(long, env, default_value = "0")]
+    relay_rng_seed: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .init();
+
+    anyhow::ensure!(args.packets_per_second > 0, "Packet rate must not be 0");
+    anyhow::ensure!(args.peers > 0, "Need at least one peer");
+
+    let secret = match args.relay_secret.clone() {
+        Some(secret) => SecretString::from(secret),
+        None => firezone_relay::auth::secret_from_seed(args.relay_rng_seed),
+    };
+
+    let stats = Arc::new(Stats::default());
+    let duration = Duration::from_secs(args.duration);
+
+    let mut peer_tasks = JoinSet::new();
+    let mut peers = Vec::with_capacity(args.peers);
+
+    for _ in 0..args.peers {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        peers.push(SocketAddr::new(
+            IpAddr::V4(args.peer_ip),
+            socket.local_addr()?.port(),
+        ));
+        peer_tasks.spawn(run_peer(socket, stats.clone()));
+    }
+
+    let config = ClientConfig {
+        relay: args.relay,
+        packet_interval: Duration::from_secs(1) / args.packets_per_second,
+        payload_size: args.payload_size,
+        deadline: tokio::time::Instant::now() + duration,
+    };
+    let expiry = SystemTime::now() + duration + Duration::from_secs(60 * 60);
+    let realm = Realm::new("firezone".to_owned()).context("Invalid realm")?;
+
+    tracing::info!(relay = %args.relay, clients = %args.clients, "Starting load generation");
+
+    let mut clients = JoinSet::new();
+
+    for id in 0..args.clients {
+        let (username, password) = make_credentials(&secret, expiry, id)?;
+
+        clients.spawn(run_client(
+            config,
+            peers[id % peers.len()],
+            username,
+            password,
+            realm.clone(),
+            stats.clone(),
+        ));
+    }
+
+    let mut allocation_latencies = Vec::with_capacity(args.clients);
+
+    while let Some(result) = clients.join_next().await {
+        match result.context("Client task panicked")? {
+            Ok(Some(latency)) => allocation_latencies.push(latency),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Client failed: {e:#}"),
+        }
+    }
+
+    tokio::time::sleep(DRAIN_PERIOD).await;
+    peer_tasks.abort_all();
+
+    report(&args, &stats, allocation_latencies, duration);
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClientConfig {
+    relay: SocketAddrV4,
+    packet_interval: Duration,
+    payload_size: usize,
+    deadline: tokio::time::Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    sent_packets: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_packets: AtomicU64,
+    received_bytes: AtomicU64,
+}
+
+/// Runs a single TURN client until the deadline.
+///
+/// Returns how long it took to make the allocation or `None` if we never got one.
+async fn run_client(
+    config: ClientConfig,
+    peer: SocketAddr,
+    username: Username,
+    password: String,
+    realm: Realm,
+    stats: Arc<Stats>,
+) -> Result<Option<Duration>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let local = socket.local_addr()?;
+
+    let started_at = Instant::now();
+    let mut allocation = Allocation::new(
+        RelaySocket::V4(config.relay),
+        username,
+        password,
+        realm,
+        started_at,
+    );
+    allocation.bind_channel(peer, started_at); // Buffered until we have an allocation.
+
+    let mut allocation_latency = None;
+    let payload = vec![0u8; config.payload_size];
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    let mut send_interval = tokio::time::interval(config.packet_interval);
+
+    loop {
+        while let Some(transmit) = allocation.poll_transmit() {
+            socket.send_to(&transmit.payload, transmit.dst).await?;
+        }
+
+        while let Some(event) = allocation.poll_event() {
+            if let CandidateEvent::New(candidate) = event {
+                if candidate.kind() == CandidateKind::Relayed && allocation_latency.is_none() {
+                    allocation_latency = Some(started_at.elapsed());
+                }
+            }
+        }
+
+        // The deadline fires first in case we don't have a timeout.
+        let timeout = allocation
+            .poll_timeout()
+            .map(tokio::time::Instant::from_std)
+            .unwrap_or(config.deadline);
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(config.deadline) => break,
+            result = socket.recv_from(&mut buf) => {
+                let (num_read, from) = result?;
+
+                allocation.handle_input(from, local, &buf[..num_read], Instant::now());
+            }
+            _ = tokio::time::sleep_until(timeout) => allocation.handle_timeout(Instant::now()),
+            _ = send_interval.tick() => {
+                // `None` until our channel is bound.
+                let Some(transmit) = allocation.encode_to_owned_transmit(peer, &payload, Instant::now()) else {
+                    continue;
+                };
+
+                socket.send_to(&transmit.payload, transmit.dst).await?;
+
+                stats.sent_packets.fetch_add(1, Ordering::Relaxed);
+                stats.sent_bytes.fetch_add(payload.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    Ok(allocation_latency)
+}
+
+/// Counts all data that the relay forwards to this peer.
+async fn run_peer(socket: UdpSocket, stats: Arc<Stats>) -> Result<()> {
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+
+    loop {
+        let (num_read, _) = socket.recv_from(&mut buf).await?;
+
+        stats.received_packets.fetch_add(1, Ordering::Relaxed);
+        stats
+            .received_bytes
+            .fetch_add(num_read as u64, Ordering::Relaxed);
+    }
+}
+
+fn make_credentials(
+    secret: &SecretString,
+    expiry: SystemTime,
+    id: usize,
+) -> Result<(Username, String)> {
+    let secs = expiry
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context("expiry must be later than UNIX_EPOCH")?
+        .as_secs();
+    let salt = format!("load-{id}");
+
+    let password = firezone_relay::auth::generate_password(secret, expiry, &salt);
+    let username = Username::new(format!("{secs}:{salt}")).context("Invalid username")?;
+
+    Ok((username, password))
+}
+
+fn report(args: &Args, stats: &Stats, mut allocation_latencies: Vec<Duration>, duration: Duration) {
+    allocation_latencies.sort_unstable();
+
+    let percentile = |p: usize| percentile(&allocation_latencies, p);
+
+    let failed = args.clients - allocation_latencies.len();
+
+    if allocation_latencies.is_empty() {
+        tracing::warn!(%failed, "No client managed to make an allocation");
+    } else {
+        tracing::info!(
+            allocated = %allocation_latencies.len(),
+            %failed,
+            p50 = ?percentile(50),
+            p90 = ?percentile(90),
+            p99 = ?percentile(99),
+            max = ?percentile(100),
+            "Allocation latency"
+        );
+    }
+
+    let sent_packets = stats.sent_packets.load(Ordering::Relaxed);
+    let received_packets = stats.received_packets.load(Ordering::Relaxed);
+    let received_bytes = stats.received_bytes.load(Ordering::Relaxed);
+
+    let loss = if sent_packets == 0 {
+        0.0
+    } else {
+        1.0 - (received_packets as f64 / sent_packets as f64)
+    };
+    let throughput_mbps = (received_bytes as f64 * 8.0) / duration.as_secs_f64() / 1_000_000.0;
+
+    tracing::info!(
+        %sent_packets,
+        sent_bytes = %stats.sent_bytes.load(Ordering::Relaxed),
+        %received_packets,
+        %received_bytes,
+        loss = %format!("{:.2}%", loss * 100.0),
+        throughput = %format!("{throughput_mbps:.2} Mbit/s"),
+        "Relayed data"
+    );
+}
+
+/// The `p`-th percentile of the non-empty, sorted `values`.
+fn percentile(values: &[Duration], p: usize) -> Duration {
+    let index = (values.len() * p / 100).min(values.len() - 1);
+
+    values[index]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_derived_from_relay_secret() {
+        let secret = SecretString::from("secret".to_owned());
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let (username, password) = make_credentials(&secret, expiry, 7).unwrap();
+
+        assert_eq!(username.name(), "1700000000:load-7");
+        assert_eq!(
+            password,
+            firezone_relay::auth::generate_password(&secret, expiry, "load-7")
+        );
+    }
+
+    #[test]
+    fn percentiles_of_latencies() {
+        let latencies = (1..=10).map(Duration::from_millis).collect::<Vec<_>>();
+
+        assert_eq!(percentile(&latencies, 0), Duration::from_millis(1));
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(6));
+        assert_eq!(percentile(&latencies, 90), Duration::from_millis(10));
+        assert_eq!(percentile(&latencies, 100), Duration::from_millis(10));
+    }
+}
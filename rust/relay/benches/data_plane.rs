@@ -0,0 +1,186 @@
+//! Measures how relaying channel data scales with the number of data-plane threads.
+//!
+//! Like the workers of the relay with `SO_REUSEPORT`, each thread relays the channel data of its own set of clients.
+//! We compare handing every batch of packets to the [`Server`] behind a lock with routing them via a snapshot of its [`Routes`].
+//! Socket I/O is left out on purpose: it scales with the number of threads anyway and would only hide the cost of the shared state.
+//!
+//! Run with `cargo bench -p firezone-relay --bench data_plane`.
+
+use firezone_relay::handover::ServerState;
+use firezone_relay::{ChannelData, ClientSocket, Route, Server};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hint::black_box;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NUM_CLIENTS_PER_THREAD: usize = 64;
+const PAYLOAD_LEN: usize = 1200;
+const CHANNEL_NUMBER: u16 = 0x4000;
+const LOWEST_PORT: u16 = 49152;
+
+const MEASUREMENT_DURATION: Duration = Duration::from_secs(2);
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let num_threads = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|n| *n <= max_threads)
+        .collect::<Vec<_>>();
+
+    let packet = channel_data_packet();
+
+    println!("threads | server lock (packets/s) | routes (packets/s)");
+
+    for num_threads in num_threads {
+        let server = Mutex::new(make_server(num_threads));
+
+        let with_server_lock = measure(num_threads, |thread, stop| {
+            let clients = clients(thread);
+            let mut num_packets = 0;
+
+            while !stop.load(Ordering::Relaxed) {
+                // Like the data plane used to, handle an entire batch with a single acquisition of the lock.
+                let mut server = server.lock().unwrap();
+                let now = Instant::now();
+
+                for client in &clients {
+                    let relay = server.handle_client_input(&packet, *client, now);
+                    assert!(relay.is_some());
+
+                    black_box(relay);
+                }
+
+                num_packets += clients.len() as u64;
+            }
+
+            num_packets
+        });
+
+        let with_routes = measure(num_threads, |thread, stop| {
+            let clients = clients(thread);
+            let mut routes = server.lock().unwrap().routes();
+            let mut num_packets = 0;
+
+            while !stop.load(Ordering::Relaxed) {
+                if routes.is_outdated() {
+                    routes = server.lock().unwrap().routes();
+                }
+
+                let now = Instant::now();
+
+                for client in &clients {
+                    let message = ChannelData::parse(&packet).unwrap();
+                    let route = routes.handle_channel_data(&message, *client, now);
+                    assert!(matches!(route, Route::Relay(_)));
+
+                    black_box(route);
+                }
+
+                num_packets += clients.len() as u64;
+            }
+
+            num_packets
+        });
+
+        println!("{num_threads:>7} | {with_server_lock:>23} | {with_routes:>18}");
+    }
+}
+
+/// Runs `relay` on `num_threads` threads for [`MEASUREMENT_DURATION`] and returns the number of packets relayed per second across all threads.
+fn measure(num_threads: usize, relay: impl Fn(usize, &AtomicBool) -> u64 + Sync) -> u64 {
+    let stop = AtomicBool::new(false);
+
+    let num_packets = thread::scope(|s| {
+        let relay = &relay;
+        let stop = &stop;
+
+        let threads = (0..num_threads)
+            .map(|thread| s.spawn(move || relay(thread, stop)))
+            .collect::<Vec<_>>();
+
+        thread::sleep(MEASUREMENT_DURATION);
+        stop.store(true, Ordering::Relaxed);
+
+        threads.into_iter().map(|t| t.join().unwrap()).sum::<u64>()
+    });
+
+    num_packets / MEASUREMENT_DURATION.as_secs()
+}
+
+/// Creates a [`Server`] with one allocation and one bound channel for each client of each thread.
+fn make_server(num_threads: usize) -> Server<StdRng> {
+    let (allocations, channels) = (0..num_threads)
+        .flat_map(|thread| {
+            clients(thread)
+                .into_iter()
+                .enumerate()
+                .map(move |(i, c)| (thread, i, c))
+        })
+        .map(|(thread, i, client)| {
+            let port = LOWEST_PORT + (thread * NUM_CLIENTS_PER_THREAD + i) as u16;
+            let peer = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 1), port));
+
+            let allocation = serde_json::json!({
+                "client": client.into_socket(),
+                "port": port,
+                "expires_in": { "secs": 600, "nanos": 0 },
+                "first_relay_addr": Ipv4Addr::new(198, 51, 100, 1),
+                "second_relay_addr": null,
+                "bytes_relayed": 0,
+                "username": "",
+            });
+            let channel = serde_json::json!({
+                "client": client.into_socket(),
+                "number": CHANNEL_NUMBER,
+                "peer": peer,
+                "port": port,
+                "bound": true,
+                "expires_in": { "secs": 600, "nanos": 0 },
+            });
+
+            (allocation, channel)
+        })
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+    let state = serde_json::from_value::<ServerState>(serde_json::json!({
+        "auth_secret": "secret",
+        "previous_auth_secrets": [],
+        "allocations": allocations,
+        "channels": channels,
+        "permissions": [],
+        "nonces": [],
+    }))
+    .unwrap();
+
+    let mut server = Server::new(
+        Ipv4Addr::new(198, 51, 100, 1),
+        StdRng::seed_from_u64(0),
+        LOWEST_PORT,
+        u16::MAX,
+    );
+    server.import_state(state, Instant::now());
+
+    server
+}
+
+fn clients(thread: usize) -> Vec<ClientSocket> {
+    (0..NUM_CLIENTS_PER_THREAD)
+        .map(|i| {
+            ClientSocket::new(SocketAddr::from((
+                Ipv4Addr::new(10, 0, thread as u8, i as u8),
+                50_000,
+            )))
+        })
+        .collect()
+}
+
+fn channel_data_packet() -> Vec<u8> {
+    let mut packet = vec![0u8; 4 + PAYLOAD_LEN];
+    packet[..2].copy_from_slice(&CHANNEL_NUMBER.to_be_bytes());
+    packet[2..4].copy_from_slice(&(PAYLOAD_LEN as u16).to_be_bytes());
+
+    packet
+}
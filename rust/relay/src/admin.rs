@@ -0,0 +1,244 @@
+//! A local-only HTTP API for inspecting and manipulating the state of a [`Server`].
+//!
+//! - `GET /allocations`: Lists all allocations.
+//! - `GET /allocations/:port`: Shows the allocation on the given port.
+//! - `DELETE /allocations/:port`: Deletes the allocation on the given port.
+//! - `DELETE /allocations/:port/channels/:number`: Deletes a channel binding of the allocation on the given port.
+//! - `GET /capture`: Shows the running packet capture.
+//! - `POST /capture`: Starts a packet capture for an allocation port or a client socket.
+//! - `DELETE /capture`: Stops the running packet capture.
+
+use crate::audit;
+use crate::capture::{CaptureFilter, CaptureInfo, Captures, StartError};
+use crate::{AllocationInfo, AllocationPort, ChannelInfo, ClientSocket, Server};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Runs the admin API on the given listener.
+///
+/// The listener is bound by the caller so that a failure to bind fails the startup of the relay.
+///
+/// Deleting allocations or channels queues new commands on the [`Server`].
+/// To make sure they are executed promptly, we send a notification to `commands_pending` after each modification.
+pub async fn serve<R>(
+    listener: TcpListener,
+    server: Arc<Mutex<Server<R>>>,
+    captures: Captures,
+    commands_pending: mpsc::Sender<()>,
+) -> io::Result<()>
+where
+    R: Send + 'static,
+{
+    let router = Router::new()
+        .route(
+            "/allocations",
+            get({
+                let server = server.clone();
+
+                move || async move {
+                    let allocations = server.lock().unwrap().allocations(Instant::now());
+
+                    Json(
+                        allocations
+                            .into_iter()
+                            .map(Allocation::from)
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            }),
+        )
+        .route(
+            "/allocations/:port",
+            get({
+                let server = server.clone();
+
+                move |Path(port): Path<u16>| async move {
+                    let allocation = server
+                        .lock()
+                        .unwrap()
+                        .allocation(AllocationPort::new(port), Instant::now());
+
+                    allocation
+                        .map(|a| Json(Allocation::from(a)))
+                        .ok_or(StatusCode::NOT_FOUND)
+                }
+            })
+            .delete({
+                let server = server.clone();
+                let commands_pending = commands_pending.clone();
+
+                move |Path(port): Path<u16>| async move {
+                    let evicted = server
+                        .lock()
+                        .unwrap()
+                        .evict_allocation(AllocationPort::new(port));
+
+                    eviction_response(evicted, &commands_pending)
+                }
+            }),
+        )
+        .route(
+            "/allocations/:port/channels/:number",
+            delete(move |Path((port, number)): Path<(u16, u16)>| async move {
+                let evicted = server
+                    .lock()
+                    .unwrap()
+                    .evict_channel_binding(AllocationPort::new(port), number);
+
+                eviction_response(evicted, &commands_pending)
+            }),
+        )
+        .route(
+            "/capture",
+            get({
+                let captures = captures.clone();
+
+                move || async move {
+                    captures
+                        .info(Instant::now())
+                        .map(|c| Json(Capture::from(c)))
+                        .ok_or(StatusCode::NOT_FOUND)
+                }
+            })
+            .post({
+                let captures = captures.clone();
+
+                move |Json(request): Json<StartCapture>| async move {
+                    let filter = match (request.port, request.client) {
+                        (Some(port), None) => CaptureFilter::Port(AllocationPort::new(port)),
+                        (None, Some(client)) => CaptureFilter::Client(ClientSocket::new(client)),
+                        _ => return Err(StatusCode::BAD_REQUEST),
+                    };
+
+                    let info = captures
+                        .start(
+                            filter,
+                            request.max_bytes,
+                            request.duration_secs.map(Duration::from_secs),
+                            Instant::now(),
+                        )
+                        .map_err(|e| match e {
+                            StartError::Disabled => StatusCode::NOT_FOUND,
+                            StartError::AlreadyRunning => StatusCode::CONFLICT,
+                            StartError::Io(e) => {
+                                tracing::warn!(target: "relay", "Failed to start packet capture: {e}");
+
+                                StatusCode::INTERNAL_SERVER_ERROR
+                            }
+                        })?;
+
+                    Ok((StatusCode::CREATED, Json(Capture::from(info))))
+                }
+            })
+            .delete(move || async move {
+                captures
+                    .stop(Instant::now())
+                    .map(|c| Json(Capture::from(c)))
+                    .ok_or(StatusCode::NOT_FOUND)
+            }),
+        );
+
+    axum::serve(listener, router.into_make_service()).await?;
+
+    Ok(())
+}
+
+fn eviction_response(evicted: bool, commands_pending: &mpsc::Sender<()>) -> StatusCode {
+    if !evicted {
+        return StatusCode::NOT_FOUND;
+    }
+
+    // If the channel is full, a notification is already pending.
+    let _ = commands_pending.try_send(());
+
+    StatusCode::NO_CONTENT
+}
+
+#[derive(serde::Serialize)]
+struct Allocation {
+    client: SocketAddr,
+    port: u16,
+    families: Vec<&'static str>,
+    expires_in_secs: u64,
+    bytes_relayed: u64,
+    channels: Vec<Channel>,
+}
+
+#[derive(serde::Serialize)]
+struct Channel {
+    number: u16,
+    peer: SocketAddr,
+    bound: bool,
+    expires_in_secs: u64,
+}
+
+/// Exactly one of `port` and `client` must be set.
+#[derive(serde::Deserialize)]
+struct StartCapture {
+    port: Option<u16>,
+    client: Option<SocketAddr>,
+    max_bytes: Option<u64>,
+    duration_secs: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct Capture {
+    port: Option<u16>,
+    client: Option<SocketAddr>,
+    path: String,
+    bytes_written: u64,
+    max_bytes: u64,
+    expires_in_secs: u64,
+    packets_dropped: u64,
+}
+
+impl From<AllocationInfo> for Allocation {
+    fn from(info: AllocationInfo) -> Self {
+        Self {
+            client: info.client.into_socket(),
+            port: info.port.value(),
+            families: info.families.into_iter().map(audit::family_name).collect(),
+            expires_in_secs: info.expires_in.as_secs(),
+            bytes_relayed: info.bytes_relayed,
+            channels: info.channels.into_iter().map(Channel::from).collect(),
+        }
+    }
+}
+
+impl From<ChannelInfo> for Channel {
+    fn from(info: ChannelInfo) -> Self {
+        Self {
+            number: info.number,
+            peer: info.peer.into_socket(),
+            bound: info.bound,
+            expires_in_secs: info.expires_in.as_secs(),
+        }
+    }
+}
+
+impl From<CaptureInfo> for Capture {
+    fn from(info: CaptureInfo) -> Self {
+        let (port, client) = match info.filter {
+            CaptureFilter::Port(port) => (Some(port.value()), None),
+            CaptureFilter::Client(client) => (None, Some(client.into_socket())),
+        };
+
+        Self {
+            port,
+            client,
+            path: info.path.display().to_string(),
+            bytes_written: info.bytes_written,
+            max_bytes: info.max_bytes,
+            expires_in_secs: info.expires_in.as_secs(),
+            packets_dropped: info.packets_dropped,
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Structured audit records of who used the relay, when and for how much traffic.
+//!
+//! The [`Server`](crate::Server) emits an [`AuditRecord`] whenever an allocation is created, refreshed or deleted and whenever a channel is bound.
+//! An [`AuditSink`] persists these records, either as JSON lines in a file or via syslog.
+//! To not stall relaying on a slow disk or syslog daemon, the [`AuditWriter`] does so on a dedicated thread.
+
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+use stun_codec::rfc8656::attributes::AddressFamily;
+
+/// The syslog priority of our records: facility `auth` (4) and severity `info` (6).
+const SYSLOG_PRIORITY: u8 = 4 * 8 + 6;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditRecord {
+    AllocationCreated {
+        client: SocketAddr,
+        /// The TURN username, encoding the expiry and salt of the credentials.
+        username: String,
+        port: u16,
+        families: Vec<&'static str>,
+        lifetime_secs: u64,
+    },
+    AllocationRefreshed {
+        client: SocketAddr,
+        username: String,
+        port: u16,
+        lifetime_secs: u64,
+        bytes_relayed: u64,
+    },
+    ChannelBound {
+        client: SocketAddr,
+        username: String,
+        port: u16,
+        channel: u16,
+        peer: SocketAddr,
+    },
+    AllocationDeleted {
+        client: SocketAddr,
+        username: String,
+        port: u16,
+        bytes_relayed: u64,
+        reason: DeletionReason,
+    },
+}
+
+/// Why an allocation was deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionReason {
+    /// The client did not refresh the allocation in time.
+    Expired,
+    /// The client deleted the allocation via a refresh with a lifetime of 0.
+    Released,
+    /// The stream-based connection of the client was closed.
+    ClientDisconnected,
+    /// The allocation was deleted via the admin API.
+    Evicted,
+    /// We failed to bind the sockets of the allocation.
+    Failed,
+}
+
+/// Where we write our [`AuditRecord`]s to.
+pub enum AuditSink {
+    /// A file with one JSON object per line.
+    File(File),
+    /// A syslog daemon listening on a unix datagram socket, e.g. `/dev/log`.
+    Syslog(UnixDatagram),
+}
+
+impl AuditSink {
+    /// Appends records to the file at `path`, creating it if it doesn't exist.
+    pub fn file(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self::File(file))
+    }
+
+    /// Sends records to the syslog socket at `path`.
+    pub fn syslog(path: &Path) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+
+        Ok(Self::Syslog(socket))
+    }
+
+    pub fn write(&mut self, record: &AuditRecord, timestamp: SystemTime) -> io::Result<()> {
+        let line = serde_json::to_string(&TimestampedRecord {
+            timestamp_ms: timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            record,
+        })?;
+
+        match self {
+            AuditSink::File(file) => {
+                // A single write per record ensures concurrent readers never see partial lines.
+                file.write_all(format!("{line}\n").as_bytes())?;
+            }
+            AuditSink::Syslog(socket) => {
+                socket.send(format!("<{SYSLOG_PRIORITY}>firezone-relay: {line}").as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes [`AuditRecord`]s to an [`AuditSink`] on a dedicated thread.
+///
+/// Records are queued in a bounded channel.
+/// If the sink cannot keep up and the channel is full, we drop the record and log it instead.
+pub struct AuditWriter {
+    tx: Option<SyncSender<(AuditRecord, SystemTime)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AuditWriter {
+    /// Spawns the thread writing to `sink`, buffering up to `capacity` records.
+    pub fn spawn(mut sink: AuditSink, capacity: usize) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<(AuditRecord, SystemTime)>(capacity);
+
+        let thread = std::thread::Builder::new()
+            .name("relay-audit-log".to_owned())
+            .spawn(move || {
+                for (record, timestamp) in rx {
+                    if let Err(e) = sink.write(&record, timestamp) {
+                        tracing::warn!(target: "relay", ?record, "Failed to write audit record: {e}");
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues `record` for writing, without blocking.
+    pub fn write(&self, record: AuditRecord, timestamp: SystemTime) {
+        let Some(tx) = self.tx.as_ref() else {
+            return;
+        };
+
+        match tx.try_send((record, timestamp)) {
+            Ok(()) => {}
+            Err(TrySendError::Full((record, _))) => {
+                tracing::warn!(target: "relay", ?record, "Audit log cannot keep up, dropping record");
+            }
+            Err(TrySendError::Disconnected((record, _))) => {
+                tracing::warn!(target: "relay", ?record, "Audit log thread is gone, dropping record");
+            }
+        }
+    }
+}
+
+impl Drop for AuditWriter {
+    /// Writes all queued records before returning.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub(crate) fn family_name(family: AddressFamily) -> &'static str {
+    match family {
+        AddressFamily::V4 => "ip4",
+        AddressFamily::V6 => "ip6",
+    }
+}
+
+#[derive(Serialize)]
+struct TimestampedRecord<'a> {
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    record: &'a AuditRecord,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn serializes_record_as_single_json_object() {
+        let record = AuditRecord::AllocationDeleted {
+            client: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50000)),
+            username: "1717000000:salt".to_owned(),
+            port: 49152,
+            bytes_relayed: 1024,
+            reason: DeletionReason::Expired,
+        };
+
+        let line = serde_json::to_string(&TimestampedRecord {
+            timestamp_ms: 1_717_000_000_000,
+            record: &record,
+        })
+        .unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"timestamp_ms":1717000000000,"event":"allocation_deleted","client":"127.0.0.1:50000","username":"1717000000:salt","port":49152,"bytes_relayed":1024,"reason":"expired"}"#
+        );
+    }
+
+    #[test]
+    fn writer_writes_all_queued_records_before_dropping() {
+        let path = std::env::temp_dir().join(format!("relay-audit-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let writer = AuditWriter::spawn(AuditSink::file(&path).unwrap(), 16).unwrap();
+
+        for port in 49152..49155 {
+            writer.write(
+                AuditRecord::AllocationDeleted {
+                    client: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50000)),
+                    username: "1717000000:salt".to_owned(),
+                    port,
+                    bytes_relayed: 0,
+                    reason: DeletionReason::Released,
+                },
+                SystemTime::now(),
+            );
+        }
+        drop(writer);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents.lines().count(), 3);
+    }
+}
@@ -1,31 +1,39 @@
 use base64::prelude::BASE64_STANDARD_NO_PAD;
 use base64::Engine;
 use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::{Rng as _, RngCore, SeedableRng as _};
 use secrecy::{ExposeSecret, SecretString};
 use sha2::digest::FixedOutput;
 use sha2::Sha256;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use stun_codec::rfc5389::attributes::{MessageIntegrity, Realm, Username};
 use uuid::Uuid;
 
+use crate::handover::{self, PreviousAuthSecret};
+use crate::ClientSocket;
+
 // TODO: Upstream a const constructor to `stun-codec`.
 pub static FIREZONE: Lazy<Realm> = Lazy::new(|| Realm::new("firezone".to_owned()).unwrap());
 
 pub(crate) trait MessageIntegrityExt {
-    fn verify(
+    /// Verifies the message integrity against each of the given secrets.
+    ///
+    /// Succeeds if the password derived from any of them matches.
+    fn verify<'a>(
         &self,
-        relay_secret: &SecretString,
+        relay_secrets: impl IntoIterator<Item = &'a SecretString>,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error>;
 }
 
 impl MessageIntegrityExt for MessageIntegrity {
-    fn verify(
+    fn verify<'a>(
         &self,
-        relay_secret: &SecretString,
+        relay_secrets: impl IntoIterator<Item = &'a SecretString>,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error> {
@@ -36,59 +44,217 @@ impl MessageIntegrityExt for MessageIntegrity {
             return Err(Error::Expired);
         }
 
-        let password = generate_password(relay_secret, expired, salt);
+        let username = Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
+            .map_err(|_| Error::InvalidUsername)?;
 
-        self.check_long_term_credential(
-            &Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
-                .map_err(|_| Error::InvalidUsername)?,
-            &FIREZONE,
-            &password,
-        )
-        .map_err(|_| Error::InvalidPassword)?;
+        let is_valid = relay_secrets.into_iter().any(|relay_secret| {
+            let password = generate_password(relay_secret, expired, salt);
+
+            self.check_long_term_credential(&username, &FIREZONE, &password)
+                .is_ok()
+        });
+
+        if !is_valid {
+            return Err(Error::InvalidPassword);
+        }
 
         Ok(())
     }
 }
 
+/// The secrets we accept for deriving passwords of long-term credentials.
+///
+/// There is always exactly one current secret which is the one we share with the portal.
+/// After a rotation, previous secrets remain valid for a grace period so credentials that clients already hold continue to work.
+pub(crate) struct AuthSecrets {
+    current: SecretString,
+    previous: Vec<(SecretString, Instant)>,
+    grace_period: Duration,
+}
+
+impl AuthSecrets {
+    /// How long a previous secret remains valid after a rotation unless configured otherwise.
+    pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+    pub(crate) fn new(current: SecretString) -> Self {
+        Self {
+            current,
+            previous: Default::default(),
+            grace_period: Self::DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    pub(crate) fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    pub(crate) fn current(&self) -> &SecretString {
+        &self.current
+    }
+
+    /// Makes `new` the current secret and keeps the previous one around for the grace period.
+    pub(crate) fn rotate(&mut self, new: SecretString, now: Instant) {
+        let previous = std::mem::replace(&mut self.current, new);
+
+        self.previous.push((previous, now + self.grace_period));
+    }
+
+    /// All secrets that are valid at `now`, starting with the current one.
+    pub(crate) fn valid(&self, now: Instant) -> impl Iterator<Item = &SecretString> {
+        std::iter::once(&self.current).chain(
+            self.previous
+                .iter()
+                .filter(move |(_, valid_until)| *valid_until > now)
+                .map(|(secret, _)| secret),
+        )
+    }
+
+    /// Removes all previous secrets whose grace period is over.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.previous.retain(|(_, valid_until)| *valid_until > now);
+    }
+
+    /// Exports the current secret and all previous secrets that are still valid at `now`.
+    pub(crate) fn export(&self, now: Instant) -> (String, Vec<PreviousAuthSecret>) {
+        let previous = self
+            .previous
+            .iter()
+            .filter(|(_, valid_until)| *valid_until > now)
+            .map(|(secret, valid_until)| PreviousAuthSecret {
+                secret: secret.expose_secret().clone(),
+                valid_for: *valid_until - now,
+            })
+            .collect();
+
+        (self.current.expose_secret().clone(), previous)
+    }
+
+    /// Replaces all secrets with the ones exported via [`AuthSecrets::export`].
+    pub(crate) fn import(
+        &mut self,
+        current: String,
+        previous: Vec<PreviousAuthSecret>,
+        now: Instant,
+    ) {
+        self.current = SecretString::from(current);
+        self.previous = previous
+            .into_iter()
+            .map(|p| (SecretString::from(p.secret), now + p.valid_for))
+            .collect();
+    }
+}
+
 /// Tracks valid nonces for the TURN relay.
 ///
 /// The semantic nature of nonces is an implementation detail of the relay in TURN.
-/// This could just as easily also be a time-based logic (i.e. nonces are valid for 10min).
 ///
-/// For simplicity reasons, we use a count-based strategy.
-/// Each nonce can be used for a certain number of requests before it is invalid.
-#[derive(Default)]
+/// Each nonce is bound to the client (i.e. its 5-tuple) it was issued to and is only valid for a certain amount of time.
+/// In addition, each nonce can only be used for a certain number of requests.
+/// Once a nonce is no longer valid, the client will receive a 438 (Stale Nonce) together with a new nonce.
 pub(crate) struct Nonces {
-    inner: HashMap<Uuid, u64>,
+    inner: HashMap<Uuid, NonceState>,
+    lifetime: Duration,
+}
+
+struct NonceState {
+    client: ClientSocket,
+    expires_at: Instant,
+    remaining_requests: u64,
+}
+
+impl Default for Nonces {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+            lifetime: Self::DEFAULT_LIFETIME,
+        }
+    }
 }
 
 impl Nonces {
     /// How many requests a client can perform with the same nonce.
     const NUM_REQUESTS: u64 = 100;
 
-    pub(crate) fn add_new(&mut self, nonce: Uuid) {
-        self.inner.insert(nonce, Self::NUM_REQUESTS);
+    /// How long a nonce is valid for unless configured otherwise.
+    pub(crate) const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+    pub(crate) fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    pub(crate) fn add_new(&mut self, nonce: Uuid, client: ClientSocket, now: Instant) {
+        self.inner.insert(
+            nonce,
+            NonceState {
+                client,
+                expires_at: now + self.lifetime,
+                remaining_requests: Self::NUM_REQUESTS,
+            },
+        );
     }
 
     /// Record the usage of a nonce in a request.
-    pub(crate) fn handle_nonce_used(&mut self, nonce: Uuid) -> Result<(), Error> {
+    pub(crate) fn handle_nonce_used(
+        &mut self,
+        nonce: Uuid,
+        client: ClientSocket,
+        now: Instant,
+    ) -> Result<(), Error> {
         let mut entry = match self.inner.entry(nonce) {
             Entry::Vacant(_) => return Err(Error::InvalidNonce),
             Entry::Occupied(entry) => entry,
         };
 
-        let remaining_requests = entry.get_mut();
+        let state = entry.get_mut();
+
+        // Don't remove the nonce here, otherwise anybody who knows the nonce could invalidate it for the actual client.
+        if state.client != client {
+            return Err(Error::InvalidNonce);
+        }
 
-        if *remaining_requests == 0 {
+        if state.expires_at <= now || state.remaining_requests == 0 {
             entry.remove();
 
             return Err(Error::InvalidNonce);
         }
 
-        *remaining_requests -= 1;
+        state.remaining_requests -= 1;
 
         Ok(())
     }
+
+    /// Removes all nonces that have expired.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.inner.retain(|_, state| state.expires_at > now);
+    }
+
+    /// Exports all nonces that are still valid at `now`.
+    pub(crate) fn export(&self, now: Instant) -> Vec<handover::NonceState> {
+        self.inner
+            .iter()
+            .filter(|(_, state)| state.expires_at > now)
+            .map(|(nonce, state)| handover::NonceState {
+                nonce: *nonce,
+                client: state.client.0,
+                expires_in: state.expires_at - now,
+                remaining_requests: state.remaining_requests,
+            })
+            .collect()
+    }
+
+    /// Adds the nonces exported via [`Nonces::export`].
+    pub(crate) fn import(&mut self, nonces: Vec<handover::NonceState>, now: Instant) {
+        self.inner.extend(nonces.into_iter().map(|n| {
+            (
+                n.nonce,
+                NonceState {
+                    client: ClientSocket(n.client),
+                    expires_at: now + n.expires_in,
+                    remaining_requests: n.remaining_requests,
+                },
+            )
+        }));
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -111,6 +277,18 @@ pub(crate) fn split_username(username: &str) -> Result<(u64, &str), Error> {
     Ok((expiry_unix_timestamp, username_salt))
 }
 
+/// Generates a new auth secret.
+pub(crate) fn generate_secret(rng: &mut impl RngCore) -> SecretString {
+    SecretString::from(hex::encode(rng.gen::<[u8; 32]>()))
+}
+
+/// The auth secret of a relay started with `--rng-seed <seed>`.
+///
+/// The secret is the first value a relay draws from its RNG.
+pub fn secret_from_seed(seed: u64) -> SecretString {
+    generate_secret(&mut StdRng::seed_from_u64(seed))
+}
+
 pub fn generate_password(
     relay_secret: &SecretString,
     expiry: SystemTime,
@@ -144,6 +322,7 @@ pub(crate) fn systemtime_from_unix(seconds: u64) -> SystemTime {
 mod tests {
     use super::*;
     use crate::Attribute;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
     use stun_codec::rfc5389::methods::BINDING;
     use stun_codec::{Message, MessageClass, TransactionId};
 
@@ -151,6 +330,21 @@ mod tests {
     const RELAY_SECRET_2: &str = "7e35e34801e766a6a29ecb9e22810ea4e3476c2b37bf75882edf94a68b1d9607";
     const SAMPLE_USERNAME: &str = "n23JJ2wKKtt30oXi";
 
+    #[test]
+    fn secret_from_seed_matches_seeded_relay() {
+        let server =
+            crate::Server::new(Ipv4Addr::LOCALHOST, StdRng::seed_from_u64(42), 49152, 65535);
+
+        assert_eq!(
+            secret_from_seed(42).expose_secret(),
+            server.auth_secret().expose_secret()
+        );
+        assert_ne!(
+            secret_from_seed(43).expose_secret(),
+            server.auth_secret().expose_secret()
+        );
+    }
+
     #[test]
     fn generate_password_test_vector() {
         let expiry = systemtime_from_unix(60 * 60 * 24 * 365 * 60);
@@ -180,7 +374,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            [&RELAY_SECRET_1.parse().unwrap()],
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000 - 1000),
         );
@@ -197,7 +391,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            [&RELAY_SECRET_1.parse().unwrap()],
             "1685199000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000),
         );
@@ -214,7 +408,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            [&RELAY_SECRET_1.parse().unwrap()],
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -231,7 +425,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            [&RELAY_SECRET_1.parse().unwrap()],
             "foobar",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -239,19 +433,72 @@ mod tests {
         assert_eq!(result.unwrap_err(), Error::InvalidUsername)
     }
 
+    #[test]
+    fn any_of_multiple_relay_secrets_makes_password_valid() {
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_2.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        let result = message_integrity.verify(
+            [
+                &RELAY_SECRET_1.parse().unwrap(),
+                &RELAY_SECRET_2.parse().unwrap(),
+            ],
+            "1685200000:n23JJ2wKKtt30oXi",
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        result.expect("credentials to be valid");
+    }
+
+    #[test]
+    fn previous_secret_is_valid_during_grace_period() {
+        let now = Instant::now();
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+
+        secrets.rotate(RELAY_SECRET_2.parse().unwrap(), now);
+
+        let valid = secrets
+            .valid(now + Duration::from_secs(60))
+            .map(|s| s.expose_secret().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(valid, vec![RELAY_SECRET_2, RELAY_SECRET_1]);
+    }
+
+    #[test]
+    fn previous_secret_is_invalid_after_grace_period() {
+        let now = Instant::now();
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+
+        secrets.rotate(RELAY_SECRET_2.parse().unwrap(), now);
+
+        let later = now + AuthSecrets::DEFAULT_GRACE_PERIOD;
+        let valid = secrets
+            .valid(later)
+            .map(|s| s.expose_secret().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(valid, vec![RELAY_SECRET_2]);
+
+        secrets.handle_timeout(later);
+        assert!(secrets.previous.is_empty());
+    }
+
     #[test]
     fn nonces_are_valid_for_100_requests() {
         let mut nonces = Nonces::default();
         let nonce = Uuid::new_v4();
+        let now = Instant::now();
 
-        nonces.add_new(nonce);
+        nonces.add_new(nonce, client(1), now);
 
         for _ in 0..100 {
-            nonces.handle_nonce_used(nonce).unwrap();
+            nonces.handle_nonce_used(nonce, client(1), now).unwrap();
         }
 
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            nonces.handle_nonce_used(nonce, client(1), now).unwrap_err(),
             Error::InvalidNonce
         );
     }
@@ -262,11 +509,61 @@ mod tests {
         let nonce = Uuid::new_v4();
 
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            nonces
+                .handle_nonce_used(nonce, client(1), Instant::now())
+                .unwrap_err(),
+            Error::InvalidNonce
+        );
+    }
+
+    #[test]
+    fn nonces_expire_after_lifetime() {
+        let mut nonces = Nonces::default();
+        let nonce = Uuid::new_v4();
+        let now = Instant::now();
+
+        nonces.add_new(nonce, client(1), now);
+        nonces
+            .handle_nonce_used(nonce, client(1), now + Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(
+            nonces
+                .handle_nonce_used(nonce, client(1), now + Nonces::DEFAULT_LIFETIME)
+                .unwrap_err(),
             Error::InvalidNonce
         );
     }
 
+    #[test]
+    fn nonces_are_only_valid_for_the_client_they_were_issued_to() {
+        let mut nonces = Nonces::default();
+        let nonce = Uuid::new_v4();
+        let now = Instant::now();
+
+        nonces.add_new(nonce, client(1), now);
+
+        assert_eq!(
+            nonces.handle_nonce_used(nonce, client(2), now).unwrap_err(),
+            Error::InvalidNonce
+        );
+        nonces
+            .handle_nonce_used(nonce, client(1), now)
+            .expect("nonce to still be valid for the original client");
+    }
+
+    #[test]
+    fn expired_nonces_are_pruned_on_timeout() {
+        let mut nonces = Nonces::default();
+        let nonce = Uuid::new_v4();
+        let now = Instant::now();
+
+        nonces.add_new(nonce, client(1), now);
+        nonces.handle_timeout(now + Nonces::DEFAULT_LIFETIME);
+
+        assert!(nonces.inner.is_empty());
+    }
+
     fn message_integrity(
         relay_secret: &SecretString,
         username_expiry: u64,
@@ -288,6 +585,13 @@ mod tests {
         .unwrap()
     }
 
+    fn client(last_octet: u8) -> ClientSocket {
+        ClientSocket::new(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(10, 0, 0, last_octet),
+            10000,
+        )))
+    }
+
     fn sample_message() -> Message<Attribute> {
         Message::new(
             MessageClass::Request,
@@ -0,0 +1,674 @@
+//! Packet captures of the traffic relayed for individual allocations or clients.
+//!
+//! A capture is started via the admin API and writes all [`ChannelData`](crate::ChannelData) messages, SEND and DATA indications and peer datagrams relayed for a single allocation port or client socket to a pcapng file.
+//! We only see the UDP payloads, thus we synthesize IP and UDP headers from the addresses involved.
+//! Each capture ends once it exceeds its size or duration limit.
+//!
+//! Recording only queues the packets, a dedicated thread per capture writes them to the file.
+//! Thus, the data plane never waits for the disk, even whilst it holds the lock of the [`Server`](crate::Server).
+
+use crate::{AllocationPort, ClientSocket, IpStack, PeerSocket};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write as _};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// See <https://www.tcpdump.org/linktypes.html>; packets start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u16 = 101;
+
+const IP4_HEADER_LEN: usize = 20;
+const IP6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+/// How many pcapng blocks we queue for the writer thread before we start dropping packets.
+const WRITE_QUEUE_CAPACITY: usize = 4096;
+
+/// Which traffic a capture records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFilter {
+    Port(AllocationPort),
+    Client(ClientSocket),
+}
+
+impl CaptureFilter {
+    fn matches(&self, client: ClientSocket, port: AllocationPort) -> bool {
+        match self {
+            CaptureFilter::Port(p) => *p == port,
+            CaptureFilter::Client(c) => *c == client,
+        }
+    }
+}
+
+impl fmt::Display for CaptureFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureFilter::Port(port) => write!(f, "port-{port}"),
+            CaptureFilter::Client(client) => {
+                let client = client.into_socket();
+
+                write!(f, "client-{}-{}", client.ip(), client.port())
+            }
+        }
+    }
+}
+
+/// A snapshot of the running capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureInfo {
+    pub filter: CaptureFilter,
+    pub path: PathBuf,
+    pub bytes_written: u64,
+    pub max_bytes: u64,
+    pub expires_in: Duration,
+    /// Packets that are missing from the file because the disk couldn't keep up.
+    pub packets_dropped: u64,
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    /// We weren't given a directory to write captures to.
+    Disabled,
+    /// Only one capture can run at a time.
+    AlreadyRunning,
+    Io(io::Error),
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Disabled => write!(f, "Packet captures are disabled"),
+            StartError::AlreadyRunning => write!(f, "A capture is already running"),
+            StartError::Io(e) => write!(f, "Failed to create capture file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StartError {}
+
+/// The, at most one, running capture of the relay.
+///
+/// Cheap to clone and shared between all data-plane threads and the admin API.
+/// Recording is a single atomic load whilst no capture is running.
+#[derive(Clone)]
+pub struct Captures {
+    current: Arc<Mutex<Option<Capture>>>,
+    active: Arc<AtomicBool>,
+    /// The writer threads of stopped captures that may still be writing their files.
+    writers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+
+    public_address: IpStack,
+    turn_port: u16,
+
+    /// Captures are disabled without a directory.
+    dir: Option<PathBuf>,
+    max_bytes: u64,
+    max_duration: Duration,
+}
+
+impl Captures {
+    /// Creates a new [`Captures`] that writes its files to `dir`, captures are disabled without one.
+    ///
+    /// Each capture is limited to `max_bytes` and `max_duration`, regardless of what is requested.
+    pub fn new(
+        public_address: IpStack,
+        turn_port: u16,
+        dir: Option<PathBuf>,
+        max_bytes: u64,
+        max_duration: Duration,
+    ) -> Self {
+        Self {
+            current: Default::default(),
+            active: Default::default(),
+            writers: Default::default(),
+            public_address,
+            turn_port,
+            dir,
+            max_bytes,
+            max_duration,
+        }
+    }
+
+    /// Starts a new capture for the traffic matching `filter`.
+    ///
+    /// The limits are capped to the ones this [`Captures`] was configured with.
+    pub fn start(
+        &self,
+        filter: CaptureFilter,
+        max_bytes: Option<u64>,
+        duration: Option<Duration>,
+        now: Instant,
+    ) -> Result<CaptureInfo, StartError> {
+        let Some(dir) = self.dir.as_ref() else {
+            return Err(StartError::Disabled);
+        };
+
+        let mut current = self.current.lock().unwrap();
+
+        if current.is_some() {
+            return Err(StartError::AlreadyRunning);
+        }
+
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = dir.join(format!("relay-{filter}-{started_at}.pcapng"));
+
+        let capture = Capture::create(
+            &path,
+            filter,
+            max_bytes.unwrap_or(self.max_bytes).min(self.max_bytes),
+            now + duration.unwrap_or(self.max_duration).min(self.max_duration),
+        )
+        .map_err(StartError::Io)?;
+        let info = capture.info(now);
+
+        tracing::info!(target: "relay", %filter, path = %path.display(), "Started packet capture");
+
+        *current = Some(capture);
+        self.active.store(true, Ordering::Relaxed);
+
+        Ok(info)
+    }
+
+    /// Stops the running capture, if any.
+    pub fn stop(&self, now: Instant) -> Option<CaptureInfo> {
+        let mut current = self.current.lock().unwrap();
+        let capture = current.take()?;
+        self.active.store(false, Ordering::Relaxed);
+
+        Some(self.finish(capture, now))
+    }
+
+    /// Whether a capture is running.
+    ///
+    /// Allows callers to skip preparing data for recording whilst no capture is running.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn info(&self, now: Instant) -> Option<CaptureInfo> {
+        self.current.lock().unwrap().as_ref().map(|c| c.info(now))
+    }
+
+    /// Stops the running capture if it exceeded its duration.
+    pub fn handle_timeout(&self, now: Instant) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut current = self.current.lock().unwrap();
+        self.stop_if_done(&mut current, now);
+    }
+
+    /// Records a message from `client` and the datagram we relayed to `peer` because of it.
+    ///
+    /// The message is either a [`ChannelData`](crate::ChannelData) message or a SEND indication.
+    pub fn record_client_to_peer(
+        &self,
+        client: ClientSocket,
+        port: AllocationPort,
+        peer: PeerSocket,
+        message: &[u8],
+        payload: &[u8],
+    ) {
+        let client = client.into_socket();
+        let peer = peer.into_socket();
+
+        self.record(ClientSocket::new(client), port, |capture, now| {
+            capture.write(
+                client,
+                self.relay_socket(client, self.turn_port),
+                &[message],
+                now,
+            );
+            capture.write(self.relay_socket(peer, port.value()), peer, &[payload], now);
+        });
+    }
+
+    /// Records a datagram from `peer` and the message we relayed to `client` because of it.
+    ///
+    /// The message is either a [`ChannelData`](crate::ChannelData) message or a DATA indication, consisting of the concatenation of `message`.
+    pub fn record_peer_to_client(
+        &self,
+        peer: PeerSocket,
+        port: AllocationPort,
+        client: ClientSocket,
+        packet: &[u8],
+        message: &[&[u8]],
+    ) {
+        let client = client.into_socket();
+        let peer = peer.into_socket();
+
+        self.record(ClientSocket::new(client), port, |capture, now| {
+            capture.write(peer, self.relay_socket(peer, port.value()), &[packet], now);
+            capture.write(
+                self.relay_socket(client, self.turn_port),
+                client,
+                message,
+                now,
+            );
+        });
+    }
+
+    fn record(
+        &self,
+        client: ClientSocket,
+        port: AllocationPort,
+        write: impl FnOnce(&mut Capture, SystemTime),
+    ) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut current = self.current.lock().unwrap();
+        let Some(capture) = current.as_mut() else {
+            return;
+        };
+
+        if !capture.filter.matches(client, port) {
+            return;
+        }
+
+        write(capture, SystemTime::now());
+
+        self.stop_if_done(&mut current, Instant::now());
+    }
+
+    fn stop_if_done(&self, current: &mut Option<Capture>, now: Instant) {
+        if !current.as_ref().is_some_and(|c| c.is_done(now)) {
+            return;
+        }
+
+        if let Some(capture) = current.take() {
+            self.finish(capture, now);
+        }
+        self.active.store(false, Ordering::Relaxed);
+    }
+
+    /// Finishes `capture` without waiting for its writer thread to write all queued packets.
+    fn finish(&self, capture: Capture, now: Instant) -> CaptureInfo {
+        let (info, writer) = capture.finish(now);
+
+        let mut writers = self.writers.lock().unwrap();
+        writers.retain(|w| !w.is_finished());
+        writers.push(writer);
+
+        info
+    }
+
+    /// Our socket for talking to `remote`, assuming we use the same address family.
+    fn relay_socket(&self, remote: SocketAddr, port: u16) -> SocketAddr {
+        let ip = match remote {
+            SocketAddr::V4(_) => IpAddr::V4(
+                self.public_address
+                    .as_v4()
+                    .copied()
+                    .unwrap_or(Ipv4Addr::UNSPECIFIED),
+            ),
+            SocketAddr::V6(_) => IpAddr::V6(
+                self.public_address
+                    .as_v6()
+                    .copied()
+                    .unwrap_or(Ipv6Addr::UNSPECIFIED),
+            ),
+        };
+
+        SocketAddr::new(ip, port)
+    }
+}
+
+/// A single capture, writing to a pcapng file.
+struct Capture {
+    /// The pcapng blocks to be written by the writer thread.
+    blocks_tx: SyncSender<Vec<u8>>,
+    writer: JoinHandle<()>,
+    path: PathBuf,
+    filter: CaptureFilter,
+
+    /// How many bytes we handed to the writer thread.
+    bytes_written: u64,
+    max_bytes: u64,
+    expires_at: Instant,
+    packets_dropped: u64,
+    /// Whether we failed to write to the file, set by the writer thread.
+    failed: Arc<AtomicBool>,
+}
+
+impl Capture {
+    fn create(
+        path: &Path,
+        filter: CaptureFilter,
+        max_bytes: u64,
+        expires_at: Instant,
+    ) -> io::Result<Self> {
+        let file = BufWriter::new(create_file(path)?);
+        let (blocks_tx, blocks_rx) = mpsc::sync_channel(WRITE_QUEUE_CAPACITY);
+        let failed = Arc::new(AtomicBool::new(false));
+
+        let writer = std::thread::Builder::new()
+            .name("relay-capture".to_owned())
+            .spawn({
+                let path = path.to_owned();
+                let failed = failed.clone();
+
+                move || write_blocks(file, blocks_rx, &path, &failed)
+            })?;
+
+        let mut capture = Self {
+            blocks_tx,
+            writer,
+            path: path.to_owned(),
+            filter,
+            bytes_written: 0,
+            max_bytes,
+            expires_at,
+            packets_dropped: 0,
+            failed,
+        };
+
+        capture.write_block(0x0A0D0D0A, |block| {
+            block.extend_from_slice(&0x1A2B3C4D_u32.to_le_bytes()); // Byte-order magic
+            block.extend_from_slice(&1_u16.to_le_bytes()); // Major version
+            block.extend_from_slice(&0_u16.to_le_bytes()); // Minor version
+            block.extend_from_slice(&(-1_i64).to_le_bytes()); // Section length is not specified
+        });
+        capture.write_block(0x00000001, |block| {
+            block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            block.extend_from_slice(&0_u16.to_le_bytes()); // Reserved
+            block.extend_from_slice(&0_u32.to_le_bytes()); // No snapshot length
+        });
+
+        Ok(capture)
+    }
+
+    /// Writes a UDP datagram from `src` to `dst`, consisting of the concatenation of `chunks`, as an enhanced packet block.
+    fn write(&mut self, src: SocketAddr, dst: SocketAddr, chunks: &[&[u8]], timestamp: SystemTime) {
+        let mut packet = Vec::new();
+        write_ip_and_udp_header(&mut packet, src, dst, chunks.iter().map(|c| c.len()).sum());
+        for chunk in chunks {
+            packet.extend_from_slice(chunk);
+        }
+
+        let timestamp_us = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        self.write_block(0x00000006, |block| {
+            block.extend_from_slice(&0_u32.to_le_bytes()); // Interface ID
+            block.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+            block.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+            block.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // Captured length
+            block.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // Original length
+            block.extend_from_slice(&packet);
+            block.resize(block.len().next_multiple_of(4), 0);
+        })
+    }
+
+    /// Queues a pcapng block of the given type, with the body written by `body`.
+    ///
+    /// If the writer thread cannot keep up, we drop the block.
+    fn write_block(&mut self, block_type: u32, body: impl FnOnce(&mut Vec<u8>)) {
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&0_u32.to_le_bytes()); // Placeholder for the total length
+        body(&mut block);
+
+        let total_length = (block.len() + 4) as u32;
+        block[4..8].copy_from_slice(&total_length.to_le_bytes());
+        block.extend_from_slice(&total_length.to_le_bytes());
+
+        let len = block.len() as u64;
+
+        match self.blocks_tx.try_send(block) {
+            Ok(()) => self.bytes_written += len,
+            Err(TrySendError::Full(_)) => self.packets_dropped += 1,
+            // The writer thread only exits early if it failed to write to the file.
+            Err(TrySendError::Disconnected(_)) => self.failed.store(true, Ordering::Relaxed),
+        }
+    }
+
+    fn is_done(&self, now: Instant) -> bool {
+        self.failed.load(Ordering::Relaxed)
+            || self.bytes_written >= self.max_bytes
+            || now >= self.expires_at
+    }
+
+    fn info(&self, now: Instant) -> CaptureInfo {
+        CaptureInfo {
+            filter: self.filter,
+            path: self.path.clone(),
+            bytes_written: self.bytes_written,
+            max_bytes: self.max_bytes,
+            expires_in: self.expires_at.saturating_duration_since(now),
+            packets_dropped: self.packets_dropped,
+        }
+    }
+
+    /// Stops queuing packets, the writer thread exits once it wrote all queued ones.
+    fn finish(self, now: Instant) -> (CaptureInfo, JoinHandle<()>) {
+        let info = self.info(now);
+
+        tracing::info!(target: "relay", filter = %info.filter, path = %info.path.display(), bytes_written = %info.bytes_written, packets_dropped = %info.packets_dropped, "Finished packet capture");
+
+        (info, self.writer)
+    }
+}
+
+/// Writes the blocks of a capture to its file until the [`Capture`] is finished.
+fn write_blocks(
+    mut file: BufWriter<File>,
+    blocks_rx: Receiver<Vec<u8>>,
+    path: &Path,
+    failed: &AtomicBool,
+) {
+    for block in blocks_rx {
+        if let Err(e) = file.write_all(&block) {
+            tracing::warn!(target: "relay", path = %path.display(), "Failed to write to packet capture: {e}");
+
+            failed.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    if let Err(e) = file.flush() {
+        tracing::warn!(target: "relay", path = %path.display(), "Failed to flush packet capture: {e}");
+    }
+}
+
+/// Synthesizes the IP and UDP header of a datagram from `src` to `dst` with a payload of `payload_len` bytes.
+///
+/// The UDP checksum is left empty.
+fn write_ip_and_udp_header(
+    buffer: &mut Vec<u8>,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload_len: usize,
+) {
+    let udp_len = (UDP_HEADER_LEN + payload_len).min(u16::MAX as usize) as u16;
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = (IP4_HEADER_LEN + udp_len as usize).min(u16::MAX as usize) as u16;
+
+            let mut header = [0u8; IP4_HEADER_LEN];
+            header[0] = 0x45; // Version 4, 5 words
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            header[8] = 64; // TTL
+            header[9] = 17; // UDP
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = ip4_header_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            buffer.extend_from_slice(&header);
+        }
+        (src_ip, dst_ip) => {
+            let mut header = [0u8; IP6_HEADER_LEN];
+            header[0] = 0x60; // Version 6
+            header[4..6].copy_from_slice(&udp_len.to_be_bytes());
+            header[6] = 17; // UDP
+            header[7] = 64; // Hop limit
+            header[8..24].copy_from_slice(&to_ip6(src_ip).octets());
+            header[24..40].copy_from_slice(&to_ip6(dst_ip).octets());
+
+            buffer.extend_from_slice(&header);
+        }
+    }
+
+    buffer.extend_from_slice(&src.port().to_be_bytes());
+    buffer.extend_from_slice(&dst.port().to_be_bytes());
+    buffer.extend_from_slice(&udp_len.to_be_bytes());
+    buffer.extend_from_slice(&0_u16.to_be_bytes()); // Checksum
+}
+
+fn ip4_header_checksum(header: &[u8; IP4_HEADER_LEN]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+
+    !(sum as u16)
+}
+
+/// Our relay may be dual-stack, thus the two ends of a datagram may be of different families.
+fn to_ip6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip4) => ip4.to_ipv6_mapped(),
+        IpAddr::V6(ip6) => ip6,
+    }
+}
+
+/// Creates a new file that only we can read.
+///
+/// Captures contain user traffic and the capture directory may be shared, thus we never follow or overwrite an existing file or symlink.
+fn create_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_ip4_header_checksum() {
+        // Example from https://en.wikipedia.org/wiki/Internet_checksum
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+
+        assert_eq!(ip4_header_checksum(&header), 0xb861);
+    }
+
+    #[test]
+    fn stops_capture_once_size_limit_is_exceeded() {
+        let dir = std::env::temp_dir().join(format!("relay-capture-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let client = ClientSocket::new("10.0.0.1:5000".parse().unwrap());
+        let peer = PeerSocket::new("10.0.0.2:6000".parse().unwrap());
+        let port = AllocationPort::new(49152);
+        let now = Instant::now();
+
+        let captures = Captures::new(
+            IpStack::Ip4(Ipv4Addr::new(192, 0, 2, 1)),
+            3478,
+            Some(dir.clone()),
+            1024,
+            Duration::from_secs(60),
+        );
+        let info = captures
+            .start(CaptureFilter::Port(port), None, None, now)
+            .unwrap();
+
+        assert!(matches!(
+            captures.start(CaptureFilter::Client(client), None, None, now),
+            Err(StartError::AlreadyRunning)
+        ));
+
+        captures.record_client_to_peer(client, port, peer, &[0; 104], &[0; 100]);
+        assert!(captures.info(now).is_some());
+
+        captures.record_client_to_peer(client, port, peer, &[0; 1004], &[0; 1000]);
+        assert!(captures.info(now).is_none());
+
+        for writer in captures.writers.lock().unwrap().drain(..) {
+            writer.join().unwrap();
+        }
+
+        let file = std::fs::read(&info.path).unwrap();
+        assert_eq!(&file[0..4], &0x0A0D0D0A_u32.to_le_bytes());
+        assert!(file.len() > 1024);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_start_without_directory() {
+        let captures = Captures::new(
+            IpStack::Ip4(Ipv4Addr::new(192, 0, 2, 1)),
+            3478,
+            None,
+            1024,
+            Duration::from_secs(60),
+        );
+
+        assert!(matches!(
+            captures.start(
+                CaptureFilter::Port(AllocationPort::new(49152)),
+                None,
+                None,
+                Instant::now()
+            ),
+            Err(StartError::Disabled)
+        ));
+    }
+
+    #[test]
+    fn does_not_follow_existing_files_or_symlinks() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir =
+            std::env::temp_dir().join(format!("relay-capture-file-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let target = dir.join("target");
+        std::fs::write(&target, b"important").unwrap();
+        let link = dir.join("link.pcapng");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert_eq!(
+            create_file(&link).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            create_file(&target).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), b"important");
+
+        let new = dir.join("new.pcapng");
+        create_file(&new).unwrap();
+        assert_eq!(
+            std::fs::metadata(&new).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
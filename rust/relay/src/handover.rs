@@ -0,0 +1,353 @@
+//! Handing over a running relay to a newly started process.
+//!
+//! The [`Server`](crate::Server) exports its state (allocations, channel bindings, nonces and auth secrets) as a [`ServerState`].
+//! We send it as JSON over a unix socket, followed by the file descriptors of all our UDP sockets via `SCM_RIGHTS`.
+//! The new process imports the state and continues reading from the very same sockets, thus clients don't notice the restart.
+//!
+//! Durations are relative to the time of the export because [`Instant`](std::time::Instant)s are meaningless across processes.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read as _, Write as _};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsFd as _, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use stun_codec::rfc8656::attributes::AddressFamily;
+use uuid::Uuid;
+
+/// How many file descriptors we send in a single message.
+///
+/// The kernel refuses messages with more than 253 (`SCM_MAX_FD`).
+const MAX_FDS_PER_MESSAGE: usize = 128;
+
+/// The state of a [`Server`](crate::Server), as exported via [`Server::export_state`](crate::Server::export_state).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerState {
+    pub(crate) auth_secret: String,
+    pub(crate) previous_auth_secrets: Vec<PreviousAuthSecret>,
+    pub(crate) allocations: Vec<AllocationState>,
+    pub(crate) channels: Vec<ChannelState>,
+    pub(crate) permissions: Vec<PermissionState>,
+    pub(crate) nonces: Vec<NonceState>,
+}
+
+impl ServerState {
+    /// The ports of all exported allocations.
+    pub fn allocation_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.allocations.iter().map(|a| a.port)
+    }
+}
+
+impl fmt::Debug for ServerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerState")
+            .field("allocations", &self.allocations.len())
+            .field("channels", &self.channels.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PreviousAuthSecret {
+    pub(crate) secret: String,
+    pub(crate) valid_for: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AllocationState {
+    pub(crate) client: SocketAddr,
+    pub(crate) port: u16,
+    pub(crate) expires_in: Duration,
+    pub(crate) first_relay_addr: IpAddr,
+    pub(crate) second_relay_addr: Option<IpAddr>,
+    pub(crate) bytes_relayed: u64,
+    pub(crate) username: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChannelState {
+    pub(crate) client: SocketAddr,
+    pub(crate) number: u16,
+    pub(crate) peer: SocketAddr,
+    pub(crate) port: u16,
+    pub(crate) bound: bool,
+    /// Zero for channels that are already expired.
+    ///
+    /// This extends how long the number of an unbound channel is blocked from being re-used, which is safe.
+    pub(crate) expires_in: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PermissionState {
+    pub(crate) port: u16,
+    pub(crate) peer: IpAddr,
+    pub(crate) expires_in: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NonceState {
+    pub(crate) nonce: Uuid,
+    pub(crate) client: SocketAddr,
+    pub(crate) expires_in: Duration,
+    pub(crate) remaining_requests: u64,
+}
+
+/// A UDP socket of the relay, handed over from one process to another.
+#[derive(Debug)]
+pub struct HandedOverSocket {
+    pub port: u16,
+    pub family: AddressFamily,
+    /// Whether packets sent from this socket must have the DF bit set.
+    pub dont_fragment: bool,
+    pub fd: OwnedFd,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    state: ServerState,
+    sockets: Vec<SocketHeader>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SocketHeader {
+    port: u16,
+    ip6: bool,
+    dont_fragment: bool,
+}
+
+/// Sends our state and sockets to the process on the other end of `stream`.
+///
+/// `stream` must be in blocking mode.
+pub fn send(
+    stream: &UnixStream,
+    state: ServerState,
+    sockets: &[HandedOverSocket],
+) -> io::Result<()> {
+    let header = serde_json::to_vec(&Header {
+        state,
+        sockets: sockets
+            .iter()
+            .map(|s| SocketHeader {
+                port: s.port,
+                ip6: s.family == AddressFamily::V6,
+                dont_fragment: s.dont_fragment,
+            })
+            .collect(),
+    })?;
+    let header_len = u32::try_from(header.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "State is too large"))?;
+
+    let mut writer = stream;
+    writer.write_all(&header_len.to_be_bytes())?;
+    writer.write_all(&header)?;
+
+    for chunk in sockets.chunks(MAX_FDS_PER_MESSAGE) {
+        let fds = chunk.iter().map(|s| s.fd.as_fd()).collect::<Vec<_>>();
+
+        send_fds(stream, &fds)?;
+    }
+
+    Ok(())
+}
+
+/// Receives the state and sockets of the process on the other end of `stream`.
+///
+/// `stream` must be in blocking mode.
+pub fn receive(stream: &UnixStream) -> io::Result<(ServerState, Vec<HandedOverSocket>)> {
+    let mut reader = stream;
+
+    let mut header_len = [0u8; 4];
+    reader.read_exact(&mut header_len)?;
+
+    let mut header = vec![0u8; u32::from_be_bytes(header_len) as usize];
+    reader.read_exact(&mut header)?;
+
+    let Header { state, sockets } = serde_json::from_slice(&header)?;
+
+    let mut fds = Vec::with_capacity(sockets.len());
+    while fds.len() < sockets.len() {
+        fds.extend(recv_fds(stream)?);
+    }
+
+    if fds.len() != sockets.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Expected {} file descriptors but got {}",
+                sockets.len(),
+                fds.len()
+            ),
+        ));
+    }
+
+    let sockets = sockets
+        .into_iter()
+        .zip(fds)
+        .map(|(header, fd)| HandedOverSocket {
+            port: header.port,
+            family: if header.ip6 {
+                AddressFamily::V6
+            } else {
+                AddressFamily::V4
+            },
+            dont_fragment: header.dont_fragment,
+            fd,
+        })
+        .collect();
+
+    Ok((state, sockets))
+}
+
+/// Sends the given file descriptors as `SCM_RIGHTS`, together with a single dummy byte.
+fn send_fds(stream: &UnixStream, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+    let raw_fds = fds.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<RawFd>>();
+    let fds_len = std::mem::size_of_val(raw_fds.as_slice()) as u32;
+
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+
+    // SAFETY: `CMSG_SPACE` is a pure computation.
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)]; // `u64` to satisfy the alignment of `cmsghdr`.
+
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+
+    // SAFETY: `control` has space for exactly one header with `fds_len` bytes of data.
+    let result = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(
+            raw_fds.as_ptr(),
+            libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+            raw_fds.len(),
+        );
+
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Receives a single message sent via [`send_fds`].
+fn recv_fds(stream: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+    let fds_len = (MAX_FDS_PER_MESSAGE * std::mem::size_of::<RawFd>()) as u32;
+
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+
+    // SAFETY: `CMSG_SPACE` is a pure computation.
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)]; // `u64` to satisfy the alignment of `cmsghdr`.
+
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+
+    // SAFETY: `msg` points to valid buffers for the payload and the control messages.
+    let result = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if result == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fds = Vec::new();
+
+    // SAFETY: The kernel filled `control` with valid control messages of the given total length.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let num_fds = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+
+                for i in 0..num_fds {
+                    // Take ownership first, so the descriptors are closed if we bail below.
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Control message was truncated",
+        ));
+    }
+
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    #[test]
+    fn hands_over_state_and_sockets() {
+        let (predecessor, successor) = UnixStream::pair().unwrap();
+
+        let sockets = (0..(MAX_FDS_PER_MESSAGE + 2))
+            .map(|_| {
+                let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+                HandedOverSocket {
+                    port: socket.local_addr().unwrap().port(),
+                    family: AddressFamily::V4,
+                    dont_fragment: true,
+                    fd: OwnedFd::from(socket),
+                }
+            })
+            .collect::<Vec<_>>();
+        let state = ServerState {
+            auth_secret: "secret".to_owned(),
+            previous_auth_secrets: vec![],
+            allocations: vec![],
+            channels: vec![],
+            permissions: vec![],
+            nonces: vec![],
+        };
+
+        let sender = std::thread::spawn(move || send(&predecessor, state, &sockets));
+        let (state, received) = receive(&successor).unwrap();
+        sender.join().unwrap().unwrap();
+
+        assert_eq!(state.auth_secret, "secret");
+        assert_eq!(received.len(), MAX_FDS_PER_MESSAGE + 2);
+
+        for socket in received {
+            let port = socket.port;
+            let socket = UdpSocket::from(socket.fd);
+
+            assert_eq!(socket.local_addr().unwrap().port(), port);
+        }
+    }
+}
@@ -2,15 +2,21 @@ mod net_ext;
 mod server;
 mod sleep;
 
+pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod capture;
+pub mod handover;
 #[cfg(feature = "proptest")]
 pub mod proptest;
 pub mod sockets;
+pub mod streams;
 
 pub use net_ext::IpAddrExt;
 pub use server::{
-    Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Allocate, AllocationInfo, AllocationPort, Attribute, BandwidthLimit, Binding, ChannelBind,
+    ChannelData, ChannelInfo, ClientMessage, Command, CreatePermission, Refresh, RequestRateLimit,
+    Route, Routes, SendIndication, Server,
 };
 pub use sleep::Sleep;
 pub use stun_codec::rfc8656::attributes::AddressFamily;
@@ -1,24 +1,40 @@
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use firezone_relay::sockets::Sockets;
+use firezone_relay::audit::{AuditSink, AuditWriter};
+use firezone_relay::capture::Captures;
+use firezone_relay::handover::{self, HandedOverSocket};
+use firezone_relay::sockets::{RecvBuffers, Sockets};
+use firezone_relay::streams::{StreamEvent, Streams};
 use firezone_relay::{
-    sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack,
-    PeerSocket, Server, Sleep,
+    sockets, streams, AddressFamily, AllocationPort, BandwidthLimit, ChannelData, ClientSocket,
+    Command, IpStack, PeerSocket, RequestRateLimit, Route, Routes, Server, Sleep,
 };
 use futures::{future, FutureExt};
+use ip_network::IpNetwork;
 use opentelemetry::KeyValue;
 use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::reader::{DefaultAggregationSelector, DefaultTemporalitySelector};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
 use phoenix_channel::{Event, LoginUrl, PhoenixChannel};
+use prometheus::Encoder as _;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{Secret, SecretString};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::iter;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use std::task::Poll;
-use std::time::{Duration, Instant};
+use std::task::{ready, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UnixListener;
 use tokio::signal::unix;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
 use tracing::{level_filters::LevelFilter, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
@@ -31,6 +47,9 @@ const TURN_PORT: u16 = 3478;
 
 const MAX_PARTITION_TIME: Duration = Duration::from_secs(60 * 15);
 
+/// How many audit records we buffer before we start dropping them.
+const AUDIT_LOG_CAPACITY: usize = 10_000;
+
 #[derive(Parser, Debug)]
 struct Args {
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
@@ -46,6 +65,60 @@ struct Args {
     /// The highest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "65535")]
     highest_port: u16,
+    /// The port on which we accept TURN connections via TLS.
+    #[arg(long, env, default_value = "443")]
+    tls_port: u16,
+    /// Path to a PEM-encoded certificate chain to use for TURN connections via TLS.
+    ///
+    /// TLS is only enabled if both, a certificate and a private key are given.
+    #[arg(long, env, requires = "tls_private_key_path")]
+    tls_certificate_path: Option<PathBuf>,
+    /// Path to the PEM-encoded private key of the TLS certificate.
+    #[arg(long, env, requires = "tls_certificate_path")]
+    tls_private_key_path: Option<PathBuf>,
+    /// The maximum bandwidth a single allocation may use, in bytes per second.
+    ///
+    /// Applies to the sum of both directions. Packets exceeding the limit are dropped.
+    #[arg(long, env)]
+    allocation_bandwidth_limit: Option<u64>,
+    /// The maximum bandwidth all allocations of a single client IP may use, in bytes per second.
+    ///
+    /// Applies to the sum of both directions. Packets exceeding the limit are dropped.
+    #[arg(long, env)]
+    client_ip_bandwidth_limit: Option<u64>,
+    /// The maximum number of unauthenticated requests per second we answer for a single client IP.
+    ///
+    /// Applies to STUN binding requests, requests that fail authentication and failing requests of clients without an allocation. Requests exceeding the limit are dropped.
+    #[arg(long, env)]
+    unauthenticated_request_rate_limit: Option<NonZeroU64>,
+    /// A comma-separated list of networks that clients must be in to use the relay.
+    ///
+    /// If empty, all clients not on the deny list may use the relay.
+    #[arg(long, env, value_delimiter = ',')]
+    client_allow_list: Vec<IpNetwork>,
+    /// A comma-separated list of networks whose clients may not use the relay.
+    #[arg(long, env, value_delimiter = ',')]
+    client_deny_list: Vec<IpNetwork>,
+    /// A comma-separated list of sibling relays (`IP:PORT`) we redirect clients to when we are at capacity or shutting down.
+    ///
+    /// Can be replaced at runtime by the portal.
+    #[arg(long, env, value_delimiter = ',')]
+    alternate_servers: Vec<SocketAddr>,
+    /// How long a nonce issued to a client remains valid, in seconds.
+    ///
+    /// Clients using an expired nonce receive a 438 (Stale Nonce) and have to re-authenticate with a new one.
+    #[arg(long, env, default_value = "3600")]
+    nonce_lifetime: u64,
+    /// How long the previous auth secret remains valid after the portal rotated it, in seconds.
+    ///
+    /// Credentials derived from the previous secret continue to work during this time.
+    #[arg(long, env, default_value = "3600")]
+    auth_secret_grace_period: u64,
+    /// The number of threads relaying UDP traffic.
+    ///
+    /// With more than one thread, each thread binds its own shard of our UDP sockets via `SO_REUSEPORT` and the kernel distributes incoming packets across them.
+    #[arg(long, env, default_value = "1")]
+    data_plane_threads: NonZeroUsize,
     #[arg(
         long,
         env = "FIREZONE_API_URL",
@@ -58,6 +131,11 @@ struct Args {
     /// If omitted, we won't connect to the portal on startup.
     #[arg(env = "FIREZONE_TOKEN")]
     token: Option<SecretString>,
+    /// A file containing the token, e.g. a Docker or Kubernetes secret.
+    ///
+    /// The file must only be accessible by its owner, i.e. have mode 0400 or 0600.
+    #[arg(long, env = "FIREZONE_TOKEN_FILE", conflicts_with = "token")]
+    token_path: Option<PathBuf>,
     /// Used as the human name for this Relay to display in the portal. If not provided,
     /// the system hostname is used by default.
     #[arg(env = "FIREZONE_NAME")]
@@ -85,6 +163,35 @@ struct Args {
 
     #[command(flatten)]
     health_check: http_health_check::HealthCheckArgs,
+    /// The address of the local interface where we should serve our admin API.
+    ///
+    /// The admin API allows inspecting and deleting allocations and must only be reachable locally, thus only loopback addresses are accepted.
+    #[arg(long, env, default_value = "127.0.0.1:8081")]
+    admin_addr: SocketAddr,
+    /// Path to a file where we append an audit record (as JSON) for every allocation and channel binding.
+    #[arg(long, env, conflicts_with = "audit_log_syslog")]
+    audit_log_file: Option<PathBuf>,
+    /// Path to a syslog socket (e.g. `/dev/log`) where we send an audit record (as JSON) for every allocation and channel binding.
+    #[arg(long, env)]
+    audit_log_syslog: Option<PathBuf>,
+    /// Path to a unix socket for handing over our allocations and sockets to a newly started relay.
+    ///
+    /// On startup, we take over from the relay listening on this socket, if there is one.
+    /// Afterwards, we listen on it ourselves so the next relay can take over from us.
+    #[arg(long, env)]
+    handover_socket: Option<PathBuf>,
+    /// The directory where packet captures started via the admin API are written to.
+    ///
+    /// Captures contain user traffic, thus this should be a directory only the relay can access.
+    /// If omitted, packet captures are disabled.
+    #[arg(long, env)]
+    capture_dir: Option<PathBuf>,
+    /// The maximum size of a single packet capture, in bytes.
+    #[arg(long, env, default_value = "104857600")]
+    max_capture_size: u64,
+    /// The maximum duration of a single packet capture, in seconds.
+    #[arg(long, env, default_value = "600")]
+    max_capture_duration: u64,
 }
 
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
@@ -96,10 +203,19 @@ enum LogFormat {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    // Modifying the environment of a running process is unsafe.
+    // Thus, we do this first, before anything else might spawn threads.
+    firezone_cli_utils::remove_token_env_var();
 
     setup_tracing(&args)?;
 
+    if let Some(path) = args.token_path.as_deref() {
+        args.token = Some(firezone_cli_utils::read_token_file(path)?);
+    }
+    let metrics_registry = setup_metrics(&args)?;
+
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
         (Some(ip4), None) => IpStack::Ip4(ip4),
@@ -109,18 +225,74 @@ async fn main() -> Result<()> {
         }
     };
 
-    let server = Server::new(
+    if !args.admin_addr.ip().is_loopback() {
+        bail!("Admin API must only listen on a loopback address")
+    }
+
+    let tls = match (&args.tls_certificate_path, &args.tls_private_key_path) {
+        (Some(certificate), Some(private_key)) => Some((
+            args.tls_port,
+            streams::tls_acceptor(certificate, private_key)?,
+        )),
+        _ => None,
+    };
+
+    let mut server = Server::new(
         public_addr,
         make_rng(args.rng_seed),
         args.lowest_port,
         args.highest_port,
-    );
+    )
+    .with_nonce_lifetime(Duration::from_secs(args.nonce_lifetime))
+    .with_auth_secret_grace_period(Duration::from_secs(args.auth_secret_grace_period))
+    .with_client_allow_list(args.client_allow_list)
+    .with_client_deny_list(args.client_deny_list)
+    .with_alternate_servers(args.alternate_servers);
+    if let Some(limit) = args.allocation_bandwidth_limit {
+        server = server.with_allocation_bandwidth_limit(BandwidthLimit::new(limit));
+    }
+    if let Some(limit) = args.client_ip_bandwidth_limit {
+        server = server.with_client_ip_bandwidth_limit(BandwidthLimit::new(limit));
+    }
+    if let Some(limit) = args.unauthenticated_request_rate_limit {
+        server = server.with_unauthenticated_request_rate_limit(RequestRateLimit::new(limit.get()));
+    }
+
+    let audit_sink = match (&args.audit_log_file, &args.audit_log_syslog) {
+        (Some(path), _) => Some(
+            AuditSink::file(path)
+                .with_context(|| format!("Failed to open audit log file {}", path.display()))?,
+        ),
+        (None, Some(path)) => Some(
+            AuditSink::syslog(path)
+                .with_context(|| format!("Failed to connect to syslog at {}", path.display()))?,
+        ),
+        (None, None) => None,
+    };
+    let audit_writer = audit_sink
+        .map(|sink| AuditWriter::spawn(sink, AUDIT_LOG_CAPACITY))
+        .transpose()
+        .context("Failed to spawn audit log writer")?;
+    if audit_writer.is_some() {
+        server = server.with_audit_log();
+    }
+
+    let handed_over_sockets = match args.handover_socket.as_deref() {
+        Some(path) => take_over(path, &mut server)?,
+        None => Vec::new(),
+    };
+    let handover_listener = args
+        .handover_socket
+        .as_deref()
+        .map(listen_for_successor)
+        .transpose()?;
 
     let last_heartbeat_sent = Arc::new(Mutex::new(Option::<Instant>::None));
 
-    tokio::spawn(http_health_check::serve(
+    tokio::spawn(http_health_check::serve_with_metrics(
         args.health_check.health_check_addr,
         make_is_healthy(last_heartbeat_sent.clone()),
+        move || render_metrics(&metrics_registry),
     ));
 
     let channel = if let Some(token) = args.token.as_ref() {
@@ -151,9 +323,54 @@ async fn main() -> Result<()> {
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr, last_heartbeat_sent)?;
+    let tls_port = tls.as_ref().map(|(port, _)| *port);
+
+    let captures = Captures::new(
+        public_addr,
+        TURN_PORT,
+        args.capture_dir,
+        args.max_capture_size,
+        Duration::from_secs(args.max_capture_duration),
+    );
+    let server = Arc::new(Mutex::new(server.with_captures(captures.clone())));
+    let (admin_tx, admin_rx) = mpsc::channel(1);
+
+    let admin_listener = tokio::net::TcpListener::bind(args.admin_addr)
+        .await
+        .with_context(|| format!("Failed to bind admin API to {}", args.admin_addr))?;
 
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {TURN_PORT}");
+    tokio::spawn({
+        let server = server.clone();
+        let captures = captures.clone();
+
+        async move {
+            if let Err(e) =
+                firezone_relay::admin::serve(admin_listener, server, captures, admin_tx).await
+            {
+                tracing::error!(target: "relay", "Admin API failed: {e}");
+            }
+        }
+    });
+
+    let mut eventloop = Eventloop::new(
+        server,
+        channel,
+        public_addr,
+        tls,
+        args.data_plane_threads,
+        last_heartbeat_sent,
+        admin_rx,
+        audit_writer,
+        handed_over_sockets,
+        handover_listener,
+        captures,
+    )?;
+
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP and TCP port {TURN_PORT}");
+
+    if let Some(tls_port) = tls_port {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TLS port {tls_port}");
+    }
 
     future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -200,18 +417,6 @@ fn setup_tracing(args: &Args) -> Result<()> {
 
             tracing::trace!(target: "relay", "Successfully initialized trace provider on tokio runtime");
 
-            let exporter = opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(grpc_endpoint);
-
-            opentelemetry_otlp::new_pipeline()
-                .metrics(opentelemetry_sdk::runtime::Tokio)
-                .with_exporter(exporter)
-                .build()
-                .context("Failed to create OTLP metrics pipeline")?;
-
-            tracing::trace!(target: "relay", "Successfully initialized metric controller on tokio runtime");
-
             tracing_subscriber::registry()
                 .with(log_layer(args))
                 .with(
@@ -232,6 +437,64 @@ fn setup_tracing(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Sets up our metrics infrastructure.
+///
+/// All metrics are always exported in the Prometheus format via the `/metrics` endpoint of our health-check server.
+/// The returned [`prometheus::Registry`] is what that endpoint renders.
+///
+/// ## Integration with OTLP
+///
+/// If the user has specified `Args.otlp_grpc_endpoint`, we additionally export all metrics to that OTLP collector.
+fn setup_metrics(args: &Args) -> Result<prometheus::Registry> {
+    let registry = prometheus::Registry::new();
+    let resource = opentelemetry_sdk::Resource::new(vec![KeyValue::new("service.name", "relay")]);
+
+    let prometheus_exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .build()
+        .context("Failed to create Prometheus exporter")?;
+
+    let mut provider = SdkMeterProvider::builder()
+        .with_resource(resource)
+        .with_reader(prometheus_exporter);
+
+    if let Some(endpoint) = args.otlp_grpc_endpoint {
+        let grpc_endpoint = format!("http://{endpoint}");
+
+        tracing::trace!(target: "relay", %grpc_endpoint, "Setting up OTLP exporter for metrics");
+
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(grpc_endpoint)
+            .build_metrics_exporter(
+                Box::new(DefaultAggregationSelector::new()),
+                Box::new(DefaultTemporalitySelector::new()),
+            )
+            .context("Failed to create OTLP metrics exporter")?;
+
+        provider = provider.with_reader(
+            PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio).build(),
+        );
+    }
+
+    opentelemetry::global::set_meter_provider(provider.build());
+
+    tracing::trace!(target: "relay", "Successfully initialized metric controller on tokio runtime");
+
+    Ok(registry)
+}
+
+/// Renders all metrics in the given registry in the Prometheus text exposition format.
+fn render_metrics(registry: &prometheus::Registry) -> String {
+    let mut buffer = Vec::new();
+
+    if let Err(e) = prometheus::TextEncoder::new().encode(&registry.gather(), &mut buffer) {
+        tracing::warn!(target: "relay", "Failed to encode metrics: {e}");
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
 /// Constructs the base log layer.
 ///
 /// The user has a choice between:
@@ -269,16 +532,82 @@ fn env_filter() -> EnvFilter {
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 enum IngressMessage {
     Init(Init),
+    RotateStampSecret(RotateStampSecret),
+    AlternateRelays(AlternateRelays),
 }
 
 #[derive(serde::Deserialize, Debug)]
 struct Init {}
 
+/// Sent by the portal to replace our auth secret.
+#[derive(serde::Deserialize)]
+struct RotateStampSecret {
+    stamp_secret: String,
+}
+
+impl std::fmt::Debug for RotateStampSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RotateStampSecret").finish_non_exhaustive()
+    }
+}
+
+/// Sent by the portal to tell us which sibling relays clients should be redirected to.
+#[derive(serde::Deserialize, Debug)]
+struct AlternateRelays {
+    addresses: Vec<SocketAddr>,
+}
+
 #[derive(serde::Serialize, PartialEq, Debug, Clone)]
 struct JoinMessage {
     stamp_secret: String,
 }
 
+/// Takes over the state and sockets of the relay listening on the handover socket at `path`, if there is one.
+///
+/// Blocks until the previous relay has exited, so we can bind the ports it still holds.
+fn take_over<R>(path: &Path, server: &mut Server<R>) -> Result<Vec<HandedOverSocket>>
+where
+    R: Rng,
+{
+    let mut stream = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(Vec::new()); // No relay is running.
+        }
+        Err(e) => return Err(e).context("Failed to connect to handover socket"),
+    };
+
+    tracing::info!(target: "relay", path = %path.display(), "Taking over from running relay");
+
+    let (state, sockets) =
+        handover::receive(&stream).context("Failed to receive state from previous relay")?;
+    server.import_state(state, Instant::now());
+
+    // The previous relay closes the connection once it exits.
+    io::copy(&mut stream, &mut io::sink()).context("Failed to wait for previous relay to exit")?;
+
+    tracing::info!(target: "relay", num_sockets = %sockets.len(), "Took over from previous relay");
+
+    Ok(sockets)
+}
+
+/// Listens on the handover socket at `path` for a newly started relay that wants to take over from us.
+fn listen_for_successor(path: &Path) -> Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("Failed to remove stale handover socket"),
+    }
+
+    UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on handover socket {}", path.display()))
+}
+
 fn make_rng(seed: Option<u64>) -> StdRng {
     let Some(seed) = seed else {
         return StdRng::from_entropy();
@@ -289,51 +618,147 @@ fn make_rng(seed: Option<u64>) -> StdRng {
     StdRng::seed_from_u64(seed)
 }
 
-const MAX_UDP_SIZE: usize = 65536;
-
 struct Eventloop<R> {
-    sockets: Sockets,
+    data_plane: DataPlane<R>,
+    workers: Workers,
+    streams: Streams,
 
-    server: Server<R>,
+    server: Arc<Mutex<Server<R>>>,
     channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
     sleep: Sleep,
 
     sigterm: unix::Signal,
     shutting_down: bool,
 
+    /// Stops the running packet capture, e.g. in case it was started with too generous limits.
+    sigusr1: unix::Signal,
+
+    /// Notifies us when the admin API queued new commands on the [`Server`].
+    admin_rx: mpsc::Receiver<()>,
+
+    /// Writes the audit records of the [`Server`] on a dedicated thread.
+    audit_writer: Option<AuditWriter>,
+
+    /// Where a newly started relay connects to take over from us.
+    handover_listener: Option<UnixListener>,
+
     stats_log_interval: tokio::time::Interval,
     last_num_bytes_relayed: u64,
 
     last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
-
-    buffer: [u8; MAX_UDP_SIZE],
 }
 
 impl<R> Eventloop<R>
 where
-    R: Rng,
+    R: Rng + Send + 'static,
 {
     fn new(
-        server: Server<R>,
+        server: Arc<Mutex<Server<R>>>,
         channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
         public_address: IpStack,
+        tls: Option<(u16, TlsAcceptor)>,
+        data_plane_threads: NonZeroUsize,
         last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
+        admin_rx: mpsc::Receiver<()>,
+        audit_writer: Option<AuditWriter>,
+        handed_over_sockets: Vec<HandedOverSocket>,
+        handover_listener: Option<UnixListener>,
+        captures: Captures,
     ) -> Result<Self> {
-        let mut sockets = Sockets::new();
+        // The eventloop itself is the first data-plane thread.
+        let num_workers = data_plane_threads.get() - 1;
+        let sockets = if num_workers > 0 {
+            Sockets::with_reuse_port()
+        } else {
+            Sockets::new()
+        };
+
+        let mut data_plane = DataPlane::new(sockets, server.clone(), captures.clone());
+        let workers = Workers::spawn(num_workers, &server, &captures)?;
+        let mut streams = Streams::new();
+
+        let families = [
+            public_address.as_v4().map(|_| AddressFamily::V4),
+            public_address.as_v6().map(|_| AddressFamily::V6),
+        ];
+
+        let mut taken_over_turn_families = Vec::new();
+
+        // The previous relay hands over one socket per port for each of its data-plane threads.
+        // Each of our threads adopts one of them, if there aren't enough, the remaining threads bind new sockets.
+        let mut handed_over_by_port = HashMap::<_, Vec<_>>::new();
+        for socket in handed_over_sockets {
+            handed_over_by_port
+                .entry((socket.port, socket.family))
+                .or_default()
+                .push(socket);
+        }
 
-        if public_address.as_v4().is_some() {
-            sockets
-                .bind(TURN_PORT, AddressFamily::V4)
+        for ((port, family), sockets) in handed_over_by_port {
+            let mut sockets = sockets.into_iter();
+            let Some(socket) = sockets.next() else {
+                continue;
+            };
+            let dont_fragment = socket.dont_fragment;
+
+            data_plane
+                .sockets
+                .adopt(socket)
+                .and_then(|()| {
+                    (0..workers.len()).try_for_each(|worker| {
+                        let command = match sockets.next() {
+                            Some(socket) => WorkerCommand::Adopt(socket),
+                            None => WorkerCommand::Bind {
+                                port,
+                                family,
+                                dont_fragment,
+                            },
+                        };
+
+                        workers.send_to(worker, command)
+                    })
+                })
                 .with_context(|| {
-                    format!("Failed to bind to port {TURN_PORT} on IPv4 interfaces")
+                    format!("Failed to take over UDP port {port} on {family} interfaces")
                 })?;
+
+            let num_surplus = sockets.count();
+            if num_surplus > 0 {
+                tracing::info!(target: "relay", %port, %family, %num_surplus, "Previous relay had more data-plane threads, closing its surplus sockets");
+            }
+
+            if port == TURN_PORT {
+                taken_over_turn_families.push(family);
+            }
         }
-        if public_address.as_v6().is_some() {
-            sockets
-                .bind(TURN_PORT, AddressFamily::V6)
-                .with_context(|| {
-                    format!("Failed to bind to port {TURN_PORT} on IPv6 interfaces")
-                })?;
+
+        for family in families.into_iter().flatten() {
+            if !taken_over_turn_families.contains(&family) {
+                data_plane
+                    .sockets
+                    .bind(TURN_PORT, family, false)
+                    .and_then(|()| {
+                        workers.send(|| WorkerCommand::Bind {
+                            port: TURN_PORT,
+                            family,
+                            dont_fragment: false,
+                        })
+                    })
+                    .with_context(|| {
+                        format!("Failed to bind to UDP port {TURN_PORT} on {family} interfaces")
+                    })?;
+            }
+            streams.listen_tcp(TURN_PORT, family).with_context(|| {
+                format!("Failed to listen on TCP port {TURN_PORT} on {family} interfaces")
+            })?;
+
+            if let Some((tls_port, acceptor)) = tls.as_ref() {
+                streams
+                    .listen_tls(*tls_port, family, acceptor.clone())
+                    .with_context(|| {
+                        format!("Failed to listen on TLS port {tls_port} on {family} interfaces")
+                    })?;
+            }
         }
 
         Ok(Self {
@@ -342,147 +767,227 @@ where
             sleep: Sleep::default(),
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
-            sockets,
-            buffer: [0u8; MAX_UDP_SIZE],
+            data_plane,
+            workers,
+            streams,
             last_heartbeat_sent,
             sigterm: unix::signal(unix::SignalKind::terminate())?,
             shutting_down: false,
+            sigusr1: unix::signal(unix::SignalKind::user_defined1())?,
+            admin_rx,
+            audit_writer,
+            handover_listener,
         })
     }
 
     fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
         loop {
-            if self.shutting_down && self.channel.is_none() && self.server.num_allocations() == 0 {
+            if self.shutting_down
+                && self.channel.is_none()
+                && self.server.lock().unwrap().num_allocations() == 0
+            {
                 return Poll::Ready(Ok(()));
             }
 
             // Priority 1: Execute the pending commands of the server.
-            if let Some(next_command) = self.server.next_command() {
-                match next_command {
+            //
+            // Note: The lock must not be held whilst executing the commands, thus we take them all at once, together with the audit records.
+            let (commands, audit_records) = {
+                let mut server = self.server.lock().unwrap();
+
+                (
+                    iter::from_fn(|| server.next_command()).collect::<Vec<_>>(),
+                    iter::from_fn(|| server.next_audit_record()).collect::<Vec<_>>(),
+                )
+            };
+            for command in commands {
+                match command {
                     Command::SendMessage { payload, recipient } => {
-                        if let Err(e) =
-                            self.sockets
-                                .try_send(TURN_PORT, recipient.into_socket(), &payload)
-                        {
+                        if let Err(e) = self.send_to_client(recipient, payload) {
                             tracing::warn!(target: "relay", %recipient, "Failed to send message: {e}");
                         }
                     }
-                    Command::CreateAllocation { port, family } => {
-                        self.sockets.bind(port.value(), family).with_context(|| {
-                            format!(
-                                "Failed to bind to port {} on {family} interfaces",
-                                port.value()
-                            )
-                        })?;
+                    Command::CreateAllocation {
+                        port,
+                        family,
+                        dont_fragment,
+                    } => {
+                        self.data_plane
+                            .sockets
+                            .bind(port.value(), family, dont_fragment)
+                            .and_then(|()| {
+                                self.workers.send(|| WorkerCommand::Bind {
+                                    port: port.value(),
+                                    family,
+                                    dont_fragment,
+                                })
+                            })
+                            .with_context(|| {
+                                format!(
+                                    "Failed to bind to port {} on {family} interfaces",
+                                    port.value()
+                                )
+                            })?;
 
                         tracing::info!(target: "relay", %port, %family, "Created allocation");
                     }
                     Command::FreeAllocation { port, family } => {
-                        self.sockets.unbind(port.value(), family).with_context(|| {
-                            format!(
-                                "Failed to unbind to port {} on {family} interfaces",
-                                port.value()
-                            )
-                        })?;
+                        self.data_plane
+                            .sockets
+                            .unbind(port.value(), family)
+                            .and_then(|()| {
+                                self.workers.send(|| WorkerCommand::Unbind {
+                                    port: port.value(),
+                                    family,
+                                })
+                            })
+                            .with_context(|| {
+                                format!(
+                                    "Failed to unbind to port {} on {family} interfaces",
+                                    port.value()
+                                )
+                            })?;
 
                         tracing::info!(target: "relay", %port, %family, "Freeing allocation");
                     }
+                    Command::RelayToPeer {
+                        payload,
+                        port,
+                        peer,
+                    } => {
+                        if let Err(e) =
+                            self.data_plane
+                                .sockets
+                                .send(port.value(), peer.into_socket(), &payload)
+                        {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+                        }
+                    }
                 }
+            }
 
-                continue; // Attempt to process more commands.
+            // All commands are executed, send the datagrams they queued.
+            self.data_plane.sockets.flush();
+
+            // Priority 1.1: Hand the audit records of the server to the writer.
+            if let Some(audit_writer) = self.audit_writer.as_ref() {
+                for record in audit_records {
+                    audit_writer.write(record, SystemTime::now());
+                }
             }
 
             // Priority 2: Read from our sockets.
-            //
-            // We read the packet with an offset of 4 bytes so we can encode the channel-data header into that without re-allocating.
-            // This only matters for relaying from an allocation to a client because the data coming in on an allocation is "raw" (i.e. unwrapped) application data.
-            // To allow clients to correctly associate this data, we need to wrap it in a channel-data message as depicted below.
-            //
-            // For traffic coming from clients that needs to be forwarded to peers, this doesn't matter because we already a channel data message and only need to forward its payload.
-            //
-            // However, we don't know which socket we will be reading from when we call `poll_recv_from`, which is why we always offset the read-buffer by 4 bytes like this:
-            //
-            //  01│23│456789....
-            // ┌──┼──┼──────────────────────────┐
-            // │CN│LN│PAYLOAD...                │
-            // └──┴──┴──────────────────────────┘
-            //       ▲
-            //       │
-            //       Start of read-buffer.
-            //
-            //  CN: Channel number
-            //  LN: Length
-            let (header, payload) = self.buffer.split_at_mut(4);
-
-            match self.sockets.poll_recv_from(payload, cx) {
-                Poll::Ready(Ok(sockets::Received {
-                    port: TURN_PORT, // Packets coming in on the TURN port are from clients.
-                    from,
-                    packet,
-                })) => {
-                    if let Some((port, peer)) = self.server.handle_client_input(
-                        packet,
-                        ClientSocket::new(from),
-                        Instant::now(),
-                    ) {
-                        // Re-parse as `ChannelData` if we should relay it.
-                        let payload = ChannelData::parse(packet)
-                            .expect("valid ChannelData if we should relay it")
-                            .data(); // When relaying data from a client to peer, we need to forward only the channel-data's payload.
+            match self.data_plane.poll(cx) {
+                Poll::Ready(Ok(event)) => {
+                    self.handle_data_plane_event(event);
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)), // Fail the event-loop. We can't operate without the `mio` worker-task.
+                Poll::Pending => {}
+            }
 
-                        if let Err(e) =
-                            self.sockets
-                                .try_send(port.value(), peer.into_socket(), payload)
-                        {
-                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+            // Priority 2.1: Read from our stream-based connections.
+            match self.streams.poll_next_event(cx) {
+                Poll::Ready(StreamEvent::Received { from, bytes }) => {
+                    let client = ClientSocket::new(from);
+
+                    let result = self.server.lock().unwrap().handle_client_stream_input(
+                        &bytes,
+                        client,
+                        Instant::now(),
+                    );
+
+                    match result {
+                        Ok(to_relay) => {
+                            for (port, peer, payload) in to_relay {
+                                if let Err(e) = self.data_plane.sockets.send(
+                                    port.value(),
+                                    peer.into_socket(),
+                                    &payload,
+                                ) {
+                                    tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+                                }
+                            }
                         }
-                    };
+                        Err(e) => {
+                            tracing::debug!(target: "relay", %client, "Closing connection: {e}");
+
+                            self.streams.close(from);
+                            self.server
+                                .lock()
+                                .unwrap()
+                                .handle_client_stream_closed(client);
+                        }
+                    }
+
                     continue;
                 }
-                Poll::Ready(Ok(sockets::Received {
-                    port, // Packets coming in on any other port are from peers.
-                    from,
-                    packet,
-                })) => {
-                    if let Some((client, channel)) = self.server.handle_peer_traffic(
-                        packet,
-                        PeerSocket::new(from),
-                        AllocationPort::new(port),
-                    ) {
-                        let total_length = ChannelData::encode_header_to_slice(
-                            channel,
-                            packet.len() as u16,
-                            header,
-                        );
-
-                        if let Err(e) = self.sockets.try_send(
-                            TURN_PORT, // Packets coming in from peers always go out on the TURN port
-                            client.into_socket(),
-                            &self.buffer[..total_length],
-                        ) {
-                            tracing::warn!(target: "relay", %client, "Failed to relay data to client: {e}");
-                        };
-                    };
+                Poll::Ready(StreamEvent::Closed { from }) => {
+                    self.server
+                        .lock()
+                        .unwrap()
+                        .handle_client_stream_closed(ClientSocket::new(from));
                     continue;
                 }
-                Poll::Ready(Err(sockets::Error::Io(e))) => {
-                    tracing::warn!(target: "relay", "Error while receiving message: {e}");
+                Poll::Pending => {}
+            }
+
+            // Priority 2.2: Handle events from our data-plane workers.
+            match self.workers.poll_next_event(cx) {
+                Poll::Ready(WorkerEvent::DataPlane(event)) => {
+                    self.handle_data_plane_event(event);
                     continue;
                 }
-                Poll::Ready(Err(sockets::Error::MioTaskCrashed(e))) => return Poll::Ready(Err(e)), // Fail the event-loop. We can't operate without the `mio` worker-task.
+                Poll::Ready(WorkerEvent::Failed(e)) => {
+                    return Poll::Ready(Err(e.context("Data-plane worker failed")));
+                }
                 Poll::Pending => {}
             }
 
-            // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
-            if let Some(timeout) = self.server.poll_timeout() {
-                Pin::new(&mut self.sleep).reset(timeout);
-                // Purposely no `continue` because we just change the state of `sleep` and we poll it below.
+            // Priority 2.3: Handle modifications via the admin API.
+            match self.admin_rx.poll_recv(cx) {
+                Poll::Ready(Some(())) => continue, // Handle potentially new commands.
+                Poll::Ready(None) | Poll::Pending => {}
             }
 
-            // Priority 4: Handle time-sensitive tasks:
-            if let Poll::Ready(deadline) = self.sleep.poll_unpin(cx) {
-                self.server.handle_timeout(deadline);
-                continue; // Handle potentially new commands.
+            // Priority 2.4: Hand over to a newly started relay.
+            if let Some(Poll::Ready(result)) = self
+                .handover_listener
+                .as_ref()
+                .map(|listener| listener.poll_accept(cx))
+            {
+                match result.and_then(|(stream, _)| stream.into_std()) {
+                    Ok(stream) => match self.hand_over(stream) {
+                        Ok(()) => return Poll::Ready(Ok(())),
+                        Err(e) if self.workers.is_stopped() => {
+                            // Without our workers, we can't continue relaying.
+                            return Poll::Ready(Err(e.context("Failed to hand over to new relay")));
+                        }
+                        Err(e) => {
+                            tracing::warn!(target: "relay", "Failed to hand over to new relay: {e:#}")
+                        }
+                    },
+                    Err(e) => {
+                        tracing::warn!(target: "relay", "Failed to accept connection on handover socket: {e}")
+                    }
+                }
+
+                continue;
+            }
+
+            // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
+            // Priority 4: Handle time-sensitive tasks.
+            {
+                let mut server = self.server.lock().unwrap();
+
+                if let Some(timeout) = server.poll_timeout() {
+                    Pin::new(&mut self.sleep).reset(timeout);
+                }
+
+                if let Poll::Ready(deadline) = self.sleep.poll_unpin(cx) {
+                    server.handle_timeout(deadline);
+                    continue; // Handle potentially new commands.
+                }
             }
 
             // Priority 5: Handle portal messages
@@ -505,9 +1010,12 @@ where
                         return Poll::Ready(Err(anyhow!("Forcing shutdown on repeated SIGTERM")));
                     }
 
-                    tracing::info!(active_allocations = %self.server.num_allocations(), "Received SIGTERM, initiating graceful shutdown");
+                    let num_allocations = self.server.lock().unwrap().num_allocations();
+
+                    tracing::info!(active_allocations = %num_allocations, "Received SIGTERM, initiating graceful shutdown");
 
                     self.shutting_down = true;
+                    self.server.lock().unwrap().start_draining();
 
                     if let Some(portal) = self.channel.as_mut() {
                         match portal.close() {
@@ -523,13 +1031,32 @@ where
                 Poll::Ready(None) | Poll::Pending => {}
             }
 
+            match self.sigusr1.poll_recv(cx) {
+                Poll::Ready(Some(())) => {
+                    if self.data_plane.captures.stop(Instant::now()).is_none() {
+                        tracing::info!(target: "relay", "Received SIGUSR1 but no packet capture is running");
+                    }
+
+                    continue;
+                }
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+
             if self.stats_log_interval.poll_tick(cx).is_ready() {
-                let num_allocations = self.server.num_allocations();
-                let num_channels = self.server.num_active_channels();
+                self.data_plane.captures.handle_timeout(Instant::now());
+
+                let (num_allocations, num_channels, num_relayed_bytes) = {
+                    let server = self.server.lock().unwrap();
 
-                let bytes_relayed_since_last_tick =
-                    self.server.num_relayed_bytes() - self.last_num_bytes_relayed;
-                self.last_num_bytes_relayed = self.server.num_relayed_bytes();
+                    (
+                        server.num_allocations(),
+                        server.num_active_channels(),
+                        server.num_relayed_bytes(),
+                    )
+                };
+
+                let bytes_relayed_since_last_tick = num_relayed_bytes - self.last_num_bytes_relayed;
+                self.last_num_bytes_relayed = num_relayed_bytes;
 
                 let avg_throughput = bytes_relayed_since_last_tick / STATS_LOG_INTERVAL.as_secs();
 
@@ -542,6 +1069,59 @@ where
         }
     }
 
+    /// Hands over our state and sockets to the relay on the other end of `stream`.
+    ///
+    /// On success, we must stop relaying traffic because the new relay now owns our state.
+    fn hand_over(&mut self, stream: std::os::unix::net::UnixStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+
+        // Stop the workers first, they must not change the state of the server after we exported it.
+        let mut sockets = self.workers.hand_over()?;
+        sockets.extend(
+            self.data_plane
+                .sockets
+                .handover()
+                .context("Failed to duplicate sockets")?,
+        );
+
+        let state = self.server.lock().unwrap().export_state(Instant::now());
+
+        // Allocations of stream-based clients are not handed over, neither are their sockets.
+        let ports = state.allocation_ports().collect::<HashSet<_>>();
+        sockets.retain(|s| s.port == TURN_PORT || ports.contains(&s.port));
+
+        handover::send(&stream, state, &sockets).context("Failed to send state")?;
+
+        tracing::info!(target: "relay", num_allocations = %ports.len(), num_sockets = %sockets.len(), "Handed over to new relay");
+
+        // The new relay waits for this connection to close before binding the ports we still hold, thus we keep it open until we exit.
+        std::mem::forget(stream);
+
+        Ok(())
+    }
+
+    fn handle_data_plane_event(&mut self, event: DataPlaneEvent) {
+        match event {
+            DataPlaneEvent::CommandsPending => {} // Commands are processed at the start of every iteration.
+            DataPlaneEvent::SendToStream { recipient, msg } => {
+                if let Err(e) = self.streams.try_send(recipient.into_socket(), msg) {
+                    tracing::warn!(target: "relay", client = %recipient, "Failed to relay data to client: {e}");
+                }
+            }
+        }
+    }
+
+    /// Sends a message to a client, either via its stream-based connection or via UDP.
+    fn send_to_client(&mut self, recipient: ClientSocket, payload: Vec<u8>) -> io::Result<()> {
+        let recipient = recipient.into_socket();
+
+        if self.streams.is_connected(recipient) {
+            return self.streams.try_send(recipient, payload);
+        }
+
+        self.data_plane.sockets.send(TURN_PORT, recipient, &payload)
+    }
+
     fn handle_portal_event(&mut self, event: phoenix_channel::Event<IngressMessage, ()>) {
         match event {
             Event::SuccessResponse { res: (), .. } => {}
@@ -559,6 +1139,28 @@ where
                 msg: IngressMessage::Init(Init {}),
                 ..
             } => {}
+            Event::InboundMessage {
+                msg: IngressMessage::RotateStampSecret(RotateStampSecret { stamp_secret }),
+                ..
+            } => {
+                // Make sure we announce the new secret in case we need to re-join the room.
+                if let Some(channel) = self.channel.as_mut() {
+                    channel.set_init_req(JoinMessage {
+                        stamp_secret: stamp_secret.clone(),
+                    });
+                }
+
+                self.server
+                    .lock()
+                    .unwrap()
+                    .rotate_auth_secret(SecretString::from(stamp_secret), Instant::now());
+            }
+            Event::InboundMessage {
+                msg: IngressMessage::AlternateRelays(AlternateRelays { addresses }),
+                ..
+            } => {
+                self.server.lock().unwrap().set_alternate_servers(addresses);
+            }
             Event::Closed => {
                 self.channel = None;
             }
@@ -566,6 +1168,387 @@ where
     }
 }
 
+/// Relays UDP traffic between clients and peers.
+///
+/// Every thread that relays UDP traffic owns a [`DataPlane`] with its own shard of our sockets.
+/// The [`Server`] is shared between all of them.
+/// To not serialize all threads on it, channel data is relayed via a snapshot of its [`Routes`], only packets we cannot route that way are handed to the [`Server`].
+struct DataPlane<R> {
+    sockets: Sockets,
+    server: Arc<Mutex<Server<R>>>,
+    routes: Routes,
+    captures: Captures,
+
+    buffers: RecvBuffers,
+    /// Events that resulted from handling the last batch of packets.
+    pending_events: VecDeque<DataPlaneEvent>,
+}
+
+/// An event from a [`DataPlane`] that needs to be handled by the [`Eventloop`].
+#[derive(Debug)]
+enum DataPlaneEvent {
+    /// We handled packets without relaying them, meaning the [`Server`] may have new commands for us.
+    CommandsPending,
+    /// A message for a client that is connected via a stream-based transport.
+    SendToStream {
+        recipient: ClientSocket,
+        msg: Vec<u8>,
+    },
+}
+
+impl<R> DataPlane<R>
+where
+    R: Rng,
+{
+    fn new(sockets: Sockets, server: Arc<Mutex<Server<R>>>, captures: Captures) -> Self {
+        let routes = server.lock().unwrap().routes();
+
+        Self {
+            sockets,
+            server,
+            routes,
+            captures,
+            buffers: RecvBuffers::new(),
+            pending_events: VecDeque::new(),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<DataPlaneEvent>> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Poll::Ready(Ok(event));
+            }
+
+            let batch = match self.sockets.poll_recv_batch(&mut self.buffers, cx) {
+                Poll::Ready(Ok(batch)) => batch,
+                Poll::Ready(Err(sockets::Error::Io(e))) => {
+                    tracing::warn!(target: "relay", "Error while receiving message: {e}");
+                    continue;
+                }
+                Poll::Ready(Err(sockets::Error::MioTaskCrashed(e))) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // Handle the entire batch with the same snapshot of our routes.
+            if self.routes.is_outdated() {
+                self.routes = self.server.lock().unwrap().routes();
+            }
+
+            let now = Instant::now();
+            let mut commands_pending = false;
+
+            // Only packets we cannot route ourselves need the server, thus we lock it lazily and at most once per batch.
+            let mut server = None;
+
+            for received in batch {
+                match received {
+                    sockets::Received {
+                        port: TURN_PORT, // Packets coming in on the TURN port are from clients.
+                        from,
+                        packet,
+                    } => {
+                        let client = ClientSocket::new(from);
+
+                        let route = match ChannelData::parse(packet) {
+                            Ok(message) => self.routes.handle_channel_data(&message, client, now),
+                            Err(_) => Route::Unknown, // Most likely a STUN message.
+                        };
+
+                        let (port, peer) = match route {
+                            Route::Relay(relay) => relay,
+                            Route::Drop => continue,
+                            Route::Unknown => {
+                                let server =
+                                    server.get_or_insert_with(|| self.server.lock().unwrap());
+
+                                let Some(relay) = server.handle_client_input(packet, client, now)
+                                else {
+                                    commands_pending = true;
+                                    continue;
+                                };
+
+                                relay
+                            }
+                        };
+
+                        // Re-parse as `ChannelData` if we should relay it.
+                        let payload = ChannelData::parse(packet)
+                            .expect("valid ChannelData if we should relay it")
+                            .data(); // When relaying data from a client to peer, we need to forward only the channel-data's payload.
+
+                        self.captures
+                            .record_client_to_peer(client, port, peer, packet, payload);
+
+                        if let Err(e) = self.sockets.send(port.value(), peer.into_socket(), payload)
+                        {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+                        }
+                    }
+                    sockets::Received {
+                        port, // Packets coming in on any other port are from peers.
+                        from,
+                        packet,
+                    } => {
+                        let peer = PeerSocket::new(from);
+                        let port = AllocationPort::new(port);
+
+                        let route = self.routes.handle_peer_traffic(packet, peer, port, now);
+
+                        let (client, channel, is_stream_client) = match route {
+                            Route::Relay((client, channel)) => {
+                                (client, channel, self.routes.is_stream_client(client))
+                            }
+                            Route::Drop => continue,
+                            Route::Unknown => {
+                                let server =
+                                    server.get_or_insert_with(|| self.server.lock().unwrap());
+
+                                let Some((client, channel)) =
+                                    server.handle_peer_traffic(packet, peer, port, now)
+                                else {
+                                    commands_pending = true;
+                                    continue;
+                                };
+
+                                (client, channel, server.is_stream_client(client))
+                            }
+                        };
+
+                        // The data coming in on an allocation is "raw" (i.e. unwrapped) application data.
+                        // To allow clients to correctly associate this data, we need to wrap it in a channel-data message.
+                        let mut header = [0u8; 4];
+                        let total_length = ChannelData::encode_header_to_slice(
+                            channel,
+                            packet.len() as u16,
+                            &mut header,
+                        );
+
+                        self.captures.record_peer_to_client(
+                            peer,
+                            port,
+                            client,
+                            packet,
+                            &[&header, packet],
+                        );
+
+                        if is_stream_client {
+                            // Over stream-based transports, channel data messages must be padded to a multiple of 4 bytes.
+                            let mut msg = Vec::with_capacity(total_length.next_multiple_of(4));
+                            msg.extend_from_slice(&header);
+                            msg.extend_from_slice(packet);
+                            msg.resize(total_length.next_multiple_of(4), 0);
+
+                            self.pending_events.push_back(DataPlaneEvent::SendToStream {
+                                recipient: client,
+                                msg,
+                            });
+                            continue;
+                        }
+
+                        if let Err(e) = self.sockets.send_vectored(
+                            TURN_PORT, // Packets coming in from peers always go out on the TURN port
+                            client.into_socket(),
+                            &[&header, packet],
+                        ) {
+                            tracing::warn!(target: "relay", %client, "Failed to relay data to client: {e}");
+                        };
+                    }
+                }
+            }
+
+            drop(server);
+
+            self.sockets.flush();
+
+            if commands_pending {
+                self.pending_events
+                    .push_back(DataPlaneEvent::CommandsPending);
+            }
+        }
+    }
+}
+
+/// Additional threads relaying UDP traffic, each with its own [`DataPlane`].
+///
+/// All sockets of the workers are bound with `SO_REUSEPORT`, meaning the kernel distributes incoming packets across all threads.
+/// The workers only relay traffic, the commands of the [`Server`] are always executed by the [`Eventloop`].
+/// Thus, the workers notify the [`Eventloop`] whenever they handled a packet that might have resulted in new commands.
+struct Workers {
+    cmd_txs: Vec<mpsc::Sender<WorkerCommand>>,
+    event_rx: mpsc::Receiver<WorkerEvent>,
+
+    /// Whether we stopped the workers to hand over to a new relay.
+    stopped: bool,
+}
+
+#[derive(Debug)]
+enum WorkerCommand {
+    Bind {
+        port: u16,
+        family: AddressFamily,
+        dont_fragment: bool,
+    },
+    Unbind {
+        port: u16,
+        family: AddressFamily,
+    },
+    /// Use a socket handed over by the previous relay instead of binding a new one.
+    Adopt(HandedOverSocket),
+    /// Stop relaying and send duplicates of all sockets back.
+    HandOver(std::sync::mpsc::Sender<io::Result<Vec<HandedOverSocket>>>),
+}
+
+enum WorkerEvent {
+    DataPlane(DataPlaneEvent),
+    Failed(anyhow::Error),
+}
+
+impl Workers {
+    fn spawn<R>(
+        num_workers: usize,
+        server: &Arc<Mutex<Server<R>>>,
+        captures: &Captures,
+    ) -> Result<Self>
+    where
+        R: Rng + Send + 'static,
+    {
+        let (event_tx, event_rx) = mpsc::channel(1_024);
+
+        let cmd_txs = (0..num_workers)
+            .map(|i| {
+                let (cmd_tx, cmd_rx) = mpsc::channel(1_000_000); // Commands are really small and this channel should really never fill up.
+                let data_plane =
+                    DataPlane::new(Sockets::with_reuse_port(), server.clone(), captures.clone());
+                let event_tx = event_tx.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("relay-worker-{i}"))
+                    .spawn(move || {
+                        if let Err(e) =
+                            futures::executor::block_on(run_worker(data_plane, cmd_rx, &event_tx))
+                        {
+                            let _ = event_tx.blocking_send(WorkerEvent::Failed(e));
+                        }
+                    })
+                    .context("Failed to spawn data-plane worker")?;
+
+                Ok(cmd_tx)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            cmd_txs,
+            event_rx,
+            stopped: false,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.cmd_txs.len()
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Sends a command created by `command` to all workers.
+    fn send(&self, command: impl Fn() -> WorkerCommand) -> Result<()> {
+        for cmd_tx in &self.cmd_txs {
+            cmd_tx.try_send(command())?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the given command to a single worker.
+    fn send_to(&self, worker: usize, command: WorkerCommand) -> Result<()> {
+        self.cmd_txs
+            .get(worker)
+            .context("No such worker")?
+            .try_send(command)?;
+
+        Ok(())
+    }
+
+    /// Stops all workers and returns duplicates of their sockets.
+    ///
+    /// Blocks until every worker has stopped.
+    fn hand_over(&mut self) -> Result<Vec<HandedOverSocket>> {
+        let (sockets_tx, sockets_rx) = std::sync::mpsc::channel();
+
+        self.stopped = true;
+
+        self.send(|| WorkerCommand::HandOver(sockets_tx.clone()))?;
+        drop(sockets_tx);
+
+        let mut sockets = Vec::new();
+        for result in sockets_rx {
+            sockets.extend(result.context("Failed to duplicate sockets of worker")?);
+        }
+
+        Ok(sockets)
+    }
+
+    fn poll_next_event(&mut self, cx: &mut std::task::Context<'_>) -> Poll<WorkerEvent> {
+        match ready!(self.event_rx.poll_recv(cx)) {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending, // We don't have any workers, this will never resolve.
+        }
+    }
+}
+
+/// Drives the [`DataPlane`] of a worker until the [`Eventloop`] is gone.
+async fn run_worker<R>(
+    mut data_plane: DataPlane<R>,
+    mut cmd_rx: mpsc::Receiver<WorkerCommand>,
+    event_tx: &mpsc::Sender<WorkerEvent>,
+) -> Result<()>
+where
+    R: Rng,
+{
+    future::poll_fn(|cx| loop {
+        match cmd_rx.poll_recv(cx) {
+            Poll::Ready(Some(WorkerCommand::Bind {
+                port,
+                family,
+                dont_fragment,
+            })) => {
+                data_plane.sockets.bind(port, family, dont_fragment)?;
+                continue;
+            }
+            Poll::Ready(Some(WorkerCommand::Unbind { port, family })) => {
+                data_plane.sockets.unbind(port, family)?;
+                continue;
+            }
+            Poll::Ready(Some(WorkerCommand::Adopt(socket))) => {
+                data_plane.sockets.adopt(socket)?;
+                continue;
+            }
+            Poll::Ready(Some(WorkerCommand::HandOver(sockets_tx))) => {
+                // From now on, the new relay is in charge of our sockets.
+                let _ = sockets_tx.send(data_plane.sockets.handover());
+
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => {}
+        }
+
+        match ready!(data_plane.poll(cx))? {
+            DataPlaneEvent::CommandsPending => {
+                // If the channel is full, the eventloop is going to process the commands anyway.
+                let _ = event_tx.try_send(WorkerEvent::DataPlane(DataPlaneEvent::CommandsPending));
+            }
+            event @ DataPlaneEvent::SendToStream { .. } => {
+                if event_tx.try_send(WorkerEvent::DataPlane(event)).is_err() {
+                    tracing::warn!(target: "relay", "Eventloop is congested, dropping packet for stream-based client");
+                }
+            }
+        }
+    })
+    .await
+}
+
 fn fmt_human_throughput(mut throughput: f64) -> String {
     let units = ["B/s", "kB/s", "MB/s", "GB/s", "TB/s"];
 
@@ -634,4 +1617,15 @@ mod tests {
 
         assert!(!is_healthy)
     }
+
+    #[test]
+    fn rejects_zero_unauthenticated_request_rate_limit() {
+        let result = Args::try_parse_from([
+            "firezone-relay",
+            "--unauthenticated-request-rate-limit",
+            "0",
+        ]);
+
+        assert!(result.is_err());
+    }
 }
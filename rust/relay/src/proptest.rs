@@ -5,7 +5,9 @@ use proptest::strategy::Just;
 use proptest::strategy::Strategy;
 use proptest::string::string_regex;
 use std::time::Duration;
-use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, RequestedTransport};
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+};
 use stun_codec::TransactionId;
 use uuid::Uuid;
 
@@ -25,6 +27,14 @@ pub fn allocation_lifetime() -> impl Strategy<Value = Lifetime> {
     (1..3600u64).prop_map(|seconds| Lifetime::new(Duration::new(seconds, 0)).unwrap())
 }
 
+pub fn even_port() -> impl Strategy<Value = EvenPort> {
+    any::<bool>().prop_map(EvenPort::new)
+}
+
+pub fn reservation_token() -> impl Strategy<Value = ReservationToken> {
+    any::<u64>().prop_map(ReservationToken::new)
+}
+
 pub fn channel_number() -> impl Strategy<Value = ChannelNumber> {
     (ChannelNumber::MIN..=ChannelNumber::MAX).prop_map(|n| ChannelNumber::new(n).unwrap())
 }
@@ -1,35 +1,54 @@
 mod channel_data;
 mod client_message;
+mod rate_limit;
+mod routes;
+mod stream;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
-    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
+    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh, SendIndication,
 };
+pub use crate::server::rate_limit::{BandwidthLimit, RequestRateLimit};
+pub use crate::server::routes::{Route, Routes};
 
-use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
+use crate::audit::{family_name, AuditRecord, DeletionReason};
+use crate::auth::{self, AuthSecrets, MessageIntegrityExt, Nonces, FIREZONE};
+use crate::capture::Captures;
+use crate::handover::{AllocationState, ChannelState, PermissionState, ServerState};
 use crate::net_ext::IpAddrExt;
+use crate::server::rate_limit::TokenBucket;
+use crate::server::routes::{Direction, Meter, Totals};
+use crate::server::stream::StreamBuffer;
 use crate::{ClientSocket, IpStack, PeerSocket};
 use anyhow::Result;
 use bytecodec::EncodeExt;
 use core::fmt;
-use opentelemetry::metrics::{Counter, Unit, UpDownCounter};
+use ip_network::IpNetwork;
+use opentelemetry::metrics::{Counter, ObservableCounter, Unit, UpDownCounter};
 use opentelemetry::KeyValue;
 use rand::Rng;
 use secrecy::SecretString;
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
+use std::io;
+use std::iter;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant, SystemTime};
 use stun_codec::rfc5389::attributes::{
-    ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+    AlternateServer, ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
 };
-use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, Unauthorized};
+use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, TryAlternate, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+    ChannelNumber, Data, DontFragment, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+    XorPeerAddress, XorRelayAddress,
 };
 use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{
+    ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH, SEND,
+};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
@@ -41,10 +60,13 @@ use uuid::Uuid;
 
 /// A sans-IO STUN & TURN server.
 ///
-/// A [`Server`] is bound to an IPv4 address and assumes to only operate on UDP.
+/// A [`Server`] is bound to an IPv4 address and relays data to peers exclusively via UDP.
 /// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
 /// we can index data simply by the sender's [`SocketAddr`].
 ///
+/// Clients may talk to the [`Server`] via UDP ([`Server::handle_client_input`]) or via a stream-based transport like TCP or TLS ([`Server::handle_client_stream_input`]).
+/// We assume that a client doesn't use the same [`SocketAddr`] for both.
+///
 /// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
 pub struct Server<R> {
     decoder: client_message::Decoder,
@@ -55,6 +77,8 @@ pub struct Server<R> {
     /// All client allocations, indexed by client's socket address.
     allocations: HashMap<ClientSocket, Allocation>,
     clients_by_allocation: HashMap<AllocationPort, ClientSocket>,
+    /// Ports reserved via EVEN-PORT that can be claimed with a RESERVATION-TOKEN.
+    reservations: HashMap<AllocationPort, Reservation>,
     /// Redundant mapping so we can look route data with a single lookup.
     channel_and_client_by_port_and_peer:
         HashMap<(AllocationPort, PeerSocket), (ClientSocket, ChannelNumber)>,
@@ -67,18 +91,61 @@ pub struct Server<R> {
     /// Channel numbers are unique between clients and peers, thus indexed by both.
     channel_numbers_by_client_and_peer: HashMap<(ClientSocket, PeerSocket), ChannelNumber>,
 
+    /// The expiry of each permission, indexed by the allocation and the IP of the peer.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-permissions>.
+    permissions: HashMap<(AllocationPort, IpAddr), Instant>,
+
+    /// The maximum bandwidth a single allocation may use, across both directions.
+    allocation_bandwidth_limit: Option<BandwidthLimit>,
+    /// The maximum bandwidth all allocations of a single client IP may use, across both directions.
+    client_ip_bandwidth_limit: Option<BandwidthLimit>,
+    /// Shared by the [`Meter`]s of all allocations of a client IP.
+    client_ip_buckets: HashMap<IpAddr, Arc<Mutex<TokenBucket>>>,
+
+    /// If non-empty, only clients within these networks may use the relay.
+    client_allow_list: Vec<IpNetwork>,
+    /// Clients within these networks may not use the relay.
+    client_deny_list: Vec<IpNetwork>,
+    /// The maximum rate of unauthenticated requests we answer per client IP.
+    unauthenticated_request_rate_limit: Option<RequestRateLimit>,
+    unauthenticated_request_buckets: HashMap<IpAddr, TokenBucket>,
+
+    /// Sibling relays we redirect clients to if we cannot serve them.
+    alternate_servers: Vec<SocketAddr>,
+    /// Whether we are shutting down and thus no longer accept new allocations.
+    draining: bool,
+
+    /// Partially received messages of clients connected via a stream-based transport.
+    stream_buffers: HashMap<ClientSocket, StreamBuffer>,
+
     pending_commands: VecDeque<Command>,
 
+    /// The snapshot of our channels returned from [`Server::routes`], until it becomes outdated.
+    routes: Option<Routes>,
+    /// Bumped whenever a channel is bound or unbound, making all [`Routes`] outdated.
+    routes_version: Arc<AtomicU64>,
+
+    /// Whether we should record [`AuditRecord`]s.
+    audit_log: bool,
+    pending_audit_records: VecDeque<AuditRecord>,
+
+    /// Records the traffic we relay ourselves, i.e. of stream-based clients and via indications.
+    captures: Option<Captures>,
+
     rng: R,
 
-    auth_secret: SecretString,
+    auth_secrets: AuthSecrets,
 
     nonces: Nonces,
 
     allocations_up_down_counter: UpDownCounter<i64>,
-    data_relayed_counter: Counter<u64>,
-    data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    channels_up_down_counter: UpDownCounter<i64>,
+    totals: Arc<Totals>,
+    /// Reports the bytes relayed in [`Totals`] whenever metrics are collected, thus relaying a packet doesn't need to touch the metrics pipeline.
+    _data_relayed_counter: ObservableCounter<u64>,
     responses_counter: Counter<u64>,
+    rejected_requests_counter: Counter<u64>,
 }
 
 /// The commands returned from a [`Server`].
@@ -95,15 +162,47 @@ pub enum Command {
     /// Any incoming data should be handed to the [`Server`] via [`Server::handle_peer_traffic`].
     /// A single allocation can reference one of either [AddressFamily]s or both.
     /// Only the combination of [AllocationPort] and [AddressFamily] is unique.
+    ///
+    /// If `dont_fragment` is set, packets sent to peers from this port must have the DF bit set.
     CreateAllocation {
         port: AllocationPort,
         family: AddressFamily,
+        dont_fragment: bool,
     },
     /// Free the allocation associated with the given [AllocationPort] and [AddressFamily].
     FreeAllocation {
         port: AllocationPort,
         family: AddressFamily,
     },
+    /// Relay the payload of a SEND indication to the [`PeerSocket`] from the given [AllocationPort].
+    ///
+    /// Payloads of [`ChannelData`] messages are not relayed via this command but returned from [`Server::handle_client_input`] to avoid copying them.
+    RelayToPeer {
+        payload: Vec<u8>,
+        port: AllocationPort,
+        peer: PeerSocket,
+    },
+}
+
+/// A snapshot of an allocation, for inspecting the state of the [`Server`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationInfo {
+    pub client: ClientSocket,
+    pub port: AllocationPort,
+    pub families: Vec<AddressFamily>,
+    pub expires_in: Duration,
+    pub bytes_relayed: u64,
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// A snapshot of a channel binding, for inspecting the state of the [`Server`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: PeerSocket,
+    /// Unbound channels are kept around for a while to prevent their number from being re-used too early.
+    pub bound: bool,
+    pub expires_in: Duration,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
@@ -133,11 +232,21 @@ const UDP_TRANSPORT: u8 = 17;
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
 const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 
+/// The lifetime of a permission.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-permissions>.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
 /// The timeout before a channel be rebound.
 ///
 /// See <https://www.rfc-editor.org/rfc/rfc8656#section-12-14>.
 const CHANNEL_REBIND_TIMEOUT: Duration = Duration::from_secs(300);
 
+/// How long a port reserved via EVEN-PORT is held for the client to claim it.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#section-7.2-3.7.2.1>.
+const RESERVATION_LIFETIME: Duration = Duration::from_secs(30);
+
 impl<R> Server<R>
 where
     R: Rng,
@@ -164,14 +273,34 @@ where
             .i64_up_down_counter("allocations_total")
             .with_description("The number of active allocations")
             .init();
+        let channels_up_down_counter = meter
+            .i64_up_down_counter("channels_total")
+            .with_description("The number of active channels")
+            .init();
         let responses_counter = meter
             .u64_counter("responses_total")
             .with_description("The number of responses")
             .init();
+        let rate_limited_packets_counter = meter
+            .u64_counter("rate_limited_packets_total")
+            .with_description(
+                "The number of packets dropped because they exceeded a bandwidth limit",
+            )
+            .init();
+        let totals = Arc::new(Totals::new(rate_limited_packets_counter));
         let data_relayed_counter = meter
-            .u64_counter("data_relayed_bytes")
+            .u64_observable_counter("data_relayed_bytes")
             .with_description("The number of bytes relayed")
             .with_unit(Unit::new("b"))
+            .with_callback({
+                let totals = totals.clone();
+
+                move |observer| observer.observe(totals.bytes_relayed(), &[])
+            })
+            .init();
+        let rejected_requests_counter = meter
+            .u64_counter("rejected_requests_total")
+            .with_description("The number of client messages dropped without a response")
             .init();
 
         Self {
@@ -180,41 +309,403 @@ where
             public_address: public_address.into(),
             allocations: Default::default(),
             clients_by_allocation: Default::default(),
+            reservations: Default::default(),
             lowest_port,
             highest_port,
             channels_by_client_and_number: Default::default(),
             channel_numbers_by_client_and_peer: Default::default(),
+            permissions: Default::default(),
+            allocation_bandwidth_limit: None,
+            client_ip_bandwidth_limit: None,
+            client_ip_buckets: Default::default(),
+            client_allow_list: Default::default(),
+            client_deny_list: Default::default(),
+            unauthenticated_request_rate_limit: None,
+            unauthenticated_request_buckets: Default::default(),
+            alternate_servers: Default::default(),
+            draining: false,
+            stream_buffers: Default::default(),
             pending_commands: Default::default(),
-            auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            routes: None,
+            routes_version: Default::default(),
+            audit_log: false,
+            pending_audit_records: Default::default(),
+            captures: None,
+            auth_secrets: AuthSecrets::new(auth::generate_secret(&mut rng)),
             rng,
             nonces: Default::default(),
             allocations_up_down_counter,
+            channels_up_down_counter,
             responses_counter,
-            data_relayed_counter,
-            data_relayed: 0,
+            totals,
+            _data_relayed_counter: data_relayed_counter,
+            rejected_requests_counter,
             channel_and_client_by_port_and_peer: Default::default(),
         }
     }
 
+    /// Limits the bandwidth a single allocation may use, across both directions.
+    ///
+    /// Packets exceeding the limit are dropped.
+    pub fn with_allocation_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.allocation_bandwidth_limit = Some(limit);
+
+        self
+    }
+
+    /// Limits the bandwidth all allocations of a single client IP may use, across both directions.
+    ///
+    /// Packets exceeding the limit are dropped.
+    pub fn with_client_ip_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.client_ip_bandwidth_limit = Some(limit);
+
+        self
+    }
+
+    /// Sets for how long previous secrets remain valid after [`Server::rotate_auth_secret`].
+    pub fn with_auth_secret_grace_period(mut self, grace_period: Duration) -> Self {
+        self.auth_secrets.set_grace_period(grace_period);
+
+        self
+    }
+
+    /// The current secret for deriving the passwords of long-term credentials.
     pub fn auth_secret(&self) -> &SecretString {
-        &self.auth_secret
+        self.auth_secrets.current()
+    }
+
+    /// Replaces the current auth secret with `new_secret`.
+    ///
+    /// Credentials derived from the previous secret remain valid for a grace period.
+    pub fn rotate_auth_secret(&mut self, new_secret: SecretString, now: Instant) {
+        self.auth_secrets.rotate(new_secret, now);
+
+        tracing::info!(target: "relay", "Rotated auth secret");
+    }
+
+    /// Only allows clients within the given networks to use the relay.
+    ///
+    /// Messages from all other clients are dropped.
+    pub fn with_client_allow_list(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.client_allow_list = networks;
+
+        self
+    }
+
+    /// Disallows clients within the given networks from using the relay.
+    ///
+    /// Messages from these clients are dropped.
+    pub fn with_client_deny_list(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.client_deny_list = networks;
+
+        self
     }
 
-    /// Registers a new, valid nonce.
+    /// Limits how many unauthenticated requests we answer per client IP.
     ///
-    /// Each nonce is valid for 10 requests.
-    pub fn add_nonce(&mut self, nonce: Uuid) {
-        self.nonces.add_new(nonce);
+    /// Binding requests, requests that fail authentication and any other failing request of a client without an allocation can be sent by anybody with a spoofed source address and thus be abused for reflection attacks.
+    /// Requests exceeding the limit are dropped.
+    pub fn with_unauthenticated_request_rate_limit(mut self, limit: RequestRateLimit) -> Self {
+        self.unauthenticated_request_rate_limit = Some(limit);
+
+        self
+    }
+
+    /// Sets the sibling relays we redirect clients to when we are at capacity or draining.
+    pub fn with_alternate_servers(mut self, servers: Vec<SocketAddr>) -> Self {
+        self.alternate_servers = servers;
+
+        self
+    }
+
+    /// Replaces the sibling relays we redirect clients to when we are at capacity or draining.
+    pub fn set_alternate_servers(&mut self, servers: Vec<SocketAddr>) {
+        tracing::info!(target: "relay", ?servers, "Updated alternate servers");
+
+        self.alternate_servers = servers;
+    }
+
+    /// Stops accepting new allocations.
+    ///
+    /// Existing allocations continue to be served until they expire.
+    /// New allocate requests are redirected to an alternate server, if we have one.
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Records an [`AuditRecord`] for every allocation that is created, refreshed or deleted and every channel that is bound.
+    ///
+    /// The records need to be consumed via [`Server::next_audit_record`].
+    pub fn with_audit_log(mut self) -> Self {
+        self.audit_log = true;
+
+        self
+    }
+
+    /// Records the data we relay for stream-based clients and via SEND and DATA indications in the given [`Captures`].
+    ///
+    /// Channel data of UDP clients is relayed by the caller and thus needs to be recorded by it.
+    pub fn with_captures(mut self, captures: Captures) -> Self {
+        self.captures = Some(captures);
+
+        self
+    }
+
+    /// Sets for how long a nonce remains valid after it has been issued.
+    pub fn with_nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.nonces.set_lifetime(lifetime);
+
+        self
+    }
+
+    /// Registers a new, valid nonce for the given client.
+    ///
+    /// Each nonce is only valid for a limited time and number of requests and only when used by `client`.
+    pub fn add_nonce(&mut self, nonce: Uuid, client: ClientSocket, now: Instant) {
+        self.nonces.add_new(nonce, client, now);
     }
 
     pub fn num_relayed_bytes(&self) -> u64 {
-        self.data_relayed
+        self.totals.bytes_relayed()
     }
 
     pub fn num_allocations(&self) -> usize {
         self.allocations.len()
     }
 
+    /// Whether the given client is connected via a stream-based transport.
+    pub fn is_stream_client(&self, client: ClientSocket) -> bool {
+        self.stream_buffers.contains_key(&client)
+    }
+
+    /// Returns a snapshot of our channels for relaying channel data without access to the [`Server`].
+    ///
+    /// The snapshot is cached until a channel is bound or unbound.
+    pub fn routes(&mut self) -> Routes {
+        if let Some(routes) = self.routes.as_ref() {
+            return routes.clone();
+        }
+
+        let channels = self
+            .channels_by_client_and_number
+            .iter()
+            .filter(|(_, channel)| channel.bound)
+            .filter_map(|((client, number), channel)| {
+                let meter = self.allocations.get(client)?.meter.clone();
+
+                Some((
+                    *client,
+                    *number,
+                    channel.allocation,
+                    channel.peer_address,
+                    meter,
+                ))
+            });
+        let routes = Routes::new(
+            channels,
+            self.stream_buffers.keys().copied(),
+            self.routes_version.clone(),
+        );
+        self.routes = Some(routes.clone());
+
+        routes
+    }
+
+    /// Returns a snapshot of all allocations, ordered by port.
+    pub fn allocations(&self, now: Instant) -> Vec<AllocationInfo> {
+        let mut allocations = self
+            .allocations
+            .iter()
+            .map(|(client, allocation)| self.allocation_info(*client, allocation, now))
+            .collect::<Vec<_>>();
+        allocations.sort_by_key(|a| a.port.0);
+
+        allocations
+    }
+
+    /// Returns a snapshot of the allocation on the given port.
+    pub fn allocation(&self, port: AllocationPort, now: Instant) -> Option<AllocationInfo> {
+        let client = self.clients_by_allocation.get(&port)?;
+        let allocation = self.allocations.get(client)?;
+
+        Some(self.allocation_info(*client, allocation, now))
+    }
+
+    /// Forcibly deletes the allocation on the given port, including all its channels.
+    ///
+    /// Returns `false` if there is no such allocation.
+    pub fn evict_allocation(&mut self, port: AllocationPort) -> bool {
+        if !self.clients_by_allocation.contains_key(&port) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", allocation = %port, "Evicting allocation");
+
+        self.delete_allocation(port, DeletionReason::Evicted);
+
+        true
+    }
+
+    /// Forcibly deletes the channel binding with the given number of the allocation on the given port.
+    ///
+    /// The channel number can immediately be re-used by the client.
+    /// Returns `false` if there is no such channel binding.
+    pub fn evict_channel_binding(&mut self, port: AllocationPort, number: u16) -> bool {
+        let Some(client) = self.clients_by_allocation.get(&port).copied() else {
+            return false;
+        };
+        let Ok(number) = ChannelNumber::new(number) else {
+            return false;
+        };
+        let Some(channel) = self.channels_by_client_and_number.get(&(client, number)) else {
+            return false;
+        };
+
+        if channel.bound {
+            self.channels_up_down_counter.add(-1, &[]);
+            self.channel_and_client_by_port_and_peer
+                .remove(&(channel.allocation, channel.peer_address));
+            self.invalidate_routes();
+        }
+
+        self.delete_channel_binding(client, number);
+
+        true
+    }
+
+    /// Exports our state so it can be taken over by a new process via [`Server::import_state`].
+    ///
+    /// This includes all allocations, channel bindings, permissions, nonces and auth secrets.
+    /// Allocations of stream-based clients are not exported: Their connections don't survive the handover, thus the new process would mistake them for UDP clients.
+    /// Pending reservations are not exported either.
+    pub fn export_state(&self, now: Instant) -> ServerState {
+        let (auth_secret, previous_auth_secrets) = self.auth_secrets.export(now);
+
+        let allocations = self
+            .allocations
+            .iter()
+            .filter(|(client, _)| !self.is_stream_client(**client))
+            .map(|(client, allocation)| AllocationState {
+                client: client.0,
+                port: allocation.port.0,
+                expires_in: allocation.expires_at.saturating_duration_since(now),
+                first_relay_addr: allocation.first_relay_addr,
+                second_relay_addr: allocation.second_relay_addr,
+                bytes_relayed: allocation.meter.bytes_relayed(),
+                username: allocation.username.clone(),
+            })
+            .collect();
+        let channels = self
+            .channels_by_client_and_number
+            .iter()
+            .filter(|((client, _), _)| !self.is_stream_client(*client))
+            .map(|((client, number), channel)| ChannelState {
+                client: client.0,
+                number: number.value(),
+                peer: channel.peer_address.0,
+                port: channel.allocation.0,
+                bound: channel.bound,
+                expires_in: channel.expiry.saturating_duration_since(now),
+            })
+            .collect();
+        let permissions = self
+            .permissions
+            .iter()
+            .filter(|((port, _), _)| {
+                self.clients_by_allocation
+                    .get(port)
+                    .is_some_and(|client| !self.is_stream_client(*client))
+            })
+            .map(|((port, peer), expires_at)| PermissionState {
+                port: port.0,
+                peer: *peer,
+                expires_in: expires_at.saturating_duration_since(now),
+            })
+            .collect();
+
+        ServerState {
+            auth_secret,
+            previous_auth_secrets,
+            allocations,
+            channels,
+            permissions,
+            nonces: self.nonces.export(now),
+        }
+    }
+
+    /// Takes over the state exported by another [`Server`] via [`Server::export_state`].
+    ///
+    /// This replaces our auth secret.
+    /// No [`Command::CreateAllocation`]s are emitted for the imported allocations, it is the caller's responsibility to take over their sockets as well.
+    pub fn import_state(&mut self, state: ServerState, now: Instant) {
+        self.auth_secrets
+            .import(state.auth_secret, state.previous_auth_secrets, now);
+        self.nonces.import(state.nonces, now);
+
+        for allocation in state.allocations {
+            let client = ClientSocket(allocation.client);
+            let port = AllocationPort(allocation.port);
+
+            let meter = self.new_meter(client, allocation.bytes_relayed, now);
+
+            self.clients_by_allocation.insert(port, client);
+            self.allocations.insert(
+                client,
+                Allocation {
+                    port,
+                    expires_at: now + allocation.expires_in,
+                    first_relay_addr: allocation.first_relay_addr,
+                    second_relay_addr: allocation.second_relay_addr,
+                    meter,
+                    username: allocation.username,
+                },
+            );
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+
+        for channel in state.channels {
+            let Ok(number) = ChannelNumber::new(channel.number) else {
+                continue;
+            };
+            let client = ClientSocket(channel.client);
+            let peer = PeerSocket(channel.peer);
+            let port = AllocationPort(channel.port);
+
+            self.channels_by_client_and_number.insert(
+                (client, number),
+                Channel {
+                    expiry: now + channel.expires_in,
+                    peer_address: peer,
+                    allocation: port,
+                    bound: channel.bound,
+                },
+            );
+            self.channel_numbers_by_client_and_peer
+                .insert((client, peer), number);
+
+            if channel.bound {
+                self.channel_and_client_by_port_and_peer
+                    .insert((port, peer), (client, number));
+                self.channels_up_down_counter.add(1, &[]);
+            }
+        }
+
+        for permission in state.permissions {
+            self.permissions.insert(
+                (AllocationPort(permission.port), permission.peer),
+                now + permission.expires_in,
+            );
+        }
+
+        self.invalidate_routes();
+
+        tracing::info!(target: "relay", num_allocations = %self.allocations.len(), num_channels = %self.num_active_channels(), "Imported state");
+    }
+
     pub fn num_active_channels(&self) -> usize {
         self.channels_by_client_and_number
             .iter()
@@ -236,13 +727,18 @@ where
     ) -> Option<(AllocationPort, PeerSocket)> {
         tracing::trace!(target: "wire", num_bytes = %bytes.len());
 
+        // Check before decoding: even error responses to malformed requests could be used for reflection.
+        if !self.is_client_permitted(sender) {
+            return None;
+        }
+
         match self.decoder.decode(bytes) {
             Ok(Ok(message)) => {
                 return self.handle_client_message(message, sender, now);
             }
             // Could parse the bytes but message was semantically invalid (like missing attribute).
             Ok(Err(error_code)) => {
-                self.queue_error_response(sender, error_code);
+                self.queue_error_response(sender, error_code, now);
             }
             // Parsing the bytes failed.
             Err(client_message::Error::BadChannelData(ref error)) => {
@@ -254,6 +750,9 @@ where
             Err(client_message::Error::UnknownMessageType(t)) => {
                 tracing::debug!(target: "relay", r#type = %t, "unknown STUN message type")
             }
+            Err(client_message::Error::MissingAttribute(attribute)) => {
+                tracing::debug!(target: "relay", %attribute, "STUN indication is missing an attribute")
+            }
             Err(client_message::Error::Eof) => {
                 tracing::debug!(target: "relay", "unexpected EOF while parsing message")
             }
@@ -262,12 +761,90 @@ where
         None
     }
 
+    /// Process the bytes received from a client over a stream-based transport like TCP or TLS.
+    ///
+    /// Streams don't preserve message boundaries, thus we buffer the bytes until we have received complete messages.
+    /// Each complete message is then processed as if it came in via [`Server::handle_client_input`].
+    ///
+    /// # Returns
+    ///
+    /// The payloads of all [`ChannelData`] messages that should be relayed, together with the [`AllocationPort`] and [`PeerSocket`] to forward them to.
+    /// Fails if the stream contains invalid framing, in which case the connection should be closed.
+    pub fn handle_client_stream_input(
+        &mut self,
+        bytes: &[u8],
+        sender: ClientSocket,
+        now: Instant,
+    ) -> io::Result<Vec<(AllocationPort, PeerSocket, Vec<u8>)>> {
+        if !self.is_client_permitted(sender) {
+            return Ok(Vec::new());
+        }
+
+        let buffer = self.stream_buffers.entry(sender).or_default();
+        buffer.extend(bytes);
+
+        let mut messages = Vec::new();
+
+        loop {
+            match buffer.next_message() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(e) => {
+                    self.stream_buffers.remove(&sender);
+
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut to_relay = Vec::new();
+
+        for message in messages {
+            let Some((port, peer)) = self.handle_client_input(&message, sender, now) else {
+                continue;
+            };
+
+            // Re-parse as `ChannelData` if we should relay it.
+            let payload = ChannelData::parse(&message)
+                .expect("valid ChannelData if we should relay it")
+                .data()
+                .to_vec();
+
+            if let Some(captures) = self.captures.as_ref() {
+                captures.record_client_to_peer(sender, port, peer, &message, &payload);
+            }
+
+            to_relay.push((port, peer, payload));
+        }
+
+        Ok(to_relay)
+    }
+
+    /// A stream-based connection to a client was closed.
+    ///
+    /// The allocation of a client is bound to the 5-tuple of its connection.
+    /// Once the connection is gone, the client can never use the allocation again, thus we delete it.
+    #[tracing::instrument(level = "debug", skip(self), fields(%sender))]
+    pub fn handle_client_stream_closed(&mut self, sender: ClientSocket) {
+        self.stream_buffers.remove(&sender);
+
+        let Some(port) = self.allocations.get(&sender).map(|a| a.port) else {
+            return;
+        };
+
+        self.delete_allocation(port, DeletionReason::ClientDisconnected);
+    }
+
     pub fn handle_client_message(
         &mut self,
         message: ClientMessage,
         sender: ClientSocket,
         now: Instant,
     ) -> Option<(AllocationPort, PeerSocket)> {
+        if !self.is_client_permitted(sender) {
+            return None;
+        }
+
         let result = match message {
             ClientMessage::Allocate(request) => self.handle_allocate_request(request, sender, now),
             ClientMessage::Refresh(request) => self.handle_refresh_request(request, sender, now),
@@ -275,14 +852,18 @@ where
                 self.handle_channel_bind_request(request, sender, now)
             }
             ClientMessage::CreatePermission(request) => {
-                self.handle_create_permission_request(request, sender)
+                self.handle_create_permission_request(request, sender, now)
+            }
+            ClientMessage::SendIndication(indication) => {
+                self.handle_send_indication(indication, sender, now);
+                return None;
             }
             ClientMessage::Binding(request) => {
-                self.handle_binding_request(request, sender);
+                self.handle_binding_request(request, sender, now);
                 return None;
             }
             ClientMessage::ChannelData(msg) => {
-                return self.handle_channel_data_message(msg, sender);
+                return self.handle_channel_data_message(msg, sender, now);
             }
         };
 
@@ -290,7 +871,7 @@ where
             return None;
         };
 
-        self.queue_error_response(sender, error_response);
+        self.queue_error_response(sender, error_response, now);
 
         None
     }
@@ -299,17 +880,28 @@ where
         &mut self,
         sender: ClientSocket,
         mut error_response: Message<Attribute>,
+        now: Instant,
     ) {
         let Some(error) = error_response.get_attribute::<ErrorCode>().cloned() else {
             debug_assert!(false, "Error response without an `ErrorCode`");
             return;
         };
 
+        let is_auth_challenge =
+            error == ErrorCode::from(Unauthorized) || error == ErrorCode::from(StaleNonce);
+
+        // Senders without an allocation may have spoofed their address, thus all errors sent to them count as unauthenticated.
+        if (is_auth_challenge || !self.allocations.contains_key(&sender))
+            && !self.allow_unauthenticated_request(sender, now)
+        {
+            return;
+        }
+
         // In case of a 401 or 438 response, attach a realm and nonce.
-        if error == ErrorCode::from(Unauthorized) || error == ErrorCode::from(StaleNonce) {
+        if is_auth_challenge {
             let new_nonce = Uuid::from_u128(self.rng.gen());
 
-            self.add_nonce(new_nonce);
+            self.add_nonce(new_nonce, sender, now);
 
             error_response.add_attribute(Nonce::new(new_nonce.to_string()).unwrap());
             error_response.add_attribute((*FIREZONE).clone());
@@ -326,36 +918,43 @@ where
     ///
     /// - [`Some`] if there is an active channel on this allocation for this peer.
     ///   In that case, you should create a [`ChannelData`] message with the returned channel number and send it to the [`ClientSocket`].
+    /// - [`None`] otherwise.
+    ///   If the allocation has a permission for the peer, the data is sent to the client in a DATA indication via [`Command::SendMessage`].
+    ///
+    /// Data exceeding one of the configured bandwidth limits is dropped.
     #[tracing::instrument(level = "debug", skip_all, fields(%sender, %allocation, recipient, channel))]
     pub fn handle_peer_traffic(
         &mut self,
         msg: &[u8],
         sender: PeerSocket,
         allocation: AllocationPort,
+        now: Instant,
     ) -> Option<(ClientSocket, ChannelNumber)> {
         let Some((client, channel_number)) = self
             .channel_and_client_by_port_and_peer
             .get(&(allocation, sender))
+            .copied()
         else {
-            tracing::debug!(target: "relay", "no channel");
+            self.send_data_indication(msg, sender, allocation, now);
 
             return None;
         };
 
         Span::current().record("recipient", field::display(&client));
 
-        self.data_relayed_counter.add(msg.len() as u64, &[]);
-        self.data_relayed += msg.len() as u64;
+        if !self.account_relayed_data(client, msg.len(), Direction::PeerToClient, now) {
+            return None;
+        }
 
         tracing::trace!(target: "wire", num_bytes = %msg.len());
 
-        Some((*client, *channel_number))
+        Some((client, channel_number))
     }
 
     /// An allocation failed.
     #[tracing::instrument(level = "debug", skip(self), fields(%allocation))]
     pub fn handle_allocation_failed(&mut self, allocation: AllocationPort) {
-        self.delete_allocation(allocation)
+        self.delete_allocation(allocation, DeletionReason::Failed)
     }
 
     /// Return the next command to be executed.
@@ -363,6 +962,13 @@ where
         self.pending_commands.pop_front()
     }
 
+    /// Return the next record for the audit log.
+    ///
+    /// Only returns records if the audit log is enabled via [`Server::with_audit_log`].
+    pub fn next_audit_record(&mut self) -> Option<AuditRecord> {
+        self.pending_audit_records.pop_front()
+    }
+
     // TODO: It might be worth to do some caching here?
     pub fn poll_timeout(&self) -> Option<Instant> {
         let channel_expiries = self.channels_by_client_and_number.values().map(|c| {
@@ -373,9 +979,12 @@ where
             }
         });
         let allocation_expiries = self.allocations.values().map(|a| a.expires_at);
+        let reservation_expiries = self.reservations.values().map(|r| r.expires_at);
 
+        // Permissions are checked against their expiry when used, so we don't need to wake up for them.
         channel_expiries
             .chain(allocation_expiries)
+            .chain(reservation_expiries)
             .fold(None, |current, next| earliest(current, Some(next)))
     }
 
@@ -387,9 +996,41 @@ where
             .collect::<Vec<_>>();
 
         for id in expired_allocations {
-            self.delete_allocation(id);
+            self.delete_allocation(id, DeletionReason::Expired);
         }
 
+        self.permissions.retain(|(allocation, peer), expiry| {
+            if *expiry > now {
+                return true;
+            }
+
+            tracing::debug!(target: "relay", %allocation, %peer, "Permission is now expired");
+
+            false
+        });
+
+        self.nonces.handle_timeout(now);
+        self.reservations.retain(|port, reservation| {
+            if reservation.expires_at > now {
+                return true;
+            }
+
+            tracing::debug!(target: "relay", %port, "Reservation is now expired");
+
+            false
+        });
+        self.auth_secrets.handle_timeout(now);
+
+        // Once no allocation of a client IP is left, we no longer need its bucket.
+        // Outdated [`Routes`] may still reference it but those will be replaced soon.
+        self.client_ip_buckets
+            .retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        // A full bucket is equivalent to a new one, no need to keep it around.
+        self.unauthenticated_request_buckets
+            .retain(|_, bucket| !bucket.is_full(now));
+
+        let mut num_unbound_channels = 0;
+
         for ((client, number), channel) in self
             .channels_by_client_and_number
             .iter_mut()
@@ -398,8 +1039,14 @@ where
             tracing::info!(target: "relay", channel = %number.value(), %client, peer = %channel.peer_address, allocation = %channel.allocation, "Channel is now expired");
 
             channel.bound = false;
+            self.channels_up_down_counter.add(-1, &[]);
             self.channel_and_client_by_port_and_peer
                 .remove(&(channel.allocation, channel.peer_address));
+            num_unbound_channels += 1;
+        }
+
+        if num_unbound_channels > 0 {
+            self.invalidate_routes();
         }
 
         let channels_to_delete = self
@@ -414,7 +1061,11 @@ where
     }
 
     #[tracing::instrument(level = "info", skip_all, fields(transaction_id = ?request.transaction_id(), %sender))]
-    fn handle_binding_request(&mut self, request: Binding, sender: ClientSocket) {
+    fn handle_binding_request(&mut self, request: Binding, sender: ClientSocket, now: Instant) {
+        if !self.allow_unauthenticated_request(sender, now) {
+            return;
+        }
+
         let mut message = Message::new(
             MessageClass::SuccessResponse,
             BINDING,
@@ -437,7 +1088,7 @@ where
         sender: ClientSocket,
         now: Instant,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request)?;
+        self.verify_auth(&request, sender, now)?;
 
         if let Some(allocation) = self.allocations.get(&sender) {
             Span::current().record("allocation", display(&allocation.port));
@@ -446,11 +1097,18 @@ where
             return Err(error_response(AllocationMismatch, &request));
         }
 
+        if self.draining {
+            tracing::info!(target: "relay", "Refusing new allocation because we are draining");
+
+            return Err(self.out_of_capacity_response(&request, sender));
+        }
+
+        // A reservation token refers to an already reserved port, thus we don't need a free one.
         let max_available_ports = self.max_available_ports() as usize;
-        if self.clients_by_allocation.len() == max_available_ports {
+        if request.reservation_token().is_none() && self.num_used_ports() >= max_available_ports {
             tracing::warn!(target: "relay", %max_available_ports, "No more ports available");
 
-            return Err(error_response(InsufficientCapacity, &request));
+            return Err(self.out_of_capacity_response(&request, sender));
         }
 
         let requested_protocol = request.requested_transport().protocol();
@@ -460,23 +1118,87 @@ where
             return Err(error_response(BadRequest, &request));
         }
 
-        let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
-            self.public_address,
-            request.requested_address_family(),
-            request.additional_address_family(),
-        )
-        .map_err(|e| error_response(e, &request))?;
+        // See <https://www.rfc-editor.org/rfc/rfc8656#section-7.2-3.5>.
+        if request.reservation_token().is_some()
+            && (request.even_port().is_some() || request.requested_address_family().is_some())
+        {
+            tracing::warn!(target: "relay", "RESERVATION-TOKEN must not be combined with EVEN-PORT or REQUESTED-ADDRESS-FAMILY");
+
+            return Err(error_response(BadRequest, &request));
+        }
 
-        // TODO: Do we need to handle DONT-FRAGMENT?
-        // TODO: Do we need to handle EVEN/ODD-PORT?
         let effective_lifetime = request.effective_lifetime();
 
-        let allocation = self.create_new_allocation(
-            now,
-            &effective_lifetime,
-            first_relay_address,
-            maybe_second_relay_addr,
-        );
+        let (port, first_relay_address, maybe_second_relay_addr, reservation_token) = match (
+            request.reservation_token(),
+            request.even_port(),
+        ) {
+            (Some(token), _) => {
+                let Some((port, reservation)) = self.redeem_reservation(token.value(), now) else {
+                    tracing::warn!(target: "relay", token = %token.value(), "Unknown or expired reservation token");
+
+                    return Err(error_response(InsufficientCapacity, &request));
+                };
+
+                (
+                    port,
+                    reservation.first_relay_addr,
+                    reservation.second_relay_addr,
+                    None,
+                )
+            }
+            (None, even_port) => {
+                let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
+                    self.public_address,
+                    request.requested_address_family(),
+                    request.additional_address_family(),
+                )
+                .map_err(|e| error_response(e, &request))?;
+
+                let (port, reservation_token) = match even_port {
+                    None => (self.random_free_port(), None),
+                    Some(even_port) => {
+                        let reserve_next = even_port.is_requested();
+
+                        let Some(port) = self.random_free_even_port(reserve_next) else {
+                            tracing::warn!(target: "relay", %reserve_next, "No even port available");
+
+                            return Err(self.out_of_capacity_response(&request, sender));
+                        };
+
+                        let reservation_token = reserve_next.then(|| {
+                            self.reserve_port(
+                                AllocationPort(port.0 + 1),
+                                first_relay_address,
+                                maybe_second_relay_addr,
+                                now,
+                            )
+                        });
+
+                        (port, reservation_token)
+                    }
+                };
+
+                (
+                    port,
+                    first_relay_address,
+                    maybe_second_relay_addr,
+                    reservation_token,
+                )
+            }
+        };
+
+        let allocation = Allocation {
+            port,
+            expires_at: now + effective_lifetime.lifetime(),
+            first_relay_addr: first_relay_address,
+            second_relay_addr: maybe_second_relay_addr,
+            meter: self.new_meter(sender, 0, now),
+            username: request
+                .username()
+                .map(|u| u.name().to_owned())
+                .unwrap_or_default(),
+        };
 
         let mut message = Message::new(
             MessageClass::SuccessResponse,
@@ -500,14 +1222,22 @@ where
         message.add_attribute(XorMappedAddress::new(sender.0));
         message.add_attribute(effective_lifetime.clone());
 
+        if let Some(token) = reservation_token {
+            message.add_attribute(ReservationToken::new(token));
+        }
+
+        let dont_fragment = request.dont_fragment();
+
         self.pending_commands.push_back(Command::CreateAllocation {
             port: allocation.port,
             family: first_relay_address.family(),
+            dont_fragment,
         });
         if let Some(second_relay_addr) = maybe_second_relay_addr {
             self.pending_commands.push_back(Command::CreateAllocation {
                 port: allocation.port,
                 family: second_relay_addr.family(),
+                dont_fragment,
             });
         }
         self.send_message(message, sender);
@@ -531,6 +1261,15 @@ where
             )
         }
 
+        let record = AuditRecord::AllocationCreated {
+            client: sender.0,
+            username: allocation.username.clone(),
+            port: allocation.port.value(),
+            families: allocation.families().map(family_name).collect(),
+            lifetime_secs: effective_lifetime.lifetime().as_secs(),
+        };
+        self.record_audit(record);
+
         self.clients_by_allocation.insert(allocation.port, sender);
         self.allocations.insert(sender, allocation);
         self.allocations_up_down_counter.add(1, &[]);
@@ -548,7 +1287,7 @@ where
         sender: ClientSocket,
         now: Instant,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request)?;
+        self.verify_auth(&request, sender, now)?;
 
         // TODO: Verify that this is the correct error code.
         let allocation = self
@@ -563,7 +1302,7 @@ where
         if effective_lifetime.lifetime().is_zero() {
             let port = allocation.port;
 
-            self.delete_allocation(port);
+            self.delete_allocation(port, DeletionReason::Released);
             self.send_message(
                 refresh_success_response(effective_lifetime, request.transaction_id()),
                 sender,
@@ -576,6 +1315,15 @@ where
 
         tracing::info!(target: "relay", "Refreshed allocation");
 
+        let record = AuditRecord::AllocationRefreshed {
+            client: sender.0,
+            username: allocation.username.clone(),
+            port: allocation.port.value(),
+            lifetime_secs: effective_lifetime.lifetime().as_secs(),
+            bytes_relayed: allocation.meter.bytes_relayed(),
+        };
+        self.record_audit(record);
+
         self.send_message(
             refresh_success_response(effective_lifetime, request.transaction_id()),
             sender,
@@ -594,7 +1342,7 @@ where
         sender: ClientSocket,
         now: Instant,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request)?;
+        self.verify_auth(&request, sender, now)?;
 
         let allocation = self
             .allocations
@@ -639,10 +1387,13 @@ where
                 return Err(error_response(BadRequest, &request));
             }
 
-            // Binding requests for existing channels act as a refresh for the binding.
+            // Binding requests for existing channels act as a refresh for the binding and, separately, for the permission.
 
             channel.refresh(now);
 
+            let port = channel.allocation;
+            self.install_permission(port, peer_address.0.ip(), now + PERMISSION_LIFETIME);
+
             tracing::info!(target: "relay", "Refreshed channel binding");
 
             self.send_message(
@@ -659,7 +1410,16 @@ where
         // TODO: Capacity checking would go here.
 
         let port = allocation.port;
+        let record = AuditRecord::ChannelBound {
+            client: sender.0,
+            username: allocation.username.clone(),
+            port: port.value(),
+            channel: requested_channel.value(),
+            peer: peer_address.0,
+        };
+
         self.create_channel_binding(sender, requested_channel, peer_address, port, now);
+        self.install_permission(port, peer_address.0.ip(), now + PERMISSION_LIFETIME);
         self.send_message(
             channel_bind_success_response(request.transaction_id()),
             sender,
@@ -667,6 +1427,8 @@ where
 
         tracing::info!(target: "relay", "Successfully bound channel");
 
+        self.record_audit(record);
+
         Ok(())
     }
 
@@ -674,15 +1436,45 @@ where
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
     ///
-    /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
-    #[tracing::instrument(level = "info", skip_all, fields(transaction_id = ?request.transaction_id(), %sender))]
+    /// Permissions are only relevant for data relayed via SEND and DATA indications.
+    /// Channel bindings implicitly install a permission for as long as the channel is bound.
+    #[tracing::instrument(level = "info", skip_all, fields(allocation, transaction_id = ?request.transaction_id(), %sender))]
     fn handle_create_permission_request(
         &mut self,
         request: CreatePermission,
         sender: ClientSocket,
+        now: Instant,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request)?;
+        self.verify_auth(&request, sender, now)?;
+
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &request))?;
+
+        Span::current().record("allocation", display(&allocation.port));
+
+        // Either all permissions are installed or none.
+        if let Some(peer) = request
+            .xor_peer_addresses()
+            .iter()
+            .map(|a| PeerSocket(a.address()))
+            .find(|peer| !allocation.can_relay_to(*peer))
+        {
+            tracing::warn!(target: "relay", %peer, "Allocation cannot relay to peer");
+
+            return Err(error_response(PeerAddressFamilyMismatch, &request));
+        }
+
+        let port = allocation.port;
+
+        for xor_peer_address in request.xor_peer_addresses() {
+            let peer = xor_peer_address.address().ip();
+
+            self.install_permission(port, peer, now + PERMISSION_LIFETIME);
+
+            tracing::info!(target: "relay", %peer, "Installed permission");
+        }
 
         self.send_message(
             create_permission_success_response(request.transaction_id()),
@@ -692,11 +1484,134 @@ where
         Ok(())
     }
 
+    /// Handle a TURN send indication.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication> for details.
+    ///
+    /// Indications are never answered, thus any failure results in the indication being silently discarded.
+    #[tracing::instrument(level = "debug", skip_all, fields(allocation, recipient, %sender))]
+    fn handle_send_indication(
+        &mut self,
+        indication: SendIndication,
+        sender: ClientSocket,
+        now: Instant,
+    ) {
+        let Some(allocation) = self.allocations.get(&sender) else {
+            tracing::debug!(target: "relay", "Client has no allocation, discarding SEND indication");
+            return;
+        };
+
+        let port = allocation.port;
+        let peer = PeerSocket(indication.xor_peer_address().address());
+
+        Span::current().record("allocation", field::display(&port));
+        Span::current().record("recipient", field::display(&peer));
+
+        if !self.has_permission(port, peer.0.ip(), now) {
+            tracing::debug!(target: "relay", "No permission for peer, discarding SEND indication");
+            return;
+        }
+
+        let data = indication.data();
+
+        if !self.account_relayed_data(sender, data.len(), Direction::ClientToPeer, now) {
+            return;
+        }
+
+        tracing::trace!(target: "wire", num_bytes = %data.len());
+
+        if let Some(captures) = self.captures.as_ref().filter(|c| c.is_active()) {
+            // We only have the decoded indication, thus re-encode it for the capture.
+            let mut message =
+                Message::new(MessageClass::Indication, SEND, indication.transaction_id());
+            message.add_attribute(indication.xor_peer_address().clone());
+            message
+                .add_attribute(Data::new(data.to_vec()).expect("data of a SEND indication to fit"));
+
+            if let Ok(bytes) = self.encoder.encode_into_bytes(message) {
+                captures.record_client_to_peer(sender, port, peer, &bytes, data);
+            }
+        }
+
+        self.pending_commands.push_back(Command::RelayToPeer {
+            payload: data.to_vec(),
+            port,
+            peer,
+        });
+    }
+
+    /// Sends data received from a peer to the client in a DATA indication, if the allocation has a permission for the peer.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-ip-datagram-fo>.
+    fn send_data_indication(
+        &mut self,
+        msg: &[u8],
+        sender: PeerSocket,
+        allocation: AllocationPort,
+        now: Instant,
+    ) {
+        if !self.has_permission(allocation, sender.0.ip(), now) {
+            tracing::debug!(target: "relay", "no channel or permission");
+            return;
+        }
+
+        let Some(client) = self.clients_by_allocation.get(&allocation).copied() else {
+            debug_assert!(false, "permission without allocation");
+            return;
+        };
+
+        let Ok(data) = Data::new(msg.to_vec()) else {
+            tracing::debug!(target: "relay", num_bytes = %msg.len(), "Data does not fit into a DATA indication");
+            return;
+        };
+
+        Span::current().record("recipient", field::display(&client));
+
+        if !self.account_relayed_data(client, msg.len(), Direction::PeerToClient, now) {
+            return;
+        }
+
+        tracing::trace!(target: "wire", num_bytes = %msg.len());
+
+        let mut message = Message::new(
+            MessageClass::Indication,
+            DATA,
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(XorPeerAddress::new(sender.0));
+        message.add_attribute(data);
+
+        if let Some(captures) = self.captures.as_ref().filter(|c| c.is_active()) {
+            if let Ok(bytes) = self.encoder.encode_into_bytes(message.clone()) {
+                captures.record_peer_to_client(sender, allocation, client, msg, &[&bytes]);
+            }
+        }
+
+        self.send_message(message, client);
+    }
+
+    fn has_permission(&self, allocation: AllocationPort, peer: IpAddr, now: Instant) -> bool {
+        self.permissions
+            .get(&(allocation, peer))
+            .is_some_and(|expiry| *expiry > now)
+    }
+
+    /// Installs or refreshes the permission for the given peer IP on the given allocation.
+    ///
+    /// A permission is never shortened, i.e. if there is already a permission that expires later, we keep that.
+    fn install_permission(&mut self, allocation: AllocationPort, peer: IpAddr, expiry: Instant) {
+        self.permissions
+            .entry((allocation, peer))
+            .and_modify(|existing| *existing = std::cmp::max(*existing, expiry))
+            .or_insert(expiry);
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(allocation, recipient, channel, %sender))] // It is important that this is level `debug` otherwise performance is shit!
     fn handle_channel_data_message(
         &mut self,
         message: ChannelData,
         sender: ClientSocket,
+        now: Instant,
     ) -> Option<(AllocationPort, PeerSocket)> {
         let channel_number = message.channel();
         let data = message.data();
@@ -717,21 +1632,155 @@ where
             return None;
         }
 
-        Span::current().record("allocation", field::display(&channel.allocation));
-        Span::current().record("recipient", field::display(&channel.peer_address));
+        let allocation = channel.allocation;
+        let peer = channel.peer_address;
+
+        Span::current().record("allocation", field::display(&allocation));
+        Span::current().record("recipient", field::display(&peer));
         Span::current().record("channel", field::display(&channel_number.value()));
 
+        if !self.account_relayed_data(sender, data.len(), Direction::ClientToPeer, now) {
+            return None;
+        }
+
         tracing::trace!(target: "wire", num_bytes = %data.len());
 
-        self.data_relayed_counter.add(data.len() as u64, &[]);
-        self.data_relayed += data.len() as u64;
+        Some((allocation, peer))
+    }
 
-        Some((channel.allocation, channel.peer_address))
+    /// Accounts for `num_bytes` being relayed via the allocation of the given client.
+    ///
+    /// Returns `false` if this would exceed one of the configured bandwidth limits, in which case the data must be dropped.
+    fn account_relayed_data(
+        &self,
+        client: ClientSocket,
+        num_bytes: usize,
+        direction: Direction,
+        now: Instant,
+    ) -> bool {
+        let Some(allocation) = self.allocations.get(&client) else {
+            debug_assert!(false, "relaying data without an allocation");
+            return false;
+        };
+
+        allocation.meter.account(num_bytes, direction, now)
+    }
+
+    /// Creates a [`Meter`] for a new allocation of the given client, enforcing the configured bandwidth limits.
+    fn new_meter(&mut self, client: ClientSocket, bytes_relayed: u64, now: Instant) -> Arc<Meter> {
+        let allocation_bucket = self
+            .allocation_bandwidth_limit
+            .map(|limit| TokenBucket::for_bandwidth(limit, now));
+        let client_ip_bucket = self.client_ip_bandwidth_limit.map(|limit| {
+            self.client_ip_buckets
+                .entry(client.0.ip())
+                .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::for_bandwidth(limit, now))))
+                .clone()
+        });
+
+        Arc::new(Meter::new(
+            bytes_relayed,
+            allocation_bucket,
+            client_ip_bucket,
+            self.totals.clone(),
+        ))
+    }
+
+    /// Marks all [`Routes`] handed out so far as outdated.
+    fn invalidate_routes(&mut self) {
+        self.routes = None;
+        self.routes_version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Creates the response for an allocate request that we cannot serve.
+    ///
+    /// If we know an alternate server that the client can reach, we redirect the client to it with a 300 (Try Alternate).
+    /// Otherwise, we respond with a 508 (Insufficient Capacity).
+    fn out_of_capacity_response(
+        &self,
+        request: &Allocate,
+        sender: ClientSocket,
+    ) -> Message<Attribute> {
+        let Some(alternate_server) = self
+            .alternate_servers
+            .iter()
+            .find(|s| s.is_ipv4() == sender.0.is_ipv4())
+        else {
+            return error_response(InsufficientCapacity, request);
+        };
+
+        tracing::info!(target: "relay", %alternate_server, "Redirecting client to alternate server");
+
+        let mut message = error_response(TryAlternate, request);
+        message.add_attribute(AlternateServer::new(*alternate_server));
+
+        message
+    }
+
+    /// Checks the client against our allow and deny lists.
+    fn is_client_permitted(&self, client: ClientSocket) -> bool {
+        let ip = client.0.ip();
+
+        if self.client_deny_list.iter().any(|n| n.contains(ip)) {
+            tracing::debug!(target: "relay", %client, "Client is on deny list, dropping message");
+
+            self.record_rejected_request("deny_list");
+            return false;
+        }
+
+        if !self.client_allow_list.is_empty()
+            && !self.client_allow_list.iter().any(|n| n.contains(ip))
+        {
+            tracing::debug!(target: "relay", %client, "Client is not on allow list, dropping message");
+
+            self.record_rejected_request("allow_list");
+            return false;
+        }
+
+        true
+    }
+
+    /// Checks whether we may answer another unauthenticated request from this client.
+    fn allow_unauthenticated_request(&mut self, client: ClientSocket, now: Instant) -> bool {
+        let Some(limit) = self.unauthenticated_request_rate_limit else {
+            return true;
+        };
+
+        let bucket = self
+            .unauthenticated_request_buckets
+            .entry(client.0.ip())
+            .or_insert_with(|| TokenBucket::for_requests(limit, now));
+
+        if !bucket.has(1, now) {
+            tracing::debug!(target: "relay", %client, "Client exceeded unauthenticated request rate limit, dropping request");
+
+            self.record_rejected_request("rate_limit");
+            return false;
+        }
+
+        bucket.take(1);
+
+        true
+    }
+
+    fn record_audit(&mut self, record: AuditRecord) {
+        if !self.audit_log {
+            return;
+        }
+
+        self.pending_audit_records.push_back(record);
+    }
+
+    fn record_rejected_request(&self, reason: &'static str) {
+        self.rejected_requests_counter
+            .add(1, &[KeyValue::new("reason", reason)]);
     }
 
     fn verify_auth(
         &mut self,
         request: &(impl StunRequest + ProtectedRequest),
+        sender: ClientSocket,
+        now: Instant,
     ) -> Result<(), Message<Attribute>> {
         let message_integrity = request
             .message_integrity()
@@ -749,41 +1798,136 @@ where
             })?;
 
         self.nonces
-            .handle_nonce_used(nonce)
+            .handle_nonce_used(nonce, sender, now)
             .map_err(|_| error_response(StaleNonce, request))?;
 
         message_integrity
-            .verify(&self.auth_secret, username.name(), SystemTime::now()) // This is impure but we don't need to control this in our tests.
+            .verify(
+                self.auth_secrets.valid(now),
+                username.name(),
+                SystemTime::now(), // This is impure but we don't need to control this in our tests.
+            )
             .map_err(|_| error_response(Unauthorized, request))?;
 
         Ok(())
     }
 
-    fn create_new_allocation(
-        &mut self,
-        now: Instant,
-        lifetime: &Lifetime,
-        first_relay_addr: IpAddr,
-        second_relay_addr: Option<IpAddr>,
-    ) -> Allocation {
+    /// Picks a random port that is neither allocated nor reserved.
+    fn random_free_port(&mut self) -> AllocationPort {
         assert!(
-            self.clients_by_allocation.len() < self.max_available_ports() as usize,
+            self.num_used_ports() < self.max_available_ports() as usize,
             "No more ports available; this would loop forever"
         );
 
-        let port = loop {
+        loop {
             let candidate = AllocationPort(self.rng.gen_range(self.lowest_port..self.highest_port));
 
-            if !self.clients_by_allocation.contains_key(&candidate) {
+            if self.is_port_free(candidate) {
                 break candidate;
             }
-        };
+        }
+    }
 
-        Allocation {
+    /// Picks a random, even port that is neither allocated nor reserved.
+    ///
+    /// If `reserve_next` is set, the next-higher port must also be free so we can reserve it.
+    fn random_free_even_port(&mut self, reserve_next: bool) -> Option<AllocationPort> {
+        let lowest_port = self.lowest_port as u32;
+        let num_ports = self.max_available_ports() as u32;
+        let start = self.rng.gen_range(self.lowest_port..self.highest_port) as u32;
+
+        (0..num_ports)
+            .map(|offset| lowest_port + (start - lowest_port + offset) % num_ports)
+            .filter(|port| port % 2 == 0)
+            .map(|port| AllocationPort(port as u16))
+            .find(|port| {
+                if !self.is_port_free(*port) {
+                    return false;
+                }
+
+                if !reserve_next {
+                    return true;
+                }
+
+                port.0 + 1 < self.highest_port && self.is_port_free(AllocationPort(port.0 + 1))
+            })
+    }
+
+    fn is_port_free(&self, port: AllocationPort) -> bool {
+        !self.clients_by_allocation.contains_key(&port) && !self.reservations.contains_key(&port)
+    }
+
+    fn num_used_ports(&self) -> usize {
+        self.clients_by_allocation.len() + self.reservations.len()
+    }
+
+    /// Reserves the given port for a future allocation and returns the token to claim it.
+    fn reserve_port(
+        &mut self,
+        port: AllocationPort,
+        first_relay_addr: IpAddr,
+        second_relay_addr: Option<IpAddr>,
+        now: Instant,
+    ) -> u64 {
+        let token = self.rng.gen();
+
+        self.reservations.insert(
             port,
-            expires_at: now + lifetime.lifetime(),
-            first_relay_addr,
-            second_relay_addr,
+            Reservation {
+                token,
+                expires_at: now + RESERVATION_LIFETIME,
+                first_relay_addr,
+                second_relay_addr,
+            },
+        );
+
+        tracing::debug!(target: "relay", %port, "Reserved port");
+
+        token
+    }
+
+    fn redeem_reservation(
+        &mut self,
+        token: u64,
+        now: Instant,
+    ) -> Option<(AllocationPort, Reservation)> {
+        let port = self
+            .reservations
+            .iter()
+            .find_map(|(port, r)| (r.token == token && r.expires_at > now).then_some(*port))?;
+        let reservation = self.reservations.remove(&port)?;
+
+        Some((port, reservation))
+    }
+
+    fn allocation_info(
+        &self,
+        client: ClientSocket,
+        allocation: &Allocation,
+        now: Instant,
+    ) -> AllocationInfo {
+        let families = allocation.families().collect();
+
+        let mut channels = self
+            .channels_by_client_and_number
+            .iter()
+            .filter(|(_, c)| c.allocation == allocation.port)
+            .map(|((_, number), c)| ChannelInfo {
+                number: number.value(),
+                peer: c.peer_address,
+                bound: c.bound,
+                expires_in: c.expiry.saturating_duration_since(now),
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| c.number);
+
+        AllocationInfo {
+            client,
+            port: allocation.port,
+            families,
+            expires_in: allocation.expires_at.saturating_duration_since(now),
+            bytes_relayed: allocation.meter.bytes_relayed(),
+            channels,
         }
     }
 
@@ -811,6 +1955,7 @@ where
             },
         );
         debug_assert!(existing.is_none());
+        self.channels_up_down_counter.add(1, &[]);
 
         let existing = self
             .channel_numbers_by_client_and_peer
@@ -823,6 +1968,8 @@ where
             .insert((id, peer), (client, requested_channel));
 
         debug_assert!(existing.is_none());
+
+        self.invalidate_routes();
     }
 
     fn send_message(&mut self, message: Message<Attribute>, recipient: ClientSocket) {
@@ -865,7 +2012,7 @@ where
         );
     }
 
-    fn delete_allocation(&mut self, port: AllocationPort) {
+    fn delete_allocation(&mut self, port: AllocationPort, reason: DeletionReason) {
         let Some(client) = self.clients_by_allocation.remove(&port) else {
             tracing::debug!(target: "relay", "Unable to delete unknown allocation");
 
@@ -902,11 +2049,27 @@ where
                     "internal state to be consistent"
                 );
 
+                if c.bound {
+                    self.channels_up_down_counter.add(-1, &[]);
+                }
+
                 tracing::info!(%peer, %number, "Deleted channel binding");
 
                 false
             });
 
+        self.permissions
+            .retain(|(allocation, _), _| *allocation != port);
+        self.invalidate_routes();
+
+        self.record_audit(AuditRecord::AllocationDeleted {
+            client: client.0,
+            username: allocation.username.clone(),
+            port: port.value(),
+            bytes_relayed: allocation.meter.bytes_relayed(),
+            reason,
+        });
+
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
             port,
@@ -974,6 +2137,21 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// Accounts for the data relayed through this allocation.
+    meter: Arc<Meter>,
+
+    /// The TURN username the allocation was created with.
+    username: String,
+}
+
+/// A port reserved via EVEN-PORT, waiting to be claimed via RESERVATION-TOKEN.
+struct Reservation {
+    token: u64,
+    expires_at: Instant,
+
+    first_relay_addr: IpAddr,
+    second_relay_addr: Option<IpAddr>,
 }
 
 struct Channel {
@@ -1032,6 +2210,12 @@ impl Allocation {
     fn is_expired(&self, now: Instant) -> bool {
         self.expires_at <= now
     }
+
+    fn families(&self) -> impl Iterator<Item = AddressFamily> {
+        iter::once(self.first_relay_addr)
+            .chain(self.second_relay_addr)
+            .map(|addr| addr.family())
+    }
 }
 
 fn error_response(
@@ -1196,7 +2380,12 @@ stun_codec::define_attribute_enums!(
         Realm,
         Username,
         RequestedAddressFamily,
-        AdditionalAddressFamily
+        AdditionalAddressFamily,
+        Data,
+        DontFragment,
+        EvenPort,
+        ReservationToken,
+        AlternateServer
     ]
 );
 
@@ -10,9 +10,10 @@ use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Userna
 use stun_codec::rfc5389::errors::BadRequest;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress,
+    ChannelNumber, Data, DontFragment, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+    XorPeerAddress,
 };
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH, SEND};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
@@ -65,10 +66,14 @@ impl Decoder {
                     (CHANNEL_BIND, Request) => {
                         Ok(ChannelBind::parse(&message).map(ClientMessage::ChannelBind))
                     }
-                    (CREATE_PERMISSION, Request) => Ok(Ok(ClientMessage::CreatePermission(
-                        CreatePermission::parse(&message),
-                    ))),
+                    (CREATE_PERMISSION, Request) => {
+                        Ok(CreatePermission::parse(&message).map(ClientMessage::CreatePermission))
+                    }
                     (_, Request) => Ok(Err(bad_request(&message))),
+                    // Indications are never answered, not even with an error.
+                    (SEND, Indication) => Ok(Ok(ClientMessage::SendIndication(
+                        SendIndication::parse(&message)?,
+                    ))),
                     (method, class) => {
                         Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
                             io::ErrorKind::Unsupported,
@@ -95,6 +100,7 @@ pub enum ClientMessage<'a> {
     Refresh(Refresh),
     ChannelBind(ChannelBind),
     CreatePermission(CreatePermission),
+    SendIndication(SendIndication),
 }
 
 impl ClientMessage<'_> {
@@ -105,6 +111,7 @@ impl ClientMessage<'_> {
             ClientMessage::Refresh(request) => Some(request.transaction_id),
             ClientMessage::ChannelBind(request) => Some(request.transaction_id),
             ClientMessage::CreatePermission(request) => Some(request.transaction_id),
+            ClientMessage::SendIndication(indication) => Some(indication.transaction_id),
             ClientMessage::ChannelData(_) => None,
         }
     }
@@ -140,6 +147,9 @@ pub struct Allocate {
     nonce: Option<Nonce>,
     requested_address_family: Option<RequestedAddressFamily>,
     additional_address_family: Option<AdditionalAddressFamily>,
+    dont_fragment: bool,
+    even_port: Option<EvenPort>,
+    reservation_token: Option<ReservationToken>,
 }
 
 impl Allocate {
@@ -156,7 +166,70 @@ impl Allocate {
             &username,
             relay_secret,
             nonce,
-            None,
+            vec![],
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            dont_fragment: false,
+            even_port: None,
+            reservation_token: None,
+        }
+    }
+
+    pub fn new_authenticated_udp_dont_fragment_ip4(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            vec![Attribute::from(DontFragment)],
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            dont_fragment: true,
+            even_port: None,
+            reservation_token: None,
+        }
+    }
+
+    pub fn new_authenticated_udp_even_port_ip4(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+        even_port: EvenPort,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            vec![Attribute::from(even_port.clone())],
         );
 
         Self {
@@ -168,6 +241,41 @@ impl Allocate {
             nonce: Some(nonce),
             requested_address_family: None, // IPv4 is the default.
             additional_address_family: None,
+            dont_fragment: false,
+            even_port: Some(even_port),
+            reservation_token: None,
+        }
+    }
+
+    pub fn new_authenticated_udp_reservation_token(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+        reservation_token: ReservationToken,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            vec![Attribute::from(reservation_token.clone())],
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // The address family is determined by the reservation.
+            additional_address_family: None,
+            dont_fragment: false,
+            even_port: None,
+            reservation_token: Some(reservation_token),
         }
     }
 
@@ -186,7 +294,7 @@ impl Allocate {
             &username,
             relay_secret,
             nonce,
-            Some(requested_address_family.clone()),
+            vec![Attribute::from(requested_address_family.clone())],
         );
 
         Self {
@@ -198,6 +306,9 @@ impl Allocate {
             nonce: Some(nonce),
             requested_address_family: Some(requested_address_family),
             additional_address_family: None,
+            dont_fragment: false,
+            even_port: None,
+            reservation_token: None,
         }
     }
 
@@ -224,6 +335,9 @@ impl Allocate {
             nonce: None,
             requested_address_family: None,
             additional_address_family: None,
+            dont_fragment: false,
+            even_port: None,
+            reservation_token: None,
         }
     }
 
@@ -233,7 +347,7 @@ impl Allocate {
         username: &Username,
         relay_secret: &SecretString,
         nonce: Uuid,
-        requested_address_family: Option<RequestedAddressFamily>,
+        additional_attributes: Vec<Attribute>,
     ) -> (RequestedTransport, Nonce, MessageIntegrity) {
         let requested_transport = RequestedTransport::new(UDP_TRANSPORT);
         let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
@@ -244,8 +358,8 @@ impl Allocate {
         message.add_attribute(username.clone());
         message.add_attribute(nonce.clone());
 
-        if let Some(requested_address_family) = requested_address_family {
-            message.add_attribute(requested_address_family);
+        for attribute in additional_attributes {
+            message.add_attribute(attribute);
         }
 
         if let Some(lifetime) = &lifetime {
@@ -275,6 +389,9 @@ impl Allocate {
         let username = message.get_attribute::<Username>().cloned();
         let requested_address_family = message.get_attribute::<RequestedAddressFamily>().cloned();
         let additional_address_family = message.get_attribute::<AdditionalAddressFamily>().cloned();
+        let dont_fragment = message.get_attribute::<DontFragment>().is_some();
+        let even_port = message.get_attribute::<EvenPort>().cloned();
+        let reservation_token = message.get_attribute::<ReservationToken>().cloned();
 
         Ok(Allocate {
             transaction_id,
@@ -285,6 +402,9 @@ impl Allocate {
             nonce,
             requested_address_family,
             additional_address_family,
+            dont_fragment,
+            even_port,
+            reservation_token,
         })
     }
 
@@ -319,6 +439,18 @@ impl Allocate {
     pub fn additional_address_family(&self) -> Option<&AdditionalAddressFamily> {
         self.additional_address_family.as_ref()
     }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
+    }
+
+    pub fn even_port(&self) -> Option<&EvenPort> {
+        self.even_port.as_ref()
+    }
+
+    pub fn reservation_token(&self) -> Option<&ReservationToken> {
+        self.reservation_token.as_ref()
+    }
 }
 
 pub struct Refresh {
@@ -500,29 +632,81 @@ impl ChannelBind {
 pub struct CreatePermission {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    xor_peer_addresses: Vec<XorPeerAddress>,
     username: Option<Username>,
     nonce: Option<Nonce>,
 }
 
 impl CreatePermission {
-    pub fn parse(message: &Message<Attribute>) -> Self {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_addresses: Vec<XorPeerAddress>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, CREATE_PERMISSION, transaction_id);
+        message.add_attribute(username.clone());
+        for xor_peer_address in &xor_peer_addresses {
+            message.add_attribute(xor_peer_address.clone());
+        }
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            xor_peer_addresses,
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|a| match a {
+                Attribute::XorPeerAddress(a) => Some(a.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if xor_peer_addresses.is_empty() {
+            return Err(bad_request(message));
+        }
 
-        CreatePermission {
+        Ok(CreatePermission {
             transaction_id,
             message_integrity,
+            xor_peer_addresses,
             username,
             nonce,
-        }
+        })
     }
 
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
 
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+
     pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
         self.message_integrity.as_ref()
     }
@@ -536,6 +720,60 @@ impl CreatePermission {
     }
 }
 
+/// A SEND indication.
+///
+/// Allows clients to relay data to a peer without binding a channel first.
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-send-and-data-methods>.
+pub struct SendIndication {
+    transaction_id: TransactionId,
+    xor_peer_address: XorPeerAddress,
+    data: Data,
+}
+
+impl SendIndication {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        data: Vec<u8>,
+    ) -> Self {
+        Self {
+            transaction_id,
+            xor_peer_address,
+            data: Data::new(data).expect("data to fit into a STUN message"),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Error> {
+        let transaction_id = message.transaction_id();
+        let xor_peer_address = message
+            .get_attribute::<XorPeerAddress>()
+            .ok_or(Error::MissingAttribute("XOR-PEER-ADDRESS"))?
+            .clone();
+        let data = message
+            .get_attribute::<Data>()
+            .ok_or(Error::MissingAttribute("DATA"))?
+            .clone();
+
+        Ok(SendIndication {
+            transaction_id,
+            xor_peer_address,
+            data,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.data()
+    }
+}
+
 /// Computes the effective lifetime of an allocation.
 fn compute_effective_lifetime(requested_lifetime: Option<&Lifetime>) -> Lifetime {
     let Some(requested) = requested_lifetime else {
@@ -571,6 +809,7 @@ pub enum Error {
     BadChannelData(io::Error),
     DecodeStun(bytecodec::Error),
     UnknownMessageType(u8),
+    MissingAttribute(&'static str),
     Eof,
 }
 
@@ -0,0 +1,174 @@
+use std::time::Instant;
+
+/// The minimum size of a [`TokenBucket`] for bandwidth limits.
+///
+/// A bucket must always be able to hold at least one full UDP datagram, otherwise large packets would never be relayed.
+const MIN_BANDWIDTH_BUCKET_SIZE: u64 = 65536;
+
+/// A limit on the sustained throughput, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    bytes_per_second: u64,
+}
+
+impl BandwidthLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self { bytes_per_second }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// How many bytes can be relayed in a single burst.
+    ///
+    /// This is one second worth of traffic but at least [`MIN_BANDWIDTH_BUCKET_SIZE`].
+    fn burst(&self) -> u64 {
+        self.bytes_per_second.max(MIN_BANDWIDTH_BUCKET_SIZE)
+    }
+}
+
+/// A limit on the sustained rate of requests, in requests per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestRateLimit {
+    requests_per_second: u64,
+}
+
+impl RequestRateLimit {
+    pub fn new(requests_per_second: u64) -> Self {
+        Self {
+            requests_per_second,
+        }
+    }
+
+    pub fn requests_per_second(&self) -> u64 {
+        self.requests_per_second
+    }
+
+    /// How many requests can be made in a single burst.
+    ///
+    /// This is one second worth of requests but at least one.
+    fn burst(&self) -> u64 {
+        self.requests_per_second.max(1)
+    }
+}
+
+/// A classic token-bucket.
+///
+/// The bucket refills continuously at a fixed rate up to its capacity.
+/// Each operation consumes a number of tokens and is only permitted if there are enough tokens in the bucket.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u64, refill_per_second: u64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_second: refill_per_second as f64,
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn for_bandwidth(limit: BandwidthLimit, now: Instant) -> Self {
+        Self::new(limit.burst(), limit.bytes_per_second(), now)
+    }
+
+    pub(crate) fn for_requests(limit: RequestRateLimit, now: Instant) -> Self {
+        Self::new(limit.burst(), limit.requests_per_second(), now)
+    }
+
+    /// Whether the bucket currently holds at least `num` tokens.
+    pub(crate) fn has(&mut self, num: u64, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= num as f64
+    }
+
+    /// Removes `num` tokens from the bucket.
+    ///
+    /// Callers should check [`TokenBucket::has`] first.
+    pub(crate) fn take(&mut self, num: u64) {
+        self.tokens = (self.tokens - num as f64).max(0.0);
+    }
+
+    /// Whether the bucket is full, i.e. it is equivalent to a newly created bucket and can be discarded.
+    pub(crate) fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.refill_per_second).min(self.capacity);
+        // Buckets may be shared across threads, which don't necessarily observe time in order.
+        self.last_refill = self.last_refill.max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn new_bucket_is_full() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 10, now);
+
+        assert!(bucket.is_full(now));
+        assert!(bucket.has(100, now));
+        assert!(!bucket.has(101, now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 10, now);
+
+        bucket.take(100);
+        assert!(!bucket.has(1, now));
+
+        let now = now + Duration::from_secs(1);
+        assert!(bucket.has(10, now));
+        assert!(!bucket.has(11, now));
+    }
+
+    #[test]
+    fn does_not_refill_beyond_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 10, now);
+
+        bucket.take(50);
+
+        let now = now + Duration::from_secs(60);
+        assert!(bucket.is_full(now));
+        assert!(!bucket.has(101, now));
+    }
+
+    #[test]
+    fn bandwidth_bucket_fits_at_least_one_datagram() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::for_bandwidth(BandwidthLimit::new(1000), now);
+
+        assert!(bucket.has(65536, now));
+    }
+
+    #[test]
+    fn request_bucket_allows_at_least_one_request() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::for_requests(RequestRateLimit::new(0), now);
+
+        assert!(bucket.has(1, now));
+        assert!(!bucket.has(2, now));
+    }
+}
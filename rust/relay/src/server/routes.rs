@@ -0,0 +1,272 @@
+use crate::server::channel_data::ChannelData;
+use crate::server::rate_limit::TokenBucket;
+use crate::server::AllocationPort;
+use crate::{ClientSocket, PeerSocket};
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use stun_codec::rfc5766::attributes::ChannelNumber;
+
+/// A snapshot of all bound channels of a [`Server`](crate::Server).
+///
+/// Relaying channel data is by far the most common thing a relay does.
+/// [`Routes`] allow doing that without access to the [`Server`](crate::Server), i.e. from many threads in parallel.
+/// Bandwidth limits and statistics are shared with the [`Server`](crate::Server) via the [`Meter`] of each allocation.
+///
+/// Binding a channel, unbinding it or deleting an allocation makes all snapshots outdated, see [`Routes::is_outdated`].
+/// Everything that isn't channel data of a bound channel needs to be handed to the [`Server`](crate::Server).
+#[derive(Clone)]
+pub struct Routes {
+    tables: Arc<Tables>,
+
+    version: u64,
+    latest_version: Arc<AtomicU64>,
+}
+
+/// The outcome of routing a packet via [`Routes`].
+#[derive(Debug, PartialEq)]
+pub enum Route<T> {
+    /// The packet should be relayed to the given destination.
+    Relay(T),
+    /// The packet exceeded a bandwidth limit and must be dropped.
+    Drop,
+    /// We don't know where to relay the packet, it needs to be handled by the [`Server`](crate::Server).
+    Unknown,
+}
+
+#[derive(Default)]
+struct Tables {
+    by_client_and_number:
+        HashMap<(ClientSocket, ChannelNumber), (AllocationPort, PeerSocket, Arc<Meter>)>,
+    by_port_and_peer:
+        HashMap<(AllocationPort, PeerSocket), (ClientSocket, ChannelNumber, Arc<Meter>)>,
+    stream_clients: HashSet<ClientSocket>,
+}
+
+impl Routes {
+    pub(crate) fn new(
+        channels: impl IntoIterator<
+            Item = (
+                ClientSocket,
+                ChannelNumber,
+                AllocationPort,
+                PeerSocket,
+                Arc<Meter>,
+            ),
+        >,
+        stream_clients: impl IntoIterator<Item = ClientSocket>,
+        latest_version: Arc<AtomicU64>,
+    ) -> Self {
+        let mut tables = Tables {
+            stream_clients: stream_clients.into_iter().collect(),
+            ..Default::default()
+        };
+
+        for (client, number, port, peer, meter) in channels {
+            tables
+                .by_client_and_number
+                .insert((client, number), (port, peer, meter.clone()));
+            tables
+                .by_port_and_peer
+                .insert((port, peer), (client, number, meter));
+        }
+
+        Self {
+            tables: Arc::new(tables),
+            version: latest_version.load(Ordering::Acquire),
+            latest_version,
+        }
+    }
+
+    /// Whether the [`Server`](crate::Server) changed its channels since this snapshot was taken.
+    ///
+    /// Outdated snapshots should be replaced via [`Server::routes`](crate::Server::routes).
+    pub fn is_outdated(&self) -> bool {
+        self.latest_version.load(Ordering::Acquire) != self.version
+    }
+
+    /// Routes a [`ChannelData`] message received from a client.
+    ///
+    /// On [`Route::Relay`], you should forward the _payload_ to the [`PeerSocket`] on the [`AllocationPort`].
+    pub fn handle_channel_data(
+        &self,
+        message: &ChannelData,
+        sender: ClientSocket,
+        now: Instant,
+    ) -> Route<(AllocationPort, PeerSocket)> {
+        let Some((port, peer, meter)) = self
+            .tables
+            .by_client_and_number
+            .get(&(sender, message.channel()))
+        else {
+            return Route::Unknown;
+        };
+
+        if !meter.account(message.data().len(), Direction::ClientToPeer, now) {
+            return Route::Drop;
+        }
+
+        Route::Relay((*port, *peer))
+    }
+
+    /// Routes data received from a peer on an allocation.
+    ///
+    /// On [`Route::Relay`], you should create a [`ChannelData`] message with the returned channel number and send it to the [`ClientSocket`].
+    pub fn handle_peer_traffic(
+        &self,
+        msg: &[u8],
+        sender: PeerSocket,
+        allocation: AllocationPort,
+        now: Instant,
+    ) -> Route<(ClientSocket, ChannelNumber)> {
+        let Some((client, number, meter)) = self.tables.by_port_and_peer.get(&(allocation, sender))
+        else {
+            return Route::Unknown;
+        };
+
+        if !meter.account(msg.len(), Direction::PeerToClient, now) {
+            return Route::Drop;
+        }
+
+        Route::Relay((*client, *number))
+    }
+
+    /// Whether the given client is connected via a stream-based transport.
+    pub fn is_stream_client(&self, client: ClientSocket) -> bool {
+        self.tables.stream_clients.contains(&client)
+    }
+}
+
+/// Accounts for the data relayed through a single allocation and enforces its bandwidth limits.
+///
+/// A [`Meter`] is shared between the [`Server`](crate::Server) and all [`Routes`], thus it is safe to use from multiple threads.
+pub(crate) struct Meter {
+    /// The number of bytes relayed through this allocation, across both directions.
+    bytes_relayed: AtomicU64,
+
+    allocation_bucket: Option<Mutex<TokenBucket>>,
+    /// Shared by the meters of all allocations of the same client IP.
+    client_ip_bucket: Option<Arc<Mutex<TokenBucket>>>,
+
+    totals: Arc<Totals>,
+}
+
+/// Statistics across all allocations.
+pub(crate) struct Totals {
+    bytes_relayed: AtomicU64,
+
+    rate_limited_packets_counter: Counter<u64>,
+}
+
+impl Meter {
+    pub(crate) fn new(
+        bytes_relayed: u64,
+        allocation_bucket: Option<TokenBucket>,
+        client_ip_bucket: Option<Arc<Mutex<TokenBucket>>>,
+        totals: Arc<Totals>,
+    ) -> Self {
+        Self {
+            bytes_relayed: AtomicU64::new(bytes_relayed),
+            allocation_bucket: allocation_bucket.map(Mutex::new),
+            client_ip_bucket,
+            totals,
+        }
+    }
+
+    pub(crate) fn bytes_relayed(&self) -> u64 {
+        self.bytes_relayed.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for `num_bytes` being relayed.
+    ///
+    /// Returns `false` if this would exceed one of the configured bandwidth limits, in which case the data must be dropped.
+    pub(crate) fn account(&self, num_bytes: usize, direction: Direction, now: Instant) -> bool {
+        let num_bytes = num_bytes as u64;
+
+        // Always lock the allocation's bucket first to avoid deadlocks.
+        let mut allocation_bucket = self
+            .allocation_bucket
+            .as_ref()
+            .map(|bucket| bucket.lock().unwrap());
+
+        if let Some(bucket) = allocation_bucket.as_mut() {
+            if !bucket.has(num_bytes, now) {
+                tracing::debug!(target: "relay", %num_bytes, "Allocation exceeded its bandwidth limit, dropping packet");
+
+                self.totals
+                    .record_rate_limited_packet("allocation", direction);
+                return false;
+            }
+        }
+
+        if let Some(bucket) = self.client_ip_bucket.as_ref() {
+            let mut bucket = bucket.lock().unwrap();
+
+            if !bucket.has(num_bytes, now) {
+                tracing::debug!(target: "relay", %num_bytes, "Client IP exceeded its bandwidth limit, dropping packet");
+
+                self.totals
+                    .record_rate_limited_packet("client_ip", direction);
+                return false;
+            }
+
+            bucket.take(num_bytes);
+        }
+
+        // Only consume from the allocation's bucket once we know the data passes all limits.
+        if let Some(bucket) = allocation_bucket.as_mut() {
+            bucket.take(num_bytes);
+        }
+
+        self.bytes_relayed.fetch_add(num_bytes, Ordering::Relaxed);
+        self.totals.record_relayed_data(num_bytes);
+
+        true
+    }
+}
+
+impl Totals {
+    pub(crate) fn new(rate_limited_packets_counter: Counter<u64>) -> Self {
+        Self {
+            bytes_relayed: AtomicU64::new(0),
+            rate_limited_packets_counter,
+        }
+    }
+
+    pub(crate) fn bytes_relayed(&self) -> u64 {
+        self.bytes_relayed.load(Ordering::Relaxed)
+    }
+
+    fn record_relayed_data(&self, num_bytes: u64) {
+        self.bytes_relayed.fetch_add(num_bytes, Ordering::Relaxed);
+    }
+
+    fn record_rate_limited_packet(&self, limit: &'static str, direction: Direction) {
+        self.rate_limited_packets_counter.add(
+            1,
+            &[
+                KeyValue::new("limit", limit),
+                KeyValue::new("direction", direction.as_str()),
+            ],
+        );
+    }
+}
+
+/// The direction in which data is relayed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    ClientToPeer,
+    PeerToClient,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::ClientToPeer => "client_to_peer",
+            Direction::PeerToClient => "peer_to_client",
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::io;
+
+/// The length of a STUN message header.
+const STUN_HEADER_LEN: usize = 20;
+
+/// The length of a channel data message header.
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Re-assembles STUN and channel data messages from a byte-stream.
+///
+/// Stream-based transports like TCP and TLS don't preserve message boundaries.
+/// Both STUN messages and channel data messages carry their length in their header, which allows us to split the stream back into individual messages.
+///
+/// Over stream-based transports, channel data messages are padded to a multiple of 4 bytes.
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-the-channeldata-message>.
+#[derive(Debug, Default)]
+pub(crate) struct StreamBuffer {
+    buffer: Vec<u8>,
+}
+
+impl StreamBuffer {
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete message from the stream.
+    ///
+    /// Returns [`None`] if we have not yet received enough bytes for the next message.
+    /// Fails if the stream contains bytes that are neither a STUN message nor a channel data message, at which point the stream is unusable and should be closed.
+    pub(crate) fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(message_len) = next_message_len(&self.buffer)? else {
+            return Ok(None);
+        };
+
+        if self.buffer.len() < message_len {
+            return Ok(None);
+        }
+
+        let remaining = self.buffer.split_off(message_len);
+        let message = std::mem::replace(&mut self.buffer, remaining);
+
+        Ok(Some(message))
+    }
+}
+
+/// Computes the length of the next message on the wire, including any padding.
+fn next_message_len(buffer: &[u8]) -> io::Result<Option<usize>> {
+    // De-multiplex as per <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
+    let (header_len, padded) = match buffer.first() {
+        Some(0..=3) => (STUN_HEADER_LEN, false),
+        Some(64..=79) => (CHANNEL_DATA_HEADER_LEN, true),
+        Some(other) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {other}"),
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let Some(length) = buffer.get(2..4) else {
+        return Ok(None);
+    };
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+    if !padded {
+        return Ok(Some(header_len + length));
+    }
+
+    Ok(Some((header_len + length).next_multiple_of(4)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_concatenated_messages() {
+        let mut buffer = StreamBuffer::default();
+
+        let stun = stun_message(8);
+        let channel_data = [0x40, 0x00, 0x00, 0x04, 1, 2, 3, 4];
+
+        buffer.extend(&stun);
+        buffer.extend(&channel_data);
+
+        assert_eq!(buffer.next_message().unwrap().unwrap(), stun);
+        assert_eq!(buffer.next_message().unwrap().unwrap(), channel_data);
+        assert_eq!(buffer.next_message().unwrap(), None);
+    }
+
+    #[test]
+    fn waits_for_fragmented_message() {
+        let mut buffer = StreamBuffer::default();
+
+        let stun = stun_message(12);
+        let (first, second) = stun.split_at(7);
+
+        buffer.extend(first);
+        assert_eq!(buffer.next_message().unwrap(), None);
+
+        buffer.extend(second);
+        assert_eq!(buffer.next_message().unwrap().unwrap(), stun);
+    }
+
+    #[test]
+    fn includes_channel_data_padding() {
+        let mut buffer = StreamBuffer::default();
+
+        buffer.extend(&[0x40, 0x01, 0x00, 0x03, 1, 2, 3]);
+        assert_eq!(buffer.next_message().unwrap(), None);
+
+        buffer.extend(&[0]);
+        assert_eq!(
+            buffer.next_message().unwrap().unwrap(),
+            [0x40, 0x01, 0x00, 0x03, 1, 2, 3, 0]
+        );
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut buffer = StreamBuffer::default();
+
+        buffer.extend(&[0xFF, 0x00, 0x00, 0x00]);
+
+        assert!(buffer.next_message().is_err());
+    }
+
+    fn stun_message(attributes_len: u16) -> Vec<u8> {
+        let mut message = vec![0u8; STUN_HEADER_LEN + attributes_len as usize];
+        message[1] = 0x01;
+        message[2..4].copy_from_slice(&attributes_len.to_be_bytes());
+
+        message
+    }
+}
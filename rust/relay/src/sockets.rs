@@ -1,23 +1,45 @@
+use crate::handover::HandedOverSocket;
 use anyhow::{bail, Result};
+use bytes::BytesMut;
+use quinn_udp::{RecvMeta, UdpSockRef, UdpSocketState, BATCH_SIZE};
 use std::{
     collections::HashMap,
-    io,
+    io::{self, IoSliceMut},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsFd as _,
     task::{ready, Context, Poll},
     time::Duration,
 };
 use stun_codec::rfc8656::attributes::AddressFamily;
 use tokio::sync::mpsc;
 
+/// The size of a single receive buffer.
+///
+/// With GRO, the kernel may coalesce several datagrams into one buffer, thus we need to be able to hold the largest possible one.
+const RECV_BUFFER_SIZE: usize = u16::MAX as usize;
+
+/// The maximum size of a single GSO batch.
+const MAX_GSO_SIZE: usize = u16::MAX as usize;
+
 /// A dynamic collection of UDP sockets, listening on all interfaces of a particular IP family.
 ///
 /// Internally, [`Sockets`] is powered by [`mio`] and uses a separate thread to poll for readiness of a socket.
-/// Whenever a socket is ready for reading, we send a message to the foreground task which then reads from the socket until it emits [`io::ErrorKind::WouldBlock`].
+/// Whenever a socket is ready for reading, we send a message to the foreground task which then reads from the socket in batches until it emits [`io::ErrorKind::WouldBlock`].
+///
+/// Reading and writing is done via [`quinn_udp`], meaning we receive up to [`BATCH_SIZE`] datagrams per syscall (`recvmmsg`) and use GRO / GSO where the kernel supports it.
+/// Outgoing datagrams are buffered until [`Sockets::flush`] is called.
+/// Consecutive datagrams of the same size to the same destination are coalesced into a single GSO batch.
+///
+/// Multiple instances of [`Sockets`] created via [`Sockets::with_reuse_port`] can bind the same ports.
+/// The kernel then distributes incoming packets across them, based on a hash of the sender's address.
 pub struct Sockets {
     /// All currently active sockets.
     ///
     /// [`mio`] operates with a concept of [`mio::Token`]s so we need to store our sockets indexed by those tokens.
-    inner: HashMap<mio::Token, mio::net::UdpSocket>,
+    inner: HashMap<mio::Token, Socket>,
+
+    /// The sockets that have buffered datagrams that need to be flushed.
+    sockets_with_pending_transmits: Vec<mio::Token>,
 
     /// Which socket we should still be reading from.
     ///
@@ -37,17 +59,27 @@ impl Default for Sockets {
 
 impl Sockets {
     pub fn new() -> Self {
+        Self::spawn(false)
+    }
+
+    /// Creates a new [`Sockets`] instance that binds all sockets with `SO_REUSEPORT`.
+    pub fn with_reuse_port() -> Self {
+        Self::spawn(true)
+    }
+
+    fn spawn(reuse_port: bool) -> Self {
         let (cmd_tx, cmd_rx) = mpsc::channel(1_000_000); // Commands are really small and this channel should really never fill up unless we have serious problems in the "mio" worker thread.
         let (event_tx, event_rx) = mpsc::channel(1_024);
 
         std::thread::spawn(move || {
-            if let Err(e) = mio_worker_task(event_tx.clone(), cmd_rx) {
+            if let Err(e) = mio_worker_task(event_tx.clone(), cmd_rx, reuse_port) {
                 let _ = event_tx.blocking_send(Event::Crashed(e));
             }
         });
 
         Self {
             inner: Default::default(),
+            sockets_with_pending_transmits: Default::default(),
             cmd_tx,
             event_rx,
             current_ready_socket: None,
@@ -56,16 +88,51 @@ impl Sockets {
 
     /// Attempts to bind a new socket on the given port and address family.
     ///
+    /// If `dont_fragment` is set, all packets sent from this socket will have the DF bit set.
+    ///
     /// Fails if the channel is:
     ///  - full (not expected to happen in production)
     ///  - disconnected (we can't operate without the [`mio`] worker thread)
-    pub fn bind(&mut self, port: u16, address_family: AddressFamily) -> Result<()> {
+    pub fn bind(
+        &mut self,
+        port: u16,
+        address_family: AddressFamily,
+        dont_fragment: bool,
+    ) -> Result<()> {
         self.cmd_tx
-            .try_send(Command::NewSocket((port, address_family)))?;
+            .try_send(Command::NewSocket((port, address_family, dont_fragment)))?;
 
         Ok(())
     }
 
+    /// Takes over a socket that was handed over from another process.
+    ///
+    /// Fails if the channel is:
+    ///  - full (not expected to happen in production)
+    ///  - disconnected (we can't operate without the [`mio`] worker thread)
+    pub fn adopt(&mut self, socket: HandedOverSocket) -> Result<()> {
+        self.cmd_tx.try_send(Command::AdoptSocket(socket))?;
+
+        Ok(())
+    }
+
+    /// Duplicates all our sockets so they can be handed over to another process.
+    pub fn handover(&self) -> io::Result<Vec<HandedOverSocket>> {
+        self.inner
+            .iter()
+            .map(|(token, socket)| {
+                let (port, family) = token_to_port_and_address_family(*token);
+
+                Ok(HandedOverSocket {
+                    port,
+                    family,
+                    dont_fragment: socket.dont_fragment,
+                    fd: socket.inner.as_fd().try_clone_to_owned()?,
+                })
+            })
+            .collect()
+    }
+
     /// Attempts to unbind a socket on the given port and address family.
     ///
     /// Fails if the channel is:
@@ -78,12 +145,27 @@ impl Sockets {
             return Ok(());
         };
 
-        self.cmd_tx.try_send(Command::DisposeSocket(socket))?;
+        self.cmd_tx.try_send(Command::DisposeSocket(socket.inner))?;
 
         Ok(())
     }
 
-    pub fn try_send(&self, port: u16, dest: SocketAddr, msg: &[u8]) -> io::Result<()> {
+    /// Queues a datagram to be sent from the given port to `dest`.
+    ///
+    /// The datagram is only sent upon the next call to [`Sockets::flush`].
+    pub fn send(&mut self, port: u16, dest: SocketAddr, msg: &[u8]) -> io::Result<()> {
+        self.send_vectored(port, dest, &[msg])
+    }
+
+    /// Queues a datagram consisting of the concatenation of `chunks` to be sent from the given port to `dest`.
+    ///
+    /// The datagram is only sent upon the next call to [`Sockets::flush`].
+    pub fn send_vectored(
+        &mut self,
+        port: u16,
+        dest: SocketAddr,
+        chunks: &[&[u8]],
+    ) -> io::Result<()> {
         let address_family = match dest {
             SocketAddr::V4(_) => AddressFamily::V4,
             SocketAddr::V6(_) => AddressFamily::V6,
@@ -92,26 +174,49 @@ impl Sockets {
 
         let socket = self
             .inner
-            .get(&token)
+            .get_mut(&token)
             .ok_or_else(|| not_connected(port, address_family))?;
 
-        let num_sent = socket.send_to(msg, dest)?;
+        if socket.pending_transmits.is_empty() {
+            self.sockets_with_pending_transmits.push(token);
+        }
 
-        debug_assert_eq!(num_sent, msg.len());
+        socket.queue(dest, chunks);
 
         Ok(())
     }
 
-    pub fn poll_recv_from<'b>(
+    /// Sends all buffered datagrams.
+    ///
+    /// Sockets are non-blocking.
+    /// If a socket's send buffer is full, the remaining datagrams for this socket are dropped.
+    pub fn flush(&mut self) {
+        for token in self.sockets_with_pending_transmits.drain(..) {
+            let Some(socket) = self.inner.get_mut(&token) else {
+                continue; // Socket got unbound in the meantime.
+            };
+
+            let (port, _) = token_to_port_and_address_family(token);
+
+            if let Err(e) = socket.flush() {
+                tracing::warn!(target: "relay", %port, "Failed to send datagrams: {e}");
+            }
+        }
+    }
+
+    /// Receives a batch of datagrams from one of our sockets.
+    ///
+    /// A single batch may contain up to [`BATCH_SIZE`] times the number of GRO segments datagrams.
+    pub fn poll_recv_batch<'b>(
         &mut self,
-        buf: &'b mut [u8],
+        buffers: &'b mut RecvBuffers,
         cx: &mut Context<'_>,
-    ) -> Poll<Result<Received<'b>, Error>> {
+    ) -> Poll<Result<impl Iterator<Item = Received<'b>> + 'b, Error>> {
         loop {
             if let Some(current) = self.current_ready_socket {
                 if let Some(socket) = self.inner.get(&current) {
-                    let (num_bytes, from) = match socket.recv_from(buf) {
-                        Ok(ok) => ok,
+                    let num_msgs = match socket.recv(buffers) {
+                        Ok(num_msgs) => num_msgs,
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                             self.current_ready_socket = None;
                             continue;
@@ -124,17 +229,21 @@ impl Sockets {
 
                     let (port, _) = token_to_port_and_address_family(current);
 
-                    return Poll::Ready(Ok(Received {
-                        port,
-                        from,
-                        packet: &buf[..num_bytes],
-                    }));
+                    return Poll::Ready(Ok(buffers.received(port, num_msgs)));
                 }
             }
 
             match ready!(self.event_rx.poll_recv(cx)) {
-                Some(Event::NewSocket(token, socket)) => {
-                    self.inner.insert(token, socket);
+                Some(Event::NewSocket(token, socket, state, dont_fragment)) => {
+                    self.inner.insert(
+                        token,
+                        Socket {
+                            inner: socket,
+                            state,
+                            dont_fragment,
+                            pending_transmits: Vec::new(),
+                        },
+                    );
                     continue;
                 }
                 Some(Event::SocketReady(ready)) => {
@@ -152,6 +261,46 @@ impl Sockets {
     }
 }
 
+/// The buffers we receive a batch of datagrams into.
+///
+/// These are large (several MB), thus they should be allocated once and re-used.
+pub struct RecvBuffers {
+    buffer: Box<[u8]>,
+    metas: [RecvMeta; BATCH_SIZE],
+}
+
+impl Default for RecvBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecvBuffers {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![0u8; RECV_BUFFER_SIZE * BATCH_SIZE].into_boxed_slice(),
+            metas: [RecvMeta::default(); BATCH_SIZE],
+        }
+    }
+
+    /// Iterates over the first `num_msgs` messages, splitting GRO-coalesced messages into their individual datagrams.
+    fn received(&self, port: u16, num_msgs: usize) -> impl Iterator<Item = Received<'_>> {
+        self.metas[..num_msgs]
+            .iter()
+            .zip(self.buffer.chunks(RECV_BUFFER_SIZE))
+            .filter(|(meta, _)| meta.len > 0)
+            .flat_map(move |(meta, buffer)| {
+                buffer[..meta.len]
+                    .chunks(meta.stride)
+                    .map(move |packet| Received {
+                        port,
+                        from: meta.addr,
+                        packet,
+                    })
+            })
+    }
+}
+
 /// A packet read from a socket.
 #[derive(Debug)]
 pub struct Received<'a> {
@@ -160,6 +309,114 @@ pub struct Received<'a> {
     pub packet: &'a [u8],
 }
 
+/// A single UDP socket and its buffered datagrams.
+struct Socket {
+    inner: mio::net::UdpSocket,
+    state: UdpSocketState,
+    /// Whether packets sent from this socket have the DF bit set.
+    dont_fragment: bool,
+
+    pending_transmits: Vec<PendingTransmit>,
+}
+
+impl Socket {
+    fn recv(&self, buffers: &mut RecvBuffers) -> io::Result<usize> {
+        let mut chunks = buffers.buffer.chunks_mut(RECV_BUFFER_SIZE);
+        let mut bufs: [IoSliceMut; BATCH_SIZE] = std::array::from_fn(|_| {
+            IoSliceMut::new(chunks.next().expect("buffer to hold `BATCH_SIZE` chunks"))
+        });
+
+        self.state
+            .recv(UdpSockRef::from(&self.inner), &mut bufs, &mut buffers.metas)
+    }
+
+    fn queue(&mut self, dst: SocketAddr, chunks: &[&[u8]]) {
+        let len = chunks.iter().map(|c| c.len()).sum::<usize>();
+        let max_segments = self.state.max_gso_segments();
+
+        match self.pending_transmits.last_mut() {
+            Some(last) if last.can_append(dst, len, max_segments) => {
+                last.extend(chunks);
+            }
+            _ => {
+                let mut transmit = PendingTransmit {
+                    destination: dst,
+                    segment_size: len,
+                    contents: BytesMut::with_capacity(len),
+                };
+                transmit.extend(chunks);
+
+                self.pending_transmits.push(transmit);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let transmits = self
+            .pending_transmits
+            .drain(..)
+            .map(PendingTransmit::into_transmit)
+            .collect::<Vec<_>>();
+        let mut transmits = transmits.as_slice();
+
+        while !transmits.is_empty() {
+            match self.state.send(UdpSockRef::from(&self.inner), transmits) {
+                Ok(num_sent) => {
+                    transmits = &transmits[num_sent..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    tracing::debug!(target: "relay", num_dropped = %transmits.len(), "Send buffer is full, dropping datagrams");
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One or more datagrams of the same size to the same destination, sent with a single GSO batch.
+///
+/// Only the last datagram of a batch may be shorter than `segment_size`.
+struct PendingTransmit {
+    destination: SocketAddr,
+    segment_size: usize,
+    contents: BytesMut,
+}
+
+impl PendingTransmit {
+    fn can_append(&self, dst: SocketAddr, len: usize, max_segments: usize) -> bool {
+        let num_segments = self.contents.len().div_ceil(self.segment_size);
+        let ends_with_short_segment = self.contents.len() % self.segment_size != 0;
+
+        self.destination == dst
+            && len > 0
+            && len <= self.segment_size
+            && !ends_with_short_segment
+            && num_segments < max_segments
+            && self.contents.len() + len <= MAX_GSO_SIZE
+    }
+
+    fn extend(&mut self, chunks: &[&[u8]]) {
+        for chunk in chunks {
+            self.contents.extend_from_slice(chunk);
+        }
+    }
+
+    fn into_transmit(self) -> quinn_udp::Transmit {
+        let is_batch = self.contents.len() > self.segment_size;
+
+        quinn_udp::Transmit {
+            destination: self.destination,
+            ecn: None,
+            contents: self.contents.freeze(),
+            segment_size: is_batch.then_some(self.segment_size),
+            src_ip: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
@@ -167,12 +424,13 @@ pub enum Error {
 }
 
 enum Command {
-    NewSocket((u16, AddressFamily)),
+    NewSocket((u16, AddressFamily, bool)),
+    AdoptSocket(HandedOverSocket),
     DisposeSocket(mio::net::UdpSocket),
 }
 
 enum Event {
-    NewSocket(mio::Token, mio::net::UdpSocket),
+    NewSocket(mio::Token, mio::net::UdpSocket, UdpSocketState, bool),
     SocketReady(mio::Token),
     Crashed(anyhow::Error),
 }
@@ -190,6 +448,7 @@ fn not_connected(port: u16, address_family: AddressFamily) -> io::Error {
 fn mio_worker_task(
     event_tx: mpsc::Sender<Event>,
     mut cmd_rx: mpsc::Receiver<Command>,
+    reuse_port: bool,
 ) -> Result<()> {
     let mut poll = mio::Poll::new()?;
     let mut events = mio::Events::with_capacity(1024);
@@ -206,14 +465,23 @@ fn mio_worker_task(
             match cmd_rx.try_recv() {
                 Err(mpsc::error::TryRecvError::Empty) => break, // Drain all events from the channel until it is empty.
 
-                Ok(Command::NewSocket((port, af))) => {
-                    let mut socket = mio::net::UdpSocket::from_std(make_wildcard_socket(af, port)?);
-                    let token = token_from_port_and_address_family(port, af);
-
-                    poll.registry()
-                        .register(&mut socket, token, mio::Interest::READABLE)?;
+                Ok(Command::NewSocket((port, af, dont_fragment))) => {
+                    let socket = make_wildcard_socket(af, port, reuse_port)?;
 
-                    event_tx.blocking_send(Event::NewSocket(token, socket))?;
+                    register_socket(&poll, &event_tx, socket, port, af, dont_fragment)?;
+                }
+                Ok(Command::AdoptSocket(handed_over)) => {
+                    let socket = std::net::UdpSocket::from(handed_over.fd);
+                    socket.set_nonblocking(true)?;
+
+                    register_socket(
+                        &poll,
+                        &event_tx,
+                        socket,
+                        handed_over.port,
+                        handed_over.family,
+                        handed_over.dont_fragment,
+                    )?;
                 }
                 Ok(Command::DisposeSocket(mut socket)) => {
                     poll.registry().deregister(&mut socket)?;
@@ -226,6 +494,31 @@ fn mio_worker_task(
     }
 }
 
+/// Registers the given socket with [`mio`] and hands it to the foreground task.
+fn register_socket(
+    poll: &mio::Poll,
+    event_tx: &mpsc::Sender<Event>,
+    socket: std::net::UdpSocket,
+    port: u16,
+    af: AddressFamily,
+    dont_fragment: bool,
+) -> Result<()> {
+    let state = UdpSocketState::new(UdpSockRef::from(&socket))?;
+
+    // `quinn_udp` configures the DF bit itself, thus we need to (re-)set it afterwards.
+    set_dont_fragment(&socket, af, dont_fragment)?;
+
+    let mut socket = mio::net::UdpSocket::from_std(socket);
+    let token = token_from_port_and_address_family(port, af);
+
+    poll.registry()
+        .register(&mut socket, token, mio::Interest::READABLE)?;
+
+    event_tx.blocking_send(Event::NewSocket(token, socket, state, dont_fragment))?;
+
+    Ok(())
+}
+
 /// Encodes a port (u16) and an [`AddressFamily`] into an [`mio::Token`] by flipping the 17th bit of the internal [`usize`] based on the [`AddressFamily`].
 fn token_from_port_and_address_family(port: u16, address_family: AddressFamily) -> mio::Token {
     let is_ipv6 = address_family == AddressFamily::V6;
@@ -252,10 +545,80 @@ fn token_to_port_and_address_family(token: mio::Token) -> (u16, AddressFamily) {
     (port, address_family)
 }
 
+/// Configures whether packets sent from this socket have the DF bit set.
+///
+/// With `dont_fragment`, we disable fragmentation via path MTU discovery.
+/// Packets that exceed the path MTU are dropped and reported back to the sender instead of being fragmented.
+/// Otherwise, we restore the kernel's default behaviour.
+fn set_dont_fragment(
+    socket: &std::net::UdpSocket,
+    family: AddressFamily,
+    dont_fragment: bool,
+) -> io::Result<()> {
+    match family {
+        AddressFamily::V4 => {
+            let value = if dont_fragment {
+                libc::IP_PMTUDISC_DO
+            } else {
+                libc::IP_PMTUDISC_WANT
+            };
+
+            set_int_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)?;
+        }
+        AddressFamily::V6 => {
+            let value = if dont_fragment {
+                libc::IPV6_PMTUDISC_DO
+            } else {
+                libc::IPV6_PMTUDISC_WANT
+            };
+
+            set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)?;
+            set_int_option(
+                socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_DONTFRAG,
+                dont_fragment as libc::c_int,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_int_option(
+    socket: &std::net::UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd as _;
+
+    // SAFETY: The file descriptor is valid for the lifetime of `socket` and `value` is a valid `c_int`.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> io::Result<std::net::UdpSocket> {
+fn make_wildcard_socket(
+    family: AddressFamily,
+    port: u16,
+    reuse_port: bool,
+) -> io::Result<std::net::UdpSocket> {
     use socket2::*;
 
     let domain = match family {
@@ -271,9 +634,88 @@ fn make_wildcard_socket(family: AddressFamily, port: u16) -> io::Result<std::net
     if family == AddressFamily::V6 {
         socket.set_only_v6(true)?;
     }
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
 
     socket.set_nonblocking(true)?;
     socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
 
     Ok(socket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1000);
+    const OTHER_DST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2000);
+
+    #[test]
+    fn appends_datagrams_of_same_size_to_same_destination() {
+        let transmit = pending_transmit(DST, 100);
+
+        assert!(transmit.can_append(DST, 100, 64));
+    }
+
+    #[test]
+    fn appends_shorter_final_datagram() {
+        let mut transmit = pending_transmit(DST, 100);
+        assert!(transmit.can_append(DST, 50, 64));
+
+        transmit.extend(&[&[0u8; 50]]);
+
+        assert!(!transmit.can_append(DST, 50, 64));
+    }
+
+    #[test]
+    fn does_not_append_to_different_destination() {
+        let transmit = pending_transmit(DST, 100);
+
+        assert!(!transmit.can_append(OTHER_DST, 100, 64));
+    }
+
+    #[test]
+    fn does_not_append_larger_datagram() {
+        let transmit = pending_transmit(DST, 100);
+
+        assert!(!transmit.can_append(DST, 101, 64));
+    }
+
+    #[test]
+    fn does_not_exceed_max_segments() {
+        let mut transmit = pending_transmit(DST, 100);
+        transmit.extend(&[&[0u8; 100]]);
+
+        assert!(!transmit.can_append(DST, 100, 2));
+    }
+
+    #[test]
+    fn single_datagram_is_not_sent_as_gso_batch() {
+        let transmit = pending_transmit(DST, 100).into_transmit();
+
+        assert_eq!(transmit.segment_size, None);
+    }
+
+    #[test]
+    fn multiple_datagrams_are_sent_as_gso_batch() {
+        let mut transmit = pending_transmit(DST, 100);
+        transmit.extend(&[&[0u8; 60], &[0u8; 40]]);
+
+        let transmit = transmit.into_transmit();
+
+        assert_eq!(transmit.segment_size, Some(100));
+        assert_eq!(transmit.contents.len(), 200);
+    }
+
+    fn pending_transmit(destination: SocketAddr, len: usize) -> PendingTransmit {
+        let mut transmit = PendingTransmit {
+            destination,
+            segment_size: len,
+            contents: BytesMut::new(),
+        };
+        transmit.extend(&[&vec![0u8; len]]);
+
+        transmit
+    }
+}
@@ -0,0 +1,360 @@
+use anyhow::{Context as _, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::TcpListener,
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+
+/// How many messages we buffer for a single connection before we start dropping them.
+const MAX_PENDING_MESSAGES_PER_CONNECTION: usize = 256;
+
+const MAX_READ_SIZE: usize = 65536;
+
+/// How many stream connections we serve at once across all listeners, including ones that are still in the TLS handshake.
+///
+/// Connections beyond this are closed right after accepting them.
+const MAX_CONNECTIONS: usize = 10_000;
+
+/// How long a client has to complete the TLS handshake before we close the connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A collection of stream-based listeners (TCP and TLS) and the client connections accepted on them.
+///
+/// Each listener and each connection is driven by its own task.
+/// Data read from a connection is sent to the foreground task via a channel, data to be written to a connection is sent to the connection's task via another channel.
+pub struct Streams {
+    /// The senders to all currently open connections, indexed by the client's address.
+    connections: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>,
+    /// Limits how many connections all listeners have open at once.
+    connection_permits: Arc<Semaphore>,
+
+    event_tx: mpsc::Sender<Event>,
+    event_rx: mpsc::Receiver<Event>,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(1_024);
+
+        Self {
+            connections: Default::default(),
+            connection_permits: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Listens for plain TCP connections on the given port and address family.
+    pub fn listen_tcp(&mut self, port: u16, address_family: AddressFamily) -> Result<()> {
+        let listener = make_wildcard_listener(address_family, port)?;
+
+        tokio::spawn(accept_loop(
+            listener,
+            None,
+            self.connection_permits.clone(),
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Listens for TLS connections on the given port and address family.
+    pub fn listen_tls(
+        &mut self,
+        port: u16,
+        address_family: AddressFamily,
+        acceptor: TlsAcceptor,
+    ) -> Result<()> {
+        let listener = make_wildcard_listener(address_family, port)?;
+
+        tokio::spawn(accept_loop(
+            listener,
+            Some(acceptor),
+            self.connection_permits.clone(),
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Whether we have an open connection to the given client.
+    pub fn is_connected(&self, client: SocketAddr) -> bool {
+        self.connections.contains_key(&client)
+    }
+
+    /// Queues the given message to be written to the connection of the given client.
+    pub fn try_send(&mut self, client: SocketAddr, msg: Vec<u8>) -> io::Result<()> {
+        let connection = self.connections.get(&client).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("No connection to {client}"),
+            )
+        })?;
+
+        connection.try_send(msg).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("Connection to {client} is congested"),
+            ),
+            mpsc::error::TrySendError::Closed(_) => io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("Connection to {client} is closed"),
+            ),
+        })
+    }
+
+    /// Closes the connection to the given client.
+    ///
+    /// Dropping the sender causes the connection's task to shut down the stream.
+    pub fn close(&mut self, client: SocketAddr) {
+        self.connections.remove(&client);
+    }
+
+    pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<StreamEvent> {
+        loop {
+            match ready!(self.event_rx.poll_recv(cx)).expect("we hold a sender ourselves") {
+                Event::Connected { from, sender } => {
+                    tracing::debug!(target: "relay", %from, "New stream connection");
+
+                    self.connections.insert(from, sender);
+                    continue;
+                }
+                Event::Received { from, bytes } => {
+                    if !self.connections.contains_key(&from) {
+                        continue; // We closed the connection in the meantime.
+                    }
+
+                    return Poll::Ready(StreamEvent::Received { from, bytes });
+                }
+                Event::Closed { from } => {
+                    if self.connections.remove(&from).is_none() {
+                        continue; // We closed the connection ourselves.
+                    }
+
+                    tracing::debug!(target: "relay", %from, "Stream connection closed");
+
+                    return Poll::Ready(StreamEvent::Closed { from });
+                }
+            }
+        }
+    }
+}
+
+/// An event from one of the connections of [`Streams`].
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// We received bytes from a client.
+    ///
+    /// These are not necessarily complete messages.
+    Received { from: SocketAddr, bytes: Vec<u8> },
+    /// The client closed the connection or the connection failed.
+    Closed { from: SocketAddr },
+}
+
+enum Event {
+    Connected {
+        from: SocketAddr,
+        sender: mpsc::Sender<Vec<u8>>,
+    },
+    Received {
+        from: SocketAddr,
+        bytes: Vec<u8>,
+    },
+    Closed {
+        from: SocketAddr,
+    },
+}
+
+/// Loads the certificate chain and private key from the given PEM files and constructs a [`TlsAcceptor`] from them.
+pub fn tls_acceptor(certificate_path: &Path, private_key_path: &Path) -> Result<TlsAcceptor> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(certificate_path).with_context(|| {
+            format!(
+                "Failed to open certificate file {}",
+                certificate_path.display()
+            )
+        })?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Failed to parse certificates")?;
+
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(private_key_path).with_context(|| {
+            format!(
+                "Failed to open private key file {}",
+                private_key_path.display()
+            )
+        })?,
+    ))
+    .context("Failed to parse private key")?
+    .context("No private key found")?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)
+        .context("Invalid certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    connection_permits: Arc<Semaphore>,
+    event_tx: mpsc::Sender<Event>,
+) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(ok) => ok,
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connection_permits.clone().try_acquire_owned() else {
+            tracing::debug!(target: "relay", %from, "Too many stream connections, closing new connection");
+            continue; // Dropping the stream closes the connection.
+        };
+
+        if let Err(e) = stream.set_nodelay(true) {
+            tracing::debug!(target: "relay", %from, "Failed to set TCP_NODELAY: {e}");
+        }
+
+        let event_tx = event_tx.clone();
+
+        match acceptor.clone() {
+            None => {
+                tokio::spawn(serve_connection(stream, from, permit, event_tx));
+            }
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    let stream = match tokio::time::timeout(
+                        TLS_HANDSHAKE_TIMEOUT,
+                        acceptor.accept(stream),
+                    )
+                    .await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            tracing::debug!(target: "relay", %from, "TLS handshake failed: {e}");
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!(target: "relay", %from, "TLS handshake timed out");
+                            return;
+                        }
+                    };
+
+                    serve_connection(stream, from, permit, event_tx).await
+                });
+            }
+        }
+    }
+}
+
+/// Drives a single connection until either side closes it.
+///
+/// The `permit` is released once the connection is closed.
+async fn serve_connection<S>(
+    stream: S,
+    from: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+    event_tx: mpsc::Sender<Event>,
+) where
+    S: AsyncRead + AsyncWrite,
+{
+    let (outbound_tx, mut outbound_rx) = mpsc::channel(MAX_PENDING_MESSAGES_PER_CONNECTION);
+
+    if event_tx
+        .send(Event::Connected {
+            from,
+            sender: outbound_tx,
+        })
+        .await
+        .is_err()
+    {
+        return; // The eventloop is gone.
+    }
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = vec![0u8; MAX_READ_SIZE];
+
+    loop {
+        tokio::select! {
+            result = reader.read(&mut buffer) => {
+                let num_read = match result {
+                    Ok(0) => break,
+                    Ok(num_read) => num_read,
+                    Err(e) => {
+                        tracing::debug!(target: "relay", %from, "Failed to read from connection: {e}");
+                        break;
+                    }
+                };
+
+                if event_tx.send(Event::Received { from, bytes: buffer[..num_read].to_vec() }).await.is_err() {
+                    return;
+                }
+            }
+            msg = outbound_rx.recv() => {
+                let Some(msg) = msg else {
+                    let _ = writer.shutdown().await; // We closed the connection.
+                    break;
+                };
+
+                if let Err(e) = writer.write_all(&msg).await {
+                    tracing::debug!(target: "relay", %from, "Failed to write to connection: {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = event_tx.send(Event::Closed { from }).await;
+}
+
+/// Creates a [`TcpListener`] via the [socket2] library that is configured for our needs.
+///
+/// Like for our UDP sockets, this sets the `IPV6_V6ONLY` flag so we can listen on IP4 and IP6 addresses on the same port.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> io::Result<TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(std::net::TcpListener::from(socket))
+}
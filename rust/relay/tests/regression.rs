@@ -1,22 +1,31 @@
 use bytecodec::{DecodeExt, EncodeExt};
+use firezone_relay::audit::{AuditRecord, DeletionReason};
 use firezone_relay::{
-    AddressFamily, Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server,
+    AddressFamily, Allocate, AllocationInfo, AllocationPort, Attribute, BandwidthLimit, Binding,
+    ChannelBind, ChannelData, ClientMessage, ClientSocket, Command, CreatePermission, IpStack,
+    PeerSocket, Refresh, RequestRateLimit, SendIndication, Server,
 };
+use ip_network::IpNetwork;
 use rand::rngs::mock::StepRng;
 use secrecy::SecretString;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant, SystemTime};
-use stun_codec::rfc5389::attributes::{ErrorCode, Nonce, Realm, Username, XorMappedAddress};
-use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, TryAlternate, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
-use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, Data, EvenPort, Lifetime, RequestedTransport, ReservationToken, XorPeerAddress,
+    XorRelayAddress,
+};
+use stun_codec::rfc5766::errors::InsufficientCapacity;
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
 use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
 use test_strategy::proptest;
 use uuid::Uuid;
-use Output::{CreateAllocation, FreeAllocation};
+use Output::{CreateAllocation, FreeAllocation, RelayToPeer};
 
 #[proptest]
 fn can_answer_stun_request_from_ip4_address(
@@ -38,6 +47,77 @@ fn can_answer_stun_request_from_ip4_address(
     );
 }
 
+#[proptest]
+fn can_answer_fragmented_stun_request_over_stream(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(1..20usize)] split_at: usize,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let mut server = TestServer::new(public_relay_addr);
+    let now = Instant::now();
+
+    let request = MessageEncoder::new()
+        .encode_into_bytes(Message::<Attribute>::new(
+            MessageClass::Request,
+            BINDING,
+            transaction_id,
+        ))
+        .unwrap();
+    let (first, second) = request.split_at(split_at);
+
+    server.assert_commands(from_client_stream(source, first, now), []);
+    server.assert_commands(
+        from_client_stream(source, second, now),
+        [send_message(
+            source,
+            binding_response(transaction_id, source),
+        )],
+    );
+}
+
+#[proptest]
+fn closing_stream_frees_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    let request = encode_allocate_request(
+        transaction_id,
+        &lifetime,
+        valid_username(&username_salt),
+        &secret,
+        nonce,
+    );
+
+    server.assert_commands(
+        from_client_stream(source, &request, now),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        client_stream_closed(source),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+
+    assert_eq!(server.server.num_allocations(), 0);
+}
+
 #[proptest]
 fn deallocate_once_time_expired(
     #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
@@ -49,7 +129,7 @@ fn deallocate_once_time_expired(
 ) {
     let now = Instant::now();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
     let secret = server.auth_secret();
 
     server.assert_commands(
@@ -85,32 +165,100 @@ fn deallocate_once_time_expired(
 }
 
 #[proptest]
-fn unauthenticated_allocate_triggers_authentication(
+fn previous_auth_secret_is_valid_during_grace_period(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let previous_secret = server.auth_secret().to_owned();
+
+    server
+        .server
+        .rotate_auth_secret(SecretString::from("new-secret".to_owned()), now);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &previous_secret,
+                nonce,
+            ),
+            now + Duration::from_secs(60),
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn previous_auth_secret_is_invalid_after_grace_period(
     #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     source: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
     // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
-    let first_nonce = Uuid::from_u128(0x0);
+    let new_nonce = Uuid::from_u128(0x0);
 
-    let mut server = TestServer::new(public_relay_addr);
-    let secret = server.auth_secret().to_owned();
+    let mut server = TestServer::new(public_relay_addr)
+        .with_auth_secret_grace_period(Duration::from_secs(30))
+        .with_nonce(nonce, source);
+    let previous_secret = server.auth_secret().to_owned();
+
+    server
+        .server
+        .rotate_auth_secret(SecretString::from("new-secret".to_owned()), now);
 
     server.assert_commands(
         from_client(
             source,
-            Allocate::new_unauthenticated_udp(transaction_id, Some(lifetime.clone())),
-            now,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &previous_secret,
+                nonce,
+            ),
+            now + Duration::from_secs(60),
         ),
         [send_message(
             source,
-            unauthorized_allocate_response(transaction_id, first_nonce),
+            unauthorized_allocate_response(transaction_id, new_nonce),
         )],
     );
+}
+
+#[proptest]
+fn evicting_allocation_frees_it(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
 
     server.assert_commands(
         from_client(
@@ -120,7 +268,7 @@ fn unauthenticated_allocate_triggers_authentication(
                 Some(lifetime.clone()),
                 valid_username(&username_salt),
                 &secret,
-                first_nonce,
+                nonce,
             ),
             now,
         ),
@@ -134,34 +282,1287 @@ fn unauthenticated_allocate_triggers_authentication(
     );
 
     assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + lifetime.lifetime())
+        server.server.allocations(now),
+        vec![AllocationInfo {
+            client: ClientSocket::new(source.into()),
+            port: AllocationPort::new(49152),
+            families: vec![AddressFamily::V4],
+            expires_in: lifetime.lifetime(),
+            bytes_relayed: 0,
+            channels: vec![],
+        }]
+    );
+
+    server.assert_commands(
+        evict_allocation(49152),
+        [free_allocation(49152, AddressFamily::V4)],
     );
+
+    assert_eq!(server.server.allocations(now), vec![]);
+    assert!(!server.server.evict_allocation(AllocationPort::new(49152)));
 }
 
 #[proptest]
-fn when_refreshed_in_time_allocation_does_not_expire(
-    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
-    #[strategy(firezone_relay::proptest::allocation_lifetime())] refresh_lifetime: Lifetime,
+fn audit_log_records_allocation_lifecycle(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let username = valid_username(&username_salt);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce, source)
+        .with_audit_log();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+    server.assert_commands(
+        forward_time_to(now + lifetime.lifetime() + Duration::from_secs(1)),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+
+    assert_eq!(
+        iter::from_fn(|| server.server.next_audit_record()).collect::<Vec<_>>(),
+        vec![
+            AuditRecord::AllocationCreated {
+                client: source.into(),
+                username: username.name().to_owned(),
+                port: 49152,
+                families: vec!["ip4"],
+                lifetime_secs: lifetime.lifetime().as_secs(),
+            },
+            AuditRecord::AllocationDeleted {
+                client: source.into(),
+                username: username.name().to_owned(),
+                port: 49152,
+                bytes_relayed: 0,
+                reason: DeletionReason::Expired,
+            }
+        ]
+    );
+}
+
+#[proptest]
+fn allocate_with_dont_fragment_sets_df_bit(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_dont_fragment_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation_dont_fragment(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn allocate_with_even_port_allocates_even_port(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::even_port())] even_port: EvenPort,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    let mut expected_response =
+        allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime);
+    if even_port.is_requested() {
+        // Tokens are generated randomly and we control the randomness in the test, thus this is deterministic.
+        expected_response.add_attribute(ReservationToken::new(0));
+    }
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_even_port_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+                even_port,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(source, expected_response),
+        ],
+    );
+}
+
+#[proptest]
+fn reserved_port_can_be_claimed_with_reservation_token(
+    #[strategy(firezone_relay::proptest::transaction_id())] first_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] first_nonce: Uuid,
+    #[strategy(firezone_relay::proptest::nonce())] second_nonce: Uuid,
+) {
+    let now = Instant::now();
+    let second_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(first_nonce, source)
+        .with_nonce(second_nonce, second_source);
+    let secret = server.auth_secret().to_owned();
+
+    let mut expected_response = allocate_response(
+        first_transaction_id,
+        public_relay_addr,
+        49152,
+        source,
+        &lifetime,
+    );
+    // Tokens are generated randomly and we control the randomness in the test, thus this is deterministic.
+    expected_response.add_attribute(ReservationToken::new(0));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_even_port_ip4(
+                first_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                first_nonce,
+                EvenPort::new(true),
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(source, expected_response),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_reservation_token(
+                second_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                second_nonce,
+                ReservationToken::new(0),
+            ),
+            now + Duration::from_secs(1),
+        ),
+        [
+            create_allocation(49153, AddressFamily::V4),
+            send_message(
+                second_source,
+                allocate_response(
+                    second_transaction_id,
+                    public_relay_addr,
+                    49153,
+                    second_source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn expired_reservation_token_is_rejected(
+    #[strategy(firezone_relay::proptest::transaction_id())] first_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] first_nonce: Uuid,
+    #[strategy(firezone_relay::proptest::nonce())] second_nonce: Uuid,
+) {
+    let now = Instant::now();
+    let second_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(first_nonce, source)
+        .with_nonce(second_nonce, second_source);
+    let secret = server.auth_secret().to_owned();
+
+    let mut expected_response = allocate_response(
+        first_transaction_id,
+        public_relay_addr,
+        49152,
+        source,
+        &lifetime,
+    );
+    expected_response.add_attribute(ReservationToken::new(0));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_even_port_ip4(
+                first_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                first_nonce,
+                EvenPort::new(true),
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(source, expected_response),
+        ],
+    );
+    server.assert_commands(forward_time_to(now + Duration::from_secs(31)), []);
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_reservation_token(
+                second_transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                second_nonce,
+                ReservationToken::new(0),
+            ),
+            now + Duration::from_secs(31),
+        ),
+        [send_message(
+            second_source,
+            insufficient_capacity_allocate_response(second_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn unknown_reservation_token_is_rejected(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::reservation_token())] token: ReservationToken,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_reservation_token(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+                token,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            insufficient_capacity_allocate_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn draining_server_redirects_allocate_to_alternate_server(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_server: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce, source)
+        .with_alternate_servers(vec![alternate_server.into()])
+        .draining();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            Instant::now(),
+        ),
+        [send_message(
+            source,
+            try_alternate_allocate_response(transaction_id, alternate_server),
+        )],
+    );
+}
+
+#[proptest]
+fn draining_server_without_reachable_alternate_server_rejects_allocate(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_server: SocketAddrV6,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce, source)
+        .with_alternate_servers(vec![alternate_server.into()])
+        .draining();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            Instant::now(),
+        ),
+        [send_message(
+            source,
+            insufficient_capacity_allocate_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn unauthenticated_allocate_triggers_authentication(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let now = Instant::now();
+
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let first_nonce = Uuid::from_u128(0x0);
+
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_unauthenticated_udp(transaction_id, Some(lifetime.clone())),
+            now,
+        ),
+        [send_message(
+            source,
+            unauthorized_allocate_response(transaction_id, first_nonce),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                first_nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + lifetime.lifetime())
+    );
+}
+
+#[proptest]
+fn allocate_with_expired_nonce_triggers_stale_nonce(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let new_nonce = Uuid::from_u128(0x0);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce_lifetime(Duration::from_secs(60))
+        .with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now + Duration::from_secs(61),
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, new_nonce),
+        )],
+    );
+}
+
+#[proptest]
+fn allocate_with_nonce_of_other_client_triggers_stale_nonce(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let new_nonce = Uuid::from_u128(0x0);
+    let other_client = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, other_client);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, new_nonce),
+        )],
+    );
+}
+
+#[proptest]
+fn when_refreshed_in_time_allocation_does_not_expire(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] refresh_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let first_wake = now + allocate_lifetime.lifetime();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(allocate_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &allocate_lifetime,
+                ),
+            ),
+        ],
+    );
+
+    assert_eq!(server.server.poll_timeout(), Some(first_wake));
+
+    // Forward time
+    let now = now + allocate_lifetime.lifetime() / 2;
+    let second_wake = now + refresh_lifetime.lifetime();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(refresh_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            refresh_response(refresh_transaction_id, refresh_lifetime.clone()),
+        )],
+    );
+
+    assert_eq!(server.server.poll_timeout(), Some(second_wake));
+
+    // The allocation MUST NOT be expired 1 sec before its refresh lifetime.
+    // Note that depending on how the lifetimes were generated, this may still be before the initial allocation lifetime.
+    // This is okay because lifetimes do not roll over, i.e. a refresh is not "added" to the initial lifetime but the allocation's lifetime is simply computed from now + requested lifetime of the refresh request.
+    server.assert_commands(
+        forward_time_to(now + refresh_lifetime.lifetime() - Duration::from_secs(1)),
+        [],
+    );
+}
+
+#[proptest]
+fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let first_wake = now + allocate_lifetime.lifetime();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(allocate_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &allocate_lifetime,
+                ),
+            ),
+        ],
+    );
+
+    assert_eq!(server.server.poll_timeout(), Some(first_wake));
+
+    // Forward time
+    let now = now + allocate_lifetime.lifetime() / 2;
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(Lifetime::new(Duration::ZERO).unwrap()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            free_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                refresh_response(
+                    refresh_transaction_id,
+                    Lifetime::new(Duration::ZERO).unwrap(),
+                ),
+            ),
+        ],
+    );
+
+    // Assert that forwarding time does not produce an obsolete event.
+    server.assert_commands(forward_time_to(first_wake + Duration::from_secs(1)), []);
+}
+
+#[proptest]
+fn freeing_allocation_clears_all_channels(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddr,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    let _ = server.server.handle_client_message(
+        ClientMessage::Allocate(Allocate::new_authenticated_udp_implicit_ip4(
+            allocate_transaction_id,
+            None,
+            valid_username(&username_salt),
+            &secret,
+            nonce,
+        )),
+        ClientSocket::new(source),
+        now,
+    );
+    let _ = server.server.handle_client_message(
+        ClientMessage::ChannelBind(ChannelBind::new(
+            channel_bind_transaction_id,
+            channel,
+            XorPeerAddress::new(peer.into()),
+            valid_username(&username_salt),
+            &secret,
+            nonce,
+        )),
+        ClientSocket::new(source),
+        now,
+    );
+    let _ = server.server.handle_client_message(
+        ClientMessage::Refresh(Refresh::new(
+            refresh_transaction_id,
+            Some(Lifetime::new(Duration::ZERO).unwrap()),
+            valid_username(&username_salt),
+            &secret,
+            nonce,
+        )),
+        ClientSocket::new(source),
+        now,
+    );
+
+    assert_eq!(server.server.num_active_channels(), 0);
+}
+
+// #[test]
+// fn server_waits_for_5_minutes_before_allowing_reuse_of_channel_number_after_expiry() {
+//     // todo!()
+// }
+
+#[proptest]
+fn ping_pong_relay(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + lifetime.lifetime())
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                client_to_peer_ping.channel(),
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + Duration::from_secs(60 * 10))
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    let maybe_forward = server.server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(source.into()),
+        now,
+    );
+
+    assert_eq!(
+        maybe_forward,
+        Some((AllocationPort::new(49152), PeerSocket::new(peer.into())))
+    );
+
+    let maybe_forward = server.server.handle_peer_traffic(
+        peer_to_client_ping.as_slice(),
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
+        now,
+    );
+
+    assert_eq!(
+        maybe_forward,
+        Some((
+            ClientSocket::new(source.into()),
+            client_to_peer_ping.channel()
+        ))
+    );
+}
+
+#[proptest]
+fn imported_state_continues_allocation_and_channel(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                client_to_peer_ping.channel(),
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    let state = server.server.export_state(now);
+
+    // A different RNG gives the new server a different auth secret, which the import must replace.
+    let mut server = TestServer {
+        server: Server::new(public_relay_addr, StepRng::new(1, 1), 49152, 65535),
+    };
+    let now = now + Duration::from_secs(1);
+
+    server.server.import_state(state, now);
+
+    assert_eq!(
+        server.server.handle_client_input(
+            client_to_peer_ping.as_msg(),
+            ClientSocket::new(source.into()),
+            now,
+        ),
+        Some((AllocationPort::new(49152), PeerSocket::new(peer.into())))
+    );
+    assert_eq!(
+        server.server.handle_peer_traffic(
+            peer_to_client_ping.as_slice(),
+            PeerSocket::new(peer.into()),
+            AllocationPort::new(49152),
+            now,
+        ),
+        Some((
+            ClientSocket::new(source.into()),
+            client_to_peer_ping.channel()
+        ))
+    );
+
+    // The client can continue to use its credentials and nonce.
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            refresh_response(refresh_transaction_id, lifetime.clone()),
+        )],
+    );
+}
+
+#[proptest]
+fn exported_state_skips_allocations_of_stream_clients(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+
+    let request = encode_allocate_request(
+        transaction_id,
+        &lifetime,
+        valid_username(&username_salt),
+        &secret,
+        nonce,
+    );
+
+    server.assert_commands(
+        from_client_stream(source, &request, now),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    let state = server.server.export_state(now);
+    assert_eq!(state.allocation_ports().count(), 0);
+
+    // The connection doesn't survive the handover, thus the new server must not treat the client as a UDP client.
+    let mut server = TestServer {
+        server: Server::new(public_relay_addr, StepRng::new(1, 1), 49152, 65535),
+    };
+    server.server.import_state(state, now);
+
+    assert_eq!(server.server.num_allocations(), 0);
+}
+
+#[proptest]
+fn allows_rebind_channel_after_expiry(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_2_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    peer2: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + lifetime.lifetime())
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    let channel_expiry = now + Duration::from_secs(60 * 10);
+    let channel_rebind = channel_expiry + Duration::from_secs(60 * 5);
+
+    assert_eq!(server.server.poll_timeout(), Some(channel_expiry));
+
+    let now = now + Duration::from_secs(60 * 10 + 1);
+
+    server.server.handle_timeout(now);
+    assert_eq!(server.server.poll_timeout(), Some(channel_rebind));
+
+    let now = now + Duration::from_secs(60 * 5 + 1);
+
+    server.server.handle_timeout(now);
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_2_transaction_id,
+                channel,
+                XorPeerAddress::new(peer2.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_2_transaction_id),
+        )],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + Duration::from_secs(60 * 10)) // For channel expiry
+    );
+}
+
+#[proptest]
+fn ping_pong_ip6_relay(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV6,
+    peer: SocketAddrV6,
+    public_relay_ip4_addr: Ipv4Addr,
+    public_relay_ip6_addr: Ipv6Addr,
+    peer_to_client_ping: [u8; 32],
+    mut client_to_peer_ping: [u8; 36],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let mut server =
+        TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr)).with_nonce(nonce, source);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_ip6(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V6),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_ip6_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + lifetime.lifetime())
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + Duration::from_secs(60 * 10))
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    ChannelData::encode_header_to_slice(channel, 32, &mut client_to_peer_ping[..4]);
+    let maybe_forward = server.server.handle_client_input(
+        client_to_peer_ping.as_slice(),
+        ClientSocket::new(source.into()),
+        now,
+    );
+
+    assert_eq!(
+        maybe_forward,
+        Some((AllocationPort::new(49152), PeerSocket::new(peer.into())))
+    );
+
+    let maybe_forward = server.server.handle_peer_traffic(
+        peer_to_client_ping.as_slice(),
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
+        now,
+    );
+
+    assert_eq!(
+        maybe_forward,
+        Some((ClientSocket::new(source.into()), channel))
+    );
+}
+
+#[proptest]
+fn send_indication_with_permission_is_relayed_to_peer(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     source: SocketAddrV4,
+    peer: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
+    payload: Vec<u8>,
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
     let secret = server.auth_secret().to_owned();
-    let first_wake = now + allocate_lifetime.lifetime();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
 
     server.assert_commands(
         from_client(
             source,
             Allocate::new_authenticated_udp_implicit_ip4(
                 allocate_transaction_id,
-                Some(allocate_lifetime.clone()),
+                Some(lifetime.clone()),
                 valid_username(&username_salt),
                 &secret,
                 nonce,
@@ -177,24 +1578,18 @@ fn when_refreshed_in_time_allocation_does_not_expire(
                     public_relay_addr,
                     49152,
                     source,
-                    &allocate_lifetime,
+                    &lifetime,
                 ),
             ),
         ],
     );
 
-    assert_eq!(server.server.poll_timeout(), Some(first_wake));
-
-    // Forward time
-    let now = now + allocate_lifetime.lifetime() / 2;
-    let second_wake = now + refresh_lifetime.lifetime();
-
     server.assert_commands(
         from_client(
             source,
-            Refresh::new(
-                refresh_transaction_id,
-                Some(refresh_lifetime.clone()),
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
                 valid_username(&username_salt),
                 &secret,
                 nonce,
@@ -203,43 +1598,57 @@ fn when_refreshed_in_time_allocation_does_not_expire(
         ),
         [send_message(
             source,
-            refresh_response(refresh_transaction_id, refresh_lifetime.clone()),
+            create_permission_response(create_permission_transaction_id),
         )],
     );
 
-    assert_eq!(server.server.poll_timeout(), Some(second_wake));
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + lifetime.lifetime())
+    );
 
-    // The allocation MUST NOT be expired 1 sec before its refresh lifetime.
-    // Note that depending on how the lifetimes were generated, this may still be before the initial allocation lifetime.
-    // This is okay because lifetimes do not roll over, i.e. a refresh is not "added" to the initial lifetime but the allocation's lifetime is simply computed from now + requested lifetime of the refresh request.
     server.assert_commands(
-        forward_time_to(now + refresh_lifetime.lifetime() - Duration::from_secs(1)),
-        [],
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                payload.clone(),
+            ),
+            now,
+        ),
+        [relay_to_peer(49152, peer, payload)],
     );
 }
 
 #[proptest]
-fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
+fn permission_installed_by_channel_bind_expires_before_channel(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     source: SocketAddrV4,
+    peer: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
+    payload: Vec<u8>,
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
     let secret = server.auth_secret().to_owned();
-    let first_wake = now + allocate_lifetime.lifetime();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
 
     server.assert_commands(
         from_client(
             source,
             Allocate::new_authenticated_udp_implicit_ip4(
                 allocate_transaction_id,
-                Some(allocate_lifetime.clone()),
+                Some(lifetime.clone()),
                 valid_username(&username_salt),
                 &secret,
                 nonce,
@@ -255,128 +1664,71 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
                     public_relay_addr,
                     49152,
                     source,
-                    &allocate_lifetime,
+                    &lifetime,
                 ),
             ),
         ],
     );
 
-    assert_eq!(server.server.poll_timeout(), Some(first_wake));
-
-    // Forward time
-    let now = now + allocate_lifetime.lifetime() / 2;
-
     server.assert_commands(
         from_client(
             source,
-            Refresh::new(
-                refresh_transaction_id,
-                Some(Lifetime::new(Duration::ZERO).unwrap()),
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
                 valid_username(&username_salt),
                 &secret,
                 nonce,
             ),
             now,
         ),
-        [
-            free_allocation(49152, AddressFamily::V4),
-            send_message(
-                source,
-                refresh_response(
-                    refresh_transaction_id,
-                    Lifetime::new(Duration::ZERO).unwrap(),
-                ),
-            ),
-        ],
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
     );
 
-    // Assert that forwarding time does not produce an obsolete event.
-    server.assert_commands(forward_time_to(first_wake + Duration::from_secs(1)), []);
-}
-
-#[proptest]
-fn freeing_allocation_clears_all_channels(
-    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())]
-    channel_bind_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
-    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
-    source: SocketAddr,
-    peer: SocketAddrV4,
-    public_relay_addr: Ipv4Addr,
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
-) {
-    let now = Instant::now();
-
-    let _ = env_logger::try_init();
+    assert_eq!(
+        server.server.poll_timeout(),
+        Some(now + Duration::from_secs(60 * 10))
+    );
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
-    let secret = server.auth_secret().to_owned();
+    // The channel lasts 10 minutes but the permission only 5.
+    let now = now + Duration::from_secs(60 * 5);
 
-    let _ = server.server.handle_client_message(
-        ClientMessage::Allocate(Allocate::new_authenticated_udp_implicit_ip4(
-            allocate_transaction_id,
-            None,
-            valid_username(&username_salt),
-            &secret,
-            nonce,
-        )),
-        ClientSocket::new(source),
-        now,
-    );
-    let _ = server.server.handle_client_message(
-        ClientMessage::ChannelBind(ChannelBind::new(
-            channel_bind_transaction_id,
-            channel,
-            XorPeerAddress::new(peer.into()),
-            valid_username(&username_salt),
-            &secret,
-            nonce,
-        )),
-        ClientSocket::new(source),
-        now,
-    );
-    let _ = server.server.handle_client_message(
-        ClientMessage::Refresh(Refresh::new(
-            refresh_transaction_id,
-            Some(Lifetime::new(Duration::ZERO).unwrap()),
-            valid_username(&username_salt),
-            &secret,
-            nonce,
-        )),
-        ClientSocket::new(source),
-        now,
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                payload,
+            ),
+            now,
+        ),
+        [],
     );
-
-    assert_eq!(server.server.num_active_channels(), 0);
 }
 
-// #[test]
-// fn server_waits_for_5_minutes_before_allowing_reuse_of_channel_number_after_expiry() {
-//     // todo!()
-// }
-
 #[proptest]
-fn ping_pong_relay(
+fn send_indication_without_permission_is_discarded(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())]
-    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     source: SocketAddrV4,
     peer: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
-    peer_to_client_ping: [u8; 32],
-    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    payload: Vec<u8>,
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
     let _ = env_logger::try_init();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
     let secret = server.auth_secret().to_owned();
-    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
 
     server.assert_commands(
         from_client(
@@ -405,87 +1757,39 @@ fn ping_pong_relay(
         ],
     );
 
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + lifetime.lifetime())
-    );
-
-    let now = now + Duration::from_secs(1);
-
     server.assert_commands(
         from_client(
             source,
-            ChannelBind::new(
-                channel_bind_transaction_id,
-                client_to_peer_ping.channel(),
+            SendIndication::new(
+                send_transaction_id,
                 XorPeerAddress::new(peer.into()),
-                valid_username(&username_salt),
-                &secret,
-                nonce,
+                payload,
             ),
             now,
         ),
-        [send_message(
-            source,
-            channel_bind_response(channel_bind_transaction_id),
-        )],
-    );
-
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + Duration::from_secs(60 * 10))
-    );
-
-    let now = now + Duration::from_secs(1);
-
-    let maybe_forward = server.server.handle_client_input(
-        client_to_peer_ping.as_msg(),
-        ClientSocket::new(source.into()),
-        now,
-    );
-
-    assert_eq!(
-        maybe_forward,
-        Some((AllocationPort::new(49152), PeerSocket::new(peer.into())))
-    );
-
-    let maybe_forward = server.server.handle_peer_traffic(
-        peer_to_client_ping.as_slice(),
-        PeerSocket::new(peer.into()),
-        AllocationPort::new(49152),
-    );
-
-    assert_eq!(
-        maybe_forward,
-        Some((
-            ClientSocket::new(source.into()),
-            client_to_peer_ping.channel()
-        ))
+        [],
     );
 }
 
 #[proptest]
-fn allows_rebind_channel_after_expiry(
+fn peer_traffic_with_permission_but_without_channel_is_relayed_as_data_indication(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::transaction_id())]
-    channel_bind_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::transaction_id())]
-    channel_bind_2_transaction_id: TransactionId,
+    create_permission_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
-    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     source: SocketAddrV4,
     peer: SocketAddrV4,
-    peer2: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
+    peer_to_client_ping: [u8; 32],
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
     let _ = env_logger::try_init();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce, source);
     let secret = server.auth_secret().to_owned();
-    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
 
     server.assert_commands(
         from_client(
@@ -514,20 +1818,15 @@ fn allows_rebind_channel_after_expiry(
         ],
     );
 
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + lifetime.lifetime())
-    );
-
-    let now = now + Duration::from_secs(1);
+    // Without a permission, data from the peer is discarded.
+    server.assert_commands(from_peer(peer, &peer_to_client_ping, 49152, now), []);
 
     server.assert_commands(
         from_client(
             source,
-            ChannelBind::new(
-                channel_bind_transaction_id,
-                channel,
-                XorPeerAddress::new(peer.into()),
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
                 valid_username(&username_salt),
                 &secret,
                 nonce,
@@ -536,79 +1835,51 @@ fn allows_rebind_channel_after_expiry(
         ),
         [send_message(
             source,
-            channel_bind_response(channel_bind_transaction_id),
+            create_permission_response(create_permission_transaction_id),
         )],
-    );
-
-    let channel_expiry = now + Duration::from_secs(60 * 10);
-    let channel_rebind = channel_expiry + Duration::from_secs(60 * 5);
-
-    assert_eq!(server.server.poll_timeout(), Some(channel_expiry));
-
-    let now = now + Duration::from_secs(60 * 10 + 1);
-
-    server.server.handle_timeout(now);
-    assert_eq!(server.server.poll_timeout(), Some(channel_rebind));
-
-    let now = now + Duration::from_secs(60 * 5 + 1);
-
-    server.server.handle_timeout(now);
-
-    let now = now + Duration::from_secs(1);
-
-    server.assert_commands(
-        from_client(
-            source,
-            ChannelBind::new(
-                channel_bind_2_transaction_id,
-                channel,
-                XorPeerAddress::new(peer2.into()),
-                valid_username(&username_salt),
-                &secret,
-                nonce,
-            ),
-            now,
-        ),
+    );
+
+    server.assert_commands(
+        from_peer(peer, &peer_to_client_ping, 49152, now),
         [send_message(
             source,
-            channel_bind_response(channel_bind_2_transaction_id),
+            data_indication(peer, &peer_to_client_ping),
         )],
     );
 
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + Duration::from_secs(60 * 10)) // For channel expiry
-    );
+    // Once the permission expires, data from the peer is discarded again.
+    let now = now + Duration::from_secs(60 * 5);
+
+    server.assert_commands(forward_time_to(now), []);
+    server.assert_commands(from_peer(peer, &peer_to_client_ping, 49152, now), []);
 }
 
 #[proptest]
-fn ping_pong_ip6_relay(
+fn peer_traffic_exceeding_allocation_bandwidth_limit_is_dropped(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::transaction_id())]
     channel_bind_transaction_id: TransactionId,
-    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
-    source: SocketAddrV6,
-    peer: SocketAddrV6,
-    public_relay_ip4_addr: Ipv4Addr,
-    public_relay_ip6_addr: Ipv6Addr,
-    peer_to_client_ping: [u8; 32],
-    mut client_to_peer_ping: [u8; 36],
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let now = Instant::now();
 
     let _ = env_logger::try_init();
 
-    let mut server =
-        TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr)).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce, source)
+        .with_allocation_bandwidth_limit(BandwidthLimit::new(1_000));
     let secret = server.auth_secret().to_owned();
-    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
 
     server.assert_commands(
         from_client(
             source,
-            Allocate::new_authenticated_udp_ip6(
+            Allocate::new_authenticated_udp_implicit_ip4(
                 allocate_transaction_id,
                 Some(lifetime.clone()),
                 valid_username(&username_salt),
@@ -618,12 +1889,12 @@ fn ping_pong_ip6_relay(
             now,
         ),
         [
-            create_allocation(49152, AddressFamily::V6),
+            create_allocation(49152, AddressFamily::V4),
             send_message(
                 source,
                 allocate_response(
                     allocate_transaction_id,
-                    public_relay_ip6_addr,
+                    public_relay_addr,
                     49152,
                     source,
                     &lifetime,
@@ -631,14 +1902,6 @@ fn ping_pong_ip6_relay(
             ),
         ],
     );
-
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + lifetime.lifetime())
-    );
-
-    let now = now + Duration::from_secs(1);
-
     server.assert_commands(
         from_client(
             source,
@@ -658,35 +1921,184 @@ fn ping_pong_ip6_relay(
         )],
     );
 
-    assert_eq!(
-        server.server.poll_timeout(),
-        Some(now + Duration::from_secs(60 * 10))
-    );
-
-    let now = now + Duration::from_secs(1);
+    let payload = [0u8; 60_000];
+    let expected = Some((ClientSocket::new(source.into()), channel));
 
-    ChannelData::encode_header_to_slice(channel, 32, &mut client_to_peer_ping[..4]);
-    let maybe_forward = server.server.handle_client_input(
-        client_to_peer_ping.as_slice(),
-        ClientSocket::new(source.into()),
+    // The first packet fits into the initial burst.
+    let maybe_forward = server.server.handle_peer_traffic(
+        &payload,
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
         now,
     );
+    assert_eq!(maybe_forward, expected);
 
-    assert_eq!(
-        maybe_forward,
-        Some((AllocationPort::new(49152), PeerSocket::new(peer.into())))
+    // The second one exceeds the limit.
+    let maybe_forward = server.server.handle_peer_traffic(
+        &payload,
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
+        now,
     );
+    assert_eq!(maybe_forward, None);
+
+    // Once enough time has passed, data is relayed again.
+    let now = now + Duration::from_secs(60);
 
     let maybe_forward = server.server.handle_peer_traffic(
-        peer_to_client_ping.as_slice(),
+        &payload,
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
+    assert_eq!(maybe_forward, expected);
+}
 
-    assert_eq!(
-        maybe_forward,
-        Some((ClientSocket::new(source.into()), channel))
+#[proptest]
+fn drops_binding_requests_exceeding_rate_limit(
+    #[strategy(firezone_relay::proptest::binding())] first: Binding,
+    #[strategy(firezone_relay::proptest::binding())] second: Binding,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_unauthenticated_request_rate_limit(RequestRateLimit::new(1));
+
+    let transaction_id = first.transaction_id();
+
+    server.assert_commands(
+        from_client(source, first, now),
+        [send_message(
+            source,
+            binding_response(transaction_id, source),
+        )],
+    );
+    server.assert_commands(from_client(source, second, now), []);
+}
+
+#[proptest]
+fn answers_binding_requests_again_once_rate_limit_refills(
+    #[strategy(firezone_relay::proptest::binding())] first: Binding,
+    #[strategy(firezone_relay::proptest::binding())] second: Binding,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_unauthenticated_request_rate_limit(RequestRateLimit::new(1));
+
+    let first_transaction_id = first.transaction_id();
+    let second_transaction_id = second.transaction_id();
+
+    server.assert_commands(
+        from_client(source, first, now),
+        [send_message(
+            source,
+            binding_response(first_transaction_id, source),
+        )],
+    );
+    server.assert_commands(
+        from_client(source, second, now + Duration::from_secs(1)),
+        [send_message(
+            source,
+            binding_response(second_transaction_id, source),
+        )],
+    );
+}
+
+#[proptest]
+fn drops_error_responses_to_clients_without_allocation_exceeding_rate_limit(
+    #[strategy(firezone_relay::proptest::transaction_id())] first: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_unauthenticated_request_rate_limit(RequestRateLimit::new(1));
+
+    // An allocate request without a REQUESTED-TRANSPORT is a bad request.
+    let first_request = MessageEncoder::new()
+        .encode_into_bytes(Message::<Attribute>::new(
+            MessageClass::Request,
+            ALLOCATE,
+            first,
+        ))
+        .unwrap();
+    let second_request = MessageEncoder::new()
+        .encode_into_bytes(Message::<Attribute>::new(
+            MessageClass::Request,
+            ALLOCATE,
+            second,
+        ))
+        .unwrap();
+
+    server.assert_commands(
+        from_client_bytes(source, &first_request, now),
+        [send_message(source, bad_request_allocate_response(first))],
     );
+    server.assert_commands(from_client_bytes(source, &second_request, now), []);
+}
+
+#[proptest]
+fn drops_requests_from_denied_clients(
+    #[strategy(firezone_relay::proptest::binding())] request: Binding,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_client_deny_list(vec![IpNetwork::from(*source.ip())]);
+
+    server.assert_commands(from_client(source, request, Instant::now()), []);
+}
+
+#[proptest]
+fn does_not_answer_malformed_requests_from_denied_clients(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_client_deny_list(vec![IpNetwork::from(*source.ip())]);
+
+    // An allocate request without a REQUESTED-TRANSPORT is a bad request.
+    let request = MessageEncoder::new()
+        .encode_into_bytes(Message::<Attribute>::new(
+            MessageClass::Request,
+            ALLOCATE,
+            transaction_id,
+        ))
+        .unwrap();
+
+    server.assert_commands(from_client_bytes(source, &request, now), []);
+    server.assert_commands(from_client_stream(source, &request, now), []);
+}
+
+#[proptest]
+fn drops_requests_from_clients_not_on_allow_list(
+    #[strategy(firezone_relay::proptest::binding())] request: Binding,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+
+    let allowed = Ipv4Addr::from(u32::from(*source.ip()).wrapping_add(1));
+    let mut server =
+        TestServer::new(public_relay_addr).with_client_allow_list(vec![IpNetwork::from(allowed)]);
+
+    server.assert_commands(from_client(source, request, Instant::now()), []);
 }
 
 struct TestServer {
@@ -700,8 +2112,63 @@ impl TestServer {
         }
     }
 
-    fn with_nonce(mut self, nonce: Uuid) -> Self {
-        self.server.add_nonce(nonce);
+    fn with_nonce(mut self, nonce: Uuid, client: impl Into<SocketAddr>) -> Self {
+        self.server
+            .add_nonce(nonce, ClientSocket::new(client.into()), Instant::now());
+
+        self
+    }
+
+    fn with_nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.server = self.server.with_nonce_lifetime(lifetime);
+
+        self
+    }
+
+    fn with_auth_secret_grace_period(mut self, grace_period: Duration) -> Self {
+        self.server = self.server.with_auth_secret_grace_period(grace_period);
+
+        self
+    }
+
+    fn with_allocation_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.server = self.server.with_allocation_bandwidth_limit(limit);
+
+        self
+    }
+
+    fn with_unauthenticated_request_rate_limit(mut self, limit: RequestRateLimit) -> Self {
+        self.server = self.server.with_unauthenticated_request_rate_limit(limit);
+
+        self
+    }
+
+    fn with_audit_log(mut self) -> Self {
+        self.server = self.server.with_audit_log();
+
+        self
+    }
+
+    fn with_alternate_servers(mut self, servers: Vec<SocketAddr>) -> Self {
+        self.server = self.server.with_alternate_servers(servers);
+
+        self
+    }
+
+    fn draining(mut self) -> Self {
+        self.server.start_draining();
+
+        self
+    }
+
+    fn with_client_allow_list(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.server = self.server.with_client_allow_list(networks);
+
+        self
+    }
+
+    fn with_client_deny_list(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.server = self.server.with_client_deny_list(networks);
 
         self
     }
@@ -715,9 +2182,27 @@ impl TestServer {
             Input::Client(sender, message, now) => {
                 self.server.handle_client_message(message, sender, now);
             }
+            Input::ClientBytes(sender, bytes, now) => {
+                self.server.handle_client_input(bytes, sender, now);
+            }
+            Input::ClientStream(sender, bytes, now) => {
+                self.server
+                    .handle_client_stream_input(bytes, sender, now)
+                    .unwrap();
+            }
+            Input::ClientStreamClosed(sender) => {
+                self.server.handle_client_stream_closed(sender);
+            }
+            Input::Peer(sender, bytes, allocation, now) => {
+                self.server
+                    .handle_peer_traffic(bytes, sender, allocation, now);
+            }
             Input::Time(now) => {
                 self.server.handle_timeout(now);
             }
+            Input::EvictAllocation(port) => {
+                assert!(self.server.evict_allocation(port));
+            }
         }
 
         for expected_output in output {
@@ -726,12 +2211,15 @@ impl TestServer {
                     Output::SendMessage((recipient, msg)) => {
                         format!("to send message {:?} to {recipient}", msg)
                     }
-                    CreateAllocation(port, family) => {
+                    CreateAllocation(port, family, _) => {
                         format!("to create allocation on port {port} for address family {family}")
                     }
                     FreeAllocation(port, family) => {
                         format!("to free allocation on port {port} for address family {family}")
                     }
+                    RelayToPeer(port, peer, _) => {
+                        format!("to relay data to {peer} from port {port}")
+                    }
                 };
 
                 panic!("No commands produced but expected {msg}");
@@ -756,14 +2244,16 @@ impl TestServer {
                     assert_eq!(recipient, to);
                 }
                 (
-                    CreateAllocation(expected_port, expected_family),
+                    CreateAllocation(expected_port, expected_family, expected_dont_fragment),
                     Command::CreateAllocation {
                         port: actual_port,
                         family: actual_family,
+                        dont_fragment: actual_dont_fragment,
                     },
                 ) => {
                     assert_eq!(expected_port, actual_port);
                     assert_eq!(expected_family, actual_family);
+                    assert_eq!(expected_dont_fragment, actual_dont_fragment);
                 }
                 (
                     FreeAllocation(port, family),
@@ -775,6 +2265,18 @@ impl TestServer {
                     assert_eq!(port, actual_port);
                     assert_eq!(family, actual_family);
                 }
+                (
+                    RelayToPeer(port, peer, payload),
+                    Command::RelayToPeer {
+                        payload: actual_payload,
+                        port: actual_port,
+                        peer: actual_peer,
+                    },
+                ) => {
+                    assert_eq!(port, actual_port);
+                    assert_eq!(peer, actual_peer);
+                    assert_eq!(payload, actual_payload);
+                }
                 (expected, actual) => panic!("Unhandled combination: {expected:?} {actual:?}"),
             }
         }
@@ -839,6 +2341,44 @@ fn unauthorized_allocate_response(
     message
 }
 
+fn stale_nonce_allocate_response(transaction_id: TransactionId, nonce: Uuid) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(StaleNonce));
+    message.add_attribute(Nonce::new(nonce.as_hyphenated().to_string()).unwrap());
+    message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+
+    message
+}
+
+fn insufficient_capacity_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+    message
+}
+
+fn bad_request_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(BadRequest));
+
+    message
+}
+
+fn try_alternate_allocate_response(
+    transaction_id: TransactionId,
+    alternate_server: impl Into<SocketAddr>,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(TryAlternate));
+    message.add_attribute(AlternateServer::new(alternate_server.into()));
+
+    message
+}
+
 fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::SuccessResponse, REFRESH, transaction_id);
@@ -851,6 +2391,57 @@ fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::<Attribute>::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn create_permission_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    )
+}
+
+fn data_indication(peer: impl Into<SocketAddr>, data: &[u8]) -> Message<Attribute> {
+    // Transaction IDs are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let mut message = Message::<Attribute>::new(
+        MessageClass::Indication,
+        DATA,
+        TransactionId::new([0u8; 12]),
+    );
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+    message.add_attribute(Data::new(data.to_vec()).unwrap());
+
+    message
+}
+
+/// Encodes an authenticated allocate request, i.e. what a client sends over a stream.
+fn encode_allocate_request(
+    transaction_id: TransactionId,
+    lifetime: &Lifetime,
+    username: Username,
+    relay_secret: &SecretString,
+    nonce: Uuid,
+) -> Vec<u8> {
+    let (expiry, salt) = username.name().split_once(':').unwrap();
+    let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(expiry.parse().unwrap());
+    let password = firezone_relay::auth::generate_password(relay_secret, expiry, salt);
+
+    let mut message = Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
+    message.add_attribute(RequestedTransport::new(17));
+    message.add_attribute(username.clone());
+    message.add_attribute(Nonce::new(nonce.as_hyphenated().to_string()).unwrap());
+    message.add_attribute(lifetime.clone());
+
+    let message_integrity = MessageIntegrity::new_long_term_credential(
+        &message,
+        &username,
+        &firezone_relay::auth::FIREZONE,
+        &password,
+    )
+    .unwrap();
+    message.add_attribute(message_integrity);
+
+    MessageEncoder::new().encode_into_bytes(message).unwrap()
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -860,7 +2451,12 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, Instant),
+    ClientBytes(ClientSocket, &'a [u8], Instant),
+    ClientStream(ClientSocket, &'a [u8], Instant),
+    ClientStreamClosed(ClientSocket),
+    Peer(PeerSocket, &'a [u8], AllocationPort, Instant),
     Time(Instant),
+    EvictAllocation(AllocationPort),
 }
 
 fn from_client<'a>(
@@ -871,6 +2467,31 @@ fn from_client<'a>(
     Input::Client(ClientSocket::new(from.into()), message.into(), now)
 }
 
+fn from_client_bytes(from: impl Into<SocketAddr>, bytes: &[u8], now: Instant) -> Input<'_> {
+    Input::ClientBytes(ClientSocket::new(from.into()), bytes, now)
+}
+
+fn from_client_stream(from: impl Into<SocketAddr>, bytes: &[u8], now: Instant) -> Input<'_> {
+    Input::ClientStream(ClientSocket::new(from.into()), bytes, now)
+}
+
+fn evict_allocation<'a>(port: u16) -> Input<'a> {
+    Input::EvictAllocation(AllocationPort::new(port))
+}
+
+fn client_stream_closed<'a>(from: impl Into<SocketAddr>) -> Input<'a> {
+    Input::ClientStreamClosed(ClientSocket::new(from.into()))
+}
+
+fn from_peer(from: impl Into<SocketAddr>, bytes: &[u8], port: u16, now: Instant) -> Input<'_> {
+    Input::Peer(
+        PeerSocket::new(from.into()),
+        bytes,
+        AllocationPort::new(port),
+        now,
+    )
+}
+
 fn forward_time_to<'a>(when: Instant) -> Input<'a> {
     Input::Time(when)
 }
@@ -878,18 +2499,31 @@ fn forward_time_to<'a>(when: Instant) -> Input<'a> {
 #[derive(Debug)]
 enum Output {
     SendMessage((ClientSocket, Message<Attribute>)),
-    CreateAllocation(AllocationPort, AddressFamily),
+    CreateAllocation(AllocationPort, AddressFamily, bool),
     FreeAllocation(AllocationPort, AddressFamily),
+    RelayToPeer(AllocationPort, PeerSocket, Vec<u8>),
 }
 
 fn create_allocation(port: u16, fam: AddressFamily) -> Output {
-    Output::CreateAllocation(AllocationPort::new(port), fam)
+    Output::CreateAllocation(AllocationPort::new(port), fam, false)
+}
+
+fn create_allocation_dont_fragment(port: u16, fam: AddressFamily) -> Output {
+    Output::CreateAllocation(AllocationPort::new(port), fam, true)
 }
 
 fn free_allocation(port: u16, fam: AddressFamily) -> Output {
     Output::FreeAllocation(AllocationPort::new(port), fam)
 }
 
+fn relay_to_peer(port: u16, peer: impl Into<SocketAddr>, payload: Vec<u8>) -> Output {
+    Output::RelayToPeer(
+        AllocationPort::new(port),
+        PeerSocket::new(peer.into()),
+        payload,
+    )
+}
+
 fn send_message(source: impl Into<SocketAddr>, message: Message<Attribute>) -> Output {
     Output::SendMessage((ClientSocket::new(source.into()), message))
 }